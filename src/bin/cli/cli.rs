@@ -1,4 +1,11 @@
-use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Args, Parser, Subcommand};
+
+use feature_flags::config::ConfigArgs;
+use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+use feature_flags::db::projects::DEFAULT_PROJECT;
+use feature_flags::db::{FlagSort, FlagType, SortOrder};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -6,6 +13,21 @@ use clap::{Args, Parser, Subcommand};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    /// Project the flag names belong to
+    #[arg(long, global = true, default_value = DEFAULT_PROJECT)]
+    pub project: String,
+
+    /// Environment whose flag states are read and changed
+    #[arg(long, global = true, default_value = DEFAULT_ENVIRONMENT)]
+    pub env: String,
+
+    /// Who is making the change, recorded in the audit log (defaults to $USER)
+    #[arg(long, global = true)]
+    pub actor: Option<String>,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
 }
 
 #[derive(Subcommand, Debug)]
@@ -14,26 +36,86 @@ pub enum Commands {
     Update(UpdateArgs),
     Get(GetArgs),
     Delete(DeleteArgs),
+    /// Create, update and delete several flags at once
+    Batch(BatchArgs),
+    /// Show who changed a flag and when
+    History(HistoryArgs),
+    /// Manage a flag's named variations
+    Variations(VariationsArgs),
+    /// Manage environments
+    Envs(EnvsArgs),
+    /// Manage projects
+    Projects(ProjectsArgs),
+    /// Manage reusable user segments
+    Segments(SegmentsArgs),
+}
+
+fn parse_flag_type(input: &str) -> Result<FlagType, String> {
+    input
+        .parse()
+        .map_err(|_| "expected one of: bool, string, integer, float, json".to_string())
+}
+
+fn parse_flag_sort(input: &str) -> Result<FlagSort, String> {
+    input
+        .parse()
+        .map_err(|_| "expected one of: id, name, created_at, updated_at".to_string())
+}
+
+fn parse_sort_order(input: &str) -> Result<SortOrder, String> {
+    input
+        .parse()
+        .map_err(|_| "expected one of: asc, desc".to_string())
 }
 
 #[derive(Args, Debug)]
 pub struct CreateArgs {
     /// Flag Name
     pub name: String,
+
+    /// Flag Type (bool, string, integer, float or json)
+    #[arg(short = 't', long = "type", default_value = "bool", value_parser = parse_flag_type)]
+    pub flag_type: FlagType,
+
+    /// Flag Value (bool flags default to true)
+    #[arg(short, long)]
+    pub value: Option<String>,
+
+    /// What the flag is for
+    #[arg(short, long)]
+    pub description: Option<String>,
+
+    /// Person or team to ask about the flag
+    #[arg(short, long)]
+    pub owner: Option<String>,
+
+    /// Label of the flag, can be repeated
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
 }
 
 #[derive(Args, Debug)]
+#[command(group(ArgGroup::new("change").required(true).multiple(true)))]
 pub struct UpdateArgs {
     /// Flag Name
     #[arg(short, long, required = true)]
     pub name: String,
     /// Flag Value
-    #[arg(short, long, required = true, help = "flag value")]
-    pub value: Option<bool>,
+    #[arg(short, long, group = "change", help = "flag value")]
+    pub value: Option<String>,
+    /// Turn the flag on or off
+    #[arg(short, long, group = "change")]
+    pub enabled: Option<bool>,
+    /// Turn the flag on for this percentage of users only (100 for everyone)
+    #[arg(short, long, group = "change")]
+    pub rollout: Option<f64>,
+    /// Only update the flag while it is at this version, as shown by `get`
+    #[arg(long)]
+    pub expect_version: Option<u64>,
 }
 
 #[derive(Args, Debug)]
-#[group(required = true, multiple = false)]
+#[command(group(ArgGroup::new("which").required(true).args(["name", "all"])))]
 pub struct GetArgs {
     /// Flag Name
     #[arg(short, long)]
@@ -42,6 +124,42 @@ pub struct GetArgs {
     /// Show All Flags
     #[arg(short, long)]
     pub all: bool,
+
+    /// Only show flags of this owner
+    #[arg(long, conflicts_with = "name")]
+    pub owner: Option<String>,
+
+    /// Only show flags with this tag
+    #[arg(long, conflicts_with = "name")]
+    pub tag: Option<String>,
+
+    /// Only show flags whose name starts with this
+    #[arg(long, conflicts_with = "name")]
+    pub prefix: Option<String>,
+
+    /// Only show flags whose name contains this
+    #[arg(long, conflicts_with = "name")]
+    pub contains: Option<String>,
+
+    /// Only show flags serving this value, e.g. true, 30 or blue
+    #[arg(long, conflicts_with = "name")]
+    pub value: Option<String>,
+
+    /// Order flags by (id, name, created_at or updated_at)
+    #[arg(long, default_value = "id", value_parser = parse_flag_sort, conflicts_with = "name")]
+    pub sort: FlagSort,
+
+    /// Sort order (asc or desc)
+    #[arg(long, default_value = "asc", value_parser = parse_sort_order, conflicts_with = "name")]
+    pub order: SortOrder,
+
+    /// Show at most this many flags, followed by the cursor of the next ones
+    #[arg(long, conflicts_with = "name")]
+    pub limit: Option<u32>,
+
+    /// Continue a listing after the flags shown before
+    #[arg(long, conflicts_with = "name")]
+    pub cursor: Option<String>,
 }
 
 #[derive(Args, Debug)]
@@ -50,11 +168,185 @@ pub struct DeleteArgs {
     pub name: String,
 }
 
+#[derive(Args, Debug)]
+pub struct BatchArgs {
+    /// JSON file of the operations, as sent to `POST /flags:batch`, or - to read stdin
+    pub file: PathBuf,
+
+    /// Keep the changes that succeed when another one fails
+    #[arg(long)]
+    pub best_effort: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    /// Flag name
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct VariationsArgs {
+    #[command(subcommand)]
+    pub command: VariationCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VariationCommands {
+    /// List a flag's variations
+    List(VariationListArgs),
+    /// Add a variation to a flag
+    Add(VariationValueArgs),
+    /// Change the value of a variation
+    Update(VariationValueArgs),
+    /// Remove a variation from a flag
+    Delete(VariationDeleteArgs),
+    /// Choose the variations served when the flag is on and off
+    Serve(VariationServeArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct VariationListArgs {
+    /// Flag Name
+    pub flag: String,
+}
+
+#[derive(Args, Debug)]
+pub struct VariationValueArgs {
+    /// Flag Name
+    pub flag: String,
+    /// Variation Name
+    pub name: String,
+    /// Variation Value
+    pub value: String,
+}
+
+#[derive(Args, Debug)]
+pub struct VariationDeleteArgs {
+    /// Flag Name
+    pub flag: String,
+    /// Variation Name
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+#[command(group(ArgGroup::new("served").required(true).multiple(true)))]
+pub struct VariationServeArgs {
+    /// Flag Name
+    pub flag: String,
+    /// Variation served while the flag is on
+    #[arg(long, group = "served", conflicts_with = "no_default")]
+    pub default: Option<String>,
+    /// Variation served while the flag is off
+    #[arg(long, group = "served", conflicts_with = "no_off")]
+    pub off: Option<String>,
+    /// Stop serving a variation while the flag is on
+    #[arg(long, group = "served")]
+    pub no_default: bool,
+    /// Stop serving a variation while the flag is off
+    #[arg(long, group = "served")]
+    pub no_off: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct EnvsArgs {
+    #[command(subcommand)]
+    pub command: EnvCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum EnvCommands {
+    /// List environments
+    List,
+    /// Add an environment
+    Create(EnvCreateArgs),
+    /// Remove an environment and its flag states
+    Delete(EnvDeleteArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct EnvCreateArgs {
+    /// Environment Name
+    pub name: String,
+    /// Environment whose flag states are copied (defaults to production)
+    #[arg(long)]
+    pub copy_from: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct EnvDeleteArgs {
+    /// Environment Name
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ProjectsArgs {
+    #[command(subcommand)]
+    pub command: ProjectCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProjectCommands {
+    /// List projects
+    List,
+    /// Add a project
+    Create(ProjectNameArgs),
+    /// Remove a project without flags
+    Delete(ProjectNameArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ProjectNameArgs {
+    /// Project Name
+    pub name: String,
+}
+
+#[derive(Args, Debug)]
+pub struct SegmentsArgs {
+    #[command(subcommand)]
+    pub command: SegmentCommands,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SegmentCommands {
+    /// List the segments of the project
+    List,
+    /// Add a segment
+    Create(SegmentArgs),
+    /// Replace the given parts of a segment
+    Update(SegmentArgs),
+    /// Remove a segment that no rule uses
+    Delete(SegmentNameArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct SegmentArgs {
+    /// Segment Name
+    pub name: String,
+    /// User key that is always in the segment
+    #[arg(long)]
+    pub include: Vec<String>,
+    /// User key that is never in the segment
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    /// Attribute rules as JSON, e.g. '[{"clauses": [...]}]'
+    #[arg(long)]
+    pub rules: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct SegmentNameArgs {
+    /// Segment Name
+    pub name: String,
+}
+
 #[cfg(test)]
 mod tests {
     use clap::Parser;
 
-    use super::{Cli, Commands};
+    use super::{
+        Cli, Commands, EnvCommands, EnvsArgs, FlagSort, FlagType, SegmentCommands, SegmentsArgs,
+        SortOrder, VariationCommands, VariationsArgs,
+    };
 
     #[test]
     fn verify_cli() {
@@ -82,10 +374,55 @@ mod tests {
 
         match cli.command {
             Commands::Get(get) => {
-                assert_eq!(true, get.all, "Failed input: {:?}", input);
+                assert!(get.all, "Failed input: {:?}", input);
             }
             _ => panic!("Get subcommand was not called"),
         }
+
+        let input = vec!["my_prog", "get", "-a", "--tag", "checkout"];
+        match Cli::parse_from(input.clone()).command {
+            Commands::Get(get) => {
+                assert_eq!(
+                    Some("checkout".to_string()),
+                    get.tag,
+                    "Failed input: {:?}",
+                    input
+                );
+            }
+            _ => panic!("Get subcommand was not called"),
+        }
+
+        // Filters only apply to listings
+        let input = vec!["my_prog", "get", "-n", "test_name", "--owner", "payments"];
+        assert!(Cli::try_parse_from(input).is_err());
+        let input = vec!["my_prog", "get", "--owner", "payments"];
+        assert!(Cli::try_parse_from(input).is_err());
+
+        let input = vec![
+            "my_prog",
+            "get",
+            "-a",
+            "--sort",
+            "updated_at",
+            "--order",
+            "desc",
+            "--limit",
+            "20",
+        ];
+        match Cli::parse_from(input.clone()).command {
+            Commands::Get(get) => {
+                assert_eq!(
+                    (get.sort, get.order, get.limit),
+                    (FlagSort::UpdatedAt, SortOrder::Desc, Some(20)),
+                    "Failed input: {:?}",
+                    input
+                );
+            }
+            _ => panic!("Get subcommand was not called"),
+        }
+
+        let input = vec!["my_prog", "get", "-a", "--sort", "size"];
+        assert!(Cli::try_parse_from(input).is_err());
     }
 
     #[test]
@@ -101,6 +438,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_batch_command() {
+        let input = vec!["my_prog", "batch", "rollout.json", "--best-effort"];
+        match Cli::parse_from(input.clone()).command {
+            Commands::Batch(batch) => {
+                assert_eq!(
+                    (batch.file.to_str(), batch.best_effort),
+                    (Some("rollout.json"), true),
+                    "Failed input: {:?}",
+                    input
+                );
+            }
+            _ => panic!("Batch subcommand was not called"),
+        }
+    }
+
     #[test]
     fn test_create_command() {
         let cases = vec![
@@ -120,6 +473,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_create_typed_command() {
+        let input = vec![
+            "my_prog", "create", "timeout", "--type", "integer", "--value", "30",
+        ];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Create(create) => {
+                assert_eq!(
+                    FlagType::Integer,
+                    create.flag_type,
+                    "Failed input: {:?}",
+                    input
+                );
+                assert_eq!(Some("30".to_string()), create.value);
+            }
+            _ => panic!("Create subcommand was not called"),
+        }
+
+        let input = vec!["my_prog", "create", "timeout", "--type", "number"];
+        assert!(Cli::try_parse_from(input).is_err());
+    }
+
+    #[test]
+    fn test_create_command_metadata() {
+        let input = vec![
+            "my_prog",
+            "create",
+            "new_checkout",
+            "--owner",
+            "payments",
+            "--tag",
+            "checkout",
+            "--tag",
+            "temporary",
+        ];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Create(create) => {
+                assert_eq!(Some("payments".to_string()), create.owner);
+                assert_eq!(
+                    vec!["checkout", "temporary"],
+                    create.tags,
+                    "Failed input: {:?}",
+                    input
+                );
+                assert_eq!(None, create.description);
+            }
+            _ => panic!("Create subcommand was not called"),
+        }
+    }
+
     #[test]
     fn test_update_command() {
         let cases = vec![
@@ -133,15 +540,182 @@ mod tests {
             match cli.command {
                 Commands::Update(update) => {
                     assert_eq!(case[3], update.name, "Failed case: {:?}", case);
-                    assert_eq!(
-                        case[5],
-                        update.value.unwrap().to_string(),
-                        "Failed case: {:?}",
-                        case
-                    );
+                    assert_eq!(case[5], update.value.unwrap(), "Failed case: {:?}", case);
                 }
                 _ => panic!("Case failed: {:?}", case),
             }
         }
     }
+
+    #[test]
+    fn test_update_enabled_command() {
+        let input = vec!["my_prog", "update", "-n", "test_name", "--enabled", "false"];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Update(update) => {
+                assert_eq!(Some(false), update.enabled, "Failed input: {:?}", input);
+                assert_eq!(None, update.value, "Failed input: {:?}", input);
+            }
+            _ => panic!("Update subcommand was not called"),
+        }
+
+        let input = vec!["my_prog", "update", "-n", "test_name", "--rollout", "12.5"];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Update(update) => {
+                assert_eq!(Some(12.5), update.rollout, "Failed input: {:?}", input);
+            }
+            _ => panic!("Update subcommand was not called"),
+        }
+
+        // Something has to change
+        let input = vec!["my_prog", "update", "-n", "test_name"];
+        assert!(Cli::try_parse_from(input).is_err());
+    }
+
+    #[test]
+    fn test_variations_command() {
+        let input = vec!["my_prog", "variations", "add", "checkout", "control", "v1"];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Variations(VariationsArgs {
+                command: VariationCommands::Add(add),
+            }) => {
+                assert_eq!("checkout", add.flag, "Failed input: {:?}", input);
+                assert_eq!("control", add.name, "Failed input: {:?}", input);
+                assert_eq!("v1", add.value, "Failed input: {:?}", input);
+            }
+            _ => panic!("Variations add subcommand was not called"),
+        }
+
+        let input = vec![
+            "my_prog",
+            "variations",
+            "serve",
+            "checkout",
+            "--default",
+            "treatment-a",
+            "--no-off",
+        ];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Variations(VariationsArgs {
+                command: VariationCommands::Serve(serve),
+            }) => {
+                assert_eq!(Some("treatment-a".to_string()), serve.default);
+                assert!(serve.no_off, "Failed input: {:?}", input);
+            }
+            _ => panic!("Variations serve subcommand was not called"),
+        }
+
+        let input = vec!["my_prog", "variations", "serve", "checkout"];
+        assert!(Cli::try_parse_from(input).is_err());
+    }
+
+    #[test]
+    fn test_env_option() {
+        let input = vec!["my_prog", "get", "-a"];
+        let cli = Cli::parse_from(input);
+        assert_eq!("production", cli.env);
+        assert_eq!("default", cli.project);
+
+        let input = vec![
+            "my_prog",
+            "update",
+            "-n",
+            "test_name",
+            "-e",
+            "false",
+            "--env",
+            "staging",
+        ];
+        let cli = Cli::parse_from(input.clone());
+        assert_eq!("staging", cli.env, "Failed input: {:?}", input);
+    }
+
+    #[test]
+    fn test_envs_command() {
+        let input = vec!["my_prog", "envs", "create", "staging", "--copy-from", "qa"];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Envs(EnvsArgs {
+                command: EnvCommands::Create(create),
+            }) => {
+                assert_eq!("staging", create.name, "Failed input: {:?}", input);
+                assert_eq!(Some("qa".to_string()), create.copy_from);
+            }
+            _ => panic!("Envs create subcommand was not called"),
+        }
+    }
+
+    #[test]
+    fn test_project_option() {
+        let input = vec![
+            "my_prog",
+            "--project",
+            "payments",
+            "get",
+            "-n",
+            "new_checkout",
+        ];
+        let cli = Cli::parse_from(input.clone());
+        assert_eq!("payments", cli.project, "Failed input: {:?}", input);
+
+        let input = vec!["my_prog", "delete", "new_checkout", "--project", "payments"];
+        let cli = Cli::parse_from(input.clone());
+        assert_eq!("payments", cli.project, "Failed input: {:?}", input);
+    }
+
+    #[test]
+    fn test_history_command() {
+        let input = vec!["my_prog", "history", "new_checkout", "--actor", "ada"];
+        let cli = Cli::parse_from(input.clone());
+        assert_eq!(
+            Some("ada".to_string()),
+            cli.actor,
+            "Failed input: {:?}",
+            input
+        );
+
+        match cli.command {
+            Commands::History(history) => {
+                assert_eq!("new_checkout", history.name, "Failed input: {:?}", input);
+            }
+            _ => panic!("History subcommand was not called"),
+        }
+    }
+
+    #[test]
+    fn test_segments_command() {
+        let input = vec![
+            "my_prog",
+            "segments",
+            "create",
+            "beta-testers",
+            "--include",
+            "ada",
+            "--include",
+            "bob",
+            "--exclude",
+            "eve",
+        ];
+        let cli = Cli::parse_from(input.clone());
+
+        match cli.command {
+            Commands::Segments(SegmentsArgs {
+                command: SegmentCommands::Create(create),
+            }) => {
+                assert_eq!("beta-testers", create.name, "Failed input: {:?}", input);
+                assert_eq!(vec!["ada", "bob"], create.include);
+                assert_eq!(vec!["eve"], create.exclude);
+                assert_eq!(None, create.rules);
+            }
+            _ => panic!("Segments create subcommand was not called"),
+        }
+    }
 }
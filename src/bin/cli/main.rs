@@ -1,52 +1,162 @@
-use std::io;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
 
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, EnvCommands, ProjectCommands, SegmentCommands, VariationCommands};
 
 mod cli;
 mod subcommands;
 
 use clap::Parser;
-use feature_flags::db::get_db_rc;
-
-fn convert_bool_to_sqlite_bool(value: bool) -> i32 {
-    match value {
-        true => 1,
-        false => 0,
-    }
-}
+use feature_flags::db::events::DEFAULT_ACTOR;
+use feature_flags::db::{open_db, FlagFilter, FlagPage};
+use feature_flags::store::{FlagStore, SqliteStore};
 
 fn main() {
     let cli_app = Cli::parse();
 
-    let db = get_db_rc();
+    let conn = cli_app
+        .config
+        .load()
+        .and_then(|config| open_db(&config.db, false));
+    let store = match conn {
+        Ok(conn) => SqliteStore::new(conn),
+        Err(err) => {
+            eprintln!("Unable to open the database: {}", err);
+            process::exit(1);
+        }
+    };
     let stdout = io::stdout();
     let writer = stdout.lock();
 
+    let project = cli_app.project.as_str();
+    let env = cli_app.env.as_str();
+    let actor = cli_app
+        .actor
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or_else(|| DEFAULT_ACTOR.to_string());
+    let actor = actor.as_str();
+
     match cli_app.command {
         Commands::Get(args) => {
             if let Some(name) = args.name {
-                subcommands::get_flags::get_flag(db, name, writer);
+                subcommands::get_flags::get_flag(&store, project, env, name, writer);
             } else if args.all {
-                subcommands::all_flags::all_flags(db, writer);
+                let filter = FlagFilter {
+                    owner: args.owner,
+                    tag: args.tag,
+                    prefix: args.prefix,
+                    contains: args.contains,
+                    value: args.value,
+                };
+                let page = FlagPage {
+                    sort: args.sort,
+                    order: args.order,
+                    limit: args.limit,
+                    cursor: args.cursor,
+                };
+                subcommands::all_flags::all_flags(&store, project, env, &filter, &page, writer);
             }
         }
         Commands::Create(args) => {
-            // All new flags are true
-            let value = convert_bool_to_sqlite_bool(true);
-
-            subcommands::create_flags::create_flag(db, args.name, value, writer);
+            subcommands::create_flags::create_flag(&store, project, env, args, actor, writer);
         }
         Commands::Update(args) => {
-            let name = args.name;
-            let value = convert_bool_to_sqlite_bool(args.value.unwrap());
-
-            subcommands::update_flags::update_flag(db, name, value, writer);
+            subcommands::update_flags::update_flag(&store, project, env, args, actor, writer);
         }
         Commands::Delete(args) => {
-            subcommands::delete_flags::delete_flag(db, args.name, writer);
+            subcommands::delete_flags::delete_flag(&store, project, args.name, actor, writer);
+        }
+        Commands::Batch(args) => {
+            let input: io::Result<Box<dyn Read>> = match args.file.to_str() {
+                Some("-") => Ok(Box::new(io::stdin())),
+                _ => File::open(&args.file).map(|file| Box::new(file) as Box<dyn Read>),
+            };
+            match input {
+                Ok(input) => subcommands::batch::apply_batch(
+                    &store,
+                    project,
+                    env,
+                    input,
+                    args.best_effort,
+                    actor,
+                    writer,
+                ),
+                Err(err) => {
+                    eprintln!("Unable to read {}: {}", args.file.display(), err);
+                    process::exit(1);
+                }
+            }
         }
+        Commands::History(args) => {
+            subcommands::history::flag_history(&store, project, args.name, writer);
+        }
+        Commands::Variations(args) => variations(&store, project, env, args.command, actor, writer),
+        Commands::Projects(args) => match args.command {
+            ProjectCommands::List => subcommands::projects::list_projects(&store, writer),
+            ProjectCommands::Create(args) => {
+                subcommands::projects::create_project(&store, args.name, writer)
+            }
+            ProjectCommands::Delete(args) => {
+                subcommands::projects::delete_project(&store, args.name, writer)
+            }
+        },
+        Commands::Segments(args) => match args.command {
+            SegmentCommands::List => subcommands::segments::list_segments(&store, project, writer),
+            SegmentCommands::Create(args) => {
+                subcommands::segments::create_segment(&store, project, args, writer)
+            }
+            SegmentCommands::Update(args) => {
+                subcommands::segments::update_segment(&store, project, args, actor, writer)
+            }
+            SegmentCommands::Delete(args) => {
+                subcommands::segments::delete_segment(&store, project, args.name, writer)
+            }
+        },
+        Commands::Envs(args) => match args.command {
+            EnvCommands::List => subcommands::environments::list_environments(&store, writer),
+            EnvCommands::Create(args) => subcommands::environments::create_environment(
+                &store,
+                args.name,
+                args.copy_from,
+                writer,
+            ),
+            EnvCommands::Delete(args) => {
+                subcommands::environments::delete_environment(&store, args.name, writer)
+            }
+        },
     };
 }
 
+fn variations(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    command: VariationCommands,
+    actor: &str,
+    writer: impl Write,
+) {
+    match command {
+        VariationCommands::List(args) => {
+            subcommands::variations::list_variations(store, project, env, args.flag, writer);
+        }
+        VariationCommands::Add(args) => {
+            subcommands::variations::add_variation(store, project, env, args, actor, writer);
+        }
+        VariationCommands::Update(args) => {
+            subcommands::variations::update_variation(store, project, env, args, actor, writer);
+        }
+        VariationCommands::Delete(args) => {
+            subcommands::variations::delete_variation(
+                store, project, env, args.flag, args.name, actor, writer,
+            );
+        }
+        VariationCommands::Serve(args) => {
+            subcommands::variations::serve_variations(store, project, env, args, actor, writer);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {}
@@ -1,69 +1,169 @@
 use std::io::Write;
 
-use feature_flags::db::{get_all_flags, DBLocal};
-
-pub fn all_flags(db: DBLocal, mut writer: impl Write) {
-    let rows = get_all_flags(db).expect("Unable to get all flags");
-    for flag in rows {
-        writer
-            .write_all(format!("flag: {}: {}\n", flag.name, flag.value).as_bytes())
-            .unwrap();
+use feature_flags::db::{FlagFilter, FlagPage};
+use feature_flags::store::FlagStore;
+
+/// Lists one page of the flags matching `filter`, ending with the cursor of the next page when
+/// there is one.
+pub fn all_flags(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    filter: &FlagFilter,
+    page: &FlagPage,
+    mut writer: impl Write,
+) {
+    match store.list_flags(project, env, filter, page) {
+        Ok(list) => {
+            for flag in list.flags {
+                writer
+                    .write_all(format!("flag: {}: {}\n", flag.name, flag.value).as_bytes())
+                    .unwrap();
+            }
+            if let Some(next) = list.next {
+                writer
+                    .write_all(format!("More with --cursor {}\n", next).as_bytes())
+                    .unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to list flags: {}\n", err).as_bytes())
+            .unwrap(),
     }
-    writer.write_all("Done\n".as_bytes()).unwrap();
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::BufWriter;
-    use std::rc::Rc;
-
-    use rusqlite::Connection;
 
-    use feature_flags::db;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagSort, FlagValue, SortOrder};
+    use feature_flags::store::MemoryStore;
 
     use super::*;
 
-    fn in_memory_db() -> db::DBLocal {
-        let conn = Connection::open_in_memory().unwrap();
-
-        let local_conn = Rc::new(conn);
-
-        db::initialize_db(local_conn.clone()).unwrap();
-
-        local_conn
-    }
-
     #[test]
     fn test_all_flags() {
-        let conn = in_memory_db();
+        let store = MemoryStore::new();
 
         let mut buffer = [0u8; 5];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
         // Case: Zero Flags
-        let _ = all_flags(conn.clone(), buf_writer);
+        all_flags(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &FlagFilter::default(),
+            &FlagPage::default(),
+            buf_writer,
+        );
 
         assert_eq!(std::str::from_utf8(&buffer).unwrap(), "Done\n");
 
         // Case: More than Zero Flags
         let flags = vec![
-            ("test_1".to_string(), 0),
-            ("test_2".to_string(), 1),
-            ("test_3".to_string(), 0),
+            ("test_1".to_string(), FlagValue::Bool(false), "web"),
+            ("test_2".to_string(), FlagValue::Bool(true), "mobile"),
+            ("test_3".to_string(), FlagValue::Integer(30), "web"),
         ];
 
-        for (name, value) in flags {
-            let _ = db::add_flag(conn.clone(), name, value).unwrap();
+        for (name, value, tag) in flags {
+            store
+                .add_flag(
+                    DEFAULT_PROJECT,
+                    DEFAULT_ENVIRONMENT,
+                    Flag {
+                        name,
+                        flag_type: None,
+                        value,
+                        metadata: FlagMetadata {
+                            tags: vec![tag.to_string()],
+                            ..FlagMetadata::default()
+                        },
+                    },
+                    "test",
+                )
+                .unwrap();
         }
 
-        let mut buffer = [0u8; 64];
+        let mut buffer = [0u8; 61];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
-        let _ = all_flags(conn.clone(), buf_writer);
+        all_flags(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &FlagFilter::default(),
+            &FlagPage::default(),
+            buf_writer,
+        );
 
         assert_eq!(
             std::str::from_utf8(&buffer).unwrap(),
-            "flag: test_1: false\nflag: test_2: true\nflag: test_3: false\nDone\n"
+            "flag: test_1: false\nflag: test_2: true\nflag: test_3: 30\nDone\n"
+        );
+
+        // Case: Filtered by tag
+        let mut buffer = [0u8; 42];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+        let filter = FlagFilter {
+            tag: Some("web".to_string()),
+            ..FlagFilter::default()
+        };
+
+        all_flags(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &filter,
+            &FlagPage::default(),
+            buf_writer,
+        );
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "flag: test_1: false\nflag: test_3: 30\nDone\n"
+        );
+
+        // Case: Sorted by name, one page at a time
+        let mut page = FlagPage {
+            sort: FlagSort::Name,
+            order: SortOrder::Desc,
+            limit: Some(2),
+            cursor: None,
+        };
+        let mut buffer = vec![];
+        all_flags(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &FlagFilter::default(),
+            &page,
+            &mut buffer,
+        );
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[..2], ["flag: test_3: 30", "flag: test_2: true"]);
+        assert_eq!(lines[3], "Done");
+
+        page.cursor = lines[2]
+            .strip_prefix("More with --cursor ")
+            .map(str::to_string);
+        let mut buffer = vec![];
+        all_flags(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &FlagFilter::default(),
+            &page,
+            &mut buffer,
+        );
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "flag: test_1: false\nDone\n"
         );
     }
 }
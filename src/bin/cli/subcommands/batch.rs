@@ -0,0 +1,128 @@
+use std::io::{Read, Write};
+
+use feature_flags::db::batch::{Batch, BatchMode, OperationResult};
+use feature_flags::store::FlagStore;
+
+/// Applies the batch read from `input`, as `POST /flags:batch` does, and writes what became of
+/// every operation.
+pub fn apply_batch(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    input: impl Read,
+    best_effort: bool,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let mut batch: Batch = match serde_json::from_reader(input) {
+        Ok(batch) => batch,
+        Err(err) => {
+            writer
+                .write_all(format!("Failed to read the batch: {}\n", err).as_bytes())
+                .unwrap();
+            return;
+        }
+    };
+    if best_effort {
+        batch.mode = BatchMode::BestEffort;
+    }
+
+    let names: Vec<String> = batch
+        .operations
+        .iter()
+        .map(|operation| operation.name().to_string())
+        .collect();
+    let batch = match store.apply_batch(project, env, batch, actor) {
+        Ok(batch) => batch,
+        Err(err) => {
+            writer
+                .write_all(format!("Failed to apply the batch: {}\n", err).as_bytes())
+                .unwrap();
+            return;
+        }
+    };
+
+    for (name, result) in names.iter().zip(batch.results) {
+        let line = match result {
+            OperationResult::Created(_) => format!("Flag {} was created\n", name),
+            OperationResult::Updated(_) => format!("Flag {} was updated\n", name),
+            OperationResult::Deleted(_) => format!("Flag {} was deleted\n", name),
+            OperationResult::Failed(err) => format!("Failed to change flag {}: {}\n", name, err),
+            OperationResult::Aborted => format!("Flag {} was left unchanged\n", name),
+        };
+        writer.write_all(line.as_bytes()).unwrap();
+    }
+
+    match batch.committed {
+        true => writer.write_all("Done\n".as_bytes()).unwrap(),
+        false => writer
+            .write_all("Nothing was changed\n".as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    const BATCH: &str = r#"{"operations": [
+        {"op": "create", "flag": {"name": "checkout", "value": true}},
+        {"op": "update", "name": "search", "update": {"enabled": false}}
+    ]}"#;
+
+    fn run(store: &MemoryStore, input: &str, best_effort: bool) -> String {
+        let mut output = Vec::new();
+        apply_batch(
+            store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            input.as_bytes(),
+            best_effort,
+            "test",
+            &mut output,
+        );
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let store = MemoryStore::new();
+
+        assert_eq!(
+            run(&store, BATCH, false),
+            "Flag checkout was left unchanged\n\
+             Failed to change flag search: Flag search was not found\n\
+             Nothing was changed\n"
+        );
+        assert!(store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "checkout")
+            .is_err());
+
+        assert_eq!(
+            run(&store, BATCH, true),
+            "Flag checkout was created\n\
+             Failed to change flag search: Flag search was not found\n\
+             Done\n"
+        );
+        assert!(store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "checkout")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_apply_invalid_batch() {
+        let store = MemoryStore::new();
+
+        let output = run(&store, r#"{"operations": [{"op": "rename"}]}"#, false);
+        assert!(
+            output.starts_with("Failed to read the batch: "),
+            "{}",
+            output
+        );
+    }
+}
@@ -1,16 +1,53 @@
 use std::io::Write;
 
-use feature_flags::db::{add_flag, DBLocal};
+use feature_flags::db::{Flag, FlagMetadata, FlagType, FlagValue};
+use feature_flags::error::FeatureFlagError;
+use feature_flags::store::FlagStore;
 
-pub fn create_flag(db: DBLocal, name: String, value: i32, mut writer: impl Write) {
-    let result = add_flag(db, name, value);
+use crate::cli::CreateArgs;
+
+pub fn create_flag(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    args: CreateArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let flag_type = args.flag_type;
+    let value = match (flag_type, args.value) {
+        (_, Some(raw)) => FlagValue::parse(flag_type, &raw),
+        // All new bool flags are true
+        (FlagType::Bool, None) => Ok(FlagValue::Bool(true)),
+        (_, None) => Err(FeatureFlagError::Validation(format!(
+            "a value is required for {} flags",
+            flag_type
+        ))),
+    };
+
+    let name = args.name;
+    let metadata = FlagMetadata {
+        description: args.description,
+        owner: args.owner,
+        tags: args.tags,
+    };
+    let result = value.and_then(|value| {
+        let flag = Flag {
+            name,
+            flag_type: Some(flag_type),
+            value,
+            metadata,
+        };
+
+        store.add_flag(project, env, flag, actor)
+    });
 
     match result {
         Ok(_) => writer
             .write_all("Successfully added to the db\n".as_bytes())
             .unwrap(),
         Err(err) => writer
-            .write_all(format!("Failed to add to db: {:?}", err).as_bytes())
+            .write_all(format!("Failed to add to db: {}", err).as_bytes())
             .unwrap(),
     }
 }
@@ -18,36 +55,104 @@ pub fn create_flag(db: DBLocal, name: String, value: i32, mut writer: impl Write
 #[cfg(test)]
 mod tests {
     use std::io::BufWriter;
-    use std::rc::Rc;
 
-    use rusqlite::Connection;
-
-    use feature_flags::db;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::store::MemoryStore;
 
     use super::*;
 
-    fn in_memory_db() -> db::DBLocal {
-        let conn = Connection::open_in_memory().unwrap();
+    #[test]
+    fn test_create_flag() {
+        let store = MemoryStore::new();
 
-        let local_conn = Rc::new(conn);
+        let mut buffer = [0u8; 29];
+        let buf_writer = BufWriter::new(buffer.as_mut());
 
-        db::initialize_db(local_conn.clone()).unwrap();
+        create_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            CreateArgs {
+                name: "test".to_string(),
+                flag_type: FlagType::Bool,
+                value: None,
+                description: None,
+                owner: None,
+                tags: vec![],
+            },
+            "test",
+            buf_writer,
+        );
 
-        local_conn
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "Successfully added to the db\n"
+        );
     }
 
     #[test]
-    fn test_create_flag() {
-        let conn = in_memory_db();
+    fn test_create_typed_flag() {
+        let store = MemoryStore::new();
 
         let mut buffer = [0u8; 29];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
-        let _ = create_flag(conn.clone(), "test".to_string(), 0, buf_writer);
+        create_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            CreateArgs {
+                name: "timeout".to_string(),
+                flag_type: FlagType::Integer,
+                value: Some("30".to_string()),
+                description: Some("Seconds until checkout gives up".to_string()),
+                owner: Some("payments".to_string()),
+                tags: vec!["checkout".to_string()],
+            },
+            "test",
+            buf_writer,
+        );
 
         assert_eq!(
             std::str::from_utf8(&buffer).unwrap(),
             "Successfully added to the db\n"
         );
+
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "timeout")
+            .unwrap();
+        assert_eq!(flag.value, FlagValue::Integer(30));
+        assert_eq!(flag.metadata.owner.as_deref(), Some("payments"));
+        assert_eq!(flag.metadata.tags, vec!["checkout".to_string()]);
+        assert_eq!(flag.created_by, "test");
+    }
+
+    #[test]
+    fn test_create_flag_missing_value() {
+        let store = MemoryStore::new();
+
+        let mut buffer = [0u8; 128];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        create_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            CreateArgs {
+                name: "colour".to_string(),
+                flag_type: FlagType::String,
+                value: None,
+                description: None,
+                owner: None,
+                tags: vec![],
+            },
+            "test",
+            buf_writer,
+        );
+
+        assert!(std::str::from_utf8(&buffer)
+            .unwrap()
+            .starts_with("Failed to add to db: a value is required for string flags"));
     }
 }
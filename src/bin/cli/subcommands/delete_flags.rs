@@ -1,18 +1,30 @@
 use std::io::Write;
 
-use feature_flags::db::{delete_flag_by_name, DBLocal};
-
-pub fn delete_flag(db: DBLocal, name: String, mut writer: impl Write) {
-    let result = delete_flag_by_name(db, name);
+use feature_flags::db::DeleteOutcome;
+use feature_flags::store::FlagStore;
+
+pub fn delete_flag(
+    store: &impl FlagStore,
+    project: &str,
+    name: String,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let result = store.delete_flag_by_name(project, &name, actor);
     match result {
-        Ok(deleted) => {
+        Ok(DeleteOutcome::Deleted) => {
+            writer
+                .write_all(format!("Flag {} was deleted\n", name).as_bytes())
+                .unwrap();
+        }
+        Ok(DeleteOutcome::NotFound) => {
             writer
-                .write_all(format!("{} row deleted\n", deleted).as_bytes())
+                .write_all(format!("Flag {} was not found\n", name).as_bytes())
                 .unwrap();
         }
         Err(err) => {
             writer
-                .write_all(format!("delete failed: {:?}\n", err).as_bytes())
+                .write_all(format!("delete failed: {}\n", err).as_bytes())
                 .unwrap();
         }
     };
@@ -21,48 +33,66 @@ pub fn delete_flag(db: DBLocal, name: String, mut writer: impl Write) {
 #[cfg(test)]
 mod tests {
     use std::io::BufWriter;
-    use std::rc::Rc;
 
-    use rusqlite::Connection;
-
-    use feature_flags::db;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagType, FlagValue};
+    use feature_flags::store::MemoryStore;
 
     use super::*;
 
-    fn in_memory_db() -> db::DBLocal {
-        let conn = Connection::open_in_memory().unwrap();
-
-        let local_conn = Rc::new(conn);
-
-        db::initialize_db(local_conn.clone()).unwrap();
-
-        local_conn
-    }
-
     #[test]
     fn test_delete_flag() {
-        let conn = in_memory_db();
+        let store = MemoryStore::new();
 
-        let mut buffer = [0u8; 14];
+        let mut buffer = [0u8; 22];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
         // add flag to db
-        let _ = db::add_flag(conn.clone(), "test".to_string(), 0);
-
-        let _ = delete_flag(conn.clone(), "test".to_string(), buf_writer);
-
-        assert_eq!(std::str::from_utf8(&buffer).unwrap(), "1 row deleted\n");
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(false),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+
+        delete_flag(
+            &store,
+            DEFAULT_PROJECT,
+            "test".to_string(),
+            "test",
+            buf_writer,
+        );
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "Flag test was deleted\n"
+        );
     }
 
     #[test]
     fn test_delete_flag_zero_rows_deleted() {
-        let conn = in_memory_db();
+        let store = MemoryStore::new();
 
-        let mut buffer = [0u8; 14];
+        let mut buffer = [0u8; 24];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
-        let _ = delete_flag(conn.clone(), "test".to_string(), buf_writer);
-
-        assert_eq!(std::str::from_utf8(&buffer).unwrap(), "0 row deleted\n");
+        delete_flag(
+            &store,
+            DEFAULT_PROJECT,
+            "test".to_string(),
+            "test",
+            buf_writer,
+        );
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "Flag test was not found\n"
+        );
     }
 }
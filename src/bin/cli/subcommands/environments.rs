@@ -0,0 +1,102 @@
+use std::io::Write;
+
+use feature_flags::db::environments::NewEnvironment;
+use feature_flags::store::FlagStore;
+
+pub fn list_environments(store: &impl FlagStore, mut writer: impl Write) {
+    match store.get_environments() {
+        Ok(rows) => {
+            for environment in rows {
+                writer
+                    .write_all(format!("environment: {}\n", environment.name).as_bytes())
+                    .unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to list environments: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// The new environment starts with a copy of the flag states in `copy_from`.
+pub fn create_environment(
+    store: &impl FlagStore,
+    name: String,
+    copy_from: Option<String>,
+    mut writer: impl Write,
+) {
+    let result = store.add_environment(NewEnvironment { name, copy_from });
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully added the environment\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to add the environment: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+pub fn delete_environment(store: &impl FlagStore, name: String, mut writer: impl Write) {
+    match store.delete_environment(&name) {
+        Ok(deleted) => writer
+            .write_all(format!("{} environment deleted\n", deleted).as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("delete failed: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_create_and_list_environments() {
+        let store = MemoryStore::new();
+
+        create_environment(&store, "staging".to_string(), None, Vec::new());
+
+        let mut buffer = [0u8; 50];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        list_environments(&store, buf_writer);
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "environment: production\nenvironment: staging\nDone\n"
+        );
+    }
+
+    #[test]
+    fn test_delete_environment() {
+        let store = MemoryStore::new();
+
+        create_environment(&store, "staging".to_string(), None, Vec::new());
+
+        let mut buffer = [0u8; 22];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        delete_environment(&store, "staging".to_string(), buf_writer);
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "1 environment deleted\n"
+        );
+
+        let mut buffer = [0u8; 80];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        delete_environment(&store, "production".to_string(), buf_writer);
+
+        assert!(std::str::from_utf8(&buffer)
+            .unwrap()
+            .starts_with("delete failed: the production environment cannot be deleted"));
+    }
+}
@@ -1,12 +1,24 @@
 use std::io::Write;
 
-use feature_flags::db::{get_flag_by_name, DBLocal};
+use feature_flags::store::FlagStore;
 
-pub fn get_flag(db: DBLocal, name: String, mut writer: impl Write) {
-    let result = get_flag_by_name(db, name);
+pub fn get_flag(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    name: String,
+    mut writer: impl Write,
+) {
+    let result = store.get_flag_by_name(project, env, &name);
     match result {
         Ok(flag) => writer
-            .write_all(format!("Flag -- {}: {}\n", flag.name, flag.value).as_bytes())
+            .write_all(
+                format!(
+                    "Flag -- {}: {} (version {})\n",
+                    flag.name, flag.value, flag.version
+                )
+                .as_bytes(),
+            )
             .unwrap(),
         Err(_) => writer.write_all("No Flag Found\n".as_bytes()).unwrap(),
     };
@@ -15,39 +27,45 @@ pub fn get_flag(db: DBLocal, name: String, mut writer: impl Write) {
 #[cfg(test)]
 mod tests {
     use std::io::BufWriter;
-    use std::rc::Rc;
 
-    use rusqlite::Connection;
-
-    use feature_flags::db;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagType, FlagValue};
+    use feature_flags::store::MemoryStore;
 
     use super::*;
 
-    fn in_memory_db() -> db::DBLocal {
-        let conn = Connection::open_in_memory().unwrap();
-
-        let local_conn = Rc::new(conn);
-
-        db::initialize_db(local_conn.clone()).unwrap();
-
-        local_conn
-    }
-
     #[test]
     fn test_get_flag() {
-        let conn = in_memory_db();
+        let store = MemoryStore::new();
 
-        let mut buffer = [0u8; 20];
+        let mut buffer = [0u8; 32];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
         // add flag to db
-        let _ = db::add_flag(conn.clone(), "test".to_string(), 0);
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(false),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
 
-        let _ = get_flag(conn.clone(), "test".to_string(), buf_writer);
+        get_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "test".to_string(),
+            buf_writer,
+        );
 
         assert_eq!(
             std::str::from_utf8(&buffer).unwrap(),
-            "Flag -- test: false\n"
+            "Flag -- test: false (version 1)\n"
         );
     }
 }
@@ -0,0 +1,121 @@
+use std::io::Write;
+
+use serde_json::Value;
+
+use feature_flags::db::events::{FlagAction, FlagEvent};
+use feature_flags::store::FlagStore;
+
+/// Fields of the flag that an update changed, e.g. `enabled: true -> false`. The version and
+/// `updated_at` change with every update, so they are left out.
+fn changes(event: &FlagEvent) -> Vec<String> {
+    let (old, new) = match (&event.old_value, &event.new_value) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => (old, new),
+        _ => return vec![],
+    };
+
+    new.iter()
+        .filter(|(field, _)| !["version", "updated_at"].contains(&field.as_str()))
+        .filter(|(field, value)| old.get(*field) != Some(value))
+        .map(|(field, value)| {
+            let before = old.get(field).unwrap_or(&Value::Null);
+            format!("{}: {} -> {}", field, before, value)
+        })
+        .collect()
+}
+
+fn describe(event: &FlagEvent) -> String {
+    let mut line = format!(
+        "{} {} {}",
+        event.timestamp,
+        event.actor,
+        event.action.as_str()
+    );
+
+    if let Some(environment) = &event.environment {
+        line.push_str(&format!(" in {}", environment));
+    }
+    if event.action == FlagAction::Updated {
+        let changes = changes(event);
+        if !changes.is_empty() {
+            line.push_str(&format!(": {}", changes.join(", ")));
+        }
+    }
+
+    line
+}
+
+/// Lists every change made to flags called `name`, including flags that were deleted since.
+pub fn flag_history(store: &impl FlagStore, project: &str, name: String, mut writer: impl Write) {
+    match store.get_flag_history_by_name(project, &name) {
+        Ok(history) => {
+            for event in history {
+                writer
+                    .write_all(format!("{}\n", describe(&event)).as_bytes())
+                    .unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to read the history: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagType, FlagUpdate, FlagValue};
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_flag_history() {
+        let store = MemoryStore::new();
+
+        store
+            .add_flag(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                Flag {
+                    name: "test".to_string(),
+                    flag_type: Some(FlagType::Bool),
+                    value: FlagValue::Bool(true),
+                    metadata: FlagMetadata::default(),
+                },
+                "ada",
+            )
+            .unwrap();
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        store
+            .update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "bob")
+            .unwrap();
+        store
+            .delete_flag_by_name(DEFAULT_PROJECT, "test", "carol")
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        flag_history(&store, DEFAULT_PROJECT, "test".to_string(), &mut buffer);
+
+        // Lines start with the timestamp
+        let output = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = output
+            .lines()
+            .map(|line| line.split_once(' ').map_or(line, |(_, rest)| rest))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "ada created",
+                "bob updated in production: enabled: true -> false",
+                "carol deleted",
+                "Done",
+            ]
+        );
+    }
+}
@@ -1,5 +1,11 @@
 pub mod all_flags;
+pub mod batch;
 pub mod create_flags;
 pub mod delete_flags;
+pub mod environments;
 pub mod get_flags;
+pub mod history;
+pub mod projects;
+pub mod segments;
 pub mod update_flags;
+pub mod variations;
@@ -0,0 +1,83 @@
+use std::io::Write;
+
+use feature_flags::db::projects::NewProject;
+use feature_flags::store::FlagStore;
+
+pub fn list_projects(store: &impl FlagStore, mut writer: impl Write) {
+    match store.get_projects() {
+        Ok(rows) => {
+            for project in rows {
+                writer
+                    .write_all(format!("project: {}\n", project.name).as_bytes())
+                    .unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to list projects: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+pub fn create_project(store: &impl FlagStore, name: String, mut writer: impl Write) {
+    match store.add_project(NewProject { name }) {
+        Ok(_) => writer
+            .write_all("Successfully added the project\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to add the project: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// Only projects without flags can be deleted.
+pub fn delete_project(store: &impl FlagStore, name: String, mut writer: impl Write) {
+    match store.delete_project(&name) {
+        Ok(deleted) => writer
+            .write_all(format!("{} project deleted\n", deleted).as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("delete failed: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_create_and_list_projects() {
+        let store = MemoryStore::new();
+
+        create_project(&store, "payments".to_string(), Vec::new());
+
+        let mut buffer = [0u8; 40];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        list_projects(&store, buf_writer);
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "project: default\nproject: payments\nDone\n"
+        );
+    }
+
+    #[test]
+    fn test_delete_project() {
+        let store = MemoryStore::new();
+
+        create_project(&store, "payments".to_string(), Vec::new());
+
+        let mut buffer = [0u8; 18];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        delete_project(&store, "payments".to_string(), buf_writer);
+
+        assert_eq!(std::str::from_utf8(&buffer).unwrap(), "1 project deleted\n");
+    }
+}
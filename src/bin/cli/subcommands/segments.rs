@@ -0,0 +1,212 @@
+use std::io::Write;
+
+use feature_flags::db::segments::SegmentUpdate;
+use feature_flags::error::FeatureFlagError;
+use feature_flags::eval::{Segment, SegmentRule};
+use feature_flags::store::FlagStore;
+
+use crate::cli::SegmentArgs;
+
+fn parse_rules(rules: &str) -> Result<Vec<SegmentRule>, FeatureFlagError> {
+    serde_json::from_str(rules)
+        .map_err(|err| FeatureFlagError::Validation(format!("invalid segment rules: {}", err)))
+}
+
+pub fn list_segments(store: &impl FlagStore, project: &str, mut writer: impl Write) {
+    match store.get_segments(project) {
+        Ok(rows) => {
+            for segment in rows {
+                writer
+                    .write_all(
+                        format!(
+                            "segment: {} ({} included, {} excluded, {} rules)\n",
+                            segment.name,
+                            segment.included.len(),
+                            segment.excluded.len(),
+                            segment.rules.len()
+                        )
+                        .as_bytes(),
+                    )
+                    .unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to list segments: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+pub fn create_segment(
+    store: &impl FlagStore,
+    project: &str,
+    args: SegmentArgs,
+    mut writer: impl Write,
+) {
+    let result = args
+        .rules
+        .as_deref()
+        .map(parse_rules)
+        .transpose()
+        .and_then(|rules| {
+            let segment = Segment {
+                name: args.name,
+                included: args.include,
+                excluded: args.exclude,
+                rules: rules.unwrap_or_default(),
+            };
+
+            store.add_segment(project, segment)
+        });
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully added the segment\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to add the segment: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// Only the lists that are given replace the stored ones.
+pub fn update_segment(
+    store: &impl FlagStore,
+    project: &str,
+    args: SegmentArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let non_empty = |keys: Vec<String>| Some(keys).filter(|keys| !keys.is_empty());
+
+    let result = args
+        .rules
+        .as_deref()
+        .map(parse_rules)
+        .transpose()
+        .and_then(|rules| {
+            let update = SegmentUpdate {
+                included: non_empty(args.include),
+                excluded: non_empty(args.exclude),
+                rules,
+            };
+
+            store.update_segment(project, &args.name, update, actor)
+        });
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully updated the segment\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to update the segment: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// Segments that targeting rules still refer to are kept.
+pub fn delete_segment(store: &impl FlagStore, project: &str, name: String, mut writer: impl Write) {
+    match store.delete_segment(project, &name) {
+        Ok(deleted) => writer
+            .write_all(format!("{} segment deleted\n", deleted).as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("delete failed: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    fn segment_args(include: &[&str], rules: Option<&str>) -> SegmentArgs {
+        SegmentArgs {
+            name: "beta-testers".to_string(),
+            include: include.iter().map(|key| key.to_string()).collect(),
+            exclude: vec![],
+            rules: rules.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_create_and_list_segments() {
+        let store = MemoryStore::new();
+
+        create_segment(
+            &store,
+            DEFAULT_PROJECT,
+            segment_args(&["ada", "bob"], None),
+            Vec::new(),
+        );
+        update_segment(
+            &store,
+            DEFAULT_PROJECT,
+            segment_args(
+                &[],
+                Some(r#"[{"clauses": [{"attribute": "plan", "op": "in", "values": ["pro"]}]}]"#),
+            ),
+            "test",
+            Vec::new(),
+        );
+
+        let mut buffer = [0u8; 61];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        list_segments(&store, DEFAULT_PROJECT, buf_writer);
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "segment: beta-testers (2 included, 0 excluded, 1 rules)\nDone\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_segment_rules() {
+        let store = MemoryStore::new();
+
+        let mut buffer = [0u8; 60];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        create_segment(
+            &store,
+            DEFAULT_PROJECT,
+            segment_args(&["ada"], Some("nope")),
+            buf_writer,
+        );
+
+        assert!(std::str::from_utf8(&buffer)
+            .unwrap()
+            .starts_with("Failed to add the segment: invalid segment rules"));
+        assert!(store.get_segments(DEFAULT_PROJECT).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_delete_segment() {
+        let store = MemoryStore::new();
+
+        create_segment(
+            &store,
+            DEFAULT_PROJECT,
+            segment_args(&["ada"], None),
+            Vec::new(),
+        );
+
+        let mut buffer = [0u8; 18];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        delete_segment(
+            &store,
+            DEFAULT_PROJECT,
+            "beta-testers".to_string(),
+            buf_writer,
+        );
+
+        assert_eq!(std::str::from_utf8(&buffer).unwrap(), "1 segment deleted\n");
+    }
+}
@@ -1,16 +1,44 @@
 use std::io::Write;
 
-use feature_flags::db;
+use feature_flags::db::{FlagUpdate, FlagValue};
+use feature_flags::store::FlagStore;
 
-pub fn update_flag(conn: db::DBLocal, name: String, value: i32, mut writer: impl Write) {
-    let result = db::update_flag(conn, name, value);
+use crate::cli::UpdateArgs;
+
+/// The new value is parsed according to the type the flag was created with. A rollout of 100
+/// turns the flag on for everyone.
+pub fn update_flag(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    args: UpdateArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let result = store
+        .get_flag_by_name(project, env, &args.name)
+        .and_then(|flag| {
+            let value = match args.value {
+                Some(value) => Some(FlagValue::parse(flag.flag_type, &value)?),
+                None => None,
+            };
+            let update = FlagUpdate {
+                value,
+                enabled: args.enabled,
+                rollout_percentage: args.rollout.map(Some),
+                expected_version: args.expect_version,
+                ..FlagUpdate::default()
+            };
+
+            store.update_flag(project, env, flag.id as u64, update, actor)
+        });
 
     match result {
         Ok(_) => writer
             .write_all("Successfully updated the db\n".as_bytes())
             .unwrap(),
         Err(err) => writer
-            .write_all(format!("Failed to add to the db: {:?}", err).as_bytes())
+            .write_all(format!("Failed to add to the db: {}", err).as_bytes())
             .unwrap(),
     }
 }
@@ -18,39 +46,228 @@ pub fn update_flag(conn: db::DBLocal, name: String, value: i32, mut writer: impl
 #[cfg(test)]
 mod tests {
     use std::io::BufWriter;
-    use std::rc::Rc;
 
-    use rusqlite::Connection;
-
-    use feature_flags::db;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagType};
+    use feature_flags::store::MemoryStore;
 
     use super::*;
 
-    fn in_memory_db() -> db::DBLocal {
-        let conn = Connection::open_in_memory().unwrap();
+    #[test]
+    fn test_update_flag() {
+        let store = MemoryStore::new();
 
-        let local_conn = Rc::new(conn);
+        let mut buffer = [0u8; 28];
+        let buf_writer = BufWriter::new(buffer.as_mut());
 
-        db::initialize_db(local_conn.clone()).unwrap();
+        // add flag to db
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(false),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            UpdateArgs {
+                name: "test".to_string(),
+                value: Some("true".to_string()),
+                enabled: None,
+                rollout: None,
+                expect_version: None,
+            },
+            "test",
+            buf_writer,
+        );
 
-        local_conn
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "Successfully updated the db\n"
+        );
     }
 
     #[test]
-    fn test_update_flag() {
-        let conn = in_memory_db();
+    fn test_update_flag_invalid_value() {
+        let store = MemoryStore::new();
 
-        let mut buffer = [0u8; 28];
+        let mut buffer = [0u8; 80];
         let buf_writer = BufWriter::new(buffer.as_mut());
 
         // add flag to db
-        let _ = db::add_flag(conn.clone(), "test".to_string(), 0);
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "timeout".to_string(),
+                flag_type: Some(FlagType::Integer),
+                value: FlagValue::Integer(30),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            UpdateArgs {
+                name: "timeout".to_string(),
+                value: Some("soon".to_string()),
+                enabled: None,
+                rollout: None,
+                expect_version: None,
+            },
+            "test",
+            buf_writer,
+        );
 
-        let _ = update_flag(conn.clone(), "test".to_string(), 1, buf_writer);
+        assert!(std::str::from_utf8(&buffer)
+            .unwrap()
+            .starts_with("Failed to add to the db: 'soon' is not a valid integer value"));
 
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "timeout")
+            .unwrap();
+        assert_eq!(flag.value, FlagValue::Integer(30));
+    }
+
+    #[test]
+    fn test_update_flag_enabled() {
+        let store = MemoryStore::new();
+
+        // add flag to db
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(true),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            UpdateArgs {
+                name: "test".to_string(),
+                value: None,
+                enabled: Some(false),
+                rollout: None,
+                expect_version: None,
+            },
+            "test",
+            Vec::new(),
+        );
+
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "test")
+            .unwrap();
+        assert!(!flag.enabled);
+        assert_eq!(flag.value, FlagValue::Bool(true));
+    }
+
+    #[test]
+    fn test_update_flag_rollout() {
+        let store = MemoryStore::new();
+
+        // add flag to db
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(true),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            UpdateArgs {
+                name: "test".to_string(),
+                value: None,
+                enabled: None,
+                rollout: Some(25.0),
+                expect_version: None,
+            },
+            "test",
+            Vec::new(),
+        );
+
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "test")
+            .unwrap();
+        assert_eq!(flag.rollout_percentage, Some(25.0));
+    }
+
+    #[test]
+    fn test_update_flag_expect_version() {
+        let store = MemoryStore::new();
+
+        // add flag to db
+        let _ = store.add_flag(
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            Flag {
+                name: "test".to_string(),
+                flag_type: Some(FlagType::Bool),
+                value: FlagValue::Bool(true),
+                metadata: FlagMetadata::default(),
+            },
+            "test",
+        );
+        let args = |expect_version| UpdateArgs {
+            name: "test".to_string(),
+            value: None,
+            enabled: Some(false),
+            rollout: None,
+            expect_version,
+        };
+
+        let mut buffer = Vec::new();
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            args(Some(1)),
+            "test",
+            &mut buffer,
+        );
         assert_eq!(
-            std::str::from_utf8(&buffer).unwrap(),
+            String::from_utf8(buffer).unwrap(),
             "Successfully updated the db\n"
         );
+
+        // The flag is at version 2 now
+        let mut buffer = Vec::new();
+        update_flag(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            args(Some(1)),
+            "test",
+            &mut buffer,
+        );
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "Failed to add to the db: flag test is at version 2, not 1"
+        );
     }
 }
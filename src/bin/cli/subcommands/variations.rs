@@ -0,0 +1,276 @@
+use std::io::Write;
+
+use feature_flags::db::variations::Variation;
+use feature_flags::db::{FlagUpdate, FlagValue};
+use feature_flags::store::FlagStore;
+
+use crate::cli::{VariationServeArgs, VariationValueArgs};
+
+pub fn list_variations(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    flag: String,
+    mut writer: impl Write,
+) {
+    let result = store
+        .get_flag_by_name(project, env, &flag)
+        .and_then(|flag| {
+            store
+                .get_variations(project, flag.id as u64)
+                .map(|variations| (flag, variations))
+        });
+
+    match result {
+        Ok((flag, variations)) => {
+            for variation in variations {
+                let mut served = vec![];
+                if flag.default_variation.as_ref() == Some(&variation.name) {
+                    served.push("default");
+                }
+                if flag.off_variation.as_ref() == Some(&variation.name) {
+                    served.push("off");
+                }
+
+                let line = if served.is_empty() {
+                    format!("variation: {}: {}\n", variation.name, variation.value)
+                } else {
+                    format!(
+                        "variation: {}: {} [{}]\n",
+                        variation.name,
+                        variation.value,
+                        served.join(", ")
+                    )
+                };
+                writer.write_all(line.as_bytes()).unwrap();
+            }
+            writer.write_all("Done\n".as_bytes()).unwrap();
+        }
+        Err(err) => writer
+            .write_all(format!("Failed to list variations: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// `value` is parsed according to the type of the flag.
+pub fn add_variation(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    args: VariationValueArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let VariationValueArgs { flag, name, value } = args;
+    let result = store
+        .get_flag_by_name(project, env, &flag)
+        .and_then(|flag| {
+            let value = FlagValue::parse(flag.flag_type, &value)?;
+
+            store.add_variation(project, flag.id as u64, Variation { name, value }, actor)
+        });
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully added the variation\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to add the variation: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+pub fn update_variation(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    args: VariationValueArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let VariationValueArgs { flag, name, value } = args;
+    let result = store
+        .get_flag_by_name(project, env, &flag)
+        .and_then(|flag| {
+            let value = FlagValue::parse(flag.flag_type, &value)?;
+
+            store.update_variation(project, flag.id as u64, &name, value, actor)
+        });
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully updated the variation\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to update the variation: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+pub fn delete_variation(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    flag: String,
+    name: String,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let result = store
+        .get_flag_by_name(project, env, &flag)
+        .and_then(|flag| store.delete_variation(project, flag.id as u64, &name, actor));
+
+    match result {
+        Ok(deleted) => writer
+            .write_all(format!("{} variation deleted\n", deleted).as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("delete failed: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+/// Only the selections that are given change, `--no-*` stops serving a variation.
+pub fn serve_variations(
+    store: &impl FlagStore,
+    project: &str,
+    env: &str,
+    args: VariationServeArgs,
+    actor: &str,
+    mut writer: impl Write,
+) {
+    let default_variation = match args.no_default {
+        true => Some(None),
+        false => args.default.map(Some),
+    };
+    let off_variation = match args.no_off {
+        true => Some(None),
+        false => args.off.map(Some),
+    };
+    let update = FlagUpdate {
+        default_variation,
+        off_variation,
+        ..FlagUpdate::default()
+    };
+
+    let result = store
+        .get_flag_by_name(project, env, &args.flag)
+        .and_then(|flag| store.update_flag(project, env, flag.id as u64, update, actor));
+
+    match result {
+        Ok(_) => writer
+            .write_all("Successfully updated the served variations\n".as_bytes())
+            .unwrap(),
+        Err(err) => writer
+            .write_all(format!("Failed to update the served variations: {}\n", err).as_bytes())
+            .unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::{Flag, FlagMetadata, FlagType};
+    use feature_flags::store::MemoryStore;
+
+    use super::*;
+
+    fn in_memory_store() -> MemoryStore {
+        let store = MemoryStore::new();
+
+        store
+            .add_flag(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                Flag {
+                    name: "checkout".to_string(),
+                    flag_type: Some(FlagType::String),
+                    value: FlagValue::String("v1".to_string()),
+                    metadata: FlagMetadata::default(),
+                },
+                "test",
+            )
+            .unwrap();
+
+        store
+    }
+
+    #[test]
+    fn test_add_variation() {
+        let store = in_memory_store();
+
+        let mut buffer = [0u8; 33];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        add_variation(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            VariationValueArgs {
+                flag: "checkout".to_string(),
+                name: "control".to_string(),
+                value: "v1".to_string(),
+            },
+            "test",
+            buf_writer,
+        );
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "Successfully added the variation\n"
+        );
+    }
+
+    #[test]
+    fn test_list_served_variations() {
+        let store = in_memory_store();
+
+        for (name, value) in [("control", "v1"), ("treatment-a", "v2")] {
+            add_variation(
+                &store,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                VariationValueArgs {
+                    flag: "checkout".to_string(),
+                    name: name.to_string(),
+                    value: value.to_string(),
+                },
+                "test",
+                Vec::new(),
+            );
+        }
+        serve_variations(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            VariationServeArgs {
+                flag: "checkout".to_string(),
+                default: Some("treatment-a".to_string()),
+                off: Some("control".to_string()),
+                no_default: false,
+                no_off: false,
+            },
+            "test",
+            Vec::new(),
+        );
+
+        let mut buffer = [0u8; 71];
+        let buf_writer = BufWriter::new(buffer.as_mut());
+
+        list_variations(
+            &store,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+            buf_writer,
+        );
+
+        assert_eq!(
+            std::str::from_utf8(&buffer).unwrap(),
+            "variation: control: v1 [off]\nvariation: treatment-a: v2 [default]\nDone\n"
+        );
+    }
+}
@@ -1,7 +1,92 @@
-use feature_flags::db::{get_db_rc, initialize_db};
+use std::process;
+use std::rc::Rc;
+
+use clap::{Args, Parser};
+
+use feature_flags::config::ConfigArgs;
+use feature_flags::db::{initialize_db, migrations, open_db, reset_db};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Create or upgrade the feature flag database", long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    action: Action,
+
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+struct Action {
+    /// Apply pending migrations, keeping existing data
+    #[arg(long)]
+    migrate: bool,
+
+    /// Drop every table and rebuild the schema (destroys all data)
+    #[arg(long)]
+    reset: bool,
+}
 
 fn main() {
-    let conn = get_db_rc();
+    let cli = Cli::parse();
+
+    let conn = match cli
+        .config
+        .load()
+        .and_then(|config| open_db(&config.db, true))
+    {
+        Ok(conn) => Rc::new(conn),
+        Err(err) => {
+            eprintln!("Unable to open the database: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let applied = if cli.action.reset {
+        reset_db(conn.clone())
+    } else {
+        initialize_db(conn.clone())
+    };
+    let result = applied.and_then(|applied| Ok((applied, migrations::current_version(&conn)?)));
+
+    let (applied, version) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("Unable to migrate the database: {}", err);
+            process::exit(1);
+        }
+    };
+    println!(
+        "Applied {} migration(s), schema is at version {}",
+        applied, version
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::Cli;
+
+    #[test]
+    fn verify_cli() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert()
+    }
+
+    #[test]
+    fn test_requires_an_action() {
+        assert!(Cli::try_parse_from(vec!["db-init"]).is_err());
+        assert!(Cli::try_parse_from(vec!["db-init", "--migrate", "--reset"]).is_err());
+    }
+
+    #[test]
+    fn test_actions() {
+        let cli = Cli::parse_from(vec!["db-init", "--migrate"]);
+        assert!(cli.action.migrate && !cli.action.reset);
 
-    initialize_db(conn).expect("Unable to initialize DB");
+        let cli = Cli::parse_from(vec!["db-init", "--reset"]);
+        assert!(cli.action.reset && !cli.action.migrate);
+    }
 }
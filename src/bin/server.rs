@@ -1,9 +1,29 @@
-use std::env;
+// warp nests a type for every route
+#![recursion_limit = "256"]
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
 use warp::Filter;
 
+use clap::{Parser, ValueEnum};
+use hyper::service::{make_service_fn, service_fn, Service};
 use serde_derive::Serialize;
+use warp::http::{Response, StatusCode};
+use warp::Reply;
+
+use feature_flags::config::{Config, ConfigArgs, ServerConfig};
+use feature_flags::db::{open_db, FlagWithID};
+use feature_flags::error::{ErrorBody, FeatureFlagError};
+use feature_flags::eval::Rule;
+use feature_flags::store::{AsyncFlagStore, FileStore, FlagStore, SqliteStore};
 
-use feature_flags::db::get_db_server;
+use self::changes::{ChangeKind, Changes};
 
 #[derive(Serialize)]
 struct ResponseMessage {
@@ -11,304 +31,4435 @@ struct ResponseMessage {
     message: String,
 }
 
+/// Optional parts of the server, see `config::Features`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Feature {
+    Evaluation,
+    History,
+    Reload,
+}
+
+/// Settings given as flags take precedence over the environment variables and the config file,
+/// see `feature_flags::config`.
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Serve feature flags over HTTP", long_about = None)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+
+    /// Address to listen on, repeat for several [default: 127.0.0.1]
+    #[arg(long)]
+    listen: Vec<IpAddr>,
+
+    /// Port to listen on [default: 3030]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Log filter in the syntax of RUST_LOG [default: info]
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Largest accepted request body in bytes [default: 16384]
+    #[arg(long)]
+    body_limit: Option<u64>,
+
+    /// Seconds before a request is answered with 503 [default: 30]
+    #[arg(long)]
+    request_timeout: Option<u64>,
+
+    /// Serve the flag files of this directory, read-only, instead of the database
+    #[arg(long)]
+    flags_dir: Option<PathBuf>,
+
+    /// Turn off an optional part of the server, repeat for several
+    #[arg(long, value_enum)]
+    disable: Vec<Feature>,
+}
+
+impl Cli {
+    fn load(&self) -> Result<Config, FeatureFlagError> {
+        let mut config = self.config.load()?;
+        let server = &mut config.server;
+
+        if !self.listen.is_empty() {
+            server.listen = self.listen.clone();
+        }
+        if let Some(port) = self.port {
+            server.port = port;
+        }
+        if let Some(log_level) = &self.log_level {
+            server.log_level = log_level.clone();
+        }
+        if let Some(body_limit) = self.body_limit {
+            server.body_limit = body_limit;
+        }
+        if let Some(request_timeout) = self.request_timeout {
+            server.request_timeout = request_timeout;
+        }
+        if let Some(dir) = &self.flags_dir {
+            server.flags_dir = Some(dir.clone());
+        }
+        for feature in self.disable.iter() {
+            match feature {
+                Feature::Evaluation => server.features.evaluation = false,
+                Feature::History => server.features.history = false,
+                Feature::Reload => server.features.reload = false,
+            }
+        }
+
+        Ok(config)
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    if env::var_os("RUST_LOG").is_none() {
-        // Setting the logger to info as the default
-        env::set_var("RUST_LOG", "todos=info");
-    }
+    let config = match Cli::parse().load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
+    };
 
-    pretty_env_logger::init();
+    pretty_env_logger::formatted_builder()
+        .parse_filters(&config.server.log_level)
+        .init();
 
-    let db_lite = get_db_server();
+    // Flags kept in files are served read-only, changes go through the files
+    match &config.server.flags_dir {
+        Some(dir) => {
+            let store = match FileStore::open(dir) {
+                Ok(store) => Arc::new(Mutex::new(store)),
+                Err(err) => {
+                    eprintln!("Unable to read the flag files: {}", err);
+                    process::exit(1);
+                }
+            };
 
-    let flags_api = filters::feature_flag_all_routes(db_lite);
+            let changes = Changes::new();
+            if config.server.features.reload {
+                tokio::spawn(reload_files(store.clone(), changes.clone()));
+            }
+            serve(store, &config.server, changes).await;
+        }
+        None => {
+            let conn = match open_db(&config.db, false) {
+                Ok(conn) => conn,
+                Err(err) => {
+                    eprintln!("Unable to open the database: {}", err);
+                    process::exit(1);
+                }
+            };
+
+            let store = Arc::new(Mutex::new(SqliteStore::new(conn)));
+            serve(store, &config.server, Changes::new()).await
+        }
+    }
+}
 
-    // match any request and return hello world!
+/// Serves the API on every address of `config` until one of them fails. Subscribers hear about
+/// the `changes` published outside of the API as well.
+async fn serve<S: AsyncFlagStore>(store: S, config: &ServerConfig, changes: Changes) {
+    let flags_api = filters::feature_flag_all_routes(store, config, changes);
     let routes = flags_api.with(warp::log("flags"));
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    // warp has no timeouts of its own, so requests are timed around its service
+    let service = warp::service(routes);
+    let timeout = Duration::from_secs(config.request_timeout);
+
+    let mut servers = vec![];
+    for ip in config.listen.iter() {
+        let addr = SocketAddr::new(*ip, config.port);
+        let service = service.clone();
+        let make_service = make_service_fn(move |_| {
+            let service = service.clone();
+
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let mut service = service.clone();
+
+                    async move {
+                        match tokio::time::timeout(timeout, service.call(request)).await {
+                            Ok(response) => response,
+                            Err(_) => Ok(timeout_reply()),
+                        }
+                    }
+                }))
+            }
+        });
+
+        match hyper::Server::try_bind(&addr) {
+            Ok(builder) => {
+                log::info!("Listening on http://{}", addr);
+                servers.push(tokio::spawn(builder.serve(make_service)));
+            }
+            Err(err) => {
+                eprintln!("Unable to listen on {}: {}", addr, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    for server in servers {
+        if let Ok(Err(err)) = server.await {
+            eprintln!("Server failed: {}", err);
+            process::exit(1);
+        }
+    }
 }
 
-mod filters {
-    use super::handlers;
-    use warp::Filter;
+fn timeout_reply() -> Response<hyper::Body> {
+    let body = ErrorBody {
+        code: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+        error: "TIMEOUT",
+        message: "The request timed out".to_string(),
+    };
+
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::SERVICE_UNAVAILABLE)
+        .into_response()
+}
 
-    use feature_flags::db::{DBLite, Flag, FlagValue};
+/// Picks up changes to the flag files, e.g. after a `git pull`, and publishes them.
+async fn reload_files(store: Arc<Mutex<FileStore>>, changes: Changes) {
+    let mut interval = tokio::time::interval(Duration::from_secs(2));
+    let mut known = flag_states(&*store.lock().await).unwrap_or_default();
 
-    /// All the Feature Flag filters combined.
-    pub fn feature_flag_all_routes(
-        db: DBLite,
-    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        feature_flag_create(db.clone())
-            .or(flags_list(db.clone()))
-            .or(flags_update(db.clone()))
-            .or(flags_delete(db))
+    loop {
+        interval.tick().await;
+
+        reload(&mut *store.lock().await, &changes, &mut known);
     }
+}
 
-    /// GET flags
-    pub fn flags_list(
-        db: DBLite,
-    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path!("flags")
-            .and(warp::get())
-            .and(with_db_lite(db))
-            .and_then(handlers::list_flags)
+/// Every flag of a store with its rules, in each environment, keyed by project and flag name.
+type FlagStates = BTreeMap<(String, String), Vec<(FlagWithID, Vec<Rule>)>>;
+
+fn flag_states(store: &FileStore) -> Result<FlagStates, FeatureFlagError> {
+    let mut states = FlagStates::new();
+    let environments = store.get_environments()?;
+
+    for project in store.get_projects()? {
+        for environment in environments.iter() {
+            for mut flag in store.get_all_flags(&project.name, &environment.name)? {
+                let rules = store.get_rules(&project.name, &environment.name, flag.id as u64)?;
+                // When the files were read, which changes with every reload
+                flag.created_at.clear();
+                flag.updated_at.clear();
+
+                states
+                    .entry((project.name.clone(), flag.name.clone()))
+                    .or_default()
+                    .push((flag, rules));
+            }
+        }
     }
 
-    /// POST Feature Flag
-    pub fn feature_flag_create(
-        db: DBLite,
-    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path!("flags")
-            .and(warp::post())
-            .and(json_flag_body())
-            .and(with_db_lite(db))
-            .and_then(handlers::create_flag)
+    Ok(states)
+}
+
+/// Reloads the flag files when they changed, and publishes how the flags differ from `known`.
+fn reload(store: &mut FileStore, changes: &Changes, known: &mut FlagStates) {
+    match store.reload_if_changed() {
+        Ok(true) => log::info!("Reloaded the flag files"),
+        Ok(false) => return,
+        Err(err) => {
+            log::error!("Keeping the previous flags: {:?}", err);
+            return;
+        }
     }
 
-    pub fn flags_update(
-        db: DBLite,
-    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path!("flags" / u64)
-            .and(warp::put())
-            .and(json_bool_body())
-            .and(with_db_lite(db))
-            .and_then(handlers::update_flag)
+    let states = match flag_states(store) {
+        Ok(states) => states,
+        Err(err) => {
+            log::error!("Unable to tell what the flag files changed: {:?}", err);
+            return;
+        }
+    };
+    // Ids follow the order of the files, a flag that got another one is a new flag
+    let id = |states: &[(FlagWithID, Vec<Rule>)]| states[0].0.id as u64;
+
+    // Deletions first, as a name can come back with another id
+    for ((project, name), old) in known.iter() {
+        let kept = states.get(&(project.clone(), name.clone()));
+        if !matches!(kept, Some(new) if id(new) == id(old)) {
+            let kind = ChangeKind::Deleted {
+                id: id(old),
+                name: name.clone(),
+                tags: old[0].0.metadata.tags.clone(),
+            };
+            changes.publish(project, None, kind);
+        }
+    }
+    for ((project, name), new) in states.iter() {
+        match known.get(&(project.clone(), name.clone())) {
+            Some(old) if id(old) == id(new) => {
+                if old != new {
+                    changes.publish(project, None, ChangeKind::Updated(id(new)));
+                }
+            }
+            _ => changes.publish(project, None, ChangeKind::Created(id(new))),
+        }
     }
 
-    /// DELETE
-    pub fn flags_delete(
-        db: DBLite,
-    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-        warp::path!("flags" / u64)
-            .and(warp::delete())
-            .and(with_db_lite(db))
-            .and_then(handlers::delete_flag)
+    *known = states;
+}
+
+/// Changes to flags, for clients that follow them instead of polling.
+mod changes {
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use tokio::sync::broadcast;
+
+    /// Changes a client that fell behind can still catch up on
+    const RECENT_CHANGES: usize = 1024;
+    /// Changes a subscriber can fall behind by before it misses some
+    const CHANNEL_CAPACITY: usize = 256;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ChangeKind {
+        Created(u64),
+        Updated(u64),
+        /// What subscribers select flags by as well, as the flag can no longer be looked up
+        Deleted {
+            id: u64,
+            name: String,
+            tags: Vec<String>,
+        },
     }
 
-    fn with_db_lite(
-        db: DBLite,
-    ) -> impl Filter<Extract = (DBLite,), Error = std::convert::Infallible> + Clone {
-        warp::any().map(move || db.clone())
+    impl ChangeKind {
+        /// Name of the event that tells clients about the change.
+        pub fn event(&self) -> &'static str {
+            match self {
+                ChangeKind::Created(_) => "flag.created",
+                ChangeKind::Updated(_) => "flag.updated",
+                ChangeKind::Deleted { .. } => "flag.deleted",
+            }
+        }
     }
 
-    fn json_flag_body() -> impl Filter<Extract = (Flag,), Error = warp::Rejection> + Clone {
-        //When accepting a body, we want a JSON body
-        // (and to reject huge payloads)
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Change {
+        /// Position among all changes since the server started, starting at 1
+        pub seq: u64,
+        pub project: String,
+        /// Environment whose state changed, `None` when the change is to every environment
+        pub environment: Option<String>,
+        pub kind: ChangeKind,
     }
 
-    fn json_bool_body() -> impl Filter<Extract = (FlagValue,), Error = warp::Rejection> + Clone {
-        warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+    struct Recent {
+        latest: u64,
+        changes: VecDeque<Change>,
     }
-}
 
-mod handlers {
-    use feature_flags::db::{DBLite, Flag, FlagValue, FlagWithID};
-    use std::convert::Infallible;
-    use warp::http::StatusCode;
+    /// What a new subscriber starts from.
+    pub struct Subscription {
+        /// Changes since the one the subscriber last saw, `None` when they are no longer known
+        pub missed: Option<Vec<Change>>,
+        /// Sequence number of the latest change
+        pub latest: u64,
+        pub receiver: broadcast::Receiver<Change>,
+    }
+
+    /// Broadcasts every change the handlers make to the subscribers of all filters, and keeps
+    /// the latest ones for clients that reconnect.
+    #[derive(Clone)]
+    pub struct Changes {
+        /// Sets the sequence numbers of this process apart from those of earlier ones, which
+        /// start at 1 as well
+        epoch: u64,
+        sender: broadcast::Sender<Change>,
+        recent: Arc<Mutex<Recent>>,
+    }
+
+    impl Changes {
+        pub fn new() -> Changes {
+            let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+            let recent = Recent {
+                latest: 0,
+                changes: VecDeque::with_capacity(RECENT_CHANGES),
+            };
 
-    use super::ResponseMessage;
-    use rusqlite::params;
+            let epoch = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_millis() as u64);
 
-    pub async fn list_flags(db: DBLite) -> Result<impl warp::Reply, Infallible> {
-        let conn = db.lock().await;
+            Changes {
+                epoch,
+                sender,
+                recent: Arc::new(Mutex::new(recent)),
+            }
+        }
 
-        let mut stmt = conn.prepare("SELECT id, name, value FROM flags").unwrap();
+        /// Id of the event for change `seq`, or for a snapshot taken at it.
+        pub fn event_id(&self, seq: u64) -> String {
+            format!("{}-{}", self.epoch, seq)
+        }
 
-        let flag_iter = stmt
-            .query_map([], |row| {
-                let value = matches!(row.get(2).unwrap(), 1);
+        /// Sequence number of an id from `event_id`. Ids handed out by another process, e.g.
+        /// before a restart, are not ours.
+        pub fn parse_event_id(&self, id: &str) -> Option<u64> {
+            let (epoch, seq) = id.trim().split_once('-')?;
+            match epoch.parse::<u64>() {
+                Ok(epoch) if epoch == self.epoch => seq.parse().ok(),
+                _ => None,
+            }
+        }
 
-                Ok(FlagWithID {
-                    id: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    value,
-                })
-            })
-            .unwrap();
+        pub fn publish(&self, project: &str, environment: Option<&str>, kind: ChangeKind) {
+            let mut recent = self.recent.lock().unwrap();
+            recent.latest += 1;
+            let change = Change {
+                seq: recent.latest,
+                project: project.to_string(),
+                environment: environment.map(str::to_string),
+                kind,
+            };
 
-        let flags_list: Vec<FlagWithID> = flag_iter.into_iter().map(|item| item.unwrap()).collect();
+            if recent.changes.len() == RECENT_CHANGES {
+                recent.changes.pop_front();
+            }
+            recent.changes.push_back(change.clone());
+            // Sent under the lock, so subscribers see changes in sequence order. Without
+            // subscribers there is nobody to tell.
+            let _ = self.sender.send(change);
+        }
 
-        Ok(warp::reply::json(&flags_list))
-    }
+        /// Follows the changes after `last_seen`, or after the latest one.
+        pub fn subscribe(&self, last_seen: Option<u64>) -> Subscription {
+            let recent = self.recent.lock().unwrap();
+            let oldest = recent
+                .changes
+                .front()
+                .map_or(recent.latest + 1, |change| change.seq);
 
-    pub async fn create_flag(new_flag: Flag, db: DBLite) -> Result<impl warp::Reply, Infallible> {
-        println!("create_flag: {:?}", new_flag);
+            // Ours as long as it comes from `parse_event_id`, but clients can still make one up
+            let missed = last_seen
+                .filter(|seen| seen + 1 >= oldest && *seen <= recent.latest)
+                .map(|seen| {
+                    recent
+                        .changes
+                        .iter()
+                        .filter(|change| change.seq > seen)
+                        .cloned()
+                        .collect()
+                });
 
-        let conn = db.lock().await;
-        let result = conn.execute(
-            "INSERT INTO flags (name, value) Values (?1, ?2)",
-            params![new_flag.name, new_flag.value],
-        );
+            Subscription {
+                missed,
+                latest: recent.latest,
+                receiver: self.sender.subscribe(),
+            }
+        }
+    }
 
-        match result {
-            Err(err) => {
-                log::debug!("Failed to create_new flag: {:?}", err);
-                //Ok(StatusCode::BAD_REQUEST)
-                Ok(warp::reply::with_status(
-                    warp::reply::json(&ResponseMessage {
-                        code: StatusCode::BAD_REQUEST.as_u16(),
-                        message: format!("{:?}", err),
-                    }),
-                    StatusCode::BAD_REQUEST,
-                ))
-            }
-            Ok(_) => Ok(warp::reply::with_status(
-                warp::reply::json(&ResponseMessage {
-                    code: StatusCode::CREATED.as_u16(),
-                    message: format!("Flag {} was created", new_flag.name),
-                }),
-                StatusCode::CREATED,
-            )),
+    impl Default for Changes {
+        fn default() -> Changes {
+            Changes::new()
         }
     }
+}
 
-    pub async fn update_flag(
-        id: u64,
-        flag_value: FlagValue,
-        db: DBLite,
-    ) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("update_flag: id: {:?}, value {:?}", id, flag_value);
+/// WebSockets that follow a selection of flags.
+///
+/// Clients send `{"type": "subscribe", "names": [..], "tags": [..]}` to hear about the flags
+/// with these names or tags, and `unsubscribe` in the same way. Both are answered with the
+/// selection as it is now, subscriptions with the flags they added as well. Changes to the
+/// selected flags arrive as `flag.created`, `flag.updated` and `flag.deleted` messages. A flag
+/// that no longer matches the selection, e.g. because it lost a tag, arrives as deleted.
+mod watch {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::time::{Duration, Instant};
 
-        let conn = db.lock().await;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_derive::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::sync::mpsc;
+    use tokio::time;
+    use warp::ws::{Message, WebSocket};
 
-        let mut stmt = conn
-            .prepare("SELECT name, value FROM flags WHERE id = ?")
-            .unwrap();
-        let exist = stmt.exists(params![id]).unwrap();
+    use super::changes::{Change, ChangeKind, Changes};
+    use feature_flags::error::{ErrorBody, FeatureFlagError};
+    use feature_flags::store::AsyncFlagStore;
+
+    /// How often the server pings. Clients that stay silent for `MISSED_HEARTBEATS` pings are
+    /// disconnected.
+    const HEARTBEAT: Duration = Duration::from_secs(30);
+    const MISSED_HEARTBEATS: u32 = 3;
+    /// Messages waiting for a slow client before it is disconnected
+    const PENDING_MESSAGES: usize = 64;
+    /// Flag names and tags one connection may subscribe to
+    const MAX_SUBSCRIPTIONS: usize = 256;
+    /// Close code for connections that missed changes
+    const TRY_AGAIN_LATER: u16 = 1013;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum Request {
+        Subscribe(Selection),
+        Unsubscribe(Selection),
+    }
+
+    #[derive(Debug, Default, Clone, Deserialize)]
+    struct Selection {
+        #[serde(default)]
+        names: BTreeSet<String>,
+        #[serde(default)]
+        tags: BTreeSet<String>,
+    }
+
+    impl Selection {
+        fn matches(&self, name: &str, tags: &[String]) -> bool {
+            self.names.contains(name) || tags.iter().any(|tag| self.tags.contains(tag))
+        }
+
+        fn len(&self) -> usize {
+            self.names.len() + self.tags.len()
+        }
 
-        // Not Found early exit
-        if !exist {
-            return Ok(StatusCode::NOT_FOUND);
+        fn is_empty(&self) -> bool {
+            self.len() == 0
         }
+    }
+
+    /// Flags a connection was told about and has not been told are gone, keyed by name, with
+    /// their tags as they were sent.
+    type Sent = BTreeMap<String, Vec<String>>;
+
+    #[derive(Serialize)]
+    struct ErrorMessage {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        #[serde(flatten)]
+        body: ErrorBody,
+    }
+
+    fn text(message: Value) -> Message {
+        Message::text(message.to_string())
+    }
 
-        let value_as_int = match flag_value.value {
-            true => 1,
-            false => 0,
+    fn error_message(err: FeatureFlagError) -> Message {
+        if err.body().code == 500 {
+            log::error!("{}", err);
+        }
+        let message = ErrorMessage {
+            kind: "error",
+            body: err.body(),
         };
 
-        let result = conn.execute(
-            "UPDATE flags SET value = ? WHERE id = ?",
-            params![value_as_int, id],
-        );
-        match result {
-            Ok(_) => Ok(StatusCode::OK),
-            Err(_) => {
-                log::debug!("Unble to update flag");
-                Ok(StatusCode::from_u16(500).unwrap())
+        Message::text(serde_json::to_string(&message).unwrap_or_default())
+    }
+
+    /// Serves one connection until either side closes it. Connections that fall behind are
+    /// closed, clients reconnect and subscribe again.
+    pub async fn session<S: AsyncFlagStore>(
+        socket: WebSocket,
+        project: String,
+        env: String,
+        store: S,
+        changes: Changes,
+    ) {
+        let (mut sink, mut incoming) = socket.split();
+        let (outgoing, mut pending) = mpsc::channel::<Message>(PENDING_MESSAGES);
+        let writer = tokio::spawn(async move {
+            while let Some(message) = pending.recv().await {
+                if sink.send(message).await.is_err() {
+                    break;
+                }
             }
-        }
+        });
+
+        let mut receiver = changes.subscribe(None).receiver;
+        let mut selection = Selection::default();
+        let mut sent = Sent::new();
+        let mut heartbeat = time::interval_at(time::Instant::now() + HEARTBEAT, HEARTBEAT);
+        let mut last_heard = Instant::now();
+
+        let close = loop {
+            let message = tokio::select! {
+                request = incoming.next() => match request {
+                    Some(Ok(request)) if request.is_close() => break None,
+                    Some(Ok(request)) => {
+                        // Pongs and anything else count as signs of life
+                        last_heard = Instant::now();
+                        match request.to_str() {
+                            Ok(request) => Some(
+                                reply(request, &mut selection, &mut sent, &store, &project, &env)
+                                    .await,
+                            ),
+                            Err(_) => None,
+                        }
+                    }
+                    _ => break None,
+                },
+                change = receiver.recv() => match change {
+                    Ok(change) => {
+                        change_message(&change, &selection, &mut sent, &store, &project, &env)
+                            .await
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        break Some(Message::close_with(TRY_AGAIN_LATER, "too far behind"));
+                    }
+                    Err(RecvError::Closed) => break None,
+                },
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > HEARTBEAT * MISSED_HEARTBEATS {
+                        break None;
+                    }
+                    Some(Message::ping(Vec::new()))
+                }
+            };
+
+            if let Some(message) = message {
+                if outgoing.try_send(message).is_err() {
+                    log::info!("Dropping a WebSocket of {} that stopped reading", project);
+                    writer.abort();
+                    return;
+                }
+            }
+        };
+
+        // What is pending is still sent
+        let _ = outgoing.try_send(close.unwrap_or_else(Message::close));
+        drop(outgoing);
+        let _ = writer.await;
     }
 
-    pub async fn delete_flag(id: u64, db: DBLite) -> Result<impl warp::Reply, Infallible> {
-        log::debug!("delete flag id <{}>", id);
+    /// Answers a subscribe or unsubscribe request.
+    async fn reply<S: AsyncFlagStore>(
+        request: &str,
+        selection: &mut Selection,
+        sent: &mut Sent,
+        store: &S,
+        project: &str,
+        env: &str,
+    ) -> Message {
+        let request = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(err) => return error_message(FeatureFlagError::Validation(err.to_string())),
+        };
 
-        let conn = db.lock().await;
-        let result = conn.execute("DELETE FROM flags WHERE id = ?", params![id]);
+        match request {
+            Request::Subscribe(added) => {
+                let mut next = selection.clone();
+                next.names.extend(added.names.iter().cloned());
+                next.tags.extend(added.tags.iter().cloned());
+                if next.len() > MAX_SUBSCRIPTIONS {
+                    return error_message(FeatureFlagError::Validation(format!(
+                        "at most {} flag names and tags can be subscribed to",
+                        MAX_SUBSCRIPTIONS
+                    )));
+                }
 
-        match result {
-            Ok(_) => Ok(StatusCode::NO_CONTENT),
-            Err(err) => {
-                log::debug!("Error when deleting a flag: {:?}", err);
-                Ok(StatusCode::from_u16(500).unwrap())
+                let flags = match store.get_all_flags(project, env).await {
+                    Ok(flags) => flags,
+                    Err(err) => return error_message(err),
+                };
+                let flags: Vec<_> = flags
+                    .into_iter()
+                    .filter(|flag| added.matches(&flag.name, &flag.metadata.tags))
+                    .collect();
+                *selection = next;
+                for flag in flags.iter() {
+                    sent.insert(flag.name.clone(), flag.metadata.tags.clone());
+                }
+
+                text(json!({
+                    "type": "subscribed",
+                    "names": selection.names,
+                    "tags": selection.tags,
+                    "flags": flags,
+                }))
+            }
+            Request::Unsubscribe(removed) => {
+                selection.names.retain(|name| !removed.names.contains(name));
+                selection.tags.retain(|tag| !removed.tags.contains(tag));
+                // Not followed anymore, so their leaving is not worth telling either
+                sent.retain(|name, tags| selection.matches(name, tags));
+
+                text(json!({
+                    "type": "unsubscribed",
+                    "names": selection.names,
+                    "tags": selection.tags,
+                }))
             }
         }
     }
+
+    /// The message about `change` when it is to a selected flag in `env`, or takes a flag that
+    /// was sent out of the selection.
+    async fn change_message<S: AsyncFlagStore>(
+        change: &Change,
+        selection: &Selection,
+        sent: &mut Sent,
+        store: &S,
+        project: &str,
+        env: &str,
+    ) -> Option<Message> {
+        if change.project != project || sent.is_empty() && selection.is_empty() {
+            return None;
+        }
+        if matches!(&change.environment, Some(changed) if changed != env) {
+            return None;
+        }
+
+        let (event, flag) = match &change.kind {
+            ChangeKind::Created(id) | ChangeKind::Updated(id) => {
+                // Gone by now when it was deleted since, which has a change of its own
+                let flag = store.get_flag_by_id(project, env, *id).await.ok()?;
+                if selection.matches(&flag.name, &flag.metadata.tags) {
+                    sent.insert(flag.name.clone(), flag.metadata.tags.clone());
+                    (change.kind.event(), serde_json::to_value(flag).ok()?)
+                } else {
+                    sent.remove(&flag.name)?;
+                    ("flag.deleted", json!({"id": id, "name": flag.name}))
+                }
+            }
+            ChangeKind::Deleted { id, name, tags } => {
+                if sent.remove(name).is_none() && !selection.matches(name, tags) {
+                    return None;
+                }
+                (change.kind.event(), json!({"id": id, "name": name}))
+            }
+        };
+
+        Some(text(json!({
+            "type": event,
+            "seq": change.seq,
+            "flag": flag,
+        })))
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Arc;
+mod filters {
+    use super::changes::Changes;
+    use super::handlers::{self, ListQuery, Poll, WriteHeaders};
+    use serde::de::DeserializeOwned;
+    use serde_derive::Deserialize;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use warp::ws::Ws;
+    use warp::Filter;
 
-    use hyper;
-    use rusqlite::Connection;
-    use serde_json::json;
-    use tokio::sync::Mutex;
-    use warp::Reply;
+    use feature_flags::config::ServerConfig;
+    use feature_flags::db::batch::Batch;
+    use feature_flags::db::changes::ChangeCursor;
+    use feature_flags::db::environments::{NewEnvironment, DEFAULT_ENVIRONMENT};
+    use feature_flags::db::events::DEFAULT_ACTOR;
+    use feature_flags::db::projects::{NewProject, DEFAULT_PROJECT};
+    use feature_flags::db::segments::SegmentUpdate;
+    use feature_flags::db::variations::{Variation, VariationUpdate};
+    use feature_flags::db::{Flag, FlagFilter, FlagPage, FlagUpdate};
+    use feature_flags::error::FeatureFlagError;
+    use feature_flags::eval::{EvaluationContext, Rule, Segment};
+    use feature_flags::store::AsyncFlagStore;
 
-    use super::filters::*;
-    use super::handlers::*;
-    use feature_flags::db::*;
+    /// All the Feature Flag filters combined, without the features `config` turns off. Every
+    /// rejection is answered with a JSON error by `handlers::handle_rejection`. Subscribers hear
+    /// about the changes made through the API, and those published to `changes` from outside.
+    pub fn feature_flag_all_routes<S: AsyncFlagStore>(
+        store: S,
+        config: &ServerConfig,
+        changes: Changes,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = Infallible> + Clone {
+        let body_limit = config.body_limit;
+        let features = &config.features;
+        // Waiting for changes is over before the request times out
+        let max_wait = Duration::from_secs(config.request_timeout.saturating_sub(1));
 
-    fn in_memery_db() -> DBLite {
-        let conn = Connection::open_in_memory().unwrap();
+        feature_flag_create(store.clone(), changes.clone(), body_limit)
+            .or(flags_batch(store.clone(), changes.clone(), body_limit))
+            .or(flags_list(store.clone(), changes.clone(), max_wait))
+            .or(flags_stream(store.clone(), changes.clone()))
+            .or(flags_watch(store.clone(), changes.clone(), body_limit))
+            .or(flag_get(store.clone()))
+            .or(flag_get_by_name(store.clone()))
+            .or(flags_update(store.clone(), changes.clone(), body_limit))
+            .or(flag_update_by_name(
+                store.clone(),
+                changes.clone(),
+                body_limit,
+            ))
+            .or(flags_delete(store.clone(), changes.clone()))
+            .or(flag_delete_by_name(store.clone(), changes.clone()))
+            .or(variations_list(store.clone()))
+            .or(variation_create(store.clone(), changes.clone(), body_limit))
+            .or(variation_update(store.clone(), changes.clone(), body_limit))
+            .or(variation_delete(store.clone(), changes.clone()))
+            .or(environments_list(store.clone()))
+            .or(environment_create(store.clone(), body_limit))
+            .or(environment_delete(store.clone()))
+            .or(projects_list(store.clone()))
+            .or(project_create(store.clone(), body_limit))
+            .or(project_delete(store.clone()))
+            .or(enabled(features.history).and(flag_history(store.clone())))
+            .or(rules_list(store.clone()))
+            .or(rules_update(store.clone(), changes.clone(), body_limit))
+            .or(segments_list(store.clone()))
+            .or(segment_get(store.clone()))
+            .or(segment_create(store.clone(), body_limit))
+            .or(segment_update(store.clone(), changes, body_limit))
+            .or(segment_delete(store.clone()))
+            .or(enabled(features.evaluation).and(flag_evaluate(store.clone(), body_limit)))
+            .or(enabled(features.evaluation).and(flags_evaluate(store, body_limit)))
+            .recover(handlers::handle_rejection)
+    }
 
-        let local_conn = Arc::new(Mutex::new(conn));
+    /// Optional `projects/{project}` prefix selecting the project, the default one when left out.
+    fn project() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::path("projects")
+            .and(warp::path::param::<String>())
+            .or(warp::any().map(|| DEFAULT_PROJECT.to_string()))
+            .unify()
+    }
 
-        local_conn
+    /// Optional `envs/{env}` prefix selecting the environment, production when left out.
+    fn environment() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+        warp::path("envs")
+            .and(warp::path::param::<String>())
+            .or(warp::any().map(|| DEFAULT_ENVIRONMENT.to_string()))
+            .unify()
     }
 
-    #[tokio::test]
-    async fn test_unknown_route() {
-        let db_conn = in_memery_db();
-        let filter = feature_flag_create(db_conn.clone());
+    /// Who is making the change, from the `X-Actor` header. Recorded in the audit log.
+    fn actor() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("x-actor")
+            .map(|actor: Option<String>| actor.unwrap_or_else(|| DEFAULT_ACTOR.to_string()))
+    }
 
-        let response = warp::test::request().path("hi").reply(&filter).await;
+    /// Entity tags of the `If-Match` header, when there is one.
+    fn if_match() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+        warp::header::optional::<String>("if-match")
+    }
 
-        assert_eq!(response.status(), 404);
+    /// `If-Match` and `X-Actor` of requests that change or delete a flag.
+    fn write_headers() -> impl Filter<Extract = (WriteHeaders,), Error = warp::Rejection> + Clone {
+        if_match()
+            .and(actor())
+            .map(|if_match, actor| WriteHeaders { if_match, actor })
     }
 
-    #[tokio::test]
-    async fn test_create_flag_endpoint() {
-        // TODO: Learn how to check the body of the response here so that
-        // I do not have to test the same thing twice.
+    /// Entity tags of the `If-None-Match` header, when there is one.
+    fn if_none_match() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone
+    {
+        warp::header::optional::<String>("if-none-match")
+    }
+
+    /// Id of the last event a reconnecting client saw, see `Changes::parse_event_id`.
+    fn last_event_id() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone
+    {
+        warp::header::optional::<String>("last-event-id")
+    }
+
+    /// Query parameters of a `GET flags` that waits for changes, see `poll`.
+    #[derive(Deserialize)]
+    struct PollQuery {
+        since: Option<String>,
+        wait: Option<String>,
+    }
+
+    /// A wait of whole seconds, e.g. `30s` or just `30`, or of milliseconds, e.g. `500ms`.
+    fn parse_wait(wait: &str) -> Result<Duration, FeatureFlagError> {
+        let (number, unit) = match wait.strip_suffix("ms") {
+            Some(number) => (number, Duration::from_millis(1)),
+            None => (
+                wait.strip_suffix('s').unwrap_or(wait),
+                Duration::from_secs(1),
+            ),
+        };
+
+        number.parse::<u32>().map(|n| unit * n).map_err(|_| {
+            FeatureFlagError::Validation(format!("wait '{}' is not a duration like 30s", wait))
+        })
+    }
+
+    /// `since` and `wait` from the query, where waits longer than `max_wait` are cut short.
+    fn poll(max_wait: Duration) -> impl Filter<Extract = (Poll,), Error = warp::Rejection> + Clone {
+        warp::query::<PollQuery>().and_then(move |query: PollQuery| async move {
+            let since = match query.since {
+                Some(since) => Some(since.parse::<ChangeCursor>()?),
+                None => None,
+            };
+            let wait = match (since, query.wait) {
+                (_, None) => Duration::ZERO,
+                (Some(_), Some(wait)) => parse_wait(&wait)?.min(max_wait),
+                (None, Some(_)) => {
+                    return Err(warp::reject::custom(FeatureFlagError::Validation(
+                        "wait needs a since cursor to wait for changes after".to_string(),
+                    )))
+                }
+            };
 
-        let db_conn = in_memery_db();
+            Ok(Poll { since, wait })
+        })
+    }
 
-        initialize_db_arc(db_conn.clone()).await.unwrap();
+    /// Filter, page and poll of `GET flags`, all from the query.
+    fn list_query(
+        max_wait: Duration,
+    ) -> impl Filter<Extract = (ListQuery,), Error = warp::Rejection> + Clone {
+        warp::query::<FlagFilter>()
+            .and(warp::query::<FlagPage>())
+            .and(poll(max_wait))
+            .and_then(|filter, page: FlagPage, poll: Poll| async move {
+                if poll.since.is_some() && (page.limit.is_some() || page.cursor.is_some()) {
+                    return Err(warp::reject::custom(FeatureFlagError::Validation(
+                        "changes since a cursor come in one piece, without limit or cursor"
+                            .to_string(),
+                    )));
+                }
 
-        let filter = feature_flag_create(db_conn.clone());
-        println!(
-            "{:?}",
-            json!(&Flag {
-                name: "test".to_string(),
-                value: true,
+                Ok(ListQuery { filter, page, poll })
             })
-            .to_string()
-        );
-        let response = warp::test::request()
-            .method("POST")
-            .path("/flags")
-            .header("accept", "application/json")
-            .body(
-                json!(&Flag {
-                    name: "test".to_string(),
-                    value: true,
-                })
-                .to_string(),
-            )
-            .reply(&filter)
-            .await;
+    }
 
-        assert_eq!(response.status(), 201);
+    /// GET [projects/{project}/][envs/{env}/]flags, filtered by `owner`, `tag`, `prefix`,
+    /// `contains` and `value`, ordered by `sort` and `order` and paged by `limit` and `cursor`.
+    /// With `since` and `wait`, the changes after a cursor instead.
+    pub fn flags_list<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        max_wait: Duration,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags"))
+            .and(warp::get())
+            .and(list_query(max_wait))
+            .and(if_none_match())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::list_flags)
     }
 
-    #[tokio::test]
-    async fn test_create_flag_handler() {
-        let db_conn = in_memery_db();
+    /// GET [projects/{project}/][envs/{env}/]stream, Server-Sent Events of every flag change
+    pub fn flags_stream<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("stream"))
+            .and(warp::get())
+            .and(last_event_id())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::stream_flags)
+    }
 
-        initialize_db_arc(db_conn.clone()).await.unwrap();
+    /// GET [projects/{project}/][envs/{env}/]ws, a WebSocket to watch selected flags on.
+    /// Messages are limited to `body_limit` like request bodies.
+    pub fn flags_watch<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("ws"))
+            .and(warp::ws().map(move |ws: Ws| ws.max_message_size(body_limit as usize)))
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::watch_flags)
+    }
 
-        let flag = Flag {
-            name: "test".to_string(),
-            value: true,
-        };
+    /// GET [projects/{project}/][envs/{env}/]flags/{id}
+    pub fn flag_get<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / u64))
+            .and(warp::get())
+            .and(if_none_match())
+            .and(with_store(store))
+            .and_then(handlers::get_flag)
+    }
 
-        let reply = create_flag(flag, db_conn.clone()).await.unwrap();
+    /// GET [projects/{project}/][envs/{env}/]flags/by-name/{name}
+    pub fn flag_get_by_name<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / "by-name" / String))
+            .and(warp::get())
+            .and(if_none_match())
+            .and(with_store(store))
+            .and_then(handlers::get_flag_by_name)
+    }
 
-        let mut response = reply.into_response();
+    /// POST Feature Flag, turned on in the environment it is created in
+    pub fn feature_flag_create<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags"))
+            .and(warp::post())
+            .and(json_body::<Flag>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::create_flag)
+    }
 
-        // Got from https://github.com/rust-lang/async-book/issues/6#issuecomment-611703749
-        let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
+    /// POST [projects/{project}/][envs/{env}/]flags:batch, creates, updates and deletes flags
+    /// in one go, see `Batch`
+    pub fn flags_batch<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags:batch"))
+            .and(warp::post())
+            .and(json_body::<Batch>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::apply_batch)
+    }
 
-        let body_string = String::from_utf8(body.to_ascii_lowercase()).unwrap();
+    /// PUT [projects/{project}/][envs/{env}/]flags/{id}
+    pub fn flags_update<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / u64))
+            .and(warp::put())
+            .and(json_body::<FlagUpdate>(body_limit))
+            .and(write_headers())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::update_flag)
+    }
 
-        assert_eq!(response.status(), 201);
-        assert_eq!(
-            body_string,
-            "{\"code\":201,\"message\":\"flag test was created\"}".to_string()
-        );
+    /// DELETE [projects/{project}/]flags/{id}, removes the flag from every environment
+    pub fn flags_delete<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64))
+            .and(warp::delete())
+            .and(write_headers())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::delete_flag)
+    }
+
+    /// PUT [projects/{project}/][envs/{env}/]flags/by-name/{name}
+    pub fn flag_update_by_name<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / "by-name" / String))
+            .and(warp::put())
+            .and(json_body::<FlagUpdate>(body_limit))
+            .and(write_headers())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::update_flag_by_name)
+    }
+
+    /// DELETE [projects/{project}/]flags/by-name/{name}, removes the flag from every environment
+    pub fn flag_delete_by_name<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / "by-name" / String))
+            .and(warp::delete())
+            .and(write_headers())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::delete_flag_by_name)
+    }
+
+    /// GET [projects/{project}/]flags/{id}/variations
+    pub fn variations_list<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64 / "variations"))
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::list_variations)
+    }
+
+    /// POST [projects/{project}/]flags/{id}/variations
+    pub fn variation_create<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64 / "variations"))
+            .and(warp::post())
+            .and(json_body::<Variation>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::create_variation)
+    }
+
+    /// PUT [projects/{project}/]flags/{id}/variations/{name}
+    pub fn variation_update<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64 / "variations" / String))
+            .and(warp::put())
+            .and(json_body::<VariationUpdate>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::update_variation)
+    }
+
+    /// DELETE [projects/{project}/]flags/{id}/variations/{name}
+    pub fn variation_delete<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64 / "variations" / String))
+            .and(warp::delete())
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::delete_variation)
+    }
+
+    /// GET [projects/{project}/]flags/{id}/history, the flag's audit log
+    pub fn flag_history<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("flags" / u64 / "history"))
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::flag_history)
+    }
+
+    /// GET [projects/{project}/][envs/{env}/]flags/{id}/rules
+    pub fn rules_list<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / u64 / "rules"))
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::list_rules)
+    }
+
+    /// PUT [projects/{project}/][envs/{env}/]flags/{id}/rules, replaces every rule
+    pub fn rules_update<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("flags" / u64 / "rules"))
+            .and(warp::put())
+            .and(json_body::<Vec<Rule>>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::update_rules)
+    }
+
+    /// GET [projects/{project}/]segments
+    pub fn segments_list<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("segments"))
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::list_segments)
+    }
+
+    /// GET [projects/{project}/]segments/{name}
+    pub fn segment_get<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("segments" / String))
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::get_segment)
+    }
+
+    /// POST [projects/{project}/]segments
+    pub fn segment_create<S: AsyncFlagStore>(
+        store: S,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("segments"))
+            .and(warp::post())
+            .and(json_body::<Segment>(body_limit))
+            .and(with_store(store))
+            .and_then(handlers::create_segment)
+    }
+
+    /// PUT [projects/{project}/]segments/{name}
+    pub fn segment_update<S: AsyncFlagStore>(
+        store: S,
+        changes: Changes,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("segments" / String))
+            .and(warp::put())
+            .and(json_body::<SegmentUpdate>(body_limit))
+            .and(actor())
+            .and(with_store(store))
+            .and(with_changes(changes))
+            .and_then(handlers::update_segment)
+    }
+
+    /// DELETE [projects/{project}/]segments/{name}, only unused segments can be removed
+    pub fn segment_delete<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(warp::path!("segments" / String))
+            .and(warp::delete())
+            .and(with_store(store))
+            .and_then(handlers::delete_segment)
+    }
+
+    /// POST [projects/{project}/][envs/{env}/]evaluate/{name}
+    pub fn flag_evaluate<S: AsyncFlagStore>(
+        store: S,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("evaluate" / String))
+            .and(warp::post())
+            .and(json_body::<EvaluationContext>(body_limit))
+            .and(with_store(store))
+            .and_then(handlers::evaluate_flag)
+    }
+
+    /// POST [projects/{project}/][envs/{env}/]evaluate, evaluates every flag
+    pub fn flags_evaluate<S: AsyncFlagStore>(
+        store: S,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        project()
+            .and(environment())
+            .and(warp::path!("evaluate"))
+            .and(warp::post())
+            .and(json_body::<EvaluationContext>(body_limit))
+            .and(with_store(store))
+            .and_then(handlers::evaluate_flags)
+    }
+
+    /// GET envs
+    pub fn environments_list<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("envs")
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::list_environments)
+    }
+
+    /// POST envs
+    pub fn environment_create<S: AsyncFlagStore>(
+        store: S,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("envs")
+            .and(warp::post())
+            .and(json_body::<NewEnvironment>(body_limit))
+            .and(with_store(store))
+            .and_then(handlers::create_environment)
+    }
+
+    /// DELETE envs/{env}
+    pub fn environment_delete<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("envs" / String)
+            .and(warp::delete())
+            .and(with_store(store))
+            .and_then(handlers::delete_environment)
+    }
+
+    /// GET projects
+    pub fn projects_list<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("projects")
+            .and(warp::get())
+            .and(with_store(store))
+            .and_then(handlers::list_projects)
+    }
+
+    /// POST projects
+    pub fn project_create<S: AsyncFlagStore>(
+        store: S,
+        body_limit: u64,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("projects")
+            .and(warp::post())
+            .and(json_body::<NewProject>(body_limit))
+            .and(with_store(store))
+            .and_then(handlers::create_project)
+    }
+
+    /// DELETE projects/{project}, only empty projects can be removed
+    pub fn project_delete<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        warp::path!("projects" / String)
+            .and(warp::delete())
+            .and(with_store(store))
+            .and_then(handlers::delete_project)
+    }
+
+    fn with_store<S: AsyncFlagStore>(
+        store: S,
+    ) -> impl Filter<Extract = (S,), Error = Infallible> + Clone {
+        warp::any().map(move || store.clone())
+    }
+
+    fn with_changes(
+        changes: Changes,
+    ) -> impl Filter<Extract = (Changes,), Error = Infallible> + Clone {
+        warp::any().map(move || changes.clone())
+    }
+
+    fn json_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+    where
+        T: DeserializeOwned + Send,
+    {
+        //When accepting a body, we want a JSON body
+        // (and to reject huge payloads)
+        warp::body::content_length_limit(limit).and(warp::body::json())
+    }
+
+    /// Passes requests on when `on`, so that optional routes can be turned off.
+    fn enabled(on: bool) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+        warp::any()
+            .and_then(move || async move {
+                match on {
+                    true => Ok(()),
+                    false => Err(warp::reject::not_found()),
+                }
+            })
+            .untuple_one()
+    }
+}
+
+mod handlers {
+    use feature_flags::db::batch::{Batch, OperationResult};
+    use feature_flags::db::changes::{ChangeCursor, DeletedFlag};
+    use feature_flags::db::environments::{NewEnvironment, DEFAULT_ENVIRONMENT};
+    use feature_flags::db::projects::NewProject;
+    use feature_flags::db::segments::SegmentUpdate;
+    use feature_flags::db::variations::{Variation, VariationUpdate};
+    use feature_flags::db::{DeleteOutcome, Flag, FlagFilter, FlagPage, FlagUpdate, FlagWithID};
+    use feature_flags::error::{ErrorBody, FeatureFlagError};
+    use feature_flags::eval::{EvaluationContext, Rule, Segment};
+    use feature_flags::store::AsyncFlagStore;
+    use futures_util::{stream, StreamExt};
+    use serde_json::json;
+    use sha1::{Digest, Sha1};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::sync::broadcast::error::RecvError;
+    use tokio::time::{self, Instant};
+    use warp::http::{HeaderValue, StatusCode};
+    use warp::reply::{Json, Response, WithStatus};
+    use warp::sse::Event;
+    use warp::ws::Ws;
+    use warp::{Rejection, Reply};
+
+    use super::changes::{Change, ChangeKind, Changes};
+    use super::{watch, ResponseMessage};
+
+    fn message_reply(code: StatusCode, message: String) -> WithStatus<Json> {
+        warp::reply::with_status(
+            warp::reply::json(&ResponseMessage {
+                code: code.as_u16(),
+                message,
+            }),
+            code,
+        )
+    }
+
+    /// The query of `GET flags`.
+    pub struct ListQuery {
+        pub filter: FlagFilter,
+        pub page: FlagPage,
+        pub poll: Poll,
+    }
+
+    /// `since` and `wait` of a `GET flags` that waits for changes.
+    pub struct Poll {
+        /// Cursor of the changes the client has seen, `None` to list the flags
+        pub since: Option<ChangeCursor>,
+        /// How long to wait for a change after the cursor
+        pub wait: Duration,
+    }
+
+    /// The headers of requests that change or delete a flag.
+    pub struct WriteHeaders {
+        /// Entity tags the flag has to be at, from `If-Match`
+        pub if_match: Option<String>,
+        /// Who makes the change, recorded in the audit log
+        pub actor: String,
+    }
+
+    /// Fails with `PreconditionFailed` unless the `If-Match` header lists `*` or the flag's
+    /// entity tag.
+    fn check_if_match(header: &str, flag: &FlagWithID) -> Result<(), Rejection> {
+        let etag = flag.etag();
+        let matches = header
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == etag);
+
+        match matches {
+            true => Ok(()),
+            false => Err(FeatureFlagError::PreconditionFailed(format!(
+                "flag {} is at version {}, which If-Match: {} does not list",
+                flag.name, flag.version, header
+            ))
+            .into()),
+        }
+    }
+
+    /// `reply` with an `ETag` header, or `304 Not Modified` when the `If-None-Match` header
+    /// already lists `etag`.
+    fn conditional_reply(
+        reply: impl Reply,
+        etag: String,
+        if_none_match: Option<String>,
+    ) -> Response {
+        // Weak comparison, as for GET requests
+        let not_modified = if_none_match.is_some_and(|header| {
+            header
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        });
+
+        match not_modified {
+            true => {
+                warp::reply::with_header(StatusCode::NOT_MODIFIED, "etag", etag).into_response()
+            }
+            false => warp::reply::with_header(reply, "etag", etag).into_response(),
+        }
+    }
+
+    /// Entity tag of a listing, which changes whenever anything in it does.
+    fn list_etag<T: serde::Serialize>(items: &T) -> String {
+        // Stable across restarts and builds, unlike the standard library's hasher
+        let digest = Sha1::digest(serde_json::to_string(items).unwrap_or_default().as_bytes());
+
+        format!("\"{:x}\"", digest)
+    }
+
+    fn rejection_body(status: StatusCode, error: &'static str, message: String) -> ErrorBody {
+        ErrorBody {
+            code: status.as_u16(),
+            error,
+            message,
+        }
+    }
+
+    /// Turns every rejection into a JSON error, see `FeatureFlagError::body`. Handlers reject
+    /// with a `FeatureFlagError`, the others come from warp's filters.
+    pub async fn handle_rejection(rejection: Rejection) -> Result<impl warp::Reply, Infallible> {
+        let body = if let Some(err) = rejection.find::<FeatureFlagError>() {
+            if err.status() == StatusCode::INTERNAL_SERVER_ERROR {
+                log::error!("{}", err);
+            }
+            err.body()
+        } else if rejection.is_not_found() {
+            rejection_body(
+                StatusCode::NOT_FOUND,
+                "NOT_FOUND",
+                "Route was not found".to_string(),
+            )
+        } else if let Some(err) = rejection.find::<warp::body::BodyDeserializeError>() {
+            rejection_body(StatusCode::BAD_REQUEST, "VALIDATION", err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::InvalidQuery>() {
+            rejection_body(StatusCode::BAD_REQUEST, "VALIDATION", err.to_string())
+        } else if let Some(err) = rejection.find::<warp::reject::PayloadTooLarge>() {
+            rejection_body(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "PAYLOAD_TOO_LARGE",
+                err.to_string(),
+            )
+        } else if let Some(err) = rejection.find::<warp::reject::LengthRequired>() {
+            rejection_body(
+                StatusCode::LENGTH_REQUIRED,
+                "LENGTH_REQUIRED",
+                err.to_string(),
+            )
+        } else if let Some(err) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+            rejection_body(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "UNSUPPORTED_MEDIA_TYPE",
+                err.to_string(),
+            )
+        } else if let Some(err) = rejection.find::<warp::reject::MethodNotAllowed>() {
+            rejection_body(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "METHOD_NOT_ALLOWED",
+                err.to_string(),
+            )
+        } else {
+            log::error!("Unhandled rejection: {:?}", rejection);
+            rejection_body(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "INTERNAL",
+                "Internal server error".to_string(),
+            )
+        };
+
+        let status = StatusCode::from_u16(body.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        Ok(warp::reply::with_status(warp::reply::json(&body), status))
+    }
+
+    /// A page of the flags matching the query's filter, with the cursor of the next page in
+    /// `X-Next-Cursor`. With `since`, the changes to them after that cursor instead, waiting up
+    /// to `wait` for there to be any. Only changes made through this server end the wait early,
+    /// changes made elsewhere, e.g. with the CLI, show once it is over.
+    pub async fn list_flags<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        query: ListQuery,
+        if_none_match: Option<String>,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let ListQuery { filter, page, poll } = query;
+        if let Some(since) = poll.since {
+            return wait_for_changes(project, env, filter, since, poll.wait, store, changes).await;
+        }
+
+        let list = store.list_flags(&project, &env, &filter, &page).await?;
+
+        let etag = list_etag(&list.flags);
+        let mut response = conditional_reply(warp::reply::json(&list.flags), etag, if_none_match);
+        if let Some(next) = list.next.and_then(|next| HeaderValue::from_str(&next).ok()) {
+            response.headers_mut().insert("x-next-cursor", next);
+        }
+
+        Ok(response)
+    }
+
+    /// Changes to the flags matching `filter` after `since`, as soon as there are any or once
+    /// `wait` is over. Only changes made through this server end the wait early, those of other
+    /// processes, e.g. the CLI, are seen once it is over.
+    ///
+    /// Flags that changed but no longer match are reported as deleted, since they are gone from
+    /// the filtered listing. Flags that never matched can be among them.
+    async fn wait_for_changes<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        filter: FlagFilter,
+        since: ChangeCursor,
+        wait: Duration,
+        store: S,
+        changes: Changes,
+    ) -> Result<Response, Rejection> {
+        // Subscribed before looking, so that a change made meanwhile is not slept through
+        let mut receiver = changes.subscribe(None).receiver;
+        let deadline = Instant::now() + wait;
+        let mut over = false;
+
+        loop {
+            let mut flag_changes = store.get_flag_changes(&project, &env, since).await?;
+            let (matching, unmatched): (Vec<_>, Vec<_>) = flag_changes
+                .flags
+                .into_iter()
+                .partition(|flag| filter.matches(flag));
+            flag_changes.flags = matching;
+            // Full answers already say so by leaving them out
+            if !flag_changes.full {
+                flag_changes
+                    .deleted
+                    .extend(unmatched.into_iter().map(|flag| DeletedFlag {
+                        id: flag.id as u64,
+                        name: flag.name,
+                    }));
+            }
+            if !flag_changes.is_empty() || over {
+                return Ok(warp::reply::json(&flag_changes).into_response());
+            }
+
+            loop {
+                match time::timeout_at(deadline, receiver.recv()).await {
+                    // One last look, for changes that were not published
+                    Err(_) | Ok(Err(RecvError::Closed)) => {
+                        over = true;
+                        break;
+                    }
+                    Ok(Ok(change)) if change.project != project => continue,
+                    // A change to the project, or missed ones that might have been
+                    Ok(_) => break,
+                }
+            }
+        }
+    }
+
+    /// A `snapshot` event with every flag, then an event for each change to them. Clients that
+    /// reconnect with `Last-Event-ID` are sent the changes they missed instead of a snapshot, as
+    /// long as they are still known.
+    pub async fn stream_flags<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        last_event_id: Option<String>,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        // Ids that are not ours are ignored, so the client starts over
+        let last_seen = last_event_id.and_then(|id| changes.parse_event_id(&id));
+        let subscription = changes.subscribe(last_seen);
+        // Also turns away projects and environments that do not exist
+        let flags = store.get_all_flags(&project, &env).await?;
+
+        let mut first = vec![];
+        match subscription.missed {
+            Some(missed) => {
+                for change in missed.iter() {
+                    first.extend(change_event(&store, &project, &env, &changes, change).await);
+                }
+            }
+            None => first.push(snapshot_event(&changes, subscription.latest, &flags)),
+        }
+
+        let state = (subscription.receiver, store, project, env, changes);
+        let follow = stream::unfold(state, |state| async move {
+            let (mut receiver, store, project, env, changes) = state;
+
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(change) => change_event(&store, &project, &env, &changes, &change).await,
+                    // Changes were missed, so the client starts over from a new snapshot
+                    Err(RecvError::Lagged(_)) => {
+                        let subscription = changes.subscribe(None);
+                        receiver = subscription.receiver;
+
+                        match store.get_all_flags(&project, &env).await {
+                            Ok(flags) => {
+                                Some(snapshot_event(&changes, subscription.latest, &flags))
+                            }
+                            Err(err) => {
+                                log::error!("Ending the stream of {}/{}: {}", project, env, err);
+                                return None;
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+
+                if let Some(event) = event {
+                    let state = (receiver, store, project, env, changes);
+                    return Some((Ok::<_, Infallible>(event), state));
+                }
+            }
+        });
+
+        let events = stream::iter(first.into_iter().map(Ok)).chain(follow);
+        Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+    }
+
+    /// Upgrades to a WebSocket on which clients pick the flags they want to hear about, see
+    /// `watch::session`.
+    pub async fn watch_flags<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        ws: Ws,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        // Turns away projects and environments that do not exist before upgrading
+        store.get_all_flags(&project, &env).await?;
+
+        Ok(ws.on_upgrade(move |socket| watch::session(socket, project, env, store, changes)))
+    }
+
+    fn snapshot_event(changes: &Changes, latest: u64, flags: &[FlagWithID]) -> Event {
+        Event::default()
+            .id(changes.event_id(latest))
+            .event("snapshot")
+            .json_data(flags)
+            .unwrap_or_default()
+    }
+
+    /// The event for `change` as seen from `project` and `env`. Nothing for changes to other
+    /// projects, or to flags that are gone by now, which have an event of their own.
+    async fn change_event<S: AsyncFlagStore>(
+        store: &S,
+        project: &str,
+        env: &str,
+        changes: &Changes,
+        change: &Change,
+    ) -> Option<Event> {
+        if change.project != project {
+            return None;
+        }
+
+        let event = Event::default()
+            .id(changes.event_id(change.seq))
+            .event(change.kind.event());
+        let data = match &change.kind {
+            ChangeKind::Created(id) | ChangeKind::Updated(id) => {
+                let flag = store.get_flag_by_id(project, env, *id).await.ok()?;
+                serde_json::to_value(&flag)
+            }
+            ChangeKind::Deleted { id, name, .. } => Ok(json!({"id": id, "name": name})),
+        };
+
+        event.json_data(data.ok()?).ok()
+    }
+
+    pub async fn get_flag<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        id: u64,
+        if_none_match: Option<String>,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let flag = store.get_flag_by_id(&project, &env, id).await?;
+
+        let etag = flag.etag();
+        Ok(conditional_reply(
+            warp::reply::json(&flag),
+            etag,
+            if_none_match,
+        ))
+    }
+
+    pub async fn get_flag_by_name<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        name: String,
+        if_none_match: Option<String>,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let flag = store.get_flag_by_name(&project, &env, &name).await?;
+
+        let etag = flag.etag();
+        Ok(conditional_reply(
+            warp::reply::json(&flag),
+            etag,
+            if_none_match,
+        ))
+    }
+
+    pub async fn create_flag<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        new_flag: Flag,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("create_flag: {}: {}: {:?}", project, env, new_flag);
+
+        let name = new_flag.name.clone();
+        store.add_flag(&project, &env, new_flag, &actor).await?;
+        let flag = store.get_flag_by_name(&project, &env, &name).await?;
+        changes.publish(&project, None, ChangeKind::Created(flag.id as u64));
+
+        Ok(message_reply(
+            StatusCode::CREATED,
+            format!("Flag {} was created", name),
+        ))
+    }
+
+    pub async fn update_flag<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        id: u64,
+        flag_update: FlagUpdate,
+        headers: WriteHeaders,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!(
+            "update_flag: {}: id: {:?}, update {:?}",
+            env,
+            id,
+            flag_update
+        );
+
+        let flag = store.get_flag_by_id(&project, &env, id).await?;
+        let changed = !flag_update.is_empty();
+        let flag = apply_update(&store, &project, &env, flag, flag_update, headers).await?;
+        if changed {
+            changes.publish(&project, Some(&env), ChangeKind::Updated(flag.id as u64));
+        }
+
+        Ok(warp::reply::with_header(
+            message_reply(StatusCode::OK, format!("Flag {} was updated", id)),
+            "etag",
+            flag.etag(),
+        ))
+    }
+
+    pub async fn update_flag_by_name<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        name: String,
+        flag_update: FlagUpdate,
+        headers: WriteHeaders,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!(
+            "update_flag_by_name: {}: {}, update {:?}",
+            env,
+            name,
+            flag_update
+        );
+
+        let flag = store.get_flag_by_name(&project, &env, &name).await?;
+        let changed = !flag_update.is_empty();
+        let flag = apply_update(&store, &project, &env, flag, flag_update, headers).await?;
+        if changed {
+            changes.publish(&project, Some(&env), ChangeKind::Updated(flag.id as u64));
+        }
+
+        Ok(warp::reply::with_header(
+            message_reply(StatusCode::OK, format!("Flag {} was updated", name)),
+            "etag",
+            flag.etag(),
+        ))
+    }
+
+    /// Applies `update` to `flag` unless `If-Match` rules it out, returns the updated flag.
+    async fn apply_update<S: AsyncFlagStore>(
+        store: &S,
+        project: &str,
+        env: &str,
+        flag: FlagWithID,
+        mut update: FlagUpdate,
+        headers: WriteHeaders,
+    ) -> Result<FlagWithID, Rejection> {
+        let id = flag.id as u64;
+        if let Some(if_match) = headers.if_match {
+            check_if_match(&if_match, &flag)?;
+            // Checked again by the store, in case the flag changes in the meantime
+            update.expected_version.get_or_insert(flag.version);
+        }
+
+        store
+            .update_flag(project, env, id, update, &headers.actor)
+            .await?;
+
+        Ok(store.get_flag_by_id(project, env, id).await?)
+    }
+
+    pub async fn delete_flag<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        headers: WriteHeaders,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete flag id <{}> of project <{}>", id, project);
+
+        // Preconditions only apply to flags that exist, missing ones stay 404. Versions are
+        // shared by all environments, so any of them will do.
+        let flag = store
+            .get_flag_by_id(&project, DEFAULT_ENVIRONMENT, id)
+            .await;
+        let flag = match headers.if_match {
+            Some(if_match) => {
+                let flag = flag?;
+                check_if_match(&if_match, &flag)?;
+                Some(flag)
+            }
+            None => flag.ok(),
+        };
+
+        match store.delete_flag(&project, id, &headers.actor).await? {
+            DeleteOutcome::Deleted => {
+                publish_deletion(&changes, &project, flag);
+                Ok(StatusCode::NO_CONTENT)
+            }
+            DeleteOutcome::NotFound => {
+                Err(FeatureFlagError::NotFound(format!("Flag {}", id)).into())
+            }
+        }
+    }
+
+    pub async fn delete_flag_by_name<S: AsyncFlagStore>(
+        project: String,
+        name: String,
+        headers: WriteHeaders,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete flag <{}> of project <{}>", name, project);
+
+        let flag = store
+            .get_flag_by_name(&project, DEFAULT_ENVIRONMENT, &name)
+            .await;
+        let flag = match headers.if_match {
+            Some(if_match) => {
+                let flag = flag?;
+                check_if_match(&if_match, &flag)?;
+                Some(flag)
+            }
+            None => flag.ok(),
+        };
+
+        match store
+            .delete_flag_by_name(&project, &name, &headers.actor)
+            .await?
+        {
+            DeleteOutcome::Deleted => {
+                publish_deletion(&changes, &project, flag);
+                Ok(StatusCode::NO_CONTENT)
+            }
+            DeleteOutcome::NotFound => {
+                Err(FeatureFlagError::NotFound(format!("Flag {}", name)).into())
+            }
+        }
+    }
+
+    /// Tells subscribers about a deleted flag, when it could be looked up before.
+    fn publish_deletion(changes: &Changes, project: &str, flag: Option<FlagWithID>) {
+        if let Some(flag) = flag {
+            let kind = ChangeKind::Deleted {
+                id: flag.id as u64,
+                name: flag.name,
+                tags: flag.metadata.tags,
+            };
+            changes.publish(project, None, kind);
+        }
+    }
+
+    /// Answers with the result of every operation. A batch that was not committed gets the
+    /// status of the operation that failed, the others `200 OK` even when some failed.
+    pub async fn apply_batch<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        batch: Batch,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!(
+            "apply_batch: {}: {} operations, {:?}",
+            env,
+            batch.operations.len(),
+            batch.mode
+        );
+
+        let batch = store.apply_batch(&project, &env, batch, &actor).await?;
+        let status = match batch.first_error() {
+            Some(err) if !batch.committed => err.status(),
+            _ => StatusCode::OK,
+        };
+
+        // Only operations that were kept succeeded, so they are all there is to publish
+        let results: Vec<_> = batch
+            .results
+            .into_iter()
+            .map(|result| match result {
+                OperationResult::Created(flag) => {
+                    changes.publish(&project, None, ChangeKind::Created(flag.id as u64));
+                    json!({ "result": "created", "flag": flag })
+                }
+                OperationResult::Updated(flag) => {
+                    changes.publish(&project, Some(&env), ChangeKind::Updated(flag.id as u64));
+                    json!({ "result": "updated", "flag": flag })
+                }
+                OperationResult::Deleted(flag) => {
+                    let body = json!({ "result": "deleted", "flag": flag });
+                    publish_deletion(&changes, &project, Some(flag));
+                    body
+                }
+                OperationResult::Failed(err) => json!({ "result": "failed", "error": err.body() }),
+                OperationResult::Aborted => json!({ "result": "aborted" }),
+            })
+            .collect();
+
+        Ok(warp::reply::with_status(
+            warp::reply::json(&json!({ "committed": batch.committed, "results": results })),
+            status,
+        ))
+    }
+
+    pub async fn flag_history<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let history = store.get_flag_history(&project, id).await?;
+
+        Ok(warp::reply::json(&history))
+    }
+
+    pub async fn list_rules<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        id: u64,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let rules = store.get_rules(&project, &env, id).await?;
+
+        Ok(warp::reply::json(&rules))
+    }
+
+    pub async fn update_rules<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        id: u64,
+        new_rules: Vec<Rule>,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("update_rules: {}: flag id: {}, {:?}", env, id, new_rules);
+
+        store
+            .set_rules(&project, &env, id, new_rules, &actor)
+            .await?;
+        changes.publish(&project, Some(&env), ChangeKind::Updated(id));
+
+        Ok(message_reply(
+            StatusCode::OK,
+            format!("Rules of flag {} were updated", id),
+        ))
+    }
+
+    pub async fn list_segments<S: AsyncFlagStore>(
+        project: String,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let segments = store.get_segments(&project).await?;
+
+        Ok(warp::reply::json(&segments))
+    }
+
+    pub async fn get_segment<S: AsyncFlagStore>(
+        project: String,
+        name: String,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let segment = store.get_segment(&project, &name).await?;
+
+        Ok(warp::reply::json(&segment))
+    }
+
+    pub async fn create_segment<S: AsyncFlagStore>(
+        project: String,
+        segment: Segment,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("create_segment: {}: {:?}", project, segment);
+
+        let name = segment.name.clone();
+        store.add_segment(&project, segment).await?;
+
+        Ok(message_reply(
+            StatusCode::CREATED,
+            format!("Segment {} was created", name),
+        ))
+    }
+
+    pub async fn update_segment<S: AsyncFlagStore>(
+        project: String,
+        name: String,
+        update: SegmentUpdate,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("update_segment: {}: {}: {:?}", project, name, update);
+
+        // Flags that refer to the segment serve other variations now, in every environment
+        for id in store
+            .update_segment(&project, &name, update, &actor)
+            .await?
+        {
+            changes.publish(&project, None, ChangeKind::Updated(id));
+        }
+
+        Ok(message_reply(
+            StatusCode::OK,
+            format!("Segment {} was updated", name),
+        ))
+    }
+
+    pub async fn delete_segment<S: AsyncFlagStore>(
+        project: String,
+        name: String,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete segment <{}> of project <{}>", name, project);
+
+        match store.delete_segment(&project, &name).await? {
+            0 => Err(FeatureFlagError::NotFound(format!("Segment {}", name)).into()),
+            _ => Ok(StatusCode::NO_CONTENT),
+        }
+    }
+
+    pub async fn evaluate_flag<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        name: String,
+        context: EvaluationContext,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("evaluate_flag: {}: {} for {:?}", env, name, context);
+
+        let evaluation = store.evaluate_flag(&project, &env, &name, &context).await?;
+
+        Ok(warp::reply::json(&evaluation))
+    }
+
+    pub async fn evaluate_flags<S: AsyncFlagStore>(
+        project: String,
+        env: String,
+        context: EvaluationContext,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("evaluate_flags: {}: {:?}", env, context);
+
+        let evaluations = store.evaluate_all_flags(&project, &env, &context).await?;
+
+        Ok(warp::reply::json(&evaluations))
+    }
+
+    pub async fn list_environments<S: AsyncFlagStore>(
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let environments = store.get_environments().await?;
+
+        Ok(warp::reply::json(&environments))
+    }
+
+    pub async fn create_environment<S: AsyncFlagStore>(
+        environment: NewEnvironment,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("create_environment: {:?}", environment);
+
+        let name = environment.name.clone();
+        store.add_environment(environment).await?;
+
+        Ok(message_reply(
+            StatusCode::CREATED,
+            format!("Environment {} was created", name),
+        ))
+    }
+
+    pub async fn delete_environment<S: AsyncFlagStore>(
+        name: String,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete environment <{}>", name);
+
+        match store.delete_environment(&name).await? {
+            0 => Err(FeatureFlagError::NotFound(format!("Environment {}", name)).into()),
+            _ => Ok(StatusCode::NO_CONTENT),
+        }
+    }
+
+    pub async fn list_projects<S: AsyncFlagStore>(store: S) -> Result<impl warp::Reply, Rejection> {
+        let projects = store.get_projects().await?;
+
+        Ok(warp::reply::json(&projects))
+    }
+
+    pub async fn create_project<S: AsyncFlagStore>(
+        project: NewProject,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("create_project: {:?}", project);
+
+        let name = project.name.clone();
+        store.add_project(project).await?;
+
+        Ok(message_reply(
+            StatusCode::CREATED,
+            format!("Project {} was created", name),
+        ))
+    }
+
+    pub async fn delete_project<S: AsyncFlagStore>(
+        name: String,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete project <{}>", name);
+
+        match store.delete_project(&name).await? {
+            0 => Err(FeatureFlagError::NotFound(format!("Project {}", name)).into()),
+            _ => Ok(StatusCode::NO_CONTENT),
+        }
+    }
+
+    pub async fn list_variations<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        store: S,
+    ) -> Result<impl warp::Reply, Rejection> {
+        let variations = store.get_variations(&project, id).await?;
+
+        Ok(warp::reply::json(&variations))
+    }
+
+    pub async fn create_variation<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        variation: Variation,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("create_variation: flag id: {}, {:?}", id, variation);
+
+        let name = variation.name.clone();
+        store.add_variation(&project, id, variation, &actor).await?;
+        changes.publish(&project, None, ChangeKind::Updated(id));
+
+        Ok(message_reply(
+            StatusCode::CREATED,
+            format!("Variation {} was created", name),
+        ))
+    }
+
+    pub async fn update_variation<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        name: String,
+        update: VariationUpdate,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("update_variation: flag id: {}, {}: {:?}", id, name, update);
+
+        store
+            .update_variation(&project, id, &name, update.value, &actor)
+            .await?;
+        changes.publish(&project, None, ChangeKind::Updated(id));
+
+        Ok(message_reply(
+            StatusCode::OK,
+            format!("Variation {} was updated", name),
+        ))
+    }
+
+    pub async fn delete_variation<S: AsyncFlagStore>(
+        project: String,
+        id: u64,
+        name: String,
+        actor: String,
+        store: S,
+        changes: Changes,
+    ) -> Result<impl warp::Reply, Rejection> {
+        log::debug!("delete variation <{}> of flag id <{}>", name, id);
+
+        match store.delete_variation(&project, id, &name, &actor).await? {
+            0 => {
+                Err(FeatureFlagError::NotFound(format!("Variation {} of flag {}", name, id)).into())
+            }
+            _ => {
+                changes.publish(&project, None, ChangeKind::Updated(id));
+                Ok(StatusCode::NO_CONTENT)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use hyper::body::HttpBody;
+    use rusqlite::Connection;
+    use serde_json::{json, Value};
+    use tokio::sync::Mutex;
+    use warp::Reply;
+
+    use super::changes::{ChangeKind, Changes};
+    use super::filters::*;
+    use super::handlers::*;
+    use super::{flag_states, reload};
+    use feature_flags::config::{Features, ServerConfig};
+    use feature_flags::db::changes::ChangeCursor;
+    use feature_flags::db::environments::DEFAULT_ENVIRONMENT;
+    use feature_flags::db::migrations;
+    use feature_flags::db::projects::DEFAULT_PROJECT;
+    use feature_flags::db::*;
+    use feature_flags::store::{AsyncFlagStore, FileStore, MemoryStore, SqliteStore};
+
+    // Every endpoint test runs against both stores, which have to behave the same
+
+    fn sqlite_store() -> Arc<Mutex<SqliteStore>> {
+        let conn = Connection::open_in_memory().unwrap();
+
+        migrations::migrate(&conn).unwrap();
+
+        Arc::new(Mutex::new(SqliteStore::new(conn)))
+    }
+
+    fn memory_store() -> Arc<Mutex<MemoryStore>> {
+        Arc::new(Mutex::new(MemoryStore::new()))
+    }
+
+    #[tokio::test]
+    async fn test_unknown_route() {
+        let filter = feature_flag_create(
+            memory_store(),
+            Changes::new(),
+            ServerConfig::default().body_limit,
+        );
+
+        let response = warp::test::request().path("hi").reply(&filter).await;
+
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn verify_cli() {
+        use clap::CommandFactory;
+        super::Cli::command().debug_assert()
+    }
+
+    #[test]
+    fn test_cli_overrides_config() {
+        use clap::Parser;
+
+        let cli = super::Cli::try_parse_from(vec![
+            "server",
+            "--listen",
+            "0.0.0.0",
+            "--listen",
+            "::",
+            "--port",
+            "8080",
+            "--disable",
+            "history",
+        ])
+        .unwrap();
+        let config = cli.load().unwrap();
+
+        assert_eq!(config.server.listen.len(), 2);
+        assert_eq!(config.server.port, 8080);
+        assert!(!config.server.features.history);
+        assert!(config.server.features.evaluation);
+    }
+
+    #[tokio::test]
+    async fn test_server_config() {
+        let config = ServerConfig {
+            body_limit: 64,
+            features: Features {
+                evaluation: false,
+                history: false,
+                reload: true,
+            },
+            ..ServerConfig::default()
+        };
+        let filter = feature_flag_all_routes(memory_store(), &config, Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .json(&json!({"name": "new_checkout", "value": true}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .json(&json!({"name": "x".repeat(64), "value": true}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 413);
+
+        let response = warp::test::request()
+            .path("/flags/1/history")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/evaluate/new_checkout")
+            .json(&json!({"key": "user-42"}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_flag_files_are_read_only() {
+        let dir = std::env::temp_dir().join(format!("feature-flags-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: checkout, value: true}]",
+        )
+        .unwrap();
+
+        let store = Arc::new(Mutex::new(FileStore::open(&dir).unwrap()));
+        let filter = feature_flag_all_routes(store, &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let flags: Vec<FlagWithID> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(flags.len(), 1);
+        assert_eq!(flags[0].name, "checkout");
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .json(&json!({"name": "search", "value": false}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 403);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], json!("READ_ONLY"));
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .json(&json!({"enabled": false}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 403);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reloaded_files_are_published() {
+        let dir = std::env::temp_dir().join(format!(
+            "feature-flags-server-reload-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: checkout, value: true}, {name: search, value: true}]",
+        )
+        .unwrap();
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let changes = Changes::new();
+        let mut receiver = changes.subscribe(None).receiver;
+        let mut known = flag_states(&store).unwrap();
+
+        reload(&mut store, &changes, &mut known);
+        assert!(receiver.try_recv().is_err());
+
+        // search goes away and dark_mode takes its id
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: checkout, value: false}, {name: dark_mode, value: true}]",
+        )
+        .unwrap();
+        reload(&mut store, &changes, &mut known);
+        let published: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|change| change.kind)
+            .collect();
+        assert_eq!(
+            published,
+            vec![
+                ChangeKind::Deleted {
+                    id: 2,
+                    name: "search".to_string(),
+                    tags: vec![]
+                },
+                ChangeKind::Updated(1),
+                ChangeKind::Created(2),
+            ]
+        );
+
+        // Changing a segment changes the flags whose rules refer to it
+        let flags = |included: &str| {
+            format!(
+                "segments: [{{name: staff, included: [{}]}}]
+flags:
+  - name: checkout
+    value: false
+    variations: [{{name: enabled, value: true}}]
+    rules: [{{clauses: [{{op: segment, values: [staff]}}], variation: enabled}}]
+  - {{name: dark_mode, value: true}}",
+                included
+            )
+        };
+        std::fs::write(dir.join("flags.yaml"), flags("ada")).unwrap();
+        reload(&mut store, &changes, &mut known);
+        assert_eq!(receiver.try_recv().unwrap().kind, ChangeKind::Updated(1));
+        assert!(receiver.try_recv().is_err());
+        std::fs::write(dir.join("flags.yaml"), flags("ada, bob")).unwrap();
+        reload(&mut store, &changes, &mut known);
+        assert_eq!(receiver.try_recv().unwrap().kind, ChangeKind::Updated(1));
+        assert!(receiver.try_recv().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejections_are_json() {
+        let config = ServerConfig {
+            body_limit: 64,
+            ..ServerConfig::default()
+        };
+        let filter = feature_flag_all_routes(memory_store(), &config, Changes::new());
+
+        let requests = [
+            ("GET", "/hi", "".to_string(), 404, "NOT_FOUND"),
+            (
+                "POST",
+                "/flags",
+                "{\"name\": ".to_string(),
+                400,
+                "VALIDATION",
+            ),
+            (
+                "POST",
+                "/flags",
+                json!({"name": "x".repeat(64), "value": true}).to_string(),
+                413,
+                "PAYLOAD_TOO_LARGE",
+            ),
+            ("DELETE", "/envs/staging", "".to_string(), 404, "NOT_FOUND"),
+        ];
+        for (method, path, body, status, error) in requests.iter() {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(body)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), *status, "{} {}", method, path);
+
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["code"], json!(status), "{} {}", method, path);
+            assert_eq!(body["error"], json!(error), "{} {}", method, path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_storage_failures() {
+        // Without the migrations every query fails, which no handler may panic on
+        let store = Arc::new(Mutex::new(SqliteStore::new(
+            Connection::open_in_memory().unwrap(),
+        )));
+        let filter = feature_flag_all_routes(store, &ServerConfig::default(), Changes::new());
+
+        let flag = json!({"name": "checkout", "value": true});
+        let requests = [
+            ("GET", "/flags", json!(null)),
+            ("POST", "/flags", flag.clone()),
+            ("PUT", "/flags/1", json!({"enabled": false})),
+            ("DELETE", "/flags/1", json!(null)),
+            ("GET", "/flags/1", json!(null)),
+            ("GET", "/flags/by-name/checkout", json!(null)),
+            ("PUT", "/flags/by-name/checkout", json!({"enabled": false})),
+            ("DELETE", "/flags/by-name/checkout", json!(null)),
+            ("GET", "/flags/1/history", json!(null)),
+            ("GET", "/flags/1/variations", json!(null)),
+            (
+                "POST",
+                "/flags/1/variations",
+                json!({"name": "beta", "value": false}),
+            ),
+            ("PUT", "/flags/1/variations/beta", json!({"value": true})),
+            ("DELETE", "/flags/1/variations/beta", json!(null)),
+            ("GET", "/flags/1/rules", json!(null)),
+            ("PUT", "/flags/1/rules", json!([])),
+            ("GET", "/segments", json!(null)),
+            ("GET", "/segments/beta-testers", json!(null)),
+            ("POST", "/segments", json!({"name": "beta-testers"})),
+            (
+                "PUT",
+                "/segments/beta-testers",
+                json!({"included": ["ada"]}),
+            ),
+            ("DELETE", "/segments/beta-testers", json!(null)),
+            ("POST", "/evaluate/checkout", json!({"key": "user-1"})),
+            ("POST", "/evaluate", json!({"key": "user-1"})),
+            ("GET", "/envs", json!(null)),
+            ("POST", "/envs", json!({"name": "staging"})),
+            ("DELETE", "/envs/staging", json!(null)),
+            ("GET", "/projects", json!(null)),
+            ("POST", "/projects", json!({"name": "payments"})),
+            ("DELETE", "/projects/payments", json!(null)),
+            ("GET", "/projects/payments/envs/staging/flags", json!(null)),
+        ];
+        for (method, path, body) in requests.iter() {
+            let mut request = warp::test::request().method(method).path(path);
+            if !body.is_null() {
+                request = request.json(body);
+            }
+            let response = request.reply(&filter).await;
+            assert_eq!(response.status(), 500, "{} {}", method, path);
+
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(
+                body,
+                json!({"code": 500, "error": "STORAGE", "message": "Internal server error"}),
+                "{} {}",
+                method,
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_flag_endpoint() {
+        check_create_flag_endpoint(sqlite_store()).await;
+        check_create_flag_endpoint(memory_store()).await;
+    }
+
+    async fn check_create_flag_endpoint<S: AsyncFlagStore>(store: S) {
+        // TODO: Learn how to check the body of the response here so that
+        // I do not have to test the same thing twice.
+
+        let filter = feature_flag_create(
+            store.clone(),
+            Changes::new(),
+            ServerConfig::default().body_limit,
+        );
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .header("accept", "application/json")
+            .body(
+                json!(&Flag {
+                    name: "test".to_string(),
+                    flag_type: None,
+                    value: FlagValue::Bool(true),
+                    metadata: FlagMetadata::default(),
+                })
+                .to_string(),
+            )
+            .reply(&filter)
+            .await;
+
+        assert_eq!(response.status(), 201);
+    }
+
+    #[tokio::test]
+    async fn test_create_flag_handler() {
+        check_create_flag_handler(sqlite_store()).await;
+        check_create_flag_handler(memory_store()).await;
+    }
+
+    async fn check_create_flag_handler<S: AsyncFlagStore>(store: S) {
+        let flag = Flag {
+            name: "test".to_string(),
+            flag_type: None,
+            value: FlagValue::Bool(true),
+            metadata: FlagMetadata::default(),
+        };
+
+        let reply = create_flag(
+            DEFAULT_PROJECT.to_string(),
+            DEFAULT_ENVIRONMENT.to_string(),
+            flag,
+            "test".to_string(),
+            store.clone(),
+            Changes::new(),
+        )
+        .await
+        .unwrap();
+
+        let mut response = reply.into_response();
+
+        // Got from https://github.com/rust-lang/async-book/issues/6#issuecomment-611703749
+        let body = hyper::body::to_bytes(response.body_mut()).await.unwrap();
+
+        let body_string = String::from_utf8(body.to_ascii_lowercase()).unwrap();
+
+        assert_eq!(response.status(), 201);
+        assert_eq!(
+            body_string,
+            "{\"code\":201,\"message\":\"flag test was created\"}".to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flag_by_name_endpoints() {
+        check_flag_by_name_endpoints(sqlite_store()).await;
+        check_flag_by_name_endpoints(memory_store()).await;
+    }
+
+    async fn check_flag_by_name_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        for path in ["/flags/1", "/flags/by-name/new_checkout"].iter() {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 200, "{}", path);
+
+            let flag: FlagWithID = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(flag.id, 1);
+            assert_eq!(flag.name, "new_checkout");
+        }
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/envs/production/flags/by-name/new_checkout")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/flags/by-name/new_checkout")
+            .reply(&filter)
+            .await;
+        let flag: FlagWithID = serde_json::from_slice(response.body()).unwrap();
+        assert!(!flag.enabled);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/by-name/new_checkout")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        for path in ["/flags/1", "/flags/by-name/new_checkout"].iter() {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 404, "{}", path);
+
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["error"], json!("NOT_FOUND"));
+        }
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/by-name/new_checkout")
+            .body(json!({"enabled": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_delete_flag_endpoint() {
+        check_delete_flag_endpoint(sqlite_store()).await;
+        check_delete_flag_endpoint(memory_store()).await;
+    }
+
+    async fn check_delete_flag_endpoint<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        for name in ["new_checkout", "search"].iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/flags")
+                .body(json!({"name": name, "value": true}).to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201);
+        }
+
+        // A version the flag is not at
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/1")
+            .header("if-match", "\"2\"")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 412);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], json!("PRECONDITION_FAILED"));
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/1")
+            .header("if-match", "\"2\", \"1\"")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/by-name/search")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        // Deleting again changes nothing and says so
+        let requests = [
+            ("/flags/1", None),
+            ("/flags/1", Some("*")),
+            ("/flags/by-name/search", None),
+            ("/flags/by-name/search", Some("*")),
+        ];
+        for (path, if_match) in requests.iter() {
+            let mut request = warp::test::request().method("DELETE").path(path);
+            if let Some(if_match) = if_match {
+                request = request.header("if-match", *if_match);
+            }
+            let response = request.reply(&filter).await;
+            assert_eq!(response.status(), 404, "{} {:?}", path, if_match);
+
+            let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body["error"], json!("NOT_FOUND"));
+        }
+
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        let flags: Vec<FlagWithID> = serde_json::from_slice(response.body()).unwrap();
+        assert!(flags.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_flag_metadata() {
+        check_flag_metadata(sqlite_store()).await;
+        check_flag_metadata(memory_store()).await;
+    }
+
+    async fn check_flag_metadata<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let flags = [
+            json!({"name": "new_checkout", "value": true, "owner": "payments",
+                "tags": ["checkout", "temporary"], "description": "The new checkout page"}),
+            json!({"name": "search", "value": true, "owner": "discovery", "tags": ["temporary"]}),
+            json!({"name": "dark_mode", "value": false}),
+        ];
+        for flag in flags.iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/flags")
+                .header("x-actor", "grace")
+                .body(flag.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201);
+        }
+
+        let response = warp::test::request()
+            .path("/flags/by-name/new_checkout")
+            .reply(&filter)
+            .await;
+        let flag: FlagWithID = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            flag.metadata.description.as_deref(),
+            Some("The new checkout page")
+        );
+        assert_eq!(flag.metadata.tags, vec!["checkout", "temporary"]);
+        assert_eq!(flag.created_by, "grace");
+        assert_eq!(flag.created_at, flag.updated_at);
+
+        let listings = [
+            ("/flags", vec!["dark_mode", "new_checkout", "search"]),
+            ("/flags?tag=temporary", vec!["new_checkout", "search"]),
+            ("/flags?owner=discovery", vec!["search"]),
+            ("/flags?owner=discovery&tag=checkout", vec![]),
+        ];
+        for (path, expected) in listings.iter() {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 200, "{}", path);
+
+            let flags: Vec<FlagWithID> = serde_json::from_slice(response.body()).unwrap();
+            let mut names: Vec<_> = flags.iter().map(|flag| flag.name.as_str()).collect();
+            names.sort_unstable();
+            assert_eq!(&names, expected, "{}", path);
+        }
+
+        // Changes move updated_at on, timestamps have millisecond precision
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/by-name/search")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "search")
+            .await
+            .unwrap();
+        assert!(flag.updated_at > flag.created_at);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "beta", "value": true, "tags": "beta"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_list_flags_pages() {
+        check_list_flags_pages(sqlite_store()).await;
+        check_list_flags_pages(memory_store()).await;
+    }
+
+    async fn check_list_flags_pages<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+        let flags = [
+            json!({"name": "search", "value": 30}),
+            json!({"name": "new_checkout", "value": "blue"}),
+            json!({"name": "new_search", "value": true}),
+            json!({"name": "dark_mode", "value": false}),
+        ];
+        for flag in flags.iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/flags")
+                .body(flag.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201);
+        }
+
+        let listings = [
+            (
+                "/flags",
+                vec!["search", "new_checkout", "new_search", "dark_mode"],
+            ),
+            (
+                "/flags?sort=name",
+                vec!["dark_mode", "new_checkout", "new_search", "search"],
+            ),
+            (
+                "/flags?sort=name&order=desc&prefix=new_",
+                vec!["new_search", "new_checkout"],
+            ),
+            ("/flags?contains=search", vec!["search", "new_search"]),
+            ("/flags?value=blue", vec!["new_checkout"]),
+            ("/flags?value=30", vec!["search"]),
+        ];
+        for (path, expected) in listings.iter() {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 200, "{}", path);
+            assert!(
+                !response.headers().contains_key("x-next-cursor"),
+                "{}",
+                path
+            );
+
+            let flags: Vec<FlagWithID> = serde_json::from_slice(response.body()).unwrap();
+            let names: Vec<_> = flags.iter().map(|flag| flag.name.as_str()).collect();
+            assert_eq!(&names, expected, "{}", path);
+        }
+
+        // Follows the cursors to the last page
+        let mut names = vec![];
+        let mut path = "/flags?sort=name&limit=3".to_string();
+        loop {
+            let response = warp::test::request().path(&path).reply(&filter).await;
+            assert_eq!(response.status(), 200, "{}", path);
+            let flags: Vec<FlagWithID> = serde_json::from_slice(response.body()).unwrap();
+            names.extend(flags.into_iter().map(|flag| flag.name));
+
+            match response.headers().get("x-next-cursor") {
+                Some(next) => {
+                    path = format!("/flags?sort=name&limit=3&cursor={}", next.to_str().unwrap())
+                }
+                None => break,
+            }
+        }
+        assert_eq!(
+            names,
+            vec!["dark_mode", "new_checkout", "new_search", "search"]
+        );
+
+        for path in [
+            "/flags?limit=0",
+            "/flags?sort=size",
+            "/flags?cursor=nonsense",
+            "/flags?since=0&limit=2",
+        ] {
+            let response = warp::test::request().path(path).reply(&filter).await;
+            assert_eq!(response.status(), 400, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flags_batch() {
+        check_flags_batch(sqlite_store()).await;
+        check_flags_batch(memory_store()).await;
+    }
+
+    async fn check_flags_batch<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+        let batch = |mode: &str| {
+            json!({
+                "mode": mode,
+                "operations": [
+                    {"op": "create", "flag": {"name": "checkout", "value": true}},
+                    {"op": "create", "flag": {"name": "search", "value": "fast"}},
+                    {"op": "update", "name": "missing", "update": {"enabled": false}},
+                ]
+            })
+        };
+        let results = |body: &Value| -> Vec<String> {
+            body["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|result| result["result"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let names = |body: &[u8]| -> Vec<String> {
+            let flags: Vec<FlagWithID> = serde_json::from_slice(body).unwrap();
+            flags.into_iter().map(|flag| flag.name).collect()
+        };
+
+        // Nothing is kept when an operation fails
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags:batch")
+            .body(batch("all_or_nothing").to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["committed"], json!(false));
+        assert_eq!(results(&body), vec!["aborted", "aborted", "failed"]);
+        assert_eq!(body["results"][2]["error"]["error"], json!("NOT_FOUND"));
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert!(names(response.body()).is_empty());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags:batch")
+            .body(batch("best_effort").to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["committed"], json!(true));
+        assert_eq!(results(&body), vec!["created", "created", "failed"]);
+        assert_eq!(body["results"][1]["flag"]["value"], json!("fast"));
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert_eq!(names(response.body()), vec!["checkout", "search"]);
+
+        // All-or-nothing is the default
+        let batch = json!({
+            "operations": [
+                {"op": "update", "name": "checkout", "update": {"enabled": false, "expected_version": 1}},
+                {"op": "delete", "name": "search"},
+                {"op": "create", "flag": {"name": "dark_mode", "value": false, "tags": ["ui"]}},
+            ]
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags:batch")
+            .header("x-actor", "ada")
+            .body(batch.to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(results(&body), vec!["updated", "deleted", "created"]);
+        assert_eq!(body["results"][0]["flag"]["enabled"], json!(false));
+        assert_eq!(body["results"][1]["flag"]["name"], json!("search"));
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert_eq!(names(response.body()), vec!["checkout", "dark_mode"]);
+
+        let response = warp::test::request()
+            .path("/flags/by-name/dark_mode")
+            .reply(&filter)
+            .await;
+        let flag: FlagWithID = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(flag.created_by, "ada");
+
+        // A stale version fails the batch like any other error
+        let batch = json!({
+            "operations": [
+                {"op": "delete", "name": "dark_mode"},
+                {"op": "update", "name": "checkout", "update": {"enabled": true, "expected_version": 1}},
+            ]
+        });
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags:batch")
+            .body(batch.to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 412);
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert_eq!(names(response.body()), vec!["checkout", "dark_mode"]);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags:batch")
+            .body(json!({"operations": [{"op": "rename", "name": "checkout"}]}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    /// Reads the next Server-Sent Event from `body` as its id, name and data.
+    async fn next_event(body: &mut hyper::Body, buffer: &mut String) -> (String, String, Value) {
+        while !buffer.contains("\n\n") {
+            let chunk = tokio::time::timeout(std::time::Duration::from_secs(5), body.data())
+                .await
+                .expect("no event within 5 seconds")
+                .unwrap()
+                .unwrap();
+            buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+
+        let end = buffer.find("\n\n").unwrap();
+        let event: String = buffer.drain(..end + 2).collect();
+        let (mut id, mut name, mut data) = (String::new(), String::new(), Value::Null);
+        for line in event.lines() {
+            match line.split_once(':') {
+                Some(("id", value)) => id = value.trim().to_string(),
+                Some(("event", value)) => name = value.trim().to_string(),
+                Some(("data", value)) => data = serde_json::from_str(value).unwrap(),
+                _ => {}
+            }
+        }
+
+        (id, name, data)
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        check_stream(sqlite_store()).await;
+        check_stream(memory_store()).await;
+    }
+
+    async fn check_stream<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+        let requests = [
+            (
+                "POST",
+                "/flags",
+                json!({"name": "new_checkout", "value": true}),
+            ),
+            ("POST", "/projects", json!({"name": "payments"})),
+        ];
+        for (method, path, body) in requests.iter() {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(body.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201, "{} {}", method, path);
+        }
+
+        let response = warp::test::request()
+            .path("/stream")
+            .filter(&filter)
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+        let mut buffer = String::new();
+
+        let (id, name, data) = next_event(&mut body, &mut buffer).await;
+        let (epoch, seq) = id.split_once('-').unwrap();
+        assert_eq!((seq, name.as_str()), ("1", "snapshot"));
+        assert_eq!(data[0]["name"], json!("new_checkout"));
+        let epoch = epoch.to_string();
+
+        // Each change as it happens, changes to other projects are in their own streams
+        let requests = [
+            (
+                "PUT",
+                "/flags/by-name/new_checkout",
+                json!({"enabled": false}),
+            ),
+            (
+                "POST",
+                "/projects/payments/flags",
+                json!({"name": "refunds", "value": true}),
+            ),
+            ("POST", "/flags", json!({"name": "search", "value": true})),
+            ("DELETE", "/flags/by-name/search", Value::Null),
+        ];
+        let expected = [
+            Some((
+                "2",
+                "flag.updated",
+                json!({"name": "new_checkout", "enabled": false}),
+            )),
+            None,
+            Some((
+                "4",
+                "flag.created",
+                json!({"name": "search", "enabled": true}),
+            )),
+            Some(("5", "flag.deleted", json!({"name": "search", "id": 3}))),
+        ];
+        for ((method, path, request_body), expected) in requests.iter().zip(expected.iter()) {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(request_body.to_string())
+                .reply(&filter)
+                .await;
+            assert!(response.status().is_success(), "{} {}", method, path);
+
+            if let Some((expected_id, expected_name, fields)) = expected {
+                let (id, name, data) = next_event(&mut body, &mut buffer).await;
+                let expected_id = format!("{}-{}", epoch, expected_id);
+                assert_eq!(
+                    (id.as_str(), name.as_str()),
+                    (expected_id.as_str(), *expected_name)
+                );
+                for (field, value) in fields.as_object().unwrap() {
+                    assert_eq!(&data[field], value, "{} {}", name, field);
+                }
+            }
+        }
+
+        // Resuming skips the flag that was deleted in the meantime
+        let response = warp::test::request()
+            .path("/stream")
+            .header("last-event-id", format!("{}-2", epoch))
+            .filter(&filter)
+            .await
+            .unwrap()
+            .into_response();
+        let mut body = response.into_body();
+        let (id, name, data) = next_event(&mut body, &mut buffer).await;
+        assert_eq!(id, format!("{}-5", epoch));
+        assert_eq!(name, "flag.deleted");
+        assert_eq!(data, json!({"id": 3, "name": "search"}));
+
+        // Ids from before a restart start over, even when the number is known now
+        for last_event_id in ["0-2", "2", format!("{}-99", epoch).as_str()] {
+            let response = warp::test::request()
+                .path("/projects/payments/stream")
+                .header("last-event-id", last_event_id)
+                .filter(&filter)
+                .await
+                .unwrap()
+                .into_response();
+            let mut body = response.into_body();
+            let (id, name, data) = next_event(&mut body, &mut buffer).await;
+            assert_eq!(id, format!("{}-5", epoch), "{}", last_event_id);
+            assert_eq!(name, "snapshot", "{}", last_event_id);
+            assert_eq!(data[0]["name"], json!("refunds"));
+        }
+
+        let response = warp::test::request()
+            .path("/envs/nowhere/stream")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    /// The next text message of a WebSocket as JSON.
+    async fn next_message(client: &mut warp::test::WsClient) -> Value {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), client.recv())
+            .await
+            .expect("no message within 5 seconds")
+            .unwrap();
+
+        serde_json::from_str(message.to_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        check_watch(sqlite_store()).await;
+        check_watch(memory_store()).await;
+    }
+
+    async fn check_watch<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+        let flags = [
+            json!({"name": "new_checkout", "value": true}),
+            json!({"name": "search", "value": true}),
+            json!({"name": "dark_mode", "value": false, "tags": ["web"]}),
+        ];
+        for flag in flags.iter() {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/flags")
+                .body(flag.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201);
+        }
+
+        let mut client = warp::test::ws()
+            .path("/ws")
+            .handshake(filter.clone())
+            .await
+            .unwrap();
+
+        client
+            .send_text(json!({"type": "subscribe", "names": ["new_checkout"]}).to_string())
+            .await;
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("subscribed"));
+        assert_eq!(message["flags"][0]["name"], json!("new_checkout"));
+
+        client
+            .send_text(json!({"type": "subscribe", "tags": ["web"]}).to_string())
+            .await;
+        let message = next_message(&mut client).await;
+        assert_eq!(message["names"], json!(["new_checkout"]));
+        assert_eq!(message["tags"], json!(["web"]));
+        // Only the flags this subscription added
+        assert_eq!(message["flags"].as_array().unwrap().len(), 1);
+        assert_eq!(message["flags"][0]["name"], json!("dark_mode"));
+
+        // Changes to flags that are not subscribed to, or made in other environments, are left
+        // out
+        let response = warp::test::request()
+            .method("POST")
+            .path("/envs")
+            .body(json!({"name": "staging"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/envs/staging/flags/by-name/dark_mode")
+            .body(json!({"enabled": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        for name in ["search", "new_checkout"].iter() {
+            let response = warp::test::request()
+                .method("PUT")
+                .path(&format!("/flags/by-name/{}", name))
+                .body(json!({"enabled": false}).to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 200);
+        }
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("flag.updated"));
+        assert_eq!(message["flag"]["name"], json!("new_checkout"));
+        assert_eq!(message["flag"]["enabled"], json!(false));
+
+        client
+            .send_text(json!({"type": "unsubscribe", "names": ["new_checkout"]}).to_string())
+            .await;
+        let message = next_message(&mut client).await;
+        assert_eq!(
+            message,
+            json!({"type": "unsubscribed", "names": [], "tags": ["web"]})
+        );
+
+        for path in ["/flags/by-name/new_checkout", "/flags/by-name/dark_mode"].iter() {
+            let response = warp::test::request()
+                .method("DELETE")
+                .path(path)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 204);
+        }
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("flag.deleted"));
+        assert_eq!(message["flag"], json!({"id": 3, "name": "dark_mode"}));
+
+        client.send_text("{\"type\": \"watch\"}").await;
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("error"));
+        assert_eq!(message["error"], json!("VALIDATION"));
+
+        let result = warp::test::ws()
+            .path("/envs/nowhere/ws")
+            .handshake(filter.clone())
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch_flags_leaving_the_selection() {
+        let dir =
+            std::env::temp_dir().join(format!("feature-flags-server-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: dark_mode, value: false, tags: [web]}]",
+        )
+        .unwrap();
+
+        let store = Arc::new(Mutex::new(FileStore::open(&dir).unwrap()));
+        let changes = Changes::new();
+        let mut known = flag_states(&*store.lock().await).unwrap();
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), changes.clone());
+
+        let mut client = warp::test::ws()
+            .path("/ws")
+            .handshake(filter.clone())
+            .await
+            .unwrap();
+        client
+            .send_text(json!({"type": "subscribe", "tags": ["web"]}).to_string())
+            .await;
+        let message = next_message(&mut client).await;
+        assert_eq!(message["flags"][0]["name"], json!("dark_mode"));
+
+        // The tag is gone, and with it the flag from the selection
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: dark_mode, value: false}]",
+        )
+        .unwrap();
+        reload(&mut *store.lock().await, &changes, &mut known);
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("flag.deleted"));
+        assert_eq!(message["flag"], json!({"id": 1, "name": "dark_mode"}));
+
+        // Once is enough
+        std::fs::write(
+            dir.join("flags.yaml"),
+            "flags: [{name: dark_mode, value: true}, {name: search, value: true, tags: [web]}]",
+        )
+        .unwrap();
+        reload(&mut *store.lock().await, &changes, &mut known);
+        let message = next_message(&mut client).await;
+        assert_eq!(message["type"], json!("flag.created"));
+        assert_eq!(message["flag"]["name"], json!("search"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_long_poll() {
+        check_long_poll(sqlite_store()).await;
+        check_long_poll(memory_store()).await;
+    }
+
+    async fn check_long_poll<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+        let get = |path: &str| warp::test::request().path(path).reply(&filter);
+        let seq = |changes: &Value| {
+            let cursor = changes["cursor"].as_str().unwrap();
+            cursor.parse::<ChangeCursor>().unwrap().seq
+        };
+
+        // Nothing happened yet, so every flag is none
+        let response = get("/flags?since=0").await;
+        assert_eq!(response.status(), 200);
+        let changes: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(seq(&changes), 0);
+        assert_eq!(
+            changes,
+            json!({"cursor": changes["cursor"], "full": true, "flags": [], "deleted": []})
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+        let changes: Value = serde_json::from_slice(get("/flags?since=0").await.body()).unwrap();
+        assert_eq!(seq(&changes), 1);
+        assert_eq!(changes["full"], json!(true));
+        assert_eq!(changes["flags"][0]["name"], json!("new_checkout"));
+        let cursor = changes["cursor"].as_str().unwrap().to_string();
+
+        // The wait is over as soon as the flag changes
+        let update = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            warp::test::request()
+                .method("PUT")
+                .path("/flags/by-name/new_checkout")
+                .body(json!({"enabled": false}).to_string())
+                .reply(&filter)
+                .await
+        };
+        let started = Instant::now();
+        let path = format!("/flags?since={}&wait=10s", cursor);
+        let (response, updated) = tokio::join!(get(&path), update);
+        assert_eq!(updated.status(), 200);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let changes: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(seq(&changes), 2);
+        assert_eq!(changes["full"], json!(false));
+        assert_eq!(changes["flags"][0]["enabled"], json!(false));
+        let cursor = changes["cursor"].as_str().unwrap().to_string();
+
+        // Or once it timed out, with nothing to report
+        let response = get(&format!("/flags?since={}&wait=100ms", cursor)).await;
+        let changes: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(changes["cursor"], json!(cursor));
+        assert_eq!(changes["flags"], json!([]));
+
+        // Changes that were not published, e.g. made with the CLI, are seen once it is over
+        let update = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let update = FlagUpdate {
+                enabled: Some(true),
+                ..FlagUpdate::default()
+            };
+            store
+                .update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "cli")
+                .await
+        };
+        let path = format!("/flags?since={}&wait=300ms", cursor);
+        let (response, updated) = tokio::join!(get(&path), update);
+        updated.unwrap();
+        let changes: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(seq(&changes), 3);
+        assert_eq!(changes["flags"][0]["enabled"], json!(true));
+        let cursor = changes["cursor"].as_str().unwrap().to_string();
+
+        // Flags changed out of the filter are gone from the filtered listing
+        let update = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            warp::test::request()
+                .method("PUT")
+                .path("/flags/by-name/new_checkout")
+                .body(json!({"value": false}).to_string())
+                .reply(&filter)
+                .await
+        };
+        let started = Instant::now();
+        let path = format!("/flags?since={}&wait=10s&value=true", cursor);
+        let (response, updated) = tokio::join!(get(&path), update);
+        assert_eq!(updated.status(), 200);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let changes: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(seq(&changes), 4);
+        assert_eq!(changes["flags"], json!([]));
+        assert_eq!(
+            changes["deleted"],
+            json!([{"id": 1, "name": "new_checkout"}])
+        );
+        let cursor = changes["cursor"].as_str().unwrap().to_string();
+
+        // Cursors of another epoch, e.g. from before the database was reset, get every flag
+        let changes: Value = serde_json::from_slice(get("/flags?since=1-2").await.body()).unwrap();
+        assert_eq!(changes["full"], json!(true));
+        assert_eq!(changes["cursor"], json!(cursor));
+        assert_eq!(changes["flags"][0]["name"], json!("new_checkout"));
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/by-name/new_checkout")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+        let path = format!("/flags?since={}&wait=1s", cursor);
+        let changes: Value = serde_json::from_slice(get(&path).await.body()).unwrap();
+        assert_eq!(
+            changes["deleted"],
+            json!([{"id": 1, "name": "new_checkout"}])
+        );
+
+        for path in [
+            "/flags?wait=1s",
+            "/flags?since=0&wait=soon",
+            "/flags?since=soon",
+        ] {
+            assert_eq!(get(path).await.status(), 400, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        check_conditional_requests(sqlite_store()).await;
+        check_conditional_requests(memory_store()).await;
+    }
+
+    async fn check_conditional_requests<S: AsyncFlagStore>(store: S) {
+        let changes = Changes::new();
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), changes.clone());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request().path("/flags/1").reply(&filter).await;
+        assert_eq!(response.headers()["etag"], "\"1\"");
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        let list_etag = response.headers()["etag"].clone();
+        // A SHA-1 digest in quotes
+        assert_eq!(list_etag.len(), 42);
+
+        // Nothing changed since
+        let requests = [
+            ("/flags/1", "\"1\""),
+            ("/flags", list_etag.to_str().unwrap()),
+        ];
+        for (path, etag) in requests.iter() {
+            let response = warp::test::request()
+                .path(path)
+                .header("if-none-match", *etag)
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 304, "{}", path);
+            assert!(response.body().is_empty());
+        }
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .header("if-match", "\"1\"")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["etag"], "\"2\"");
+
+        // An update that sets nothing leaves the flag, and its subscribers, alone
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body("{}")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["etag"], "\"2\"");
+        assert_eq!(changes.subscribe(None).latest, 2);
+
+        // The second writer started from the same version and loses
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/by-name/new_checkout")
+            .header("if-match", "\"1\"")
+            .body(json!({"enabled": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 412);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"enabled": true, "expected_version": 1}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 412);
+
+        let response = warp::test::request()
+            .path("/flags/1")
+            .header("if-none-match", "\"1\"")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let flag: FlagWithID = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(flag.version, 2);
+        assert!(!flag.enabled);
+
+        let response = warp::test::request()
+            .path("/flags")
+            .header("if-none-match", list_etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_typed_flag_endpoints() {
+        check_typed_flag_endpoints(sqlite_store()).await;
+        check_typed_flag_endpoints(memory_store()).await;
+    }
+
+    async fn check_typed_flag_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "timeout", "type": "float", "value": 30}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        // A value of the wrong type is rejected
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"value": "slow"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"value": 12.5}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/2")
+            .body(json!({"value": 12.5}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/flags")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        let mut flags = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        // Timestamps change from run to run
+        for field in ["created_at", "updated_at"] {
+            let timestamp = flags[0].as_object_mut().unwrap().remove(field).unwrap();
+            assert!(timestamp.as_str().is_some_and(|time| time.ends_with('Z')));
+        }
+        assert_eq!(
+            flags,
+            json!([{
+                "id": 1,
+                "name": "timeout",
+                "type": "float",
+                "value": 12.5,
+                "enabled": true,
+                "default_variation": null,
+                "off_variation": null,
+                "rollout_percentage": null,
+                "salt": "",
+                "version": 2,
+                "description": null,
+                "owner": null,
+                "tags": [],
+                "created_by": "anonymous",
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_variation_endpoints() {
+        check_variation_endpoints(sqlite_store()).await;
+        check_variation_endpoints(memory_store()).await;
+    }
+
+    async fn check_variation_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "checkout", "value": "control"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        for (name, value) in [("control", "v1"), ("treatment-a", "v2")] {
+            let response = warp::test::request()
+                .method("POST")
+                .path("/flags/1/variations")
+                .body(json!({"name": name, "value": value}).to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201);
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags/1/variations")
+            .body(json!({"name": "control", "value": "v3"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1/variations/treatment-a")
+            .body(json!({"value": "v2.1"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        // Serve the variations
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(
+                json!({"default_variation": "treatment-a", "off_variation": "control"}).to_string(),
+            )
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"default_variation": "treatment-b"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/flags/1/variations")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!([
+                {"name": "control", "value": "v1"},
+                {"name": "treatment-a", "value": "v2.1"},
+            ])
+        );
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/flags/1/variations/missing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/flags/2/variations")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_environment_endpoints() {
+        check_environment_endpoints(sqlite_store()).await;
+        check_environment_endpoints(memory_store()).await;
+    }
+
+    async fn check_environment_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/envs")
+            .body(json!({"name": "staging"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/envs")
+            .body(json!({"name": "staging"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+
+        // Toggling in staging leaves production alone
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/envs/staging/flags/1")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let enabled = |body: &[u8]| {
+            let flags: serde_json::Value = serde_json::from_slice(body).unwrap();
+            flags[0]["enabled"].clone()
+        };
+
+        let response = warp::test::request()
+            .path("/envs/staging/flags")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(enabled(response.body()), json!(false));
+
+        let response = warp::test::request().path("/flags").reply(&filter).await;
+        assert_eq!(enabled(response.body()), json!(true));
+
+        let response = warp::test::request()
+            .path("/envs/production/flags")
+            .reply(&filter)
+            .await;
+        assert_eq!(enabled(response.body()), json!(true));
+
+        let response = warp::test::request()
+            .path("/envs/qa/flags")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request().path("/envs").reply(&filter).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!([{"id": 1, "name": "production"}, {"id": 2, "name": "staging"}])
+        );
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/envs/staging")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/envs/production")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_project_endpoints() {
+        check_project_endpoints(sqlite_store()).await;
+        check_project_endpoints(memory_store()).await;
+    }
+
+    async fn check_project_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/projects")
+            .body(json!({"name": "payments"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        // The same name can be used once per project
+        for path in ["/flags", "/projects/payments/flags"] {
+            let response = warp::test::request()
+                .method("POST")
+                .path(path)
+                .body(json!({"name": "new_checkout", "value": true}).to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201, "POST {}", path);
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/projects/payments/flags")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+
+        let response = warp::test::request()
+            .path("/projects/payments/envs/production/flags")
+            .reply(&filter)
+            .await;
+        let flags: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(flags[0]["id"], json!(2));
+
+        // Flags are only reachable through their own project
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/2")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/projects/payments/flags/2")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/projects/search/flags")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], json!("NOT_FOUND"));
+        assert_eq!(body["message"], json!("Project search was not found"));
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/projects/payments")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/projects/payments/flags/2")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/projects/payments")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        let response = warp::test::request().path("/projects").reply(&filter).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!([{"id": 1, "name": "default"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_endpoint() {
+        check_evaluate_endpoint(sqlite_store()).await;
+        check_evaluate_endpoint(memory_store()).await;
+    }
+
+    async fn check_evaluate_endpoint<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"rollout_percentage": 50}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        // Every user gets the same answer each time, and about half of them are in
+        let mut on = 0;
+        for n in 0..100 {
+            let user = json!({"key": format!("user-{}", n)}).to_string();
+            let mut values = vec![];
+
+            for _ in 0..2 {
+                let response = warp::test::request()
+                    .method("POST")
+                    .path("/evaluate/new_checkout")
+                    .body(user.clone())
+                    .reply(&filter)
+                    .await;
+                assert_eq!(response.status(), 200);
+
+                let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+                values.push(body["value"].clone());
+            }
+
+            assert_eq!(values[0], values[1], "{}", user);
+            if values[0] == json!(true) {
+                on += 1;
+            }
+        }
+        assert!((30..=70).contains(&on), "{} users in a 50% rollout", on);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"rollout_percentage": 101}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/evaluate/missing")
+            .body(json!({"key": "user-1"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1")
+            .body(json!({"enabled": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/evaluate/new_checkout")
+            .body(json!({"key": "user-1"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!({"value": false, "variation": null, "reason": {"kind": "OFF"}})
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .body(json!({"name": "banner", "value": "hello"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        // Every flag of the project at once
+        let response = warp::test::request()
+            .method("POST")
+            .path("/evaluate")
+            .body(json!({"key": "user-1"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!({
+                "banner": {"value": "hello", "variation": null, "reason": {"kind": "FALLTHROUGH"}},
+                "new_checkout": {"value": false, "variation": null, "reason": {"kind": "OFF"}}
+            })
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/envs/qa/evaluate")
+            .body(json!({"key": "user-1"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_rule_endpoints() {
+        check_rule_endpoints(sqlite_store()).await;
+        check_rule_endpoints(memory_store()).await;
+    }
+
+    async fn check_rule_endpoints<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let requests = [
+            ("POST", "/flags", json!({"name": "checkout", "value": "v1"})),
+            (
+                "POST",
+                "/flags/1/variations",
+                json!({"name": "beta", "value": "v2"}),
+            ),
+        ];
+        for (method, path, body) in requests.iter() {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(body.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201, "{} {}", method, path);
+        }
+
+        let rules = json!([{
+            "clauses": [
+                {"attribute": "country", "op": "in", "values": ["US", "CA"]},
+                {"attribute": "version", "op": "semver>=", "values": ["2.3.0"]}
+            ],
+            "variation": "beta"
+        }]);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1/rules")
+            .body(rules.to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/flags/1/rules")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            rules
+        );
+
+        let evaluate = |context: serde_json::Value| {
+            warp::test::request()
+                .method("POST")
+                .path("/evaluate/checkout")
+                .body(context.to_string())
+                .reply(&filter)
+        };
+
+        let response = evaluate(json!({
+            "key": "user-1",
+            "attributes": {"country": "CA", "version": "2.4.1"}
+        }))
+        .await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!({
+                "value": "v2",
+                "variation": "beta",
+                "reason": {"kind": "RULE_MATCH", "rule_index": 0}
+            })
+        );
+
+        let response = evaluate(json!({"key": "user-1", "attributes": {"country": "CA"}})).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!({"value": "v1", "variation": null, "reason": {"kind": "FALLTHROUGH"}})
+        );
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1/rules")
+            .body(json!([{"clauses": [], "variation": "missing"}]).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_segment_endpoints() {
+        check_segment_endpoints(sqlite_store()).await;
+        check_segment_endpoints(memory_store()).await;
+    }
+
+    async fn check_segment_endpoints<S: AsyncFlagStore>(store: S) {
+        let changes = Changes::new();
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), changes.clone());
+
+        let requests = [
+            ("POST", "/flags", json!({"name": "checkout", "value": "v1"})),
+            (
+                "POST",
+                "/flags/1/variations",
+                json!({"name": "beta", "value": "v2"}),
+            ),
+            (
+                "POST",
+                "/segments",
+                json!({"name": "beta-testers", "included": ["ada"]}),
+            ),
+        ];
+        for (method, path, body) in requests.iter() {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .body(body.to_string())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), 201, "{} {}", method, path);
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/segments")
+            .body(json!({"name": "beta-testers"}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1/rules")
+            .body(
+                json!([{
+                    "clauses": [{"op": "segment", "values": ["beta-testers"]}],
+                    "variation": "beta"
+                }])
+                .to_string(),
+            )
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let mut receiver = changes.subscribe(None).receiver;
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/segments/beta-testers")
+            .header("x-actor", "ada")
+            .body(json!({"included": ["bob"]}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        // Along with the flag that refers to it, in every environment
+        let change = receiver.try_recv().unwrap();
+        assert_eq!(change.kind, ChangeKind::Updated(1));
+        assert_eq!(change.environment, None);
+        let flag = store
+            .get_flag_by_id(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1)
+            .await
+            .unwrap();
+        assert_eq!(flag.version, 4);
+        let history = store.get_flag_history(DEFAULT_PROJECT, 1).await.unwrap();
+        let event = history.last().unwrap();
+        assert_eq!(event.actor, "ada");
+        assert_eq!(
+            event.new_value.as_ref().unwrap()["segment"]["included"],
+            json!(["bob"])
+        );
+
+        let response = warp::test::request()
+            .path("/segments/beta-testers")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(response.body()).unwrap(),
+            json!({"name": "beta-testers", "included": ["bob"], "excluded": [], "rules": []})
+        );
+
+        // The segment change applies to the flag right away
+        let response = warp::test::request()
+            .method("POST")
+            .path("/evaluate/checkout")
+            .body(json!({"key": "bob"}).to_string())
+            .reply(&filter)
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["value"], json!("v2"));
+
+        // Segments in use cannot be deleted
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/segments/beta-testers")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 409);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["error"], json!("CONFLICT"));
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/flags/1/rules")
+            .body(json!([]).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/segments/beta-testers")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+
+        let response = warp::test::request()
+            .path("/segments/beta-testers")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .path("/projects/search/segments")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_flag_history_endpoint() {
+        check_flag_history_endpoint(sqlite_store()).await;
+        check_flag_history_endpoint(memory_store()).await;
+    }
+
+    async fn check_flag_history_endpoint<S: AsyncFlagStore>(store: S) {
+        let filter =
+            feature_flag_all_routes(store.clone(), &ServerConfig::default(), Changes::new());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/flags")
+            .header("x-actor", "ada")
+            .body(json!({"name": "new_checkout", "value": true}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/envs/production/flags/1")
+            .body(json!({"value": false}).to_string())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .path("/flags/1/history")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let history: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        let summary: Vec<_> = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| (event["action"].clone(), event["actor"].clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (json!("created"), json!("ada")),
+                (json!("updated"), json!("anonymous"))
+            ]
+        );
+        assert_eq!(history[1]["old_value"]["value"], json!(true));
+        assert_eq!(history[1]["new_value"]["value"], json!(false));
+
+        let response = warp::test::request()
+            .path("/flags/2/history")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
     }
 }
@@ -0,0 +1,331 @@
+//! Settings shared by the binaries.
+//!
+//! Each setting comes from the first of: command line flags, environment variables, the TOML
+//! config file and the defaults. The config file is `--config`, else `$FEATURE_FLAGS_CONFIG`,
+//! else `feature-flags.toml` in the working directory when there is one:
+//!
+//! ```toml
+//! [db]
+//! path = "/var/lib/feature-flags/flag.db"   # or ":memory:"
+//! read_only = false
+//!
+//! [server]
+//! listen = ["127.0.0.1", "::1"]
+//! port = 3030
+//! log_level = "info"            # or any RUST_LOG filter, e.g. "info,warp=debug"
+//! body_limit = 16384            # bytes
+//! request_timeout = 30          # seconds
+//! flags_dir = "flags"           # serve flag files read-only instead of the database
+//!
+//! [server.features]
+//! evaluation = true
+//! history = true
+//! reload = true
+//! ```
+
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::Args;
+use serde::Deserialize;
+
+use crate::error::FeatureFlagError;
+
+pub const CONFIG_ENV: &str = "FEATURE_FLAGS_CONFIG";
+pub const DB_ENV: &str = "FEATURE_FLAGS_DB";
+/// Comma separated addresses
+pub const LISTEN_ENV: &str = "FEATURE_FLAGS_LISTEN";
+pub const PORT_ENV: &str = "FEATURE_FLAGS_PORT";
+pub const LOG_LEVEL_ENV: &str = "RUST_LOG";
+pub const BODY_LIMIT_ENV: &str = "FEATURE_FLAGS_BODY_LIMIT";
+pub const REQUEST_TIMEOUT_ENV: &str = "FEATURE_FLAGS_REQUEST_TIMEOUT";
+pub const FLAGS_DIR_ENV: &str = "FEATURE_FLAGS_DIR";
+pub const DEFAULT_CONFIG_FILE: &str = "feature-flags.toml";
+pub const DEFAULT_DB: &str = "instance/flag.db";
+/// Database path of a new, migrated database that only lives as long as the process
+pub const MEMORY_DB: &str = ":memory:";
+
+type Result<T> = std::result::Result<T, FeatureFlagError>;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub db: DbConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| FeatureFlagError::Config(format!("{}: {}", path.display(), err)))?;
+
+        toml::from_str(&content)
+            .map_err(|err| FeatureFlagError::Config(format!("{}: {}", path.display(), err)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DbConfig {
+    /// SQLite file, or `:memory:`
+    #[serde(default = "default_db_path")]
+    pub path: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+fn default_db_path() -> String {
+    DEFAULT_DB.to_string()
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        DbConfig {
+            path: default_db_path(),
+            read_only: false,
+        }
+    }
+}
+
+impl DbConfig {
+    pub fn is_memory(&self) -> bool {
+        self.path == MEMORY_DB
+    }
+}
+
+/// Settings of the `server` binary only.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Addresses to listen on, all with the same port
+    pub listen: Vec<IpAddr>,
+    pub port: u16,
+    /// Filter in the syntax of `RUST_LOG`
+    pub log_level: String,
+    /// Largest accepted request body, in bytes
+    pub body_limit: u64,
+    /// Seconds a request may take before it is answered with 503
+    pub request_timeout: u64,
+    /// Serves the flag files of this directory instead of the database, see `store::file`
+    pub flags_dir: Option<PathBuf>,
+    pub features: Features,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            port: 3030,
+            log_level: "info".to_string(),
+            body_limit: 1024 * 16,
+            request_timeout: 30,
+            flags_dir: None,
+            features: Features::default(),
+        }
+    }
+}
+
+/// Optional parts of the server, all turned on by default.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    /// The `/evaluate` endpoints, not needed when clients evaluate flags themselves
+    pub evaluation: bool,
+    /// The audit log endpoints
+    pub history: bool,
+    /// Picking up changes to the flag files of `flags_dir`
+    pub reload: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            evaluation: true,
+            history: true,
+            reload: true,
+        }
+    }
+}
+
+/// The environment variable `name` parsed as `T`, when it is set.
+fn parse_var<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>> {
+    match var(name) {
+        Some(value) => value.trim().parse().map(Some).map_err(|_| {
+            FeatureFlagError::Config(format!("{} has an invalid value: {}", name, value))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Command line flags for the settings, flattened into each binary's arguments.
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// TOML config file [default: $FEATURE_FLAGS_CONFIG, then ./feature-flags.toml if present]
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// SQLite database file, or :memory: [default: $FEATURE_FLAGS_DB, then the config file, then
+    /// instance/flag.db]
+    #[arg(long, global = true)]
+    pub db: Option<String>,
+
+    /// Open the database read-only
+    #[arg(long, global = true)]
+    pub read_only: bool,
+}
+
+impl ConfigArgs {
+    pub fn load(&self) -> Result<Config> {
+        self.load_with(|name| env::var(name).ok())
+    }
+
+    /// `var` looks up environment variables, so tests do not have to change the real ones.
+    fn load_with(&self, var: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        let file = match (&self.config, var(CONFIG_ENV)) {
+            (Some(path), _) => Some(path.clone()),
+            (None, Some(path)) => Some(PathBuf::from(path)),
+            (None, None) => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.is_file()),
+        };
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+
+        if let Some(path) = var(DB_ENV) {
+            config.db.path = path;
+        }
+        if let Some(listen) = var(LISTEN_ENV) {
+            config.server.listen = listen
+                .split(',')
+                .map(|address| {
+                    address.trim().parse().map_err(|_| {
+                        FeatureFlagError::Config(format!(
+                            "{} has an invalid address: {}",
+                            LISTEN_ENV, address
+                        ))
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(port) = parse_var(&var, PORT_ENV)? {
+            config.server.port = port;
+        }
+        if let Some(log_level) = var(LOG_LEVEL_ENV) {
+            config.server.log_level = log_level;
+        }
+        if let Some(body_limit) = parse_var(&var, BODY_LIMIT_ENV)? {
+            config.server.body_limit = body_limit;
+        }
+        if let Some(request_timeout) = parse_var(&var, REQUEST_TIMEOUT_ENV)? {
+            config.server.request_timeout = request_timeout;
+        }
+        if let Some(dir) = var(FLAGS_DIR_ENV) {
+            config.server.flags_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(path) = &self.db {
+            config.db.path = path.clone();
+        }
+        config.db.read_only |= self.read_only;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let path = env::temp_dir().join(format!("feature-flags-config-{}.toml", process::id()));
+        fs::write(&path, "[db]\npath = \"from-file.db\"\nread_only = true\n").unwrap();
+
+        let args = ConfigArgs {
+            config: Some(path.clone()),
+            ..ConfigArgs::default()
+        };
+        let config = args.load_with(|_| None).unwrap();
+        assert_eq!(config.db.path, "from-file.db");
+        assert!(config.db.read_only);
+
+        // The environment beats the file
+        let config = args
+            .load_with(|name| Some(format!("{}.db", name)).filter(|_| name == DB_ENV))
+            .unwrap();
+        assert_eq!(config.db.path, "FEATURE_FLAGS_DB.db");
+
+        // Flags beat both
+        let args = ConfigArgs {
+            db: Some(MEMORY_DB.to_string()),
+            ..args
+        };
+        let config = args
+            .load_with(|name| Some("from-env.db".to_string()).filter(|_| name == DB_ENV))
+            .unwrap();
+        assert!(config.db.is_memory());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = ConfigArgs::default().load_with(|_| None).unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.db.path, DEFAULT_DB);
+    }
+
+    #[test]
+    fn test_server_settings() {
+        let config: Config = toml::from_str(
+            "[server]\nlisten = [\"0.0.0.0\", \"::\"]\nport = 8080\n[server.features]\nhistory = false\n",
+        )
+        .unwrap();
+        assert_eq!(config.server.listen.len(), 2);
+        assert_eq!(config.server.port, 8080);
+        assert!(!config.server.features.history);
+        // Everything left out keeps its default
+        assert_eq!(config.server.body_limit, 1024 * 16);
+        assert!(config.server.features.evaluation);
+
+        let config = ConfigArgs::default()
+            .load_with(|name| match name {
+                LISTEN_ENV => Some("127.0.0.1, ::1".to_string()),
+                PORT_ENV => Some("8080".to_string()),
+                REQUEST_TIMEOUT_ENV => Some("5".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.server.listen.len(), 2);
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.request_timeout, 5);
+
+        let result = ConfigArgs::default().load_with(|name| match name {
+            PORT_ENV => Some("http".to_string()),
+            _ => None,
+        });
+        assert!(matches!(result, Err(FeatureFlagError::Config(_))));
+    }
+
+    #[test]
+    fn test_invalid_config_files() {
+        let args = ConfigArgs {
+            config: Some(PathBuf::from("/no/such/config.toml")),
+            ..ConfigArgs::default()
+        };
+        assert!(matches!(
+            args.load_with(|_| None),
+            Err(FeatureFlagError::Config(_))
+        ));
+
+        let result = toml::from_str::<Config>("[db]\nfile = \"flag.db\"\n");
+        assert!(result.is_err());
+    }
+}
@@ -1,141 +1,742 @@
+pub mod batch;
+pub mod changes;
+pub mod environments;
+pub mod events;
+pub mod listing;
+pub mod migrations;
+pub mod projects;
+pub mod rules;
+pub mod segments;
+pub mod variations;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::Deref;
 use std::path::Path;
 use std::rc::Rc;
 use std::sync::Arc;
 
-use rusqlite::{params, Connection};
-use serde::{Deserialize, Serialize};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::sync::Mutex;
 
+use self::environments::environment_id;
+use self::events::{record_event, Change, FlagAction};
+pub use self::listing::{FlagFilter, FlagList, FlagPage, FlagSort, SortOrder};
+use self::projects::project_id;
+use crate::config::DbConfig;
 use crate::error::FeatureFlagError;
+use crate::eval::{self, Evaluation, EvaluationContext};
+pub use crate::value::{FlagType, FlagValue};
 
 pub type DBLite = Arc<Mutex<Connection>>;
 pub type DBLocal = Rc<Connection>;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct FlagWithID {
     pub id: i32,
     pub name: String,
-    pub value: bool,
+    #[serde(rename = "type")]
+    pub flag_type: FlagType,
+    pub value: FlagValue,
+    pub enabled: bool,
+    /// Variation served while the flag is on
+    pub default_variation: Option<String>,
+    /// Variation served while the flag is off
+    pub off_variation: Option<String>,
+    /// Share of users the flag is on for, everyone when `None`
+    pub rollout_percentage: Option<f64>,
+    /// Mixed into the rollout hash, changing it reshuffles which users are in the rollout
+    pub salt: String,
+    /// Starts at 1 and goes up with every change to the flag, in any environment
+    pub version: u64,
+    #[serde(flatten)]
+    pub metadata: FlagMetadata,
+    /// UTC, in the format of the audit log's timestamps
+    pub created_at: String,
+    /// Time of the last change, which also raised `version`
+    pub updated_at: String,
+    /// Actor that created the flag
+    pub created_by: String,
+}
+
+impl FlagWithID {
+    /// Entity tag of the flag for `ETag` and `If-Match` headers, quoted as HTTP wants it.
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.version)
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Flag {
     pub name: String,
-    pub value: bool,
+    /// Inferred from `value` when not given
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub flag_type: Option<FlagType>,
+    pub value: FlagValue,
+    #[serde(flatten)]
+    pub metadata: FlagMetadata,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct FlagValue {
-    pub value: bool,
+/// What a flag is for and who looks after it, given when the flag is created.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct FlagMetadata {
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Person or team to ask about the flag
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Free-form labels, e.g. `checkout` or `temporary`
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Flag {
+    pub fn resolved_type(&self) -> FlagType {
+        self.flag_type.unwrap_or_else(|| self.value.flag_type())
+    }
 }
 
-pub fn get_db() -> Connection {
-    let path = Path::new("instance").join("flag.db");
+/// What became of a flag that was asked to be deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    Deleted,
+    /// There was no such flag, so nothing changed
+    NotFound,
+}
 
-    Connection::open(path).expect("Unable to find the db")
+/// Partial update of a flag. Fields that are left out are not changed.
+#[derive(Debug, Default, Deserialize)]
+pub struct FlagUpdate {
+    #[serde(default)]
+    pub value: Option<FlagValue>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// `null` stops serving a variation
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub default_variation: Option<Option<String>>,
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub off_variation: Option<Option<String>>,
+    /// Percentage between 0 and 100, `null` turns the flag on for everyone
+    #[serde(default, deserialize_with = "explicit_null")]
+    pub rollout_percentage: Option<Option<f64>>,
+    /// Shared by all environments
+    #[serde(default)]
+    pub salt: Option<String>,
+    /// Fails the update with `PreconditionFailed` unless the flag is still at this version
+    #[serde(default)]
+    pub expected_version: Option<u64>,
 }
 
-pub fn get_db_rc() -> DBLocal {
-    Rc::new(get_db())
+impl FlagUpdate {
+    /// True when the update sets no field, so there is nothing to change.
+    pub fn is_empty(&self) -> bool {
+        self.value.is_none()
+            && self.enabled.is_none()
+            && self.default_variation.is_none()
+            && self.off_variation.is_none()
+            && self.rollout_percentage.is_none()
+            && self.salt.is_none()
+    }
 }
 
-pub fn get_db_server() -> DBLite {
-    let conn = get_db();
-    Arc::new(Mutex::new(conn))
+/// Lets a missing field (`None`) be told apart from an explicit `null` (`Some(None)`).
+fn explicit_null<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
-pub fn initialize_db(conn: DBLocal) -> Result<(), FeatureFlagError> {
-    conn.execute_batch(
-        "DROP TABLE IF EXISTS flags;
+/// Opens the database of `config`. Missing files are only created with `create`, as they need
+/// `db-init` to be usable. `:memory:` databases start out migrated.
+pub fn open_db(config: &DbConfig, create: bool) -> Result<Connection, FeatureFlagError> {
+    if config.is_memory() {
+        if config.read_only {
+            return Err(FeatureFlagError::Config(
+                "a :memory: database cannot be read-only".to_string(),
+            ));
+        }
 
-        CREATE TABLE flags (
-            id    INTEGER UNIQUE,
-            name  TEXT NOT NULL UNIQUE,
-            value INTEGER NOT NULL CHECK(value == 0 OR value == 1),
-            PRIMARY KEY(id)
-        );",
-    )?;
+        let conn = Connection::open_in_memory()?;
+        migrations::migrate(&conn)?;
 
-    Ok(())
+        return Ok(conn);
+    }
+
+    let path = Path::new(&config.path);
+    if !path.exists() {
+        if !create || config.read_only {
+            return Err(FeatureFlagError::MissingDatabase(config.path.clone()));
+        }
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    let flags = match config.read_only {
+        true => {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                | OpenFlags::SQLITE_OPEN_URI
+        }
+        false => OpenFlags::default(),
+    };
+
+    Ok(Connection::open_with_flags(path, flags)?)
 }
 
-pub async fn initialize_db_arc(conn_mutex: DBLite) -> Result<(), FeatureFlagError> {
+/// Brings the schema up to date by applying any pending migrations. Existing data is kept.
+pub fn initialize_db(conn: DBLocal) -> Result<usize, FeatureFlagError> {
+    migrations::migrate(&conn)
+}
+
+pub async fn initialize_db_arc(conn_mutex: DBLite) -> Result<usize, FeatureFlagError> {
     let conn = conn_mutex.lock().await;
-    conn.execute_batch(
-        "DROP TABLE IF EXISTS flags;
-
-        CREATE TABLE flags (
-            id    INTEGER UNIQUE,
-            name  TEXT NOT NULL UNIQUE,
-            value INTEGER NOT NULL CHECK(value == 0 OR value == 1),
-            PRIMARY KEY(id)
-        );",
-    )?;
 
-    Ok(())
+    migrations::migrate(&conn)
 }
-pub fn get_flag_by_name(conn: DBLocal, name: String) -> Result<FlagWithID, FeatureFlagError> {
-    let result = conn.query_row(
-        "SELECT id, name, value FROM flags WHERE name = ?",
-        params![name],
-        |row| {
-            let value = matches!(row.get(2)?, 1);
 
-            Ok(FlagWithID {
-                id: row.get(0)?,
-                name: row.get(1)?,
-                value,
-            })
+/// Drops all data and rebuilds the schema from the first migration.
+pub fn reset_db(conn: DBLocal) -> Result<usize, FeatureFlagError> {
+    migrations::reset(&conn)
+}
+
+/// Flag definitions joined with their state in one environment.
+/// Callers append a `WHERE` clause and bind the environment id as `?1`.
+const FLAG_SELECT: &str = "SELECT flags.id, flags.name, flags.type, state.value, state.enabled,
+        state.default_variation, state.off_variation, state.rollout_percentage, flags.salt,
+        flags.version, flags.description, flags.owner, flags.tags, flags.created_at,
+        flags.updated_at, flags.created_by
+    FROM flags
+    JOIN flag_states AS state ON state.flag_id = flags.id AND state.environment_id = ?1";
+
+fn flag_from_row(row: &Row) -> rusqlite::Result<FlagWithID> {
+    let flag_type: FlagType = row.get(2)?;
+    let value = FlagValue::from_json(flag_type, row.get(3)?)
+        .ok_or_else(|| rusqlite::Error::InvalidColumnType(3, "value".to_string(), Type::Text))?;
+
+    Ok(FlagWithID {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        flag_type,
+        value,
+        enabled: row.get(4)?,
+        default_variation: row.get(5)?,
+        off_variation: row.get(6)?,
+        rollout_percentage: row.get(7)?,
+        salt: row.get(8)?,
+        version: row.get(9)?,
+        metadata: FlagMetadata {
+            description: row.get(10)?,
+            owner: row.get(11)?,
+            tags: serde_json::from_str(&row.get::<_, String>(12)?).map_err(|_| {
+                rusqlite::Error::InvalidColumnType(12, "tags".to_string(), Type::Text)
+            })?,
         },
-    )?;
+        created_at: row.get(13)?,
+        updated_at: row.get(14)?,
+        created_by: row.get(15)?,
+    })
+}
 
-    Ok(result)
+/// A transaction that can also be opened inside another one, as the changes of a batch are.
+/// It is rolled back unless committed.
+pub(crate) struct Savepoint<'conn> {
+    conn: &'conn Connection,
+    committed: bool,
 }
 
-pub fn get_all_flags(conn: DBLocal) -> Result<Vec<FlagWithID>, FeatureFlagError> {
-    let mut stmt = conn.prepare("SELECT id, name, value FROM flags")?;
+impl<'conn> Savepoint<'conn> {
+    pub(crate) fn new(conn: &'conn Connection) -> Result<Savepoint<'conn>, FeatureFlagError> {
+        // Outside of a transaction this begins one, like `unchecked_transaction`
+        conn.execute_batch("SAVEPOINT flags")?;
+
+        Ok(Savepoint {
+            conn,
+            committed: false,
+        })
+    }
+
+    /// Keeps the changes, for good unless an enclosing savepoint is rolled back.
+    pub(crate) fn commit(mut self) -> Result<(), FeatureFlagError> {
+        self.conn.execute_batch("RELEASE flags")?;
+        self.committed = true;
 
-    let rows = stmt.query_map([], |row| {
-        let value = matches!(row.get(2)?, 1);
+        Ok(())
+    }
+}
+
+impl Deref for Savepoint<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = self.conn.execute_batch("ROLLBACK TO flags; RELEASE flags");
+        }
+    }
+}
+
+/// Fails with `Validation` unless `name`, of a `kind` such as a project, is made of letters,
+/// digits, '-' and '_'.
+pub(crate) fn validate_name(kind: &str, name: &str) -> Result<(), FeatureFlagError> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !valid {
+        return Err(FeatureFlagError::Validation(format!(
+            "{} name '{}' may only contain letters, digits, '-' and '_'",
+            kind, name
+        )));
+    }
 
-        Ok(FlagWithID {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            value,
+    Ok(())
+}
+
+/// Marks a change to the flag, see `FlagWithID::version` and `FlagWithID::updated_at`.
+pub(crate) fn bump_version(conn: &Connection, id: u64) -> Result<(), FeatureFlagError> {
+    conn.execute(
+        "UPDATE flags SET version = version + 1,
+            updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+            WHERE id = ?",
+        params![id],
+    )?;
+
+    Ok(())
+}
+
+/// Fails with `PreconditionFailed` when `flag` is no longer at `expected` version.
+pub(crate) fn check_version(
+    flag: &FlagWithID,
+    expected: Option<u64>,
+) -> Result<(), FeatureFlagError> {
+    match expected {
+        Some(expected) if expected != flag.version => {
+            Err(FeatureFlagError::PreconditionFailed(format!(
+                "flag {} is at version {}, not {}",
+                flag.name, flag.version, expected
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Type of a flag, which is shared by all environments.
+pub(crate) fn get_flag_type(conn: &Connection, id: u64) -> Result<FlagType, FeatureFlagError> {
+    let result = conn
+        .query_row("SELECT type FROM flags WHERE id = ?", params![id], |row| {
+            row.get(0)
         })
-    })?;
+        .optional()?;
+
+    result.ok_or_else(|| FeatureFlagError::NotFound(format!("Flag {}", id)))
+}
+
+pub fn get_flag_by_name(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    name: String,
+) -> Result<FlagWithID, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+    let env_id = environment_id(conn, env)?;
+
+    let result = conn
+        .query_row(
+            &format!(
+                "{} WHERE flags.project_id = ?2 AND flags.name = ?3",
+                FLAG_SELECT
+            ),
+            params![env_id, project_id, name],
+            flag_from_row,
+        )
+        .optional()?;
+
+    result.ok_or_else(|| FeatureFlagError::NotFound(format!("Flag {}", name)))
+}
+
+pub fn get_flag_by_id(
+    conn: &Connection,
+    env: &str,
+    id: u64,
+) -> Result<FlagWithID, FeatureFlagError> {
+    let env_id = environment_id(conn, env)?;
+
+    let result = conn
+        .query_row(
+            &format!("{} WHERE flags.id = ?2", FLAG_SELECT),
+            params![env_id, id],
+            flag_from_row,
+        )
+        .optional()?;
+
+    result.ok_or_else(|| FeatureFlagError::NotFound(format!("Flag {}", id)))
+}
+
+pub fn get_all_flags(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+) -> Result<Vec<FlagWithID>, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+    let env_id = environment_id(conn, env)?;
+
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE flags.project_id = ?2 ORDER BY flags.id",
+        FLAG_SELECT
+    ))?;
+
+    let rows = stmt.query_map(params![env_id, project_id], flag_from_row)?;
 
     // Convert rows to vec of items
     let mut result = vec![];
     for item in rows {
-        result.push(item.unwrap())
+        result.push(item?)
     }
 
     Ok(result)
 }
 
-pub fn delete_flag_by_name(conn: DBLocal, name: String) -> Result<usize, FeatureFlagError> {
-    let result = conn.execute("DELETE FROM flags WHERE name = ?", params![name])?;
+/// State of a flag in every environment, keyed by environment name.
+fn flag_snapshot(conn: &Connection, id: u64) -> Result<serde_json::Value, FeatureFlagError> {
+    let mut snapshot = serde_json::Map::new();
+    for environment in environments::get_environments(conn)? {
+        let flag = get_flag_by_id(conn, &environment.name, id)?;
+        snapshot.insert(
+            environment.name,
+            serde_json::to_value(flag).unwrap_or_default(),
+        );
+    }
+
+    Ok(serde_json::Value::Object(snapshot))
+}
 
-    Ok(result)
+/// Deletes the flag from every environment.
+pub fn delete_flag_by_name(
+    conn: &Connection,
+    project: &str,
+    name: String,
+    actor: &str,
+) -> Result<DeleteOutcome, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+
+    let id: Option<u64> = conn
+        .query_row(
+            "SELECT id FROM flags WHERE project_id = ? AND name = ?",
+            params![project_id, name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match id {
+        Some(id) => delete_flag_by_id(conn, id, actor),
+        None => Ok(DeleteOutcome::NotFound),
+    }
+}
+
+/// Deletes the flag from every environment.
+pub fn delete_flag_by_id(
+    conn: &Connection,
+    id: u64,
+    actor: &str,
+) -> Result<DeleteOutcome, FeatureFlagError> {
+    let tx = Savepoint::new(conn)?;
+
+    let exists = tx
+        .prepare("SELECT 1 FROM flags WHERE id = ?")?
+        .exists(params![id])?;
+    if !exists {
+        return Ok(DeleteOutcome::NotFound);
+    }
+
+    let change = Change {
+        flag_id: id,
+        environment: None,
+        action: FlagAction::Deleted,
+        old_value: Some(flag_snapshot(&tx, id)?),
+        new_value: None,
+    };
+    record_event(&tx, actor, change)?;
+
+    tx.execute("DELETE FROM rules WHERE flag_id = ?", params![id])?;
+    tx.execute("DELETE FROM variations WHERE flag_id = ?", params![id])?;
+    tx.execute("DELETE FROM flag_states WHERE flag_id = ?", params![id])?;
+    tx.execute("DELETE FROM flags WHERE id = ?", params![id])?;
+
+    tx.commit()?;
+
+    Ok(DeleteOutcome::Deleted)
 }
 
-pub fn add_flag(conn: DBLocal, name: String, value: i32) -> Result<usize, FeatureFlagError> {
-    let result = conn.execute(
-        "INSERT INTO flags (name, value) VALUES (?1, ?2)",
-        params![name, value],
+/// Adds a flag to `project`, failing with `Validation` when `value` cannot be stored as
+/// `flag_type`.
+///
+/// The flag is turned on in `env` and created turned off, with the same value, in every other
+/// environment so that adding a flag in one environment never changes what another one serves.
+pub fn add_flag(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    name: String,
+    flag_type: FlagType,
+    value: FlagValue,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let flag = Flag {
+        name,
+        flag_type: Some(flag_type),
+        value,
+        metadata: FlagMetadata::default(),
+    };
+
+    insert_flag(conn, project, env, flag, actor)
+}
+
+/// `add_flag` with the flag's metadata, `actor` is recorded as its creator.
+pub fn insert_flag(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    flag: Flag,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let flag_type = flag.resolved_type();
+    let value = flag.value.into_type(flag_type)?;
+    let project_id = project_id(conn, project)?;
+    let env_id = environment_id(conn, env)?;
+    let metadata = flag.metadata;
+    let tags = serde_json::to_string(&metadata.tags)
+        .map_err(|err| FeatureFlagError::Validation(err.to_string()))?;
+
+    let tx = Savepoint::new(conn)?;
+
+    let name = &flag.name;
+    let result = tx
+        .execute(
+            "INSERT INTO flags (project_id, name, type, description, owner, tags, created_at,
+                    updated_at, created_by)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+                    strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), ?7)",
+            params![
+                project_id,
+                name,
+                flag_type,
+                metadata.description,
+                metadata.owner,
+                tags,
+                actor
+            ],
+        )
+        .map_err(|err| FeatureFlagError::from(err).naming(format!("Flag {}", name)))?;
+    let id = tx.last_insert_rowid() as u64;
+    tx.execute(
+        "INSERT INTO flag_states (flag_id, environment_id, enabled, value)
+            SELECT ?1, id, id = ?2, ?3 FROM environments",
+        params![id, env_id, value.to_json()],
     )?;
 
+    let change = Change {
+        flag_id: id,
+        environment: None,
+        action: FlagAction::Created,
+        old_value: None,
+        new_value: Some(flag_snapshot(&tx, id)?),
+    };
+    record_event(&tx, actor, change)?;
+
+    tx.commit()?;
+
     Ok(result)
 }
 
-pub fn update_flag(conn: DBLocal, name: String, value: i32) -> Result<usize, FeatureFlagError> {
-    let _ = get_flag_by_name(conn.clone(), name.clone())?;
+/// Sets a new value on an existing flag. The value must match the type the flag was created with.
+pub fn update_flag(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    name: String,
+    value: FlagValue,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let flag = get_flag_by_name(conn, project, env, name)?;
+
+    update_flag_by_id(conn, env, flag.id as u64, value, actor)
+}
+
+pub fn update_flag_by_id(
+    conn: &Connection,
+    env: &str,
+    id: u64,
+    value: FlagValue,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let update = FlagUpdate {
+        value: Some(value),
+        ..FlagUpdate::default()
+    };
+
+    apply_flag_update(conn, env, id, update, actor).map(|_| 1)
+}
 
-    let result = conn.execute(
-        "UPDATE flags SET value = ? WHERE name = ?",
-        params![value, name],
-    )?;
+/// Applies every field set on `update` to the flag's state in `env`, in one transaction that
+/// also records the change for `actor`. Served variations must exist.
+pub fn apply_flag_update(
+    conn: &Connection,
+    env: &str,
+    id: u64,
+    update: FlagUpdate,
+    actor: &str,
+) -> Result<(), FeatureFlagError> {
+    let tx = Savepoint::new(conn)?;
+
+    let flag = get_flag_by_id(&tx, env, id)?;
+    check_version(&flag, update.expected_version)?;
+    if update.is_empty() {
+        return Ok(());
+    }
+    let env_id = environment_id(&tx, env)?;
+
+    if let Some(value) = update.value {
+        let value = value.into_type(flag.flag_type)?;
+
+        tx.execute(
+            "UPDATE flag_states SET value = ?1 WHERE flag_id = ?2 AND environment_id = ?3",
+            params![value.to_json(), id, env_id],
+        )?;
+    }
+
+    if let Some(enabled) = update.enabled {
+        tx.execute(
+            "UPDATE flag_states SET enabled = ?1 WHERE flag_id = ?2 AND environment_id = ?3",
+            params![enabled, id, env_id],
+        )?;
+    }
+
+    let served = [
+        ("default_variation", update.default_variation),
+        ("off_variation", update.off_variation),
+    ];
+    for (column, variation) in served.iter() {
+        // Field left out of the update
+        let variation = match variation {
+            Some(variation) => variation,
+            None => continue,
+        };
+
+        if let Some(name) = variation {
+            if !variations::variation_exists(&tx, id, name)? {
+                return Err(FeatureFlagError::Validation(format!(
+                    "flag {} has no variation named {}",
+                    flag.name, name
+                )));
+            }
+        }
+
+        tx.execute(
+            &format!(
+                "UPDATE flag_states SET {} = ?1 WHERE flag_id = ?2 AND environment_id = ?3",
+                column
+            ),
+            params![variation, id, env_id],
+        )?;
+    }
+
+    if let Some(percentage) = update.rollout_percentage {
+        if let Some(percentage) = percentage {
+            if !(0.0..=100.0).contains(&percentage) {
+                return Err(FeatureFlagError::Validation(format!(
+                    "rollout percentage {} is not between 0 and 100",
+                    percentage
+                )));
+            }
+        }
+
+        tx.execute(
+            "UPDATE flag_states SET rollout_percentage = ?1
+                WHERE flag_id = ?2 AND environment_id = ?3",
+            params![percentage, id, env_id],
+        )?;
+    }
+
+    if let Some(salt) = update.salt {
+        tx.execute(
+            "UPDATE flags SET salt = ?1 WHERE id = ?2",
+            params![salt, id],
+        )?;
+    }
+    bump_version(&tx, id)?;
+
+    let change = Change {
+        flag_id: id,
+        environment: Some(env),
+        action: FlagAction::Updated,
+        old_value: Some(serde_json::to_value(flag).unwrap_or_default()),
+        new_value: Some(serde_json::to_value(get_flag_by_id(&tx, env, id)?).unwrap_or_default()),
+    };
+    record_event(&tx, actor, change)?;
+
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Evaluates a flag in `env` for `context`, see `eval::evaluate`.
+pub fn evaluate_flag(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    name: String,
+    context: &EvaluationContext,
+) -> Result<Evaluation, FeatureFlagError> {
+    let flag = get_flag_by_name(conn, project, env, name)?;
+    let variations = variations::get_variations(conn, flag.id as u64)?;
+    let rules = rules::get_rules(conn, env, flag.id as u64)?;
+    let segments = segments::get_segments(conn, project)?;
+
+    Ok(eval::evaluate(
+        &flag,
+        &variations,
+        &rules,
+        &segments,
+        context,
+    ))
+}
+
+/// Evaluates every flag of `project` in `env` for `context`, keyed by flag name. A flag whose
+/// variations or rules cannot be loaded gets an `ERROR` evaluation instead of failing the rest.
+pub fn evaluate_all_flags(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    context: &EvaluationContext,
+) -> Result<BTreeMap<String, Evaluation>, FeatureFlagError> {
+    let flags = get_all_flags(conn, project, env)?;
+    let segments = segments::get_segments(conn, project)?;
+
+    let mut result = BTreeMap::new();
+    for flag in flags {
+        let loaded = variations::get_variations(conn, flag.id as u64)
+            .and_then(|variations| Ok((variations, rules::get_rules(conn, env, flag.id as u64)?)));
+
+        let evaluation = match loaded {
+            Ok((variations, rules)) => {
+                eval::evaluate(&flag, &variations, &rules, &segments, context)
+            }
+            Err(err) => Evaluation::error(&err),
+        };
+        result.insert(flag.name, evaluation);
+    }
 
     Ok(result)
 }
@@ -146,6 +747,8 @@ mod tests {
 
     use rusqlite::Connection;
 
+    use super::environments::DEFAULT_ENVIRONMENT;
+    use super::projects::DEFAULT_PROJECT;
     use super::*;
 
     fn in_member_db() -> DBLocal {
@@ -162,33 +765,40 @@ mod tests {
     fn test_delete_flag_failure() {
         let conn = in_member_db();
 
-        let result = delete_flag_by_name(conn.clone(), "test".to_string()).unwrap();
+        let result =
+            delete_flag_by_name(&conn, DEFAULT_PROJECT, "test".to_string(), "test").unwrap();
 
-        assert_eq!(result, 0)
+        assert_eq!(result, DeleteOutcome::NotFound)
     }
 
     #[test]
     fn test_update_flag_error() {
         let conn = in_member_db();
 
-        let result = update_flag(conn.clone(), "test".to_string(), 0);
+        let result = update_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "test".to_string(),
+            FlagValue::Bool(false),
+            "test",
+        );
 
-        assert_eq!(
-            format!("{:?}", result),
-            "Err(RusqliteError(QueryReturnedNoRows))"
-        )
+        assert_eq!(format!("{:?}", result), "Err(NotFound(\"Flag test\"))")
     }
 
     #[test]
     fn test_get_flag_by_name_error() {
         let conn = in_member_db();
 
-        let result = get_flag_by_name(conn.clone(), "test".to_string());
+        let result = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "test".to_string(),
+        );
 
-        assert_eq!(
-            format!("{:?}", result),
-            "Err(RusqliteError(QueryReturnedNoRows))"
-        )
+        assert_eq!(format!("{:?}", result), "Err(NotFound(\"Flag test\"))")
     }
 
     #[test]
@@ -198,32 +808,74 @@ mod tests {
         let conn = in_member_db();
 
         // Initialize the flag to True
-        let _ = add_flag(conn.clone(), flag_name.clone(), 1);
-
-        let result = get_flag_by_name(conn.clone(), flag_name.clone()).unwrap();
-        assert_eq!(result.value, true);
+        let _ = add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        );
+
+        let result = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+        )
+        .unwrap();
+        assert_eq!(result.value, FlagValue::Bool(true));
 
         // Update the flag value to False
-        let _ = update_flag(conn.clone(), flag_name.clone(), 0).unwrap();
+        let _ = update_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+            FlagValue::Bool(false),
+            "test",
+        )
+        .unwrap();
 
-        let result = get_flag_by_name(conn.clone(), flag_name.clone()).unwrap();
-        assert_eq!(result.value, false);
+        let result = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+        )
+        .unwrap();
+        assert_eq!(result.value, FlagValue::Bool(false));
     }
 
     #[test]
     fn test_add_single_flag() {
         let flag_name = "test_flag".to_string();
-        let flag_value_int = 1;
-        let flag_value_bool = true;
+        let flag_value = FlagValue::Bool(true);
 
         let conn = in_member_db();
 
-        let _ = add_flag(conn.clone(), flag_name.clone(), flag_value_int.clone()).unwrap();
+        let _ = add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+            FlagType::Bool,
+            flag_value.clone(),
+            "test",
+        )
+        .unwrap();
 
-        let result = get_flag_by_name(conn.clone(), flag_name.clone()).unwrap();
+        let result = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+        )
+        .unwrap();
 
         assert_eq!(result.name, flag_name);
-        assert_eq!(result.value, flag_value_bool);
+        assert_eq!(result.value, flag_value);
     }
 
     #[test]
@@ -232,16 +884,27 @@ mod tests {
 
         let conn = in_member_db();
 
-        let _ = add_flag(conn.clone(), flag_name.clone(), 1).unwrap();
+        let _ = add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            flag_name.clone(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        )
+        .unwrap();
 
         // Make sure the flag was added to the DB
-        let flags = get_all_flags(conn.clone()).unwrap();
+        let flags = get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
         assert_eq!(1, flags.len());
 
         // Delete flag
-        let _ = delete_flag_by_name(conn.clone(), flag_name.clone()).unwrap();
+        let result =
+            delete_flag_by_name(&conn, DEFAULT_PROJECT, flag_name.clone(), "test").unwrap();
+        assert_eq!(result, DeleteOutcome::Deleted);
 
-        let flags = get_all_flags(conn.clone()).unwrap();
+        let flags = get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
         assert_eq!(0, flags.len());
     }
 
@@ -250,22 +913,383 @@ mod tests {
         let conn = in_member_db();
 
         // Case: Zero Flags
-        let result = get_all_flags(conn.clone()).unwrap();
+        let result = get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
         assert_eq!(0, result.len());
 
         // Case: More than Zero flags
         let flags = vec![
-            ("test_1".to_string(), 0),
-            ("test_2".to_string(), 1),
-            ("test_3".to_string(), 0),
+            ("test_1".to_string(), FlagValue::Bool(false)),
+            ("test_2".to_string(), FlagValue::Integer(30)),
+            ("test_3".to_string(), FlagValue::String("blue".to_string())),
         ];
         let expected_num_of_flags = flags.len();
 
         for (name, value) in flags {
-            let _ = add_flag(conn.clone(), name, value).unwrap();
+            let _ = add_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                name,
+                value.flag_type(),
+                value,
+                "test",
+            )
+            .unwrap();
         }
 
-        let result = get_all_flags(conn.clone()).unwrap();
+        let result = get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
         assert_eq!(expected_num_of_flags, result.len());
     }
+
+    #[test]
+    fn test_typed_flags_round_trip() {
+        let conn = in_member_db();
+
+        let flags = vec![
+            ("timeout", FlagType::Integer, FlagValue::Integer(30)),
+            ("ratio", FlagType::Float, FlagValue::Float(0.25)),
+            (
+                "colour",
+                FlagType::String,
+                FlagValue::String("blue".to_string()),
+            ),
+            (
+                "layout",
+                FlagType::Json,
+                FlagValue::Json(serde_json::json!({"columns": 3})),
+            ),
+        ];
+
+        for (name, flag_type, value) in flags {
+            add_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                name.to_string(),
+                flag_type,
+                value.clone(),
+                "test",
+            )
+            .unwrap();
+
+            let result = get_flag_by_name(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                name.to_string(),
+            )
+            .unwrap();
+            assert_eq!(result.flag_type, flag_type);
+            assert_eq!(result.value, value);
+        }
+    }
+
+    #[test]
+    fn test_typed_flag_validation() {
+        let conn = in_member_db();
+
+        // Integers are widened for float flags
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "ratio".to_string(),
+            FlagType::Float,
+            FlagValue::Integer(1),
+            "test",
+        )
+        .unwrap();
+        let result = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "ratio".to_string(),
+        )
+        .unwrap();
+        assert_eq!(result.value, FlagValue::Float(1.0));
+
+        let result = add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "bad".to_string(),
+            FlagType::Bool,
+            FlagValue::Integer(1),
+            "test",
+        );
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        // Updates must keep the type the flag was created with
+        let result = update_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "ratio".to_string(),
+            FlagValue::String("high".to_string()),
+            "test",
+        );
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+
+    #[test]
+    fn test_rollout_update() {
+        let conn = in_member_db();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "new_checkout".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        )
+        .unwrap();
+
+        let rollout = |percentage| FlagUpdate {
+            rollout_percentage: Some(percentage),
+            ..FlagUpdate::default()
+        };
+        let evaluate = |user: &str| {
+            evaluate_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                "new_checkout".to_string(),
+                &EvaluationContext::new(user),
+            )
+            .unwrap()
+            .value
+        };
+
+        let result = apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, rollout(Some(120.0)), "test");
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, rollout(Some(0.0)), "test").unwrap();
+        assert_eq!(evaluate("user-1"), Some(FlagValue::Bool(false)));
+
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, rollout(None), "test").unwrap();
+        assert_eq!(evaluate("user-1"), Some(FlagValue::Bool(true)));
+
+        apply_flag_update(
+            &conn,
+            DEFAULT_ENVIRONMENT,
+            1,
+            FlagUpdate {
+                salt: Some("2023".to_string()),
+                ..FlagUpdate::default()
+            },
+            "test",
+        )
+        .unwrap();
+        let flag = get_flag_by_id(&conn, DEFAULT_ENVIRONMENT, 1).unwrap();
+        assert_eq!(flag.salt, "2023");
+        assert_eq!(flag.rollout_percentage, None);
+    }
+
+    #[test]
+    fn test_flag_versions() {
+        let conn = in_member_db();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "new_checkout".to_string(),
+            FlagType::String,
+            FlagValue::String("v1".to_string()),
+            "test",
+        )
+        .unwrap();
+        let version = || {
+            get_flag_by_id(&conn, DEFAULT_ENVIRONMENT, 1)
+                .unwrap()
+                .version
+        };
+        assert_eq!(version(), 1);
+
+        let update = |expected_version| FlagUpdate {
+            enabled: Some(false),
+            expected_version,
+            ..FlagUpdate::default()
+        };
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, update(Some(1)), "test").unwrap();
+        assert_eq!(version(), 2);
+
+        // An update that sets nothing changes nothing, but still checks the version
+        let empty = FlagUpdate::default();
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, empty, "test").unwrap();
+        assert_eq!(version(), 2);
+        assert_eq!(events::get_flag_history(&conn, 1).unwrap().len(), 2);
+        let stale = FlagUpdate {
+            expected_version: Some(1),
+            ..FlagUpdate::default()
+        };
+        let result = apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, stale, "test");
+        assert!(matches!(
+            result,
+            Err(FeatureFlagError::PreconditionFailed(_))
+        ));
+
+        // Someone else's change came first
+        let result = apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, update(Some(1)), "test");
+        assert!(matches!(
+            result,
+            Err(FeatureFlagError::PreconditionFailed(_))
+        ));
+        assert_eq!(version(), 2);
+
+        let variation = variations::Variation {
+            name: "v2".to_string(),
+            value: FlagValue::String("v2".to_string()),
+        };
+        variations::add_variation(&conn, 1, variation, "test").unwrap();
+        rules::set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![], "test").unwrap();
+        variations::delete_variation(&conn, 1, "v2".to_string(), "test").unwrap();
+        assert_eq!(version(), 5);
+        assert_eq!(
+            get_flag_by_id(&conn, DEFAULT_ENVIRONMENT, 1)
+                .unwrap()
+                .etag(),
+            "\"5\""
+        );
+    }
+
+    #[test]
+    fn test_flag_metadata() {
+        let conn = in_member_db();
+
+        let flag = Flag {
+            name: "new_checkout".to_string(),
+            flag_type: None,
+            value: FlagValue::Bool(true),
+            metadata: FlagMetadata {
+                description: Some("The new checkout page".to_string()),
+                owner: Some("payments".to_string()),
+                tags: vec!["checkout".to_string(), "temporary".to_string()],
+            },
+        };
+        insert_flag(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, flag, "ada").unwrap();
+
+        let flag = get_flag_by_id(&conn, DEFAULT_ENVIRONMENT, 1).unwrap();
+        assert_eq!(flag.metadata.owner.as_deref(), Some("payments"));
+        assert_eq!(flag.metadata.tags, vec!["checkout", "temporary"]);
+        assert_eq!(flag.created_by, "ada");
+        assert_eq!(flag.created_at, flag.updated_at);
+
+        let filter = FlagFilter {
+            tag: Some("temporary".to_string()),
+            ..FlagFilter::default()
+        };
+        assert!(filter.matches(&flag));
+        let filter = FlagFilter {
+            owner: Some("search".to_string()),
+            ..filter
+        };
+        assert!(!filter.matches(&flag));
+    }
+
+    #[test]
+    fn test_evaluate_all_flags() {
+        let conn = in_member_db();
+
+        for (name, enabled) in [("new_checkout", true), ("dark_mode", false)] {
+            add_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                name.to_string(),
+                FlagType::Bool,
+                FlagValue::Bool(true),
+                "test",
+            )
+            .unwrap();
+            let flag = get_flag_by_name(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                name.to_string(),
+            )
+            .unwrap();
+            let update = FlagUpdate {
+                enabled: Some(enabled),
+                ..FlagUpdate::default()
+            };
+            apply_flag_update(&conn, DEFAULT_ENVIRONMENT, flag.id as u64, update, "test").unwrap();
+        }
+
+        let result = evaluate_all_flags(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &EvaluationContext::new("user-1"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            result.keys().collect::<Vec<_>>(),
+            vec!["dark_mode", "new_checkout"]
+        );
+        assert_eq!(result["dark_mode"].value, Some(FlagValue::Bool(false)));
+        assert_eq!(result["dark_mode"].reason, eval::Reason::Off);
+        assert_eq!(result["new_checkout"].value, Some(FlagValue::Bool(true)));
+        assert_eq!(result["new_checkout"].reason, eval::Reason::Fallthrough);
+    }
+
+    #[test]
+    fn test_open_db() {
+        let dir = std::env::temp_dir().join(format!("feature-flags-db-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = DbConfig {
+            path: dir.join("instance").join("flag.db").display().to_string(),
+            read_only: false,
+        };
+
+        // Only db-init creates missing databases
+        assert!(matches!(
+            open_db(&config, false),
+            Err(FeatureFlagError::MissingDatabase(_))
+        ));
+        let conn = open_db(&config, true).unwrap();
+        migrations::migrate(&conn).unwrap();
+        drop(conn);
+
+        config.read_only = true;
+        let conn = open_db(&config, false).unwrap();
+        assert!(get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT)
+            .unwrap()
+            .is_empty());
+        let added = add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "test".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        );
+        assert!(added.is_err());
+        drop(conn);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_memory_db() {
+        let mut config = DbConfig {
+            path: crate::config::MEMORY_DB.to_string(),
+            read_only: false,
+        };
+
+        let conn = open_db(&config, false).unwrap();
+        assert!(get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT)
+            .unwrap()
+            .is_empty());
+
+        config.read_only = true;
+        assert!(matches!(
+            open_db(&config, false),
+            Err(FeatureFlagError::Config(_))
+        ));
+    }
 }
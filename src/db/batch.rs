@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Flag, FlagUpdate, FlagWithID};
+use crate::error::FeatureFlagError;
+
+/// One change of a `Batch`. Flags are addressed by name, as ids are not known before a flag is
+/// created.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    Create { flag: Flag },
+    Update { name: String, update: FlagUpdate },
+    Delete { name: String },
+}
+
+impl BatchOperation {
+    /// Name of the flag the operation is about.
+    pub fn name(&self) -> &str {
+        match self {
+            BatchOperation::Create { flag } => &flag.name,
+            BatchOperation::Update { name, .. } | BatchOperation::Delete { name } => name,
+        }
+    }
+}
+
+/// What a batch does when one of its operations fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    /// Keep none of the changes
+    #[default]
+    AllOrNothing,
+    /// Keep the changes of every operation that succeeded
+    BestEffort,
+}
+
+/// Operations applied in order to the flags of one project and environment, see
+/// `FlagStore::apply_batch`.
+#[derive(Debug, Deserialize)]
+pub struct Batch {
+    #[serde(default)]
+    pub mode: BatchMode,
+    pub operations: Vec<BatchOperation>,
+}
+
+/// What became of one operation of a batch.
+#[derive(Debug)]
+pub enum OperationResult {
+    Created(FlagWithID),
+    Updated(FlagWithID),
+    /// The flag as it was before it was deleted
+    Deleted(FlagWithID),
+    Failed(FeatureFlagError),
+    /// Not applied, or undone, because another operation of an all-or-nothing batch failed
+    Aborted,
+}
+
+/// Results of a batch, one for each operation and in the same order.
+#[derive(Debug)]
+pub struct BatchResult {
+    /// Whether the changes were kept, which they are unless an all-or-nothing batch failed
+    pub committed: bool,
+    pub results: Vec<OperationResult>,
+}
+
+impl BatchResult {
+    /// The error of the first operation that failed.
+    pub fn first_error(&self) -> Option<&FeatureFlagError> {
+        self.results.iter().find_map(|result| match result {
+            OperationResult::Failed(err) => Some(err),
+            _ => None,
+        })
+    }
+}
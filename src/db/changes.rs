@@ -0,0 +1,306 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use super::environments::environment_id;
+use super::projects::project_id;
+use super::{get_all_flags, get_flag_by_id, FlagWithID, Savepoint};
+use crate::error::FeatureFlagError;
+
+/// A flag that was deleted, see `FlagChanges`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct DeletedFlag {
+    pub id: u64,
+    pub name: String,
+}
+
+/// Position in the changes of a store, written `epoch-seq`.
+///
+/// Every change to a flag takes the next number of a sequence that only ever grows. The epoch
+/// sets the numbers of a store apart from those of one that starts over, e.g. a database after
+/// `db-init --reset`. A cursor without an epoch, such as `0`, is not one of any store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChangeCursor {
+    pub epoch: u64,
+    /// Number of the latest change, 0 before the first one
+    pub seq: u64,
+}
+
+impl fmt::Display for ChangeCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for ChangeCursor {
+    type Err = FeatureFlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (epoch, seq) = s.split_once('-').unwrap_or(("0", s));
+
+        match (epoch.parse(), seq.parse()) {
+            (Ok(epoch), Ok(seq)) => Ok(ChangeCursor { epoch, seq }),
+            _ => Err(FeatureFlagError::Validation(format!(
+                "cursor '{}' is not one like 0 or 1700000000000-12",
+                s
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for ChangeCursor {
+    type Error = FeatureFlagError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ChangeCursor> for String {
+    fn from(cursor: ChangeCursor) -> String {
+        cursor.to_string()
+    }
+}
+
+/// What happened to the flags of a project after a cursor.
+///
+/// Deletions are listed before the flags because an id or a name can come back as a new flag.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FlagChanges {
+    /// Pass it back to get the changes after these
+    pub cursor: ChangeCursor,
+    /// Whether `flags` holds every flag of the project, either because the cursor was 0 or
+    /// because it is not one the store handed out. Flags left out of it are gone.
+    pub full: bool,
+    /// Flags created or changed after the cursor, with their state now
+    pub flags: Vec<FlagWithID>,
+    pub deleted: Vec<DeletedFlag>,
+}
+
+impl FlagChanges {
+    /// Whether nothing happened after the cursor.
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.flags.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Cursor of the latest change to any flag.
+pub fn latest_change(conn: &Connection) -> Result<ChangeCursor, FeatureFlagError> {
+    // The newest row is never removed, older ones are once their flag changes again
+    let cursor = conn.query_row(
+        "SELECT epoch, (SELECT COALESCE(MAX(seq), 0) FROM flag_changes) FROM change_epoch",
+        [],
+        |row| {
+            Ok(ChangeCursor {
+                epoch: row.get(0)?,
+                seq: row.get(1)?,
+            })
+        },
+    )?;
+
+    Ok(cursor)
+}
+
+/// Flags of `project` created, changed or deleted after `cursor`, with their state in `env`.
+///
+/// A cursor of another epoch, e.g. from before the database was reset, gets every flag instead.
+/// Nothing happened after the latest change, even when that is 0.
+pub fn get_flag_changes(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    cursor: ChangeCursor,
+) -> Result<FlagChanges, FeatureFlagError> {
+    // Changes made meanwhile by another connection must not slip between the queries
+    let tx = Savepoint::new(conn)?;
+
+    let project_id = project_id(&tx, project)?;
+    environment_id(&tx, env)?;
+    let latest = latest_change(&tx)?;
+
+    let mut changes = FlagChanges {
+        cursor: latest,
+        full: false,
+        flags: vec![],
+        deleted: vec![],
+    };
+    if cursor == latest {
+        return Ok(changes);
+    }
+    // Clients can still make one up
+    if cursor.epoch != latest.epoch || cursor.seq > latest.seq {
+        changes.full = true;
+        changes.flags = get_all_flags(&tx, project, env)?;
+        return Ok(changes);
+    }
+
+    let mut stmt = tx.prepare(
+        "SELECT flag_id, name, deleted FROM flag_changes
+            WHERE project_id = ?1 AND seq > ?2
+            ORDER BY seq",
+    )?;
+    let rows = stmt.query_map(params![project_id, cursor.seq], |row| {
+        Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+    })?;
+
+    for row in rows {
+        let (id, name, deleted) = row?;
+        if deleted {
+            changes.deleted.push(DeletedFlag { id, name });
+        } else {
+            changes.flags.push(get_flag_by_id(&tx, env, id)?);
+        }
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::migrations::reset;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::{add_flag, apply_flag_update, delete_flag_by_name, initialize_db, DBLocal};
+    use crate::db::{FlagType, FlagUpdate, FlagValue};
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn add(conn: &Connection, name: &str) {
+        add_flag(
+            conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            name.to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "ada",
+        )
+        .unwrap();
+    }
+
+    fn names(changes: &FlagChanges) -> Vec<&str> {
+        changes
+            .flags
+            .iter()
+            .map(|flag| flag.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_flag_changes() {
+        let conn = in_memory_db();
+        let epoch = latest_change(&conn).unwrap().epoch;
+        let changes_after = |seq| {
+            let cursor = ChangeCursor { epoch, seq };
+            get_flag_changes(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, cursor).unwrap()
+        };
+
+        let changes = changes_after(0);
+        assert_eq!(changes.cursor, ChangeCursor { epoch, seq: 0 });
+        assert!(changes.is_empty());
+
+        add(&conn, "checkout");
+        add(&conn, "search");
+        let start = ChangeCursor::default();
+        let changes = get_flag_changes(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, start).unwrap();
+        assert_eq!(changes.cursor.seq, 2);
+        assert!(changes.full);
+        assert_eq!(names(&changes), vec!["checkout", "search"]);
+
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, update, "bob").unwrap();
+        let changes = changes_after(2);
+        assert_eq!(changes.cursor.seq, 3);
+        assert!(!changes.full);
+        assert_eq!(names(&changes), vec!["checkout"]);
+        assert!(!changes.flags[0].enabled);
+
+        delete_flag_by_name(&conn, DEFAULT_PROJECT, "checkout".to_string(), "carol").unwrap();
+        let changes = changes_after(2);
+        assert_eq!(changes.cursor.seq, 4);
+        assert!(changes.flags.is_empty());
+        assert_eq!(
+            changes.deleted,
+            vec![DeletedFlag {
+                id: 1,
+                name: "checkout".to_string()
+            }]
+        );
+
+        // Nothing after the latest change, and a deletion is still reported once the name is back
+        assert!(changes_after(4).is_empty());
+        add(&conn, "checkout");
+        let changes = changes_after(3);
+        assert_eq!(changes.deleted.len(), 1);
+        assert_eq!(names(&changes), vec!["checkout"]);
+
+        // A cursor that was never handed out
+        let changes = changes_after(99);
+        assert!(changes.full);
+        assert_eq!(changes.cursor.seq, 5);
+        assert_eq!(changes.flags.len(), 2);
+    }
+
+    #[test]
+    fn test_cursors_from_before_a_reset() {
+        let conn = in_memory_db();
+        add(&conn, "checkout");
+        add(&conn, "search");
+        let before = latest_change(&conn).unwrap();
+
+        reset(&conn).unwrap();
+        add(&conn, "checkout");
+        add(&conn, "search");
+        add(&conn, "dark_mode");
+
+        // The sequence starts over, but the epoch does not
+        let changes =
+            get_flag_changes(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, before).unwrap();
+        assert!(changes.full);
+        assert_eq!(changes.cursor.seq, 3);
+        assert_ne!(changes.cursor.epoch, before.epoch);
+        assert_eq!(names(&changes), vec!["checkout", "search", "dark_mode"]);
+    }
+
+    #[test]
+    fn test_cursor_strings() {
+        let cursor = ChangeCursor {
+            epoch: 1700000000000,
+            seq: 12,
+        };
+        assert_eq!(cursor.to_string(), "1700000000000-12");
+        assert_eq!("1700000000000-12".parse::<ChangeCursor>().unwrap(), cursor);
+        assert_eq!(
+            serde_json::to_value(cursor).unwrap(),
+            serde_json::json!("1700000000000-12")
+        );
+
+        // Cursors without an epoch are of no store
+        assert_eq!(
+            "0".parse::<ChangeCursor>().unwrap(),
+            ChangeCursor::default()
+        );
+        assert_eq!("12".parse::<ChangeCursor>().unwrap().epoch, 0);
+        for invalid in ["", "soon", "1-", "-1", "1-2-3"].iter() {
+            assert!(invalid.parse::<ChangeCursor>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{validate_name, Savepoint};
+use crate::error::FeatureFlagError;
+
+/// Environment used when none is given. Existing flags were moved here by the migration.
+pub const DEFAULT_ENVIRONMENT: &str = "production";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Environment {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewEnvironment {
+    pub name: String,
+    /// Environment whose flag states are copied, defaults to `DEFAULT_ENVIRONMENT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub copy_from: Option<String>,
+}
+
+/// Looks up an environment, failing with `NotFound` when it does not exist.
+pub fn environment_id(conn: &Connection, name: &str) -> Result<i64, FeatureFlagError> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM environments WHERE name = ?",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    id.ok_or_else(|| FeatureFlagError::NotFound(format!("Environment {}", name)))
+}
+
+pub fn get_environments(conn: &Connection) -> Result<Vec<Environment>, FeatureFlagError> {
+    let mut stmt = conn.prepare("SELECT id, name FROM environments ORDER BY id")?;
+
+    let rows = stmt.query_map([], |row| {
+        Ok(Environment {
+            id: row.get(0)?,
+            name: row.get(1)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+/// Creates an environment holding a copy of every flag's state in another environment.
+pub fn add_environment(
+    conn: &Connection,
+    environment: NewEnvironment,
+) -> Result<usize, FeatureFlagError> {
+    validate_name("environment", &environment.name)?;
+
+    let source = environment
+        .copy_from
+        .unwrap_or_else(|| DEFAULT_ENVIRONMENT.to_string());
+    let source_id = environment_id(conn, &source)?;
+
+    let tx = Savepoint::new(conn)?;
+
+    let name = &environment.name;
+    let result = tx
+        .execute("INSERT INTO environments (name) VALUES (?)", params![name])
+        .map_err(|err| FeatureFlagError::from(err).naming(format!("Environment {}", name)))?;
+    let env_id = tx.last_insert_rowid();
+
+    tx.execute(
+        "INSERT INTO flag_states
+            (flag_id, environment_id, enabled, value, default_variation, off_variation,
+                rollout_percentage)
+            SELECT flag_id, ?1, enabled, value, default_variation, off_variation,
+                rollout_percentage
+            FROM flag_states WHERE environment_id = ?2",
+        params![env_id, source_id],
+    )?;
+    tx.execute(
+        "INSERT INTO rules (flag_id, environment_id, position, clauses, variation)
+            SELECT flag_id, ?1, position, clauses, variation
+            FROM rules WHERE environment_id = ?2",
+        params![env_id, source_id],
+    )?;
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+/// Deletes an environment with the flag states and rules in it. The default environment cannot
+/// be deleted.
+pub fn delete_environment(conn: &Connection, name: String) -> Result<usize, FeatureFlagError> {
+    if name == DEFAULT_ENVIRONMENT {
+        return Err(FeatureFlagError::Validation(format!(
+            "the {} environment cannot be deleted",
+            DEFAULT_ENVIRONMENT
+        )));
+    }
+
+    let tx = Savepoint::new(conn)?;
+
+    for table in ["flag_states", "rules"].iter() {
+        tx.execute(
+            &format!(
+                "DELETE FROM {}
+                    WHERE environment_id IN (SELECT id FROM environments WHERE name = ?)",
+                table
+            ),
+            params![name],
+        )?;
+    }
+    let result = tx.execute("DELETE FROM environments WHERE name = ?", params![name])?;
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::{
+        add_flag, get_all_flags, get_flag_by_name, initialize_db, update_flag, DBLocal, FlagType,
+        FlagValue,
+    };
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn new_environment(name: &str) -> NewEnvironment {
+        NewEnvironment {
+            name: name.to_string(),
+            copy_from: None,
+        }
+    }
+
+    #[test]
+    fn test_default_environment_exists() {
+        let conn = in_memory_db();
+
+        let result = get_environments(&conn).unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(DEFAULT_ENVIRONMENT, result[0].name);
+
+        let result = delete_environment(&conn, DEFAULT_ENVIRONMENT.to_string());
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+
+    #[test]
+    fn test_environments_are_independent() {
+        let conn = in_memory_db();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        )
+        .unwrap();
+
+        // New environments start from a copy of production
+        add_environment(&conn, new_environment("staging")).unwrap();
+        let result =
+            get_flag_by_name(&conn, DEFAULT_PROJECT, "staging", "checkout".to_string()).unwrap();
+        assert_eq!(result.value, FlagValue::Bool(true));
+        assert!(result.enabled);
+
+        update_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            "staging",
+            "checkout".to_string(),
+            FlagValue::Bool(false),
+            "test",
+        )
+        .unwrap();
+
+        let staging =
+            get_flag_by_name(&conn, DEFAULT_PROJECT, "staging", "checkout".to_string()).unwrap();
+        let production = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+        )
+        .unwrap();
+        assert_eq!(staging.value, FlagValue::Bool(false));
+        assert_eq!(production.value, FlagValue::Bool(true));
+
+        // Flags added in one environment start off everywhere else
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            "staging",
+            "search".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        )
+        .unwrap();
+        let production = get_flag_by_name(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "search".to_string(),
+        )
+        .unwrap();
+        assert!(!production.enabled);
+
+        assert_eq!(1, delete_environment(&conn, "staging".to_string()).unwrap());
+        assert_eq!(
+            2,
+            get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT)
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_unknown_environment() {
+        let conn = in_memory_db();
+
+        let result = get_all_flags(&conn, DEFAULT_PROJECT, "qa");
+        assert_eq!(format!("{:?}", result), "Err(NotFound(\"Environment qa\"))");
+
+        let result = add_environment(
+            &conn,
+            NewEnvironment {
+                name: "qa".to_string(),
+                copy_from: Some("dev".to_string()),
+            },
+        );
+        assert!(matches!(result, Err(FeatureFlagError::NotFound(_))));
+
+        let result = add_environment(&conn, new_environment("q a"));
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+
+    #[test]
+    fn test_environments_inside_a_savepoint() {
+        let conn = in_memory_db();
+
+        let tx = Savepoint::new(&conn).unwrap();
+        add_environment(&tx, new_environment("staging")).unwrap();
+        assert_eq!(1, delete_environment(&tx, "staging".to_string()).unwrap());
+        add_environment(&tx, new_environment("qa")).unwrap();
+        drop(tx);
+
+        // Rolling back the enclosing savepoint undoes them all
+        assert_eq!(1, get_environments(&conn).unwrap().len());
+    }
+}
@@ -0,0 +1,345 @@
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::projects::project_id;
+use super::variations::Variation;
+use crate::error::FeatureFlagError;
+use crate::eval::{Rule, Segment};
+
+/// Actor recorded when a change does not say who made it.
+pub const DEFAULT_ACTOR: &str = "anonymous";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagAction {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl FlagAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlagAction::Created => "created",
+            FlagAction::Updated => "updated",
+            FlagAction::Deleted => "deleted",
+        }
+    }
+}
+
+impl ToSql for FlagAction {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for FlagAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "created" => Ok(FlagAction::Created),
+            "updated" => Ok(FlagAction::Updated),
+            "deleted" => Ok(FlagAction::Deleted),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// One entry of the audit log. Entries are never changed or removed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FlagEvent {
+    pub id: i64,
+    pub flag_id: u64,
+    pub project: String,
+    pub flag: String,
+    /// Environment whose state changed, `None` for changes to every environment
+    pub environment: Option<String>,
+    /// Who made the change
+    pub actor: String,
+    /// UTC, e.g. `2023-05-01T12:00:00.000Z`
+    pub timestamp: String,
+    pub action: FlagAction,
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+}
+
+/// A change to record, see `record_event`.
+pub(crate) struct Change<'a> {
+    pub flag_id: u64,
+    pub environment: Option<&'a str>,
+    pub action: FlagAction,
+    pub old_value: Option<Value>,
+    pub new_value: Option<Value>,
+}
+
+/// Appends `change` to the audit log. Call it with the transaction making the change, before a
+/// deleted flag's row is gone.
+pub(crate) fn record_event(
+    conn: &Connection,
+    actor: &str,
+    change: Change,
+) -> Result<(), FeatureFlagError> {
+    conn.execute(
+        "INSERT INTO flag_events
+            (flag_id, project, flag, environment, actor, action, old_value, new_value)
+            SELECT flags.id, projects.name, flags.name, ?2, ?3, ?4, ?5, ?6
+            FROM flags JOIN projects ON projects.id = flags.project_id
+            WHERE flags.id = ?1",
+        params![
+            change.flag_id,
+            change.environment,
+            actor,
+            change.action,
+            change.old_value,
+            change.new_value
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// What the audit log records of a flag's variations, before and after they change.
+pub(crate) fn variations_value(variations: &[Variation]) -> Value {
+    json!({ "variations": variations })
+}
+
+/// What the audit log records of a flag's rules in one environment, before and after they
+/// change.
+pub(crate) fn rules_value(rules: &[Rule]) -> Value {
+    json!({ "rules": rules })
+}
+
+/// What the audit log records of a segment the rules of a flag refer to, before and after it
+/// changes.
+pub(crate) fn segment_value(segment: &Segment) -> Value {
+    json!({ "segment": segment })
+}
+
+const EVENT_SELECT: &str = "SELECT id, flag_id, project, flag, environment, actor, timestamp,
+        action, old_value, new_value
+    FROM flag_events";
+
+fn event_from_row(row: &Row) -> rusqlite::Result<FlagEvent> {
+    Ok(FlagEvent {
+        id: row.get(0)?,
+        flag_id: row.get(1)?,
+        project: row.get(2)?,
+        flag: row.get(3)?,
+        environment: row.get(4)?,
+        actor: row.get(5)?,
+        timestamp: row.get(6)?,
+        action: row.get(7)?,
+        old_value: row.get(8)?,
+        new_value: row.get(9)?,
+    })
+}
+
+/// Events of the flag with `id`, oldest first.
+///
+/// Ids of deleted flags can be handed out again, so only events since the flag was last created
+/// are returned.
+pub fn get_flag_history(conn: &Connection, id: u64) -> Result<Vec<FlagEvent>, FeatureFlagError> {
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE flag_id = ?1 AND id >= (
+            SELECT COALESCE(MAX(id), 0) FROM flag_events
+                WHERE flag_id = ?1 AND action = 'created'
+        )
+        ORDER BY id",
+        EVENT_SELECT
+    ))?;
+
+    let rows = stmt.query_map(params![id], event_from_row)?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+/// Events of every flag that was ever called `name` in `project`, including deleted ones.
+pub fn get_flag_history_by_name(
+    conn: &Connection,
+    project: &str,
+    name: &str,
+) -> Result<Vec<FlagEvent>, FeatureFlagError> {
+    // Fails for unknown projects
+    project_id(conn, project)?;
+
+    let mut stmt = conn.prepare(&format!(
+        "{} WHERE project = ?1 AND flag = ?2 ORDER BY id",
+        EVENT_SELECT
+    ))?;
+
+    let rows = stmt.query_map(params![project, name], event_from_row)?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::rules::set_rules;
+    use crate::db::variations::{add_variation, delete_variation, update_variation};
+    use crate::db::{add_flag, apply_flag_update, delete_flag_by_name, initialize_db, DBLocal};
+    use crate::db::{FlagType, FlagUpdate, FlagValue};
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn add_checkout(conn: &Connection) {
+        add_flag(
+            conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "ada",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_history() {
+        let conn = in_memory_db();
+
+        add_checkout(&conn);
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        apply_flag_update(&conn, DEFAULT_ENVIRONMENT, 1, update, "bob").unwrap();
+        delete_flag_by_name(&conn, DEFAULT_PROJECT, "checkout".to_string(), "carol").unwrap();
+
+        let history = get_flag_history(&conn, 1).unwrap();
+        let summary: Vec<_> = history
+            .iter()
+            .map(|event| {
+                (
+                    event.action,
+                    event.actor.as_str(),
+                    event.environment.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (FlagAction::Created, "ada", None),
+                (FlagAction::Updated, "bob", Some(DEFAULT_ENVIRONMENT)),
+                (FlagAction::Deleted, "carol", None),
+            ]
+        );
+
+        let update = &history[1];
+        assert_eq!(update.old_value.as_ref().unwrap()["enabled"], json!(true));
+        assert_eq!(update.new_value.as_ref().unwrap()["enabled"], json!(false));
+        assert_eq!(
+            history[2].old_value.as_ref().unwrap()[DEFAULT_ENVIRONMENT]["enabled"],
+            json!(false)
+        );
+        assert_eq!(history[2].new_value, None);
+
+        // A new flag reusing the id starts with an empty history, the name keeps all of it
+        add_checkout(&conn);
+        assert_eq!(get_flag_history(&conn, 1).unwrap().len(), 1);
+        assert_eq!(
+            get_flag_history_by_name(&conn, DEFAULT_PROJECT, "checkout")
+                .unwrap()
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_history_of_variations_and_rules() {
+        let conn = in_memory_db();
+
+        add_checkout(&conn);
+        let variation = Variation {
+            name: "off".to_string(),
+            value: FlagValue::Bool(true),
+        };
+        add_variation(&conn, 1, variation, "bob").unwrap();
+        update_variation(&conn, 1, "off".to_string(), FlagValue::Bool(false), "bob").unwrap();
+        let rule: Rule = serde_json::from_value(json!({
+            "clauses": [{"attribute": "country", "op": "in", "values": ["fr"]}],
+            "variation": "off"
+        }))
+        .unwrap();
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![rule], "carol").unwrap();
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![], "carol").unwrap();
+        delete_variation(&conn, 1, "off".to_string(), "dave").unwrap();
+
+        let history = get_flag_history(&conn, 1).unwrap();
+        let summary: Vec<_> = history
+            .iter()
+            .map(|event| (event.actor.as_str(), event.environment.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("ada", None),
+                ("bob", None),
+                ("bob", None),
+                ("carol", Some(DEFAULT_ENVIRONMENT)),
+                ("carol", Some(DEFAULT_ENVIRONMENT)),
+                ("dave", None),
+            ]
+        );
+
+        let values = |event: &FlagEvent| (event.old_value.clone(), event.new_value.clone());
+        assert_eq!(
+            values(&history[2]),
+            (
+                Some(json!({"variations": [{"name": "off", "value": true}]})),
+                Some(json!({"variations": [{"name": "off", "value": false}]}))
+            )
+        );
+        assert_eq!(
+            history[4].old_value.as_ref().unwrap()["rules"][0]["variation"],
+            json!("off")
+        );
+        assert_eq!(history[4].new_value, Some(json!({"rules": []})));
+        assert_eq!(history[5].new_value, Some(json!({"variations": []})));
+
+        // Failed changes are not recorded
+        assert!(
+            update_variation(&conn, 1, "off".to_string(), FlagValue::Bool(true), "eve").is_err()
+        );
+        assert_eq!(get_flag_history(&conn, 1).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn test_history_is_append_only() {
+        let conn = in_memory_db();
+
+        add_checkout(&conn);
+
+        assert!(conn
+            .execute("UPDATE flag_events SET actor = 'mallory'", [])
+            .is_err());
+        assert!(conn.execute("DELETE FROM flag_events", []).is_err());
+        assert_eq!(get_flag_history(&conn, 1).unwrap()[0].actor, "ada");
+    }
+}
@@ -0,0 +1,534 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use rusqlite::types::ToSql;
+use rusqlite::{params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::environments::environment_id;
+use super::projects::project_id;
+use super::{flag_from_row, FlagWithID, FLAG_SELECT};
+use crate::error::FeatureFlagError;
+
+/// Narrows down a listing of flags. Fields that are left out match every flag.
+#[derive(Debug, Default, Deserialize)]
+pub struct FlagFilter {
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Only flags that have this tag
+    #[serde(default)]
+    pub tag: Option<String>,
+    /// Only flags whose name starts with this
+    #[serde(default)]
+    pub prefix: Option<String>,
+    /// Only flags whose name contains this
+    #[serde(default)]
+    pub contains: Option<String>,
+    /// Only flags serving this value, as JSON, e.g. `true` or `30`. Anything that is not JSON is
+    /// taken as a string, so `blue` and `"blue"` are the same.
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+impl FlagFilter {
+    pub fn matches(&self, flag: &FlagWithID) -> bool {
+        let owner = match &self.owner {
+            Some(owner) => flag.metadata.owner.as_ref() == Some(owner),
+            None => true,
+        };
+        let tag = match &self.tag {
+            Some(tag) => flag.metadata.tags.contains(tag),
+            None => true,
+        };
+        let prefix = match &self.prefix {
+            Some(prefix) => flag.name.starts_with(prefix.as_str()),
+            None => true,
+        };
+        let contains = match &self.contains {
+            Some(part) => flag.name.contains(part.as_str()),
+            None => true,
+        };
+        let value = match self.value_json() {
+            Some(value) => flag.value.to_json() == value,
+            None => true,
+        };
+
+        owner && tag && prefix && contains && value
+    }
+
+    fn value_json(&self) -> Option<Value> {
+        self.value.as_ref().map(|value| {
+            serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.clone()))
+        })
+    }
+}
+
+/// What a listing of flags is ordered by. Flags that tie are ordered by id, so that the order
+/// is the same on every page.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagSort {
+    #[default]
+    Id,
+    Name,
+    CreatedAt,
+    UpdatedAt,
+}
+
+impl FlagSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlagSort::Id => "id",
+            FlagSort::Name => "name",
+            FlagSort::CreatedAt => "created_at",
+            FlagSort::UpdatedAt => "updated_at",
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            FlagSort::Id => "flags.id",
+            FlagSort::Name => "flags.name",
+            FlagSort::CreatedAt => "flags.created_at",
+            FlagSort::UpdatedAt => "flags.updated_at",
+        }
+    }
+
+    /// Where `flag` goes in the listing, compared as SQLite compares text.
+    fn position(&self, flag: &FlagWithID) -> Position {
+        let key = match self {
+            FlagSort::Id => String::new(),
+            FlagSort::Name => flag.name.clone(),
+            FlagSort::CreatedAt => flag.created_at.clone(),
+            FlagSort::UpdatedAt => flag.updated_at.clone(),
+        };
+
+        Position {
+            key,
+            id: flag.id as u64,
+        }
+    }
+}
+
+impl FromStr for FlagSort {
+    type Err = FeatureFlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(FlagSort::Id),
+            "name" => Ok(FlagSort::Name),
+            "created_at" => Ok(FlagSort::CreatedAt),
+            "updated_at" => Ok(FlagSort::UpdatedAt),
+            other => Err(FeatureFlagError::Validation(format!(
+                "unknown sort '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = FeatureFlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            other => Err(FeatureFlagError::Validation(format!(
+                "unknown sort order '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+/// Which flags of a listing to return, see `list_flags`. By default every flag, by id.
+#[derive(Debug, Default, Deserialize)]
+pub struct FlagPage {
+    #[serde(default)]
+    pub sort: FlagSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// At most this many flags, all of them when left out
+    #[serde(default)]
+    pub limit: Option<u32>,
+    /// `next` of the page before, to continue after it
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+/// One page of a listing of flags.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FlagList {
+    pub flags: Vec<FlagWithID>,
+    /// Cursor of the next page, `None` on the last one
+    pub next: Option<String>,
+}
+
+/// The sort key and id of the last flag of a page, which the next page starts after.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    key: String,
+    id: u64,
+}
+
+impl FlagPage {
+    fn limit(&self) -> Result<Option<usize>, FeatureFlagError> {
+        match self.limit {
+            Some(0) => Err(FeatureFlagError::Validation(
+                "limit must be at least 1".to_string(),
+            )),
+            limit => Ok(limit.map(|limit| limit as usize)),
+        }
+    }
+
+    /// Cursors say how the listing was sorted, so that they are not used with another order.
+    /// They are hex encoded to be safe in URLs.
+    fn cursor(&self, position: &Position) -> String {
+        let cursor = format!(
+            "{}:{}:{}:{}",
+            self.sort.as_str(),
+            self.order.as_str(),
+            position.id,
+            position.key
+        );
+
+        cursor.bytes().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn after(&self) -> Result<Option<Position>, FeatureFlagError> {
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor,
+            None => return Ok(None),
+        };
+        let invalid = || {
+            FeatureFlagError::Validation(format!(
+                "cursor {} is not one of a listing by {} {}",
+                cursor,
+                self.sort.as_str(),
+                self.order.as_str()
+            ))
+        };
+
+        let bytes = (0..cursor.len())
+            .step_by(2)
+            .map(|start| {
+                cursor
+                    .get(start..start + 2)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let decoded = String::from_utf8(bytes).map_err(|_| invalid())?;
+
+        let mut parts = decoded.splitn(4, ':');
+        let (sort, order, id, key) = (parts.next(), parts.next(), parts.next(), parts.next());
+        if sort != Some(self.sort.as_str()) || order != Some(self.order.as_str()) {
+            return Err(invalid());
+        }
+        let id = id.and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+        let key = key.ok_or_else(invalid)?.to_string();
+
+        Ok(Some(Position { key, id }))
+    }
+
+    /// The page of `flags`, which are in listing order and one more than the limit when there
+    /// are more.
+    fn page(&self, mut flags: Vec<FlagWithID>, limit: Option<usize>) -> FlagList {
+        let next = match limit {
+            Some(limit) if flags.len() > limit => {
+                flags.truncate(limit);
+                flags
+                    .last()
+                    .map(|flag| self.cursor(&self.sort.position(flag)))
+            }
+            _ => None,
+        };
+
+        FlagList { flags, next }
+    }
+}
+
+/// Filters, sorts and pages `flags` the way `list_flags` does in SQL, for stores that keep
+/// their flags elsewhere.
+pub fn page_flags(
+    flags: Vec<FlagWithID>,
+    filter: &FlagFilter,
+    page: &FlagPage,
+) -> Result<FlagList, FeatureFlagError> {
+    let limit = page.limit()?;
+    let after = page.after()?;
+
+    let mut flags: Vec<(Position, FlagWithID)> = flags
+        .into_iter()
+        .filter(|flag| filter.matches(flag))
+        .map(|flag| (page.sort.position(&flag), flag))
+        .collect();
+    flags.sort_by(|(a, _), (b, _)| match page.order {
+        SortOrder::Asc => a.cmp(b),
+        SortOrder::Desc => b.cmp(a),
+    });
+
+    let flags = flags
+        .into_iter()
+        .filter(|(position, _)| match &after {
+            Some(after) => {
+                let ordering = position.cmp(after);
+                match page.order {
+                    SortOrder::Asc => ordering == Ordering::Greater,
+                    SortOrder::Desc => ordering == Ordering::Less,
+                }
+            }
+            None => true,
+        })
+        .map(|(_, flag)| flag)
+        .take(limit.map_or(usize::MAX, |limit| limit + 1))
+        .collect();
+
+    Ok(page.page(flags, limit))
+}
+
+/// One page of the flags of `project` that match `filter`, with their state in `env`.
+pub fn list_flags(
+    conn: &Connection,
+    project: &str,
+    env: &str,
+    filter: &FlagFilter,
+    page: &FlagPage,
+) -> Result<FlagList, FeatureFlagError> {
+    let limit = page.limit()?;
+    let after = page.after()?;
+    let project_id = project_id(conn, project)?;
+    let env_id = environment_id(conn, env)?;
+
+    let mut values: Vec<Box<dyn ToSql>> = vec![Box::new(env_id), Box::new(project_id)];
+    // Adds a value to bind and returns its placeholder
+    let mut bind = |value: Box<dyn ToSql>| {
+        values.push(value);
+        format!("?{}", values.len())
+    };
+
+    let mut conditions = vec!["flags.project_id = ?2".to_string()];
+    if let Some(owner) = &filter.owner {
+        conditions.push(format!("flags.owner = {}", bind(Box::new(owner.clone()))));
+    }
+    if let Some(tag) = &filter.tag {
+        conditions.push(format!(
+            "EXISTS (SELECT 1 FROM json_each(flags.tags) WHERE json_each.value = {})",
+            bind(Box::new(tag.clone()))
+        ));
+    }
+    if let Some(prefix) = &filter.prefix {
+        // LIKE would ignore case and treat % and _ as wildcards
+        let prefix = bind(Box::new(prefix.clone()));
+        conditions.push(format!(
+            "substr(flags.name, 1, length({})) = {}",
+            prefix, prefix
+        ));
+    }
+    if let Some(part) = &filter.contains {
+        conditions.push(format!(
+            "instr(flags.name, {}) > 0",
+            bind(Box::new(part.clone()))
+        ));
+    }
+    if let Some(value) = filter.value_json() {
+        conditions.push(format!(
+            "json(state.value) = json({})",
+            bind(Box::new(value.to_string()))
+        ));
+    }
+
+    let direction = match page.order {
+        SortOrder::Asc => ("ASC", ">"),
+        SortOrder::Desc => ("DESC", "<"),
+    };
+    let column = page.sort.column();
+    if let Some(after) = after {
+        let id = bind(Box::new(after.id));
+        match page.sort {
+            FlagSort::Id => conditions.push(format!("flags.id {} {}", direction.1, id)),
+            _ => {
+                let key = bind(Box::new(after.key));
+                conditions.push(format!(
+                    "({}, flags.id) {} ({}, {})",
+                    column, direction.1, key, id
+                ));
+            }
+        }
+    }
+
+    let mut sql = format!(
+        "{} WHERE {} ORDER BY {} {}, flags.id {}",
+        FLAG_SELECT,
+        conditions.join(" AND "),
+        column,
+        direction.0,
+        direction.0
+    );
+    if let Some(limit) = limit {
+        // One more to tell whether there is a next page
+        sql.push_str(&format!(" LIMIT {}", bind(Box::new(limit as i64 + 1))));
+    }
+
+    let mut stmt = conn.prepare(&sql)?;
+    let rows = stmt.query_map(params_from_iter(values.iter()), flag_from_row)?;
+
+    let mut flags = vec![];
+    for item in rows {
+        flags.push(item?)
+    }
+
+    Ok(page.page(flags, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::{get_all_flags, initialize_db, insert_flag, DBLocal, Flag, FlagMetadata};
+    use crate::value::FlagValue;
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn names(list: &FlagList) -> Vec<&str> {
+        list.flags.iter().map(|flag| flag.name.as_str()).collect()
+    }
+
+    /// Lists through SQL and in memory, which have to agree.
+    fn list(conn: &Connection, filter: &FlagFilter, page: &FlagPage) -> FlagList {
+        let listed = list_flags(conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, filter, page).unwrap();
+        let flags = get_all_flags(conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
+        assert_eq!(page_flags(flags, filter, page).unwrap(), listed);
+
+        listed
+    }
+
+    #[test]
+    fn test_list_flags() {
+        let conn = in_memory_db();
+        let flags = [
+            ("search_v2", FlagValue::Bool(true), "web"),
+            ("checkout", FlagValue::String("blue".to_string()), "web"),
+            ("search", FlagValue::Integer(30), "mobile"),
+            ("checkout_%", FlagValue::Bool(false), "web"),
+        ];
+        for (name, value, tag) in flags {
+            let flag = Flag {
+                name: name.to_string(),
+                flag_type: None,
+                value,
+                metadata: FlagMetadata {
+                    tags: vec![tag.to_string()],
+                    ..FlagMetadata::default()
+                },
+            };
+            insert_flag(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, flag, "ada").unwrap();
+        }
+
+        let all = list(&conn, &FlagFilter::default(), &FlagPage::default());
+        assert_eq!(
+            names(&all),
+            vec!["search_v2", "checkout", "search", "checkout_%"]
+        );
+        assert_eq!(all.next, None);
+
+        let filters = [
+            ("prefix", "check", vec!["checkout", "checkout_%"]),
+            ("prefix", "checkout_", vec!["checkout_%"]),
+            ("contains", "arch", vec!["search_v2", "search"]),
+            ("value", "blue", vec!["checkout"]),
+            ("value", "\"blue\"", vec!["checkout"]),
+            ("value", "30", vec!["search"]),
+            ("value", "true", vec!["search_v2"]),
+            ("tag", "mobile", vec!["search"]),
+        ];
+        for (field, value, expected) in filters {
+            let filter: FlagFilter =
+                serde_json::from_value(serde_json::json!({ field: value })).unwrap();
+            let listed = list(&conn, &filter, &FlagPage::default());
+            assert_eq!(names(&listed), expected, "{}={}", field, value);
+        }
+
+        // Pages by name, in both directions
+        for (order, expected) in [
+            (
+                SortOrder::Asc,
+                ["checkout", "checkout_%", "search", "search_v2"],
+            ),
+            (
+                SortOrder::Desc,
+                ["search_v2", "search", "checkout_%", "checkout"],
+            ),
+        ] {
+            let mut page = FlagPage {
+                sort: FlagSort::Name,
+                order,
+                limit: Some(3),
+                cursor: None,
+            };
+            let first = list(&conn, &FlagFilter::default(), &page);
+            assert_eq!(names(&first), expected[..3]);
+
+            page.cursor = first.next;
+            let second = list(&conn, &FlagFilter::default(), &page);
+            assert_eq!(names(&second), expected[3..]);
+            assert_eq!(second.next, None);
+        }
+
+        // Cursors only continue the listing they came from
+        let page = FlagPage {
+            limit: Some(1),
+            ..FlagPage::default()
+        };
+        let first = list(&conn, &FlagFilter::default(), &page);
+        let page = FlagPage {
+            sort: FlagSort::Name,
+            cursor: first.next,
+            ..FlagPage::default()
+        };
+        let result = list_flags(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            &FlagFilter::default(),
+            &page,
+        );
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+        let page = FlagPage {
+            limit: Some(0),
+            ..FlagPage::default()
+        };
+        let result = page_flags(vec![], &FlagFilter::default(), &page);
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+}
@@ -0,0 +1,524 @@
+use rusqlite::{params, Connection};
+
+use crate::error::FeatureFlagError;
+
+/// A single, ordered step in the evolution of the database schema.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration the application knows about, in the order they must be applied.
+///
+/// Never edit a migration that has shipped; append a new one instead.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create flags table",
+        // `IF NOT EXISTS` lets databases created before migrations existed adopt version 1
+        sql: "CREATE TABLE IF NOT EXISTS flags (
+            id    INTEGER UNIQUE,
+            name  TEXT NOT NULL UNIQUE,
+            value INTEGER NOT NULL CHECK(value == 0 OR value == 1),
+            PRIMARY KEY(id)
+        );",
+    },
+    Migration {
+        version: 2,
+        description: "typed flag values stored as json",
+        // SQLite cannot alter a CHECK constraint, so the table is rebuilt
+        sql: "CREATE TABLE flags_typed (
+            id    INTEGER UNIQUE,
+            name  TEXT NOT NULL UNIQUE,
+            type  TEXT NOT NULL DEFAULT 'bool'
+                  CHECK(type IN ('bool', 'string', 'integer', 'float', 'json')),
+            value TEXT NOT NULL CHECK(json_valid(value)),
+            PRIMARY KEY(id)
+        );
+
+        INSERT INTO flags_typed (id, name, type, value)
+            SELECT id, name, 'bool', CASE value WHEN 1 THEN 'true' ELSE 'false' END
+            FROM flags;
+
+        DROP TABLE flags;
+        ALTER TABLE flags_typed RENAME TO flags;",
+    },
+    Migration {
+        version: 3,
+        description: "named variations and on/off state",
+        sql: "CREATE TABLE variations (
+            id      INTEGER PRIMARY KEY,
+            flag_id INTEGER NOT NULL REFERENCES flags(id),
+            name    TEXT NOT NULL,
+            value   TEXT NOT NULL CHECK(json_valid(value)),
+            UNIQUE(flag_id, name)
+        );
+
+        ALTER TABLE flags ADD COLUMN enabled INTEGER NOT NULL DEFAULT 1
+            CHECK(enabled == 0 OR enabled == 1);
+        ALTER TABLE flags ADD COLUMN default_variation TEXT;
+        ALTER TABLE flags ADD COLUMN off_variation TEXT;",
+    },
+    Migration {
+        version: 4,
+        description: "per environment flag state",
+        // Existing state moves to the production environment and `flags` keeps the definition
+        sql: "CREATE TABLE environments (
+            id   INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        INSERT INTO environments (name) VALUES ('production');
+
+        CREATE TABLE flag_states (
+            flag_id           INTEGER NOT NULL REFERENCES flags(id),
+            environment_id    INTEGER NOT NULL REFERENCES environments(id),
+            enabled           INTEGER NOT NULL DEFAULT 1 CHECK(enabled == 0 OR enabled == 1),
+            value             TEXT NOT NULL CHECK(json_valid(value)),
+            default_variation TEXT,
+            off_variation     TEXT,
+            PRIMARY KEY(flag_id, environment_id)
+        );
+
+        INSERT INTO flag_states
+            (flag_id, environment_id, enabled, value, default_variation, off_variation)
+            SELECT flags.id, environments.id, enabled, value, default_variation, off_variation
+            FROM flags, environments;
+
+        CREATE TABLE flag_definitions (
+            id    INTEGER UNIQUE,
+            name  TEXT NOT NULL UNIQUE,
+            type  TEXT NOT NULL DEFAULT 'bool'
+                  CHECK(type IN ('bool', 'string', 'integer', 'float', 'json')),
+            PRIMARY KEY(id)
+        );
+
+        INSERT INTO flag_definitions (id, name, type) SELECT id, name, type FROM flags;
+
+        DROP TABLE flags;
+        ALTER TABLE flag_definitions RENAME TO flags;",
+    },
+    Migration {
+        version: 5,
+        description: "projects",
+        // Existing flags move to the default project, names are unique per project
+        sql: "CREATE TABLE projects (
+            id   INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        INSERT INTO projects (name) VALUES ('default');
+
+        CREATE TABLE flag_definitions (
+            id         INTEGER UNIQUE,
+            project_id INTEGER NOT NULL REFERENCES projects(id),
+            name       TEXT NOT NULL,
+            type       TEXT NOT NULL DEFAULT 'bool'
+                       CHECK(type IN ('bool', 'string', 'integer', 'float', 'json')),
+            PRIMARY KEY(id),
+            UNIQUE(project_id, name)
+        );
+
+        INSERT INTO flag_definitions (id, project_id, name, type)
+            SELECT flags.id, projects.id, flags.name, flags.type FROM flags, projects;
+
+        DROP TABLE flags;
+        ALTER TABLE flag_definitions RENAME TO flags;",
+    },
+    Migration {
+        version: 6,
+        description: "percentage rollouts",
+        sql: "ALTER TABLE flags ADD COLUMN salt TEXT NOT NULL DEFAULT '';
+
+        ALTER TABLE flag_states ADD COLUMN rollout_percentage REAL
+            CHECK(rollout_percentage IS NULL OR rollout_percentage BETWEEN 0 AND 100);",
+    },
+    Migration {
+        version: 7,
+        description: "targeting rules",
+        sql: "CREATE TABLE rules (
+            id             INTEGER PRIMARY KEY,
+            flag_id        INTEGER NOT NULL REFERENCES flags(id),
+            environment_id INTEGER NOT NULL REFERENCES environments(id),
+            position       INTEGER NOT NULL,
+            clauses        TEXT NOT NULL CHECK(json_valid(clauses)),
+            variation      TEXT NOT NULL,
+            UNIQUE(flag_id, environment_id, position)
+        );",
+    },
+    Migration {
+        version: 8,
+        description: "segments",
+        sql: "CREATE TABLE segments (
+            id         INTEGER PRIMARY KEY,
+            project_id INTEGER NOT NULL REFERENCES projects(id),
+            name       TEXT NOT NULL,
+            included   TEXT NOT NULL DEFAULT '[]' CHECK(json_valid(included)),
+            excluded   TEXT NOT NULL DEFAULT '[]' CHECK(json_valid(excluded)),
+            rules      TEXT NOT NULL DEFAULT '[]' CHECK(json_valid(rules)),
+            UNIQUE(project_id, name)
+        );",
+    },
+    Migration {
+        version: 9,
+        description: "flag audit log",
+        // Names are copied rather than referenced so that events outlive what they describe
+        sql: "CREATE TABLE flag_events (
+            id          INTEGER PRIMARY KEY,
+            flag_id     INTEGER NOT NULL,
+            project     TEXT NOT NULL,
+            flag        TEXT NOT NULL,
+            environment TEXT,
+            actor       TEXT NOT NULL,
+            timestamp   TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ', 'now')),
+            action      TEXT NOT NULL CHECK(action IN ('created', 'updated', 'deleted')),
+            old_value   TEXT CHECK(old_value IS NULL OR json_valid(old_value)),
+            new_value   TEXT CHECK(new_value IS NULL OR json_valid(new_value))
+        );
+
+        CREATE INDEX flag_events_flag ON flag_events (flag_id);
+
+        CREATE TRIGGER flag_events_no_update BEFORE UPDATE ON flag_events
+        BEGIN
+            SELECT RAISE(ABORT, 'flag_events is append-only');
+        END;
+
+        CREATE TRIGGER flag_events_no_delete BEFORE DELETE ON flag_events
+        BEGIN
+            SELECT RAISE(ABORT, 'flag_events is append-only');
+        END;",
+    },
+    Migration {
+        version: 10,
+        description: "flag versions",
+        sql: "ALTER TABLE flags ADD COLUMN version INTEGER NOT NULL DEFAULT 1;",
+    },
+    Migration {
+        version: 11,
+        description: "flag metadata",
+        // Flags that already exist are dated by the audit log, ids can be reused so the latest
+        // creation is theirs
+        sql: "ALTER TABLE flags ADD COLUMN description TEXT;
+        ALTER TABLE flags ADD COLUMN owner TEXT;
+        ALTER TABLE flags ADD COLUMN tags TEXT NOT NULL DEFAULT '[]' CHECK(json_valid(tags));
+        ALTER TABLE flags ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+        ALTER TABLE flags ADD COLUMN updated_at TEXT NOT NULL DEFAULT '';
+        ALTER TABLE flags ADD COLUMN created_by TEXT NOT NULL DEFAULT 'anonymous';
+
+        UPDATE flags SET
+            created_at = COALESCE(
+                (SELECT MAX(timestamp) FROM flag_events
+                    WHERE flag_id = flags.id AND action = 'created'),
+                strftime('%Y-%m-%dT%H:%M:%fZ', 'now')
+            ),
+            created_by = COALESCE(
+                (SELECT actor FROM flag_events
+                    WHERE flag_id = flags.id AND action = 'created' ORDER BY id DESC LIMIT 1),
+                'anonymous'
+            );
+        UPDATE flags SET updated_at = MAX(
+            created_at,
+            COALESCE((SELECT MAX(timestamp) FROM flag_events WHERE flag_id = flags.id), '')
+        );",
+    },
+    Migration {
+        version: 12,
+        description: "flag change sequence",
+        // Only the latest change of a flag is kept, a deleted flag leaves a row behind for good.
+        // AUTOINCREMENT never hands out a number twice, not even after the newest row is gone.
+        // Triggers rather than the code writing flags, so that no way of changing one is missed
+        sql: "CREATE TABLE flag_changes (
+            seq        INTEGER PRIMARY KEY AUTOINCREMENT,
+            project_id INTEGER NOT NULL,
+            flag_id    INTEGER NOT NULL,
+            name       TEXT NOT NULL,
+            deleted    INTEGER NOT NULL DEFAULT 0 CHECK(deleted IN (0, 1))
+        );
+
+        CREATE INDEX flag_changes_project ON flag_changes (project_id, seq);
+
+        INSERT INTO flag_changes (project_id, flag_id, name)
+            SELECT project_id, id, name FROM flags ORDER BY id;
+
+        CREATE TRIGGER flag_changes_insert AFTER INSERT ON flags
+        BEGIN
+            DELETE FROM flag_changes WHERE flag_id = NEW.id AND deleted = 0;
+            INSERT INTO flag_changes (project_id, flag_id, name)
+                VALUES (NEW.project_id, NEW.id, NEW.name);
+        END;
+
+        CREATE TRIGGER flag_changes_update AFTER UPDATE OF version ON flags
+        BEGIN
+            DELETE FROM flag_changes WHERE flag_id = NEW.id AND deleted = 0;
+            INSERT INTO flag_changes (project_id, flag_id, name)
+                VALUES (NEW.project_id, NEW.id, NEW.name);
+        END;
+
+        CREATE TRIGGER flag_changes_delete AFTER DELETE ON flags
+        BEGIN
+            DELETE FROM flag_changes WHERE flag_id = OLD.id AND deleted = 0;
+            INSERT INTO flag_changes (project_id, flag_id, name, deleted)
+                VALUES (OLD.project_id, OLD.id, OLD.name, 1);
+        END;",
+    },
+    Migration {
+        version: 13,
+        description: "flag change epoch",
+        // Milliseconds since 1970 when the database was created, so that cursors of the changes
+        // before a reset are told apart from the ones after
+        sql: "CREATE TABLE change_epoch (
+            epoch INTEGER NOT NULL
+        );
+
+        INSERT INTO change_epoch (epoch)
+            VALUES (CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER));",
+    },
+];
+
+/// Version of the newest migration.
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn ensure_version_table(conn: &Connection) -> Result<(), FeatureFlagError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version     INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at  TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+
+    Ok(())
+}
+
+/// Version the database is currently at. Zero means no migration has been applied.
+pub fn current_version(conn: &Connection) -> Result<i64, FeatureFlagError> {
+    ensure_version_table(conn)?;
+
+    let version: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })?;
+
+    Ok(version.unwrap_or(0))
+}
+
+/// Migrations that have not been applied to the database yet.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, FeatureFlagError> {
+    let current = current_version(conn)?;
+
+    if current > latest_version() {
+        return Err(FeatureFlagError::UnknownSchemaVersion(current));
+    }
+
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+/// Runs `f` with foreign key enforcement turned off, restoring the previous setting afterwards.
+///
+/// Rebuilding a table (create, copy, drop, rename) would otherwise trip the constraints of
+/// the tables referencing it. The pragma is a no-op inside a transaction, so `f` opens its own.
+fn without_foreign_keys<T>(
+    conn: &Connection,
+    f: impl FnOnce() -> Result<T, FeatureFlagError>,
+) -> Result<T, FeatureFlagError> {
+    let enabled: bool = conn.query_row("PRAGMA foreign_keys", [], |row| row.get(0))?;
+
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let result = f();
+    if enabled {
+        conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    }
+
+    result
+}
+
+/// Applies every pending migration inside a single transaction and returns how many ran.
+///
+/// If any step fails the whole transaction is rolled back and the schema is left untouched.
+pub fn migrate(conn: &Connection) -> Result<usize, FeatureFlagError> {
+    let pending = pending(conn)?;
+
+    without_foreign_keys(conn, || {
+        let tx = conn.unchecked_transaction()?;
+        for migration in &pending {
+            tx.execute_batch(migration.sql)?;
+            tx.execute(
+                "INSERT INTO schema_version (version, description) VALUES (?1, ?2)",
+                params![migration.version, migration.description],
+            )?;
+        }
+
+        // The migrations must leave every reference intact
+        let violations = tx.prepare("PRAGMA foreign_key_check")?.exists([])?;
+        if violations {
+            return Err(FeatureFlagError::Validation(
+                "migration left dangling foreign keys".to_string(),
+            ));
+        }
+
+        tx.commit()?;
+
+        Ok(pending.len())
+    })
+}
+
+/// Drops every table in the database and rebuilds the schema from scratch.
+///
+/// This destroys all data and should only be reachable through an explicit opt-in.
+pub fn reset(conn: &Connection) -> Result<usize, FeatureFlagError> {
+    let tables = {
+        let mut stmt = conn.prepare(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'",
+        )?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<Vec<String>, _>>()?
+    };
+    // Missing from databases that never had the migration
+    let epoch: Option<u64> = conn
+        .query_row("SELECT epoch FROM change_epoch", [], |row| row.get(0))
+        .ok();
+
+    without_foreign_keys(conn, || {
+        let tx = conn.unchecked_transaction()?;
+        for table in tables {
+            tx.execute_batch(&format!("DROP TABLE IF EXISTS \"{}\";", table))?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    })?;
+
+    let applied = migrate(conn)?;
+    // Cursors from before must not pass for new ones, even within the same millisecond
+    if let Some(epoch) = epoch {
+        conn.execute(
+            "UPDATE change_epoch SET epoch = MAX(epoch, ?)",
+            params![epoch + 1],
+        )?;
+    }
+
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_flags(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM flags", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_fresh_db() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        let applied = migrate(&conn).unwrap();
+        assert_eq!(applied, MIGRATIONS.len());
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        // Running again is a no-op
+        assert_eq!(migrate(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_migrate_keeps_data() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO flags (project_id, name) VALUES (1, 'keep')",
+            [],
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+        assert_eq!(count_flags(&conn), 1);
+    }
+
+    #[test]
+    fn test_migrate_adopts_legacy_db() {
+        let conn = Connection::open_in_memory().unwrap();
+
+        // Schema created by the old `initialize_db`, without a version table
+        conn.execute_batch(
+            "CREATE TABLE flags (
+                id    INTEGER UNIQUE,
+                name  TEXT NOT NULL UNIQUE,
+                value INTEGER NOT NULL CHECK(value == 0 OR value == 1),
+                PRIMARY KEY(id)
+            );
+            INSERT INTO flags (name, value) VALUES ('legacy', 0);",
+        )
+        .unwrap();
+
+        migrate(&conn).unwrap();
+
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(count_flags(&conn), 1);
+
+        let (flag_type, value): (String, String) = conn
+            .query_row(
+                "SELECT type, value FROM flags
+                    JOIN flag_states ON flag_states.flag_id = flags.id
+                    WHERE name = 'legacy'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(flag_type, "bool");
+        assert_eq!(value, "false");
+
+        // Flags from before metadata have no creation event to date them by
+        let (created_at, created_by): (String, String) = conn
+            .query_row(
+                "SELECT created_at, created_by FROM flags WHERE name = 'legacy'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert!(!created_at.is_empty());
+        assert_eq!(created_by, "anonymous");
+    }
+
+    #[test]
+    fn test_reset_drops_data() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO flags (project_id, name) VALUES (1, 'gone')",
+            [],
+        )
+        .unwrap();
+
+        reset(&conn).unwrap();
+
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert_eq!(count_flags(&conn), 0);
+    }
+
+    #[test]
+    fn test_unknown_schema_version() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        conn.execute(
+            "INSERT INTO schema_version (version, description) VALUES (?1, 'future')",
+            params![latest_version() + 1],
+        )
+        .unwrap();
+
+        let result = migrate(&conn);
+        assert_eq!(
+            format!("{:?}", result),
+            format!("Err(UnknownSchemaVersion({}))", latest_version() + 1)
+        );
+    }
+}
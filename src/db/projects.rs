@@ -0,0 +1,234 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{validate_name, Savepoint};
+use crate::error::FeatureFlagError;
+
+/// Project used when none is given. Existing flags were moved here by the migration.
+pub const DEFAULT_PROJECT: &str = "default";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Project {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewProject {
+    pub name: String,
+}
+
+/// Looks up a project, failing with `NotFound` when it does not exist.
+pub fn project_id(conn: &Connection, name: &str) -> Result<i64, FeatureFlagError> {
+    let id = conn
+        .query_row(
+            "SELECT id FROM projects WHERE name = ?",
+            params![name],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    id.ok_or_else(|| FeatureFlagError::NotFound(format!("Project {}", name)))
+}
+
+/// Fails with `NotFound` unless flag `id` belongs to `project`.
+pub fn ensure_flag_in_project(
+    conn: &Connection,
+    project: &str,
+    id: u64,
+) -> Result<(), FeatureFlagError> {
+    match flag_in_project(conn, project, id)? {
+        true => Ok(()),
+        false => Err(FeatureFlagError::NotFound(format!("Flag {}", id))),
+    }
+}
+
+/// Whether flag `id` belongs to `project`, failing with `NotFound` when the project does not
+/// exist.
+pub fn flag_in_project(
+    conn: &Connection,
+    project: &str,
+    id: u64,
+) -> Result<bool, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+
+    let exists = conn
+        .prepare("SELECT 1 FROM flags WHERE id = ?1 AND project_id = ?2")?
+        .exists(params![id, project_id])?;
+
+    Ok(exists)
+}
+
+pub fn get_projects(conn: &Connection) -> Result<Vec<Project>, FeatureFlagError> {
+    let mut stmt = conn.prepare("SELECT id, name FROM projects ORDER BY id")?;
+
+    let rows = stmt.query_map([], |row| {
+        Ok(Project {
+            id: row.get(0)?,
+            name: row.get(1)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+pub fn add_project(conn: &Connection, project: NewProject) -> Result<usize, FeatureFlagError> {
+    validate_name("project", &project.name)?;
+
+    let result = conn
+        .execute(
+            "INSERT INTO projects (name) VALUES (?)",
+            params![project.name],
+        )
+        .map_err(|err| FeatureFlagError::from(err).naming(format!("Project {}", project.name)))?;
+
+    Ok(result)
+}
+
+/// Deletes an empty project. The default project and projects that still have flags are kept.
+pub fn delete_project(conn: &Connection, name: String) -> Result<usize, FeatureFlagError> {
+    if name == DEFAULT_PROJECT {
+        return Err(FeatureFlagError::Validation(format!(
+            "the {} project cannot be deleted",
+            DEFAULT_PROJECT
+        )));
+    }
+
+    let flags: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM flags
+            JOIN projects ON projects.id = flags.project_id
+            WHERE projects.name = ?",
+        params![name],
+        |row| row.get(0),
+    )?;
+    if flags > 0 {
+        return Err(FeatureFlagError::Conflict(format!(
+            "project {} still has {} flag(s)",
+            name, flags
+        )));
+    }
+
+    let tx = Savepoint::new(conn)?;
+    tx.execute(
+        "DELETE FROM segments
+            WHERE project_id IN (SELECT id FROM projects WHERE name = ?)",
+        params![name],
+    )?;
+    let result = tx.execute("DELETE FROM projects WHERE name = ?", params![name])?;
+    tx.commit()?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::{add_flag, get_all_flags, get_flag_by_name, initialize_db, DBLocal};
+    use crate::db::{FlagType, FlagValue};
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn new_project(name: &str) -> NewProject {
+        NewProject {
+            name: name.to_string(),
+        }
+    }
+
+    fn add_bool_flag(conn: &Connection, project: &str, name: &str, value: bool) {
+        add_flag(
+            conn,
+            project,
+            DEFAULT_ENVIRONMENT,
+            name.to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(value),
+            "test",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_names_are_unique_per_project() {
+        let conn = in_memory_db();
+
+        add_project(&conn, new_project("payments")).unwrap();
+
+        add_bool_flag(&conn, DEFAULT_PROJECT, "new_checkout", true);
+        add_bool_flag(&conn, "payments", "new_checkout", false);
+
+        let result = get_flag_by_name(
+            &conn,
+            "payments",
+            DEFAULT_ENVIRONMENT,
+            "new_checkout".to_string(),
+        )
+        .unwrap();
+        assert_eq!(result.value, FlagValue::Bool(false));
+        assert!(ensure_flag_in_project(&conn, "payments", result.id as u64).is_ok());
+        assert!(ensure_flag_in_project(&conn, DEFAULT_PROJECT, result.id as u64).is_err());
+
+        let result = get_all_flags(&conn, DEFAULT_PROJECT, DEFAULT_ENVIRONMENT).unwrap();
+        assert_eq!(1, result.len());
+        assert_eq!(result[0].value, FlagValue::Bool(true));
+
+        // Still unique within a project
+        let result = add_flag(
+            &conn,
+            "payments",
+            DEFAULT_ENVIRONMENT,
+            "new_checkout".to_string(),
+            FlagType::Bool,
+            FlagValue::Bool(true),
+            "test",
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_delete_project() {
+        let conn = in_memory_db();
+
+        add_project(&conn, new_project("payments")).unwrap();
+        add_project(&conn, new_project("search")).unwrap();
+        add_bool_flag(&conn, "payments", "new_checkout", true);
+
+        let result = delete_project(&conn, "payments".to_string());
+        assert!(matches!(result, Err(FeatureFlagError::Conflict(_))));
+
+        let result = delete_project(&conn, DEFAULT_PROJECT.to_string());
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        assert_eq!(1, delete_project(&conn, "search".to_string()).unwrap());
+        assert_eq!(2, get_projects(&conn).unwrap().len());
+    }
+
+    #[test]
+    fn test_unknown_project() {
+        let conn = in_memory_db();
+
+        let result = get_all_flags(&conn, "payments", DEFAULT_ENVIRONMENT);
+        assert_eq!(
+            format!("{:?}", result),
+            "Err(NotFound(\"Project payments\"))"
+        );
+
+        let result = add_project(&conn, new_project("pay ments"));
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+}
@@ -0,0 +1,232 @@
+use rusqlite::types::Type;
+use rusqlite::{params, Connection};
+
+use super::environments::environment_id;
+use super::events::{record_event, rules_value, Change, FlagAction};
+use super::segments::segment_exists_for_flag;
+use super::variations::variation_exists;
+use super::{bump_version, get_flag_type, Savepoint};
+use crate::error::FeatureFlagError;
+use crate::eval::{Clause, Rule};
+
+/// Targeting rules of a flag in `env`, in the order they are evaluated.
+pub fn get_rules(
+    conn: &Connection,
+    env: &str,
+    flag_id: u64,
+) -> Result<Vec<Rule>, FeatureFlagError> {
+    get_flag_type(conn, flag_id)?;
+    let env_id = environment_id(conn, env)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT clauses, variation FROM rules
+            WHERE flag_id = ?1 AND environment_id = ?2 ORDER BY position",
+    )?;
+
+    let rows = stmt.query_map(params![flag_id, env_id], |row| {
+        let clauses: Vec<Clause> = serde_json::from_value(row.get(0)?).map_err(|_| {
+            rusqlite::Error::InvalidColumnType(0, "clauses".to_string(), Type::Text)
+        })?;
+
+        Ok(Rule {
+            clauses,
+            variation: row.get(1)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+/// Replaces the targeting rules of a flag in `env`. Every rule has to serve an existing variation
+/// and can only refer to segments of the flag's project.
+pub fn set_rules(
+    conn: &Connection,
+    env: &str,
+    flag_id: u64,
+    rules: Vec<Rule>,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    get_flag_type(conn, flag_id)?;
+    let env_id = environment_id(conn, env)?;
+
+    for rule in rules.iter() {
+        for clause in rule.clauses.iter() {
+            clause.validate()?;
+        }
+
+        for segment in rule.segments() {
+            if !segment_exists_for_flag(conn, flag_id, segment)? {
+                return Err(FeatureFlagError::Validation(format!(
+                    "rule refers to unknown segment {}",
+                    segment
+                )));
+            }
+        }
+
+        if !variation_exists(conn, flag_id, &rule.variation)? {
+            return Err(FeatureFlagError::Validation(format!(
+                "rule serves unknown variation {}",
+                rule.variation
+            )));
+        }
+    }
+
+    let tx = Savepoint::new(conn)?;
+    let old_value = rules_value(&get_rules(&tx, env, flag_id)?);
+
+    tx.execute(
+        "DELETE FROM rules WHERE flag_id = ?1 AND environment_id = ?2",
+        params![flag_id, env_id],
+    )?;
+
+    for (position, rule) in rules.iter().enumerate() {
+        let clauses = serde_json::to_value(&rule.clauses)
+            .map_err(|err| FeatureFlagError::Validation(err.to_string()))?;
+
+        tx.execute(
+            "INSERT INTO rules (flag_id, environment_id, position, clauses, variation)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![flag_id, env_id, position as i64, clauses, rule.variation],
+        )?;
+    }
+    bump_version(&tx, flag_id)?;
+
+    let change = Change {
+        flag_id,
+        environment: Some(env),
+        action: FlagAction::Updated,
+        old_value: Some(old_value),
+        new_value: Some(rules_value(&rules)),
+    };
+    record_event(&tx, actor, change)?;
+
+    tx.commit()?;
+
+    Ok(rules.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::environments::{add_environment, NewEnvironment, DEFAULT_ENVIRONMENT};
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::variations::{add_variation, delete_variation, Variation};
+    use crate::db::{add_flag, evaluate_flag, initialize_db, DBLocal, FlagType, FlagValue};
+    use crate::eval::EvaluationContext;
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+            FlagType::String,
+            FlagValue::String("v1".to_string()),
+            "test",
+        )
+        .unwrap();
+        add_variation(
+            &conn,
+            1,
+            Variation {
+                name: "beta".to_string(),
+                value: FlagValue::String("v2".to_string()),
+            },
+            "test",
+        )
+        .unwrap();
+
+        conn
+    }
+
+    fn corp_rule() -> Rule {
+        serde_json::from_value(json!({
+            "clauses": [{"attribute": "email", "op": "ends_with", "values": ["@corp.com"]}],
+            "variation": "beta"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rules_round_trip() {
+        let conn = in_memory_db();
+
+        assert_eq!(get_rules(&conn, DEFAULT_ENVIRONMENT, 1).unwrap(), vec![]);
+
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![corp_rule()], "test").unwrap();
+        assert_eq!(
+            get_rules(&conn, DEFAULT_ENVIRONMENT, 1).unwrap(),
+            vec![corp_rule()]
+        );
+
+        // New environments start with a copy of the rules
+        add_environment(
+            &conn,
+            NewEnvironment {
+                name: "staging".to_string(),
+                copy_from: None,
+            },
+        )
+        .unwrap();
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![], "test").unwrap();
+        assert_eq!(get_rules(&conn, "staging", 1).unwrap(), vec![corp_rule()]);
+
+        let context = EvaluationContext::new("ada").with_attribute("email", json!("ada@corp.com"));
+        let evaluate = |env: &str| {
+            evaluate_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                env,
+                "checkout".to_string(),
+                &context,
+            )
+            .unwrap()
+            .value
+        };
+        assert_eq!(
+            evaluate("staging"),
+            Some(FlagValue::String("v2".to_string()))
+        );
+        assert_eq!(
+            evaluate(DEFAULT_ENVIRONMENT),
+            Some(FlagValue::String("v1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let conn = in_memory_db();
+
+        let mut rule = corp_rule();
+        rule.variation = "missing".to_string();
+        let result = set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![rule], "test");
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        let rule: Rule = serde_json::from_value(json!({
+            "clauses": [{"attribute": "version", "op": "semver>=", "values": ["latest"]}],
+            "variation": "beta"
+        }))
+        .unwrap();
+        let result = set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![rule], "test");
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        // Variations used by a rule cannot be removed
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![corp_rule()], "test").unwrap();
+        let result = delete_variation(&conn, 1, "beta".to_string(), "test");
+        assert!(matches!(result, Err(FeatureFlagError::Conflict(_))));
+    }
+}
@@ -0,0 +1,391 @@
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::events::{record_event, segment_value, Change, FlagAction};
+use super::projects::project_id;
+use super::{bump_version, validate_name, Savepoint};
+use crate::error::FeatureFlagError;
+use crate::eval::{Segment, SegmentRule};
+
+/// Partial update of a segment. Lists that are given replace the stored ones.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SegmentUpdate {
+    #[serde(default)]
+    pub included: Option<Vec<String>>,
+    #[serde(default)]
+    pub excluded: Option<Vec<String>>,
+    #[serde(default)]
+    pub rules: Option<Vec<SegmentRule>>,
+}
+
+fn json_column<T: DeserializeOwned>(row: &Row, index: usize, name: &str) -> rusqlite::Result<T> {
+    serde_json::from_value(row.get(index)?)
+        .map_err(|_| rusqlite::Error::InvalidColumnType(index, name.to_string(), Type::Text))
+}
+
+fn segment_from_row(row: &Row) -> rusqlite::Result<Segment> {
+    Ok(Segment {
+        name: row.get(0)?,
+        included: json_column(row, 1, "included")?,
+        excluded: json_column(row, 2, "excluded")?,
+        rules: json_column(row, 3, "rules")?,
+    })
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Value, FeatureFlagError> {
+    serde_json::to_value(value).map_err(|err| FeatureFlagError::Validation(err.to_string()))
+}
+
+pub fn get_segments(conn: &Connection, project: &str) -> Result<Vec<Segment>, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT name, included, excluded, rules FROM segments
+            WHERE project_id = ? ORDER BY id",
+    )?;
+
+    let rows = stmt.query_map(params![project_id], segment_from_row)?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+pub fn get_segment(
+    conn: &Connection,
+    project: &str,
+    name: &str,
+) -> Result<Segment, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+
+    let result = conn
+        .query_row(
+            "SELECT name, included, excluded, rules FROM segments
+            WHERE project_id = ?1 AND name = ?2",
+            params![project_id, name],
+            segment_from_row,
+        )
+        .optional()?;
+
+    result.ok_or_else(|| FeatureFlagError::NotFound(format!("Segment {}", name)))
+}
+
+/// Whether the project of a flag has a segment called `name`.
+pub(crate) fn segment_exists_for_flag(
+    conn: &Connection,
+    flag_id: u64,
+    name: &str,
+) -> Result<bool, FeatureFlagError> {
+    let mut stmt = conn.prepare(
+        "SELECT 1 FROM segments
+            JOIN flags ON flags.project_id = segments.project_id
+            WHERE flags.id = ?1 AND segments.name = ?2",
+    )?;
+
+    Ok(stmt.exists(params![flag_id, name])?)
+}
+
+pub fn add_segment(
+    conn: &Connection,
+    project: &str,
+    segment: Segment,
+) -> Result<usize, FeatureFlagError> {
+    validate_name("segment", &segment.name)?;
+    segment.validate()?;
+    let project_id = project_id(conn, project)?;
+
+    let result = conn
+        .execute(
+            "INSERT INTO segments (project_id, name, included, excluded, rules)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                project_id,
+                segment.name,
+                to_json(&segment.included)?,
+                to_json(&segment.excluded)?,
+                to_json(&segment.rules)?
+            ],
+        )
+        .map_err(|err| FeatureFlagError::from(err).naming(format!("Segment {}", segment.name)))?;
+
+    Ok(result)
+}
+
+/// Ids and names of the flags of a project whose rules refer to segment `name`, in any
+/// environment.
+fn flags_using_segment(
+    conn: &Connection,
+    project_id: i64,
+    name: &str,
+) -> Result<Vec<(u64, String)>, FeatureFlagError> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT flags.id, flags.name FROM rules
+            JOIN flags ON flags.id = rules.flag_id,
+                json_each(rules.clauses) AS clause,
+                json_each(clause.value, '$.values') AS segment
+            WHERE flags.project_id = ?1
+                AND json_extract(clause.value, '$.op') = 'segment'
+                AND segment.value = ?2
+            ORDER BY flags.id",
+    )?;
+    let rows = stmt.query_map(params![project_id, name], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })?;
+
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// Changes a segment, which changes every flag whose rules refer to it. Returns the ids of these
+/// flags.
+pub fn update_segment(
+    conn: &Connection,
+    project: &str,
+    name: &str,
+    update: SegmentUpdate,
+    actor: &str,
+) -> Result<Vec<u64>, FeatureFlagError> {
+    let old = get_segment(conn, project, name)?;
+    let mut segment = old.clone();
+
+    if let Some(included) = update.included {
+        segment.included = included;
+    }
+    if let Some(excluded) = update.excluded {
+        segment.excluded = excluded;
+    }
+    if let Some(rules) = update.rules {
+        segment.rules = rules;
+    }
+    segment.validate()?;
+    let project_id = project_id(conn, project)?;
+
+    let tx = Savepoint::new(conn)?;
+    tx.execute(
+        "UPDATE segments SET included = ?1, excluded = ?2, rules = ?3
+            WHERE project_id = ?4 AND name = ?5",
+        params![
+            to_json(&segment.included)?,
+            to_json(&segment.excluded)?,
+            to_json(&segment.rules)?,
+            project_id,
+            name
+        ],
+    )?;
+
+    let flags = flags_using_segment(&tx, project_id, name)?;
+    for (id, _) in flags.iter() {
+        bump_version(&tx, *id)?;
+
+        let change = Change {
+            flag_id: *id,
+            environment: None,
+            action: FlagAction::Updated,
+            old_value: Some(segment_value(&old)),
+            new_value: Some(segment_value(&segment)),
+        };
+        record_event(&tx, actor, change)?;
+    }
+
+    tx.commit()?;
+
+    Ok(flags.into_iter().map(|(id, _)| id).collect())
+}
+
+/// Deletes a segment. Segments that rules still refer to cannot be removed.
+pub fn delete_segment(
+    conn: &Connection,
+    project: &str,
+    name: &str,
+) -> Result<usize, FeatureFlagError> {
+    let project_id = project_id(conn, project)?;
+
+    let flags: Vec<String> = flags_using_segment(conn, project_id, name)?
+        .into_iter()
+        .map(|(_, name)| name)
+        .collect();
+    if !flags.is_empty() {
+        return Err(FeatureFlagError::Conflict(format!(
+            "segment {} is used by {}",
+            name,
+            flags.join(", ")
+        )));
+    }
+
+    let result = conn.execute(
+        "DELETE FROM segments WHERE project_id = ?1 AND name = ?2",
+        params![project_id, name],
+    )?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::events::get_flag_history;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::rules::set_rules;
+    use crate::db::variations::{add_variation, Variation};
+    use crate::db::{add_flag, evaluate_flag, get_flag_by_id, initialize_db, DBLocal};
+    use crate::db::{FlagType, FlagValue};
+    use crate::eval::{EvaluationContext, Rule};
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        conn
+    }
+
+    fn beta_testers() -> Segment {
+        Segment {
+            name: "beta-testers".to_string(),
+            included: vec!["ada".to_string()],
+            ..Segment::default()
+        }
+    }
+
+    fn segment_rule() -> Rule {
+        serde_json::from_value(json!({
+            "clauses": [{"op": "segment", "values": ["beta-testers"]}],
+            "variation": "beta"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_segment_crud() {
+        let conn = in_memory_db();
+
+        add_segment(&conn, DEFAULT_PROJECT, beta_testers()).unwrap();
+        assert!(add_segment(&conn, DEFAULT_PROJECT, beta_testers()).is_err());
+        assert_eq!(
+            get_segments(&conn, DEFAULT_PROJECT).unwrap(),
+            vec![beta_testers()]
+        );
+
+        // No flag uses it yet
+        let changed = update_segment(
+            &conn,
+            DEFAULT_PROJECT,
+            "beta-testers",
+            SegmentUpdate {
+                excluded: Some(vec!["bob".to_string()]),
+                ..SegmentUpdate::default()
+            },
+            "test",
+        )
+        .unwrap();
+        assert!(changed.is_empty());
+        let result = get_segment(&conn, DEFAULT_PROJECT, "beta-testers").unwrap();
+        assert_eq!(result.included, vec!["ada".to_string()]);
+        assert_eq!(result.excluded, vec!["bob".to_string()]);
+
+        let update = SegmentUpdate::default();
+        let result = update_segment(&conn, DEFAULT_PROJECT, "missing", update, "test");
+        assert_eq!(
+            format!("{:?}", result),
+            "Err(NotFound(\"Segment missing\"))"
+        );
+
+        assert_eq!(
+            delete_segment(&conn, DEFAULT_PROJECT, "beta-testers").unwrap(),
+            1
+        );
+        assert_eq!(
+            delete_segment(&conn, DEFAULT_PROJECT, "missing").unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_rules_use_segments() {
+        let conn = in_memory_db();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "checkout".to_string(),
+            FlagType::String,
+            FlagValue::String("v1".to_string()),
+            "test",
+        )
+        .unwrap();
+        add_variation(
+            &conn,
+            1,
+            Variation {
+                name: "beta".to_string(),
+                value: FlagValue::String("v2".to_string()),
+            },
+            "test",
+        )
+        .unwrap();
+
+        // Rules can only refer to existing segments
+        let result = set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![segment_rule()], "test");
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        add_segment(&conn, DEFAULT_PROJECT, beta_testers()).unwrap();
+        set_rules(&conn, DEFAULT_ENVIRONMENT, 1, vec![segment_rule()], "test").unwrap();
+
+        let evaluate = |key: &str| {
+            evaluate_flag(
+                &conn,
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                "checkout".to_string(),
+                &EvaluationContext::new(key),
+            )
+            .unwrap()
+            .variation
+        };
+        assert_eq!(evaluate("ada"), Some("beta".to_string()));
+        assert_eq!(evaluate("bob"), None);
+
+        // Segment edits apply to every flag using it, which moves on to its next version
+        let changed = update_segment(
+            &conn,
+            DEFAULT_PROJECT,
+            "beta-testers",
+            SegmentUpdate {
+                included: Some(vec!["bob".to_string()]),
+                ..SegmentUpdate::default()
+            },
+            "ada",
+        )
+        .unwrap();
+        assert_eq!(changed, vec![1]);
+        assert_eq!(evaluate("ada"), None);
+        assert_eq!(evaluate("bob"), Some("beta".to_string()));
+        let flag = get_flag_by_id(&conn, DEFAULT_ENVIRONMENT, 1).unwrap();
+        assert_eq!(flag.version, 4);
+        let history = get_flag_history(&conn, 1).unwrap();
+        let event = history.last().unwrap();
+        assert_eq!(
+            (event.actor.as_str(), event.environment.as_deref()),
+            ("ada", None)
+        );
+        assert_eq!(
+            event.old_value.as_ref().unwrap()["segment"],
+            serde_json::to_value(beta_testers()).unwrap()
+        );
+
+        let result = delete_segment(&conn, DEFAULT_PROJECT, "beta-testers");
+        assert!(matches!(result, Err(FeatureFlagError::Conflict(_))));
+    }
+}
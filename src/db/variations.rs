@@ -0,0 +1,322 @@
+use rusqlite::types::Type;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::events::{record_event, variations_value, Change, FlagAction};
+use super::{bump_version, get_flag_type, Savepoint};
+use crate::error::FeatureFlagError;
+use crate::value::FlagValue;
+
+/// A named value a flag can serve, e.g. `control` or `treatment-a`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Variation {
+    pub name: String,
+    pub value: FlagValue,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VariationUpdate {
+    pub value: FlagValue,
+}
+
+pub fn get_variations(conn: &Connection, flag_id: u64) -> Result<Vec<Variation>, FeatureFlagError> {
+    let flag_type = get_flag_type(conn, flag_id)?;
+
+    let mut stmt =
+        conn.prepare("SELECT name, value FROM variations WHERE flag_id = ? ORDER BY id")?;
+
+    let rows = stmt.query_map(params![flag_id], |row| {
+        let value = FlagValue::from_json(flag_type, row.get(1)?).ok_or_else(|| {
+            rusqlite::Error::InvalidColumnType(1, "value".to_string(), Type::Text)
+        })?;
+
+        Ok(Variation {
+            name: row.get(0)?,
+            value,
+        })
+    })?;
+
+    let mut result = vec![];
+    for item in rows {
+        result.push(item?)
+    }
+
+    Ok(result)
+}
+
+pub fn variation_exists(
+    conn: &Connection,
+    flag_id: u64,
+    name: &str,
+) -> Result<bool, FeatureFlagError> {
+    let mut stmt = conn.prepare("SELECT 1 FROM variations WHERE flag_id = ? AND name = ?")?;
+
+    Ok(stmt.exists(params![flag_id, name])?)
+}
+
+/// Records the change of a flag's variations from `old_value` to what they are now.
+fn record_variations(
+    conn: &Connection,
+    flag_id: u64,
+    old_value: Value,
+    actor: &str,
+) -> Result<(), FeatureFlagError> {
+    let change = Change {
+        flag_id,
+        environment: None,
+        action: FlagAction::Updated,
+        old_value: Some(old_value),
+        new_value: Some(variations_value(&get_variations(conn, flag_id)?)),
+    };
+
+    record_event(conn, actor, change)
+}
+
+/// Adds a variation to a flag. Its value must match the flag's type.
+pub fn add_variation(
+    conn: &Connection,
+    flag_id: u64,
+    variation: Variation,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let flag_type = get_flag_type(conn, flag_id)?;
+
+    if variation.name.trim().is_empty() {
+        return Err(FeatureFlagError::Validation(
+            "variation name must not be empty".to_string(),
+        ));
+    }
+    let value = variation.value.into_type(flag_type)?;
+
+    let tx = Savepoint::new(conn)?;
+    let old_value = variations_value(&get_variations(&tx, flag_id)?);
+
+    let name = &variation.name;
+    let result = tx
+        .execute(
+            "INSERT INTO variations (flag_id, name, value) VALUES (?1, ?2, ?3)",
+            params![flag_id, name, value.to_json()],
+        )
+        .map_err(|err| FeatureFlagError::from(err).naming(format!("Variation {}", name)))?;
+    bump_version(&tx, flag_id)?;
+    record_variations(&tx, flag_id, old_value, actor)?;
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+pub fn update_variation(
+    conn: &Connection,
+    flag_id: u64,
+    name: String,
+    value: FlagValue,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    let value = value.into_type(get_flag_type(conn, flag_id)?)?;
+
+    let tx = Savepoint::new(conn)?;
+    let old_value = variations_value(&get_variations(&tx, flag_id)?);
+
+    let result = tx.execute(
+        "UPDATE variations SET value = ?1 WHERE flag_id = ?2 AND name = ?3",
+        params![value.to_json(), flag_id, name],
+    )?;
+
+    if result == 0 {
+        return Err(FeatureFlagError::NotFound(format!(
+            "Variation {} of flag {}",
+            name, flag_id
+        )));
+    }
+    bump_version(&tx, flag_id)?;
+    record_variations(&tx, flag_id, old_value, actor)?;
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+/// Removes a variation. Variations served by the flag or its rules in any environment cannot be
+/// removed.
+pub fn delete_variation(
+    conn: &Connection,
+    flag_id: u64,
+    name: String,
+    actor: &str,
+) -> Result<usize, FeatureFlagError> {
+    get_flag_type(conn, flag_id)?;
+
+    let tx = Savepoint::new(conn)?;
+    let old_value = variations_value(&get_variations(&tx, flag_id)?);
+
+    let serving = {
+        let mut stmt = tx.prepare(
+            "SELECT environments.name FROM flag_states
+                JOIN environments ON environments.id = flag_states.environment_id
+                WHERE flag_id = ?1 AND (default_variation = ?2 OR off_variation = ?2)
+            UNION
+            SELECT environments.name FROM rules
+                JOIN environments ON environments.id = rules.environment_id
+                WHERE flag_id = ?1 AND variation = ?2",
+        )?;
+        let rows = stmt.query_map(params![flag_id, name], |row| row.get::<_, String>(0))?;
+        rows.collect::<Result<Vec<String>, _>>()?
+    };
+
+    if !serving.is_empty() {
+        return Err(FeatureFlagError::Conflict(format!(
+            "variation {} is served in {}",
+            name,
+            serving.join(", ")
+        )));
+    }
+
+    let result = tx.execute(
+        "DELETE FROM variations WHERE flag_id = ? AND name = ?",
+        params![flag_id, name],
+    )?;
+    if result > 0 {
+        bump_version(&tx, flag_id)?;
+        record_variations(&tx, flag_id, old_value, actor)?;
+    }
+
+    tx.commit()?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use rusqlite::Connection;
+
+    use super::*;
+    use crate::db::environments::DEFAULT_ENVIRONMENT;
+    use crate::db::projects::DEFAULT_PROJECT;
+    use crate::db::{add_flag, apply_flag_update, initialize_db, DBLocal, FlagType, FlagUpdate};
+
+    fn in_memory_db() -> DBLocal {
+        let conn = Rc::new(Connection::open_in_memory().unwrap());
+
+        initialize_db(conn.clone()).unwrap();
+
+        add_flag(
+            &conn,
+            DEFAULT_PROJECT,
+            DEFAULT_ENVIRONMENT,
+            "colour".to_string(),
+            FlagType::String,
+            FlagValue::String("blue".to_string()),
+            "test",
+        )
+        .unwrap();
+
+        conn
+    }
+
+    fn variation(name: &str, value: &str) -> Variation {
+        Variation {
+            name: name.to_string(),
+            value: FlagValue::String(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_add_and_list_variations() {
+        let conn = in_memory_db();
+
+        add_variation(&conn, 1, variation("control", "blue"), "test").unwrap();
+        add_variation(&conn, 1, variation("treatment-a", "green"), "test").unwrap();
+
+        let result = get_variations(&conn, 1).unwrap();
+        assert_eq!(
+            result,
+            vec![
+                variation("control", "blue"),
+                variation("treatment-a", "green")
+            ]
+        );
+
+        // Names are unique per flag
+        assert!(add_variation(&conn, 1, variation("control", "red"), "test").is_err());
+
+        // Values must match the flag type
+        let result = add_variation(
+            &conn,
+            1,
+            Variation {
+                name: "numeric".to_string(),
+                value: FlagValue::Integer(1),
+            },
+            "test",
+        );
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+    }
+
+    #[test]
+    fn test_update_variation() {
+        let conn = in_memory_db();
+
+        add_variation(&conn, 1, variation("control", "blue"), "test").unwrap();
+
+        update_variation(
+            &conn,
+            1,
+            "control".to_string(),
+            FlagValue::String("navy".to_string()),
+            "test",
+        )
+        .unwrap();
+        assert_eq!(
+            get_variations(&conn, 1).unwrap(),
+            vec![variation("control", "navy")]
+        );
+
+        let result = update_variation(
+            &conn,
+            1,
+            "missing".to_string(),
+            FlagValue::String("navy".to_string()),
+            "test",
+        );
+        assert_eq!(
+            format!("{:?}", result),
+            "Err(NotFound(\"Variation missing of flag 1\"))"
+        );
+    }
+
+    #[test]
+    fn test_delete_served_variation() {
+        let conn = in_memory_db();
+
+        add_variation(&conn, 1, variation("control", "blue"), "test").unwrap();
+        add_variation(&conn, 1, variation("treatment-a", "green"), "test").unwrap();
+
+        apply_flag_update(
+            &conn,
+            DEFAULT_ENVIRONMENT,
+            1,
+            FlagUpdate {
+                default_variation: Some(Some("treatment-a".to_string())),
+                ..FlagUpdate::default()
+            },
+            "test",
+        )
+        .unwrap();
+
+        let result = delete_variation(&conn, 1, "treatment-a".to_string(), "test");
+        assert!(matches!(result, Err(FeatureFlagError::Conflict(_))));
+
+        assert_eq!(
+            delete_variation(&conn, 1, "control".to_string(), "test").unwrap(),
+            1
+        );
+        assert_eq!(
+            get_variations(&conn, 1).unwrap(),
+            vec![variation("treatment-a", "green")]
+        );
+    }
+}
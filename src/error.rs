@@ -1,12 +1,231 @@
+use std::error::Error;
+use std::fmt;
+
 use rusqlite;
+use serde_derive::Serialize;
+use warp::http::StatusCode;
 
+/// Everything that can go wrong in the library. The server answers each variant with its own
+/// `status` and `code`, and the CLI prints the `Display` message.
 #[derive(Debug)]
 pub enum FeatureFlagError {
-    RusqliteError(rusqlite::Error),
+    /// What was looked for, e.g. "Flag checkout"
+    NotFound(String),
+    /// What is already taken, e.g. "Segment beta-testers"
+    AlreadyExists(String),
+    /// Input that can never be accepted, e.g. a value of the wrong type
+    Validation(String),
+    /// The change clashes with other data, e.g. deleting a segment that rules still use
+    Conflict(String),
+    /// The store does not accept changes, e.g. flags served from files
+    ReadOnly(String),
+    /// A condition of the request, e.g. `If-Match`, does not hold
+    PreconditionFailed(String),
+    /// The config file could not be read or has invalid settings
+    Config(String),
+    /// No database at the path, which `db-init` creates
+    MissingDatabase(String),
+    /// The database was migrated by a newer version
+    UnknownSchemaVersion(i64),
+    /// SQLite failed in a way the other variants do not cover
+    Storage(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+/// The JSON body of error responses. `error` is one of the stable codes of
+/// `FeatureFlagError::code`, `code` is the HTTP status as in every other response.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub code: u16,
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl FeatureFlagError {
+    /// Name of the variant for clients to match on, which does not change between versions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            FeatureFlagError::NotFound(_) => "NOT_FOUND",
+            FeatureFlagError::AlreadyExists(_) => "ALREADY_EXISTS",
+            FeatureFlagError::Validation(_) => "VALIDATION",
+            FeatureFlagError::Conflict(_) => "CONFLICT",
+            FeatureFlagError::ReadOnly(_) => "READ_ONLY",
+            FeatureFlagError::PreconditionFailed(_) => "PRECONDITION_FAILED",
+            FeatureFlagError::Config(_) => "CONFIG",
+            FeatureFlagError::MissingDatabase(_) => "MISSING_DATABASE",
+            FeatureFlagError::UnknownSchemaVersion(_) => "UNKNOWN_SCHEMA_VERSION",
+            FeatureFlagError::Storage(_) => "STORAGE",
+            FeatureFlagError::Io(_) => "IO",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FeatureFlagError::NotFound(_) => StatusCode::NOT_FOUND,
+            FeatureFlagError::AlreadyExists(_) | FeatureFlagError::Conflict(_) => {
+                StatusCode::CONFLICT
+            }
+            FeatureFlagError::Validation(_) => StatusCode::BAD_REQUEST,
+            FeatureFlagError::ReadOnly(_) => StatusCode::FORBIDDEN,
+            FeatureFlagError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What clients are told. Server failures are only described in the logs.
+    pub fn body(&self) -> ErrorBody {
+        let status = self.status();
+        let message = match status {
+            StatusCode::INTERNAL_SERVER_ERROR => "Internal server error".to_string(),
+            _ => self.to_string(),
+        };
+
+        ErrorBody {
+            code: status.as_u16(),
+            error: self.code(),
+            message,
+        }
+    }
+
+    /// Names `what` in `AlreadyExists` errors, which SQLite only describes by their columns.
+    pub(crate) fn naming(self, what: String) -> FeatureFlagError {
+        match self {
+            FeatureFlagError::AlreadyExists(_) => FeatureFlagError::AlreadyExists(what),
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for FeatureFlagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeatureFlagError::NotFound(what) => write!(f, "{} was not found", what),
+            FeatureFlagError::AlreadyExists(what) => write!(f, "{} already exists", what),
+            FeatureFlagError::Validation(message)
+            | FeatureFlagError::Conflict(message)
+            | FeatureFlagError::ReadOnly(message)
+            | FeatureFlagError::PreconditionFailed(message) => write!(f, "{}", message),
+            FeatureFlagError::Config(message) => write!(f, "invalid configuration: {}", message),
+            FeatureFlagError::MissingDatabase(path) => {
+                write!(f, "there is no database at {}, run db-init first", path)
+            }
+            FeatureFlagError::UnknownSchemaVersion(version) => write!(
+                f,
+                "the database schema version {} is newer than this build",
+                version
+            ),
+            FeatureFlagError::Storage(err) => write!(f, "storage error: {}", err),
+            FeatureFlagError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl Error for FeatureFlagError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FeatureFlagError::Storage(err) => Some(err),
+            FeatureFlagError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
+impl warp::reject::Reject for FeatureFlagError {}
+
+impl warp::Reply for FeatureFlagError {
+    fn into_response(self) -> warp::reply::Response {
+        if self.status() == StatusCode::INTERNAL_SERVER_ERROR {
+            log::error!("{}", self);
+        }
+
+        warp::reply::with_status(warp::reply::json(&self.body()), self.status()).into_response()
+    }
+}
+
+/// Sorts SQLite errors into the variants they stand for.
 impl From<rusqlite::Error> for FeatureFlagError {
     fn from(error: rusqlite::Error) -> Self {
-        FeatureFlagError::RusqliteError(error)
+        match error {
+            rusqlite::Error::QueryReturnedNoRows => {
+                FeatureFlagError::NotFound("Record".to_string())
+            }
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::ConstraintViolation,
+                    extended_code,
+                },
+                message,
+            ) => {
+                let message = message.unwrap_or_else(|| "constraint failed".to_string());
+
+                match extended_code {
+                    rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                    | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+                        FeatureFlagError::AlreadyExists(message)
+                    }
+                    _ => FeatureFlagError::Conflict(message),
+                }
+            }
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error {
+                    code: rusqlite::ErrorCode::ReadOnly,
+                    ..
+                },
+                _,
+            ) => FeatureFlagError::ReadOnly("the database is opened read-only".to_string()),
+            error => FeatureFlagError::Storage(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for FeatureFlagError {
+    fn from(error: std::io::Error) -> Self {
+        FeatureFlagError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_errors() {
+        let err = FeatureFlagError::from(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE),
+            Some("UNIQUE constraint failed: flags.name".to_string()),
+        ));
+        let err = err.naming("Flag checkout".to_string());
+        assert_eq!(err.to_string(), "Flag checkout already exists");
+        assert_eq!(err.status(), StatusCode::CONFLICT);
+
+        let err = FeatureFlagError::from(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            None,
+        ));
+        assert_eq!(err.code(), "CONFLICT");
+
+        let err = FeatureFlagError::from(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_READONLY),
+            None,
+        ));
+        assert_eq!(err.code(), "READ_ONLY");
+        assert_eq!(err.status(), StatusCode::FORBIDDEN);
+
+        let err = FeatureFlagError::from(rusqlite::Error::InvalidQuery);
+        assert_eq!(err.code(), "STORAGE");
+    }
+
+    #[test]
+    fn test_body() {
+        let body = FeatureFlagError::NotFound("Flag 3".to_string()).body();
+        assert_eq!(
+            serde_json::to_string(&body).unwrap(),
+            "{\"code\":404,\"error\":\"NOT_FOUND\",\"message\":\"Flag 3 was not found\"}"
+        );
+
+        // Internals stay in the logs
+        let body = FeatureFlagError::Storage(rusqlite::Error::InvalidQuery).body();
+        assert_eq!(body.code, 500);
+        assert_eq!(body.message, "Internal server error");
     }
 }
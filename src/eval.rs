@@ -0,0 +1,702 @@
+mod semver;
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use self::semver::Version;
+use crate::db::variations::Variation;
+use crate::db::FlagWithID;
+use crate::error::FeatureFlagError;
+use crate::rollout;
+use crate::value::FlagValue;
+
+/// Who a flag is evaluated for: a user key plus any attributes rules can match on.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct EvaluationContext {
+    pub key: String,
+    #[serde(default)]
+    pub attributes: HashMap<String, Value>,
+}
+
+impl EvaluationContext {
+    pub fn new(key: &str) -> EvaluationContext {
+        EvaluationContext {
+            key: key.to_string(),
+            attributes: HashMap::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, name: &str, value: Value) -> EvaluationContext {
+        self.attributes.insert(name.to_string(), value);
+        self
+    }
+
+    /// Value of an attribute. `key` is the user key unless an attribute overrides it.
+    pub fn get(&self, attribute: &str) -> Option<Value> {
+        match self.attributes.get(attribute) {
+            Some(value) => Some(value.clone()),
+            None if attribute == "key" => Some(Value::String(self.key.clone())),
+            None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Operator {
+    #[serde(rename = "in")]
+    In,
+    #[serde(rename = "starts_with")]
+    StartsWith,
+    #[serde(rename = "ends_with")]
+    EndsWith,
+    #[serde(rename = "contains")]
+    Contains,
+    #[serde(rename = "<")]
+    LessThan,
+    #[serde(rename = "<=")]
+    LessThanOrEqual,
+    #[serde(rename = ">")]
+    GreaterThan,
+    #[serde(rename = ">=")]
+    GreaterThanOrEqual,
+    #[serde(rename = "semver=")]
+    SemverEqual,
+    #[serde(rename = "semver<")]
+    SemverLessThan,
+    #[serde(rename = "semver<=")]
+    SemverLessThanOrEqual,
+    #[serde(rename = "semver>")]
+    SemverGreaterThan,
+    #[serde(rename = "semver>=")]
+    SemverGreaterThanOrEqual,
+    /// The context is in one of the segments named by the values, the attribute is not used
+    #[serde(rename = "segment")]
+    InSegment,
+}
+
+/// Test of one attribute, e.g. `country in [US, CA]`. It matches when the attribute matches any
+/// of the values, or any element of the attribute does when it is a list.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Clause {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub attribute: String,
+    pub op: Operator,
+    pub values: Vec<Value>,
+    /// Matches when the clause otherwise would not
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub negate: bool,
+}
+
+/// Serves `variation` to contexts matching every clause.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Rule {
+    pub clauses: Vec<Clause>,
+    pub variation: String,
+}
+
+/// Attribute clauses of a segment, all of which have to match.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SegmentRule {
+    pub clauses: Vec<Clause>,
+}
+
+/// A reusable group of users, e.g. "beta testers", that rules refer to by name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Segment {
+    pub name: String,
+    /// User keys that are always in the segment
+    #[serde(default)]
+    pub included: Vec<String>,
+    /// User keys that are never in the segment, unless also included
+    #[serde(default)]
+    pub excluded: Vec<String>,
+    /// Other users are in the segment when any of these match
+    #[serde(default)]
+    pub rules: Vec<SegmentRule>,
+}
+
+impl Segment {
+    pub fn contains(&self, context: &EvaluationContext) -> bool {
+        if self.included.contains(&context.key) {
+            return true;
+        }
+        if self.excluded.contains(&context.key) {
+            return false;
+        }
+
+        self.rules.iter().any(|rule| {
+            rule.clauses
+                .iter()
+                .all(|clause| clause.matches(context, &[]))
+        })
+    }
+
+    /// Segment rules cannot refer to other segments.
+    pub fn validate(&self) -> Result<(), FeatureFlagError> {
+        for clause in self.rules.iter().flat_map(|rule| rule.clauses.iter()) {
+            if clause.op == Operator::InSegment {
+                return Err(FeatureFlagError::Validation(format!(
+                    "segment {} cannot refer to other segments",
+                    self.name
+                )));
+            }
+            clause.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Why an evaluation served what it did.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Reason {
+    /// The flag is disabled
+    Off,
+    /// A rule listing user keys matched the context's key
+    TargetMatch,
+    /// The rule at `rule_index` matched
+    RuleMatch { rule_index: usize },
+    /// No rule matched, so the percentage rollout decided
+    Fallthrough,
+    /// The flag could not be evaluated
+    Error { message: String },
+}
+
+/// What a flag serves to one context.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Evaluation {
+    /// `None` when the flag is off and there is nothing to serve
+    pub value: Option<FlagValue>,
+    /// Variation the value came from
+    pub variation: Option<String>,
+    pub reason: Reason,
+}
+
+impl Evaluation {
+    /// An evaluation that failed with `err`, described as clients are told about errors, see
+    /// `FeatureFlagError::body`.
+    pub fn error(err: &FeatureFlagError) -> Evaluation {
+        Evaluation {
+            value: None,
+            variation: None,
+            reason: Reason::Error {
+                message: err.body().message,
+            },
+        }
+    }
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    value.as_f64()
+}
+
+fn as_version(value: &Value) -> Option<Version> {
+    value.as_str().and_then(Version::parse)
+}
+
+fn matches_value(op: Operator, actual: &Value, expected: &Value) -> bool {
+    let strings = || Some((actual.as_str()?, expected.as_str()?));
+    let numbers = || Some((as_number(actual)?, as_number(expected)?));
+    let versions = || Some((as_version(actual)?, as_version(expected)?));
+
+    match op {
+        Operator::In => match numbers() {
+            // 1 and 1.0 are the same number
+            Some((actual, expected)) => actual == expected,
+            None => actual == expected,
+        },
+        Operator::StartsWith => strings().is_some_and(|(a, e)| a.starts_with(e)),
+        Operator::EndsWith => strings().is_some_and(|(a, e)| a.ends_with(e)),
+        Operator::Contains => strings().is_some_and(|(a, e)| a.contains(e)),
+        Operator::LessThan => numbers().is_some_and(|(a, e)| a < e),
+        Operator::LessThanOrEqual => numbers().is_some_and(|(a, e)| a <= e),
+        Operator::GreaterThan => numbers().is_some_and(|(a, e)| a > e),
+        Operator::GreaterThanOrEqual => numbers().is_some_and(|(a, e)| a >= e),
+        Operator::SemverEqual => versions().is_some_and(|(a, e)| a == e),
+        Operator::SemverLessThan => versions().is_some_and(|(a, e)| a < e),
+        Operator::SemverLessThanOrEqual => versions().is_some_and(|(a, e)| a <= e),
+        Operator::SemverGreaterThan => versions().is_some_and(|(a, e)| a > e),
+        Operator::SemverGreaterThanOrEqual => versions().is_some_and(|(a, e)| a >= e),
+        // Needs the segments, see `Clause::matches`
+        Operator::InSegment => false,
+    }
+}
+
+impl Clause {
+    /// Missing attributes never match, even when the clause is negated. Segments that are not in
+    /// `segments` contain no one.
+    pub fn matches(&self, context: &EvaluationContext, segments: &[Segment]) -> bool {
+        if self.op == Operator::InSegment {
+            let matched = segments.iter().any(|segment| {
+                self.values.contains(&Value::String(segment.name.clone()))
+                    && segment.contains(context)
+            });
+
+            return matched != self.negate;
+        }
+
+        let actual = match context.get(&self.attribute) {
+            Some(actual) => actual,
+            None => return false,
+        };
+
+        let candidates = match &actual {
+            Value::Array(items) => items.iter().collect(),
+            actual => vec![actual],
+        };
+
+        let matched = candidates.iter().any(|actual| {
+            self.values
+                .iter()
+                .any(|expected| matches_value(self.op, actual, expected))
+        });
+
+        matched != self.negate
+    }
+
+    /// Rejects clauses whose values can never match their operator.
+    pub fn validate(&self) -> Result<(), FeatureFlagError> {
+        if self.op != Operator::InSegment && self.attribute.trim().is_empty() {
+            return Err(FeatureFlagError::Validation(
+                "clause attribute must not be empty".to_string(),
+            ));
+        }
+
+        let valid = |value: &Value| match self.op {
+            Operator::In => true,
+            Operator::StartsWith | Operator::EndsWith | Operator::Contains => value.is_string(),
+            Operator::LessThan
+            | Operator::LessThanOrEqual
+            | Operator::GreaterThan
+            | Operator::GreaterThanOrEqual => value.is_number(),
+            Operator::SemverEqual
+            | Operator::SemverLessThan
+            | Operator::SemverLessThanOrEqual
+            | Operator::SemverGreaterThan
+            | Operator::SemverGreaterThanOrEqual => as_version(value).is_some(),
+            Operator::InSegment => value.is_string(),
+        };
+
+        match self.values.iter().find(|value| !valid(value)) {
+            Some(value) => Err(FeatureFlagError::Validation(format!(
+                "{} is not a valid value for {}",
+                value,
+                serde_json::to_string(&self.op).unwrap_or_default()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, context: &EvaluationContext, segments: &[Segment]) -> bool {
+        self.clauses
+            .iter()
+            .all(|clause| clause.matches(context, segments))
+    }
+
+    /// Whether the rule only lists user keys, i.e. targets individual users.
+    pub fn targets_users(&self) -> bool {
+        !self.clauses.is_empty()
+            && self.clauses.iter().all(|clause| {
+                clause.attribute == "key" && clause.op == Operator::In && !clause.negate
+            })
+    }
+
+    /// Names of the segments the rule refers to.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.clauses
+            .iter()
+            .filter(|clause| clause.op == Operator::InSegment)
+            .flat_map(|clause| clause.values.iter().filter_map(Value::as_str))
+    }
+}
+
+fn variation_value(variations: &[Variation], name: &Option<String>) -> Option<FlagValue> {
+    let name = name.as_ref()?;
+
+    variations
+        .iter()
+        .find(|variation| &variation.name == name)
+        .map(|variation| variation.value.clone())
+}
+
+fn off(flag: &FlagWithID, variations: &[Variation], reason: Reason) -> Evaluation {
+    match variation_value(variations, &flag.off_variation) {
+        Some(value) => Evaluation {
+            value: Some(value),
+            variation: flag.off_variation.clone(),
+            reason,
+        },
+        None => Evaluation {
+            value: match flag.value {
+                FlagValue::Bool(_) => Some(FlagValue::Bool(false)),
+                _ => None,
+            },
+            variation: None,
+            reason,
+        },
+    }
+}
+
+/// Evaluates a flag for `context`.
+///
+/// A disabled flag serves its off variation. Otherwise the first rule matching the context
+/// decides the variation, with `segments` holding the segments rules refer to. Contexts no rule
+/// matches fall through to the percentage rollout, which
+/// serves the default variation (or the flag's value) to users in it and the off variation to
+/// everyone else. Without an off variation, bool flags serve `false` and other flags serve
+/// nothing.
+pub fn evaluate(
+    flag: &FlagWithID,
+    variations: &[Variation],
+    rules: &[Rule],
+    segments: &[Segment],
+    context: &EvaluationContext,
+) -> Evaluation {
+    if !flag.enabled {
+        return off(flag, variations, Reason::Off);
+    }
+
+    let matching = rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.matches(context, segments));
+    for (rule_index, rule) in matching {
+        let variation = Some(rule.variation.clone());
+
+        // Rules serving a variation that no longer exists are skipped
+        if let Some(value) = variation_value(variations, &variation) {
+            let reason = match rule.targets_users() {
+                true => Reason::TargetMatch,
+                false => Reason::RuleMatch { rule_index },
+            };
+
+            return Evaluation {
+                value: Some(value),
+                variation,
+                reason,
+            };
+        }
+    }
+
+    let in_rollout = match flag.rollout_percentage {
+        Some(percentage) => rollout::in_rollout(percentage, &flag.name, &context.key, &flag.salt),
+        None => true,
+    };
+    if !in_rollout {
+        return off(flag, variations, Reason::Fallthrough);
+    }
+
+    match variation_value(variations, &flag.default_variation) {
+        Some(value) => Evaluation {
+            value: Some(value),
+            variation: flag.default_variation.clone(),
+            reason: Reason::Fallthrough,
+        },
+        None => Evaluation {
+            value: Some(flag.value.clone()),
+            variation: None,
+            reason: Reason::Fallthrough,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::FlagMetadata;
+    use crate::value::FlagType;
+
+    fn flag() -> FlagWithID {
+        FlagWithID {
+            id: 1,
+            name: "new_checkout".to_string(),
+            flag_type: FlagType::String,
+            value: FlagValue::String("v1".to_string()),
+            enabled: true,
+            default_variation: None,
+            off_variation: None,
+            rollout_percentage: None,
+            salt: String::new(),
+            version: 1,
+            metadata: FlagMetadata::default(),
+            created_at: String::new(),
+            updated_at: String::new(),
+            created_by: String::new(),
+        }
+    }
+
+    fn variations() -> Vec<Variation> {
+        ["v1", "v2", "v3"]
+            .iter()
+            .map(|name| Variation {
+                name: name.to_string(),
+                value: FlagValue::String(name.to_string()),
+            })
+            .collect()
+    }
+
+    fn clause(attribute: &str, op: &str, values: Value) -> Clause {
+        serde_json::from_value(json!({"attribute": attribute, "op": op, "values": values})).unwrap()
+    }
+
+    fn served(evaluation: Evaluation) -> Option<String> {
+        evaluation.variation
+    }
+
+    #[test]
+    fn test_clause_operators() {
+        let context = EvaluationContext::new("user-1")
+            .with_attribute("country", json!("CA"))
+            .with_attribute("email", json!("ada@corp.com"))
+            .with_attribute("version", json!("2.10.1"))
+            .with_attribute("age", json!(42))
+            .with_attribute("groups", json!(["beta", "staff"]));
+
+        let matching = [
+            clause("country", "in", json!(["US", "CA"])),
+            clause("key", "in", json!(["user-1"])),
+            clause("email", "ends_with", json!(["@corp.com"])),
+            clause("email", "starts_with", json!(["ada@"])),
+            clause("email", "contains", json!(["@corp"])),
+            clause("version", "semver>=", json!(["2.3.0"])),
+            clause("version", "semver>", json!(["2.9"])),
+            clause("version", "semver=", json!(["2.10.1+build.7"])),
+            clause("age", ">=", json!([42])),
+            clause("age", "<", json!([50.5])),
+            clause("age", "in", json!([42.0])),
+            clause("groups", "in", json!(["staff"])),
+        ];
+        for clause in matching.iter() {
+            assert!(clause.matches(&context, &[]), "{:?}", clause);
+        }
+
+        let failing = [
+            clause("country", "in", json!(["US", "MX"])),
+            clause("email", "ends_with", json!(["@example.com"])),
+            clause("version", "semver<", json!(["2.3.0"])),
+            clause("age", ">", json!([42])),
+            clause("age", "in", json!(["42"])),
+            clause("missing", "in", json!(["anything"])),
+        ];
+        for clause in failing.iter() {
+            assert!(!clause.matches(&context, &[]), "{:?}", clause);
+        }
+
+        let mut negated = clause("country", "in", json!(["US"]));
+        negated.negate = true;
+        assert!(negated.matches(&context, &[]));
+    }
+
+    #[test]
+    fn test_clause_validation() {
+        assert!(clause("version", "semver>=", json!(["2.3.0"]))
+            .validate()
+            .is_ok());
+        assert!(clause("version", "semver>=", json!(["latest"]))
+            .validate()
+            .is_err());
+        assert!(clause("age", ">", json!(["old"])).validate().is_err());
+        assert!(clause("", "in", json!([])).validate().is_err());
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let rules = vec![
+            Rule {
+                clauses: vec![clause("email", "ends_with", json!(["@corp.com"]))],
+                variation: "v2".to_string(),
+            },
+            Rule {
+                clauses: vec![
+                    clause("country", "in", json!(["US", "CA"])),
+                    clause("version", "semver>=", json!(["2.3.0"])),
+                ],
+                variation: "v3".to_string(),
+            },
+        ];
+
+        let staff = EvaluationContext::new("ada")
+            .with_attribute("email", json!("ada@corp.com"))
+            .with_attribute("country", json!("US"))
+            .with_attribute("version", json!("2.3.0"));
+        assert_eq!(
+            served(evaluate(&flag(), &variations(), &rules, &[], &staff)),
+            Some("v2".to_string())
+        );
+
+        let customer = EvaluationContext::new("bob")
+            .with_attribute("country", json!("US"))
+            .with_attribute("version", json!("2.3.0"));
+        assert_eq!(
+            served(evaluate(&flag(), &variations(), &rules, &[], &customer)),
+            Some("v3".to_string())
+        );
+
+        // Every clause has to match, otherwise the flag falls through
+        let old_client = customer.with_attribute("version", json!("2.2.9"));
+        assert_eq!(
+            evaluate(&flag(), &variations(), &rules, &[], &old_client),
+            Evaluation {
+                value: Some(FlagValue::String("v1".to_string())),
+                variation: None,
+                reason: Reason::Fallthrough,
+            }
+        );
+
+        // Rules are not used while the flag is off
+        let mut disabled = flag();
+        disabled.enabled = false;
+        assert_eq!(
+            evaluate(&disabled, &variations(), &rules, &[], &staff),
+            Evaluation {
+                value: None,
+                variation: None,
+                reason: Reason::Off,
+            }
+        );
+    }
+
+    #[test]
+    fn test_reasons() {
+        let rules = vec![
+            Rule {
+                clauses: vec![clause("key", "in", json!(["ada"]))],
+                variation: "v2".to_string(),
+            },
+            Rule {
+                clauses: vec![clause("country", "in", json!(["CA"]))],
+                variation: "v3".to_string(),
+            },
+        ];
+        let reason = |flag: &FlagWithID, context: EvaluationContext| {
+            evaluate(flag, &variations(), &rules, &[], &context).reason
+        };
+
+        assert_eq!(
+            reason(&flag(), EvaluationContext::new("ada")),
+            Reason::TargetMatch
+        );
+        assert_eq!(
+            reason(
+                &flag(),
+                EvaluationContext::new("bob").with_attribute("country", json!("CA"))
+            ),
+            Reason::RuleMatch { rule_index: 1 }
+        );
+        assert_eq!(
+            reason(&flag(), EvaluationContext::new("bob")),
+            Reason::Fallthrough
+        );
+
+        let mut disabled = flag();
+        disabled.enabled = false;
+        assert_eq!(
+            reason(&disabled, EvaluationContext::new("ada")),
+            Reason::Off
+        );
+
+        assert_eq!(
+            serde_json::to_value(Reason::RuleMatch { rule_index: 1 }).unwrap(),
+            json!({"kind": "RULE_MATCH", "rule_index": 1})
+        );
+    }
+
+    #[test]
+    fn test_rollout_falls_through() {
+        let mut flag = flag();
+        flag.flag_type = FlagType::Bool;
+        flag.value = FlagValue::Bool(true);
+        flag.rollout_percentage = Some(0.0);
+
+        let context = EvaluationContext::new("user-1");
+        assert_eq!(
+            evaluate(&flag, &[], &[], &[], &context).value,
+            Some(FlagValue::Bool(false))
+        );
+
+        flag.rollout_percentage = Some(100.0);
+        assert_eq!(
+            evaluate(&flag, &[], &[], &[], &context).value,
+            Some(FlagValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_segments() {
+        let segment = Segment {
+            name: "beta-testers".to_string(),
+            included: vec!["ada".to_string()],
+            excluded: vec!["bob".to_string(), "ada".to_string()],
+            rules: vec![SegmentRule {
+                clauses: vec![clause("email", "ends_with", json!(["@corp.com"]))],
+            }],
+        };
+        let rules = vec![Rule {
+            clauses: vec![clause("", "segment", json!(["beta-testers"]))],
+            variation: "v2".to_string(),
+        }];
+
+        let evaluate = |context: &EvaluationContext| {
+            served(evaluate(
+                &flag(),
+                &variations(),
+                &rules,
+                std::slice::from_ref(&segment),
+                context,
+            ))
+        };
+        let corp = |key: &str| {
+            EvaluationContext::new(key).with_attribute("email", json!("someone@corp.com"))
+        };
+
+        // Included keys win over excluded ones and rules
+        assert_eq!(
+            evaluate(&EvaluationContext::new("ada")),
+            Some("v2".to_string())
+        );
+        assert_eq!(evaluate(&corp("bob")), None);
+        assert_eq!(evaluate(&corp("carol")), Some("v2".to_string()));
+        assert_eq!(evaluate(&EvaluationContext::new("carol")), None);
+
+        // Unknown segments contain no one
+        assert!(!rules[0].matches(&corp("carol"), &[]));
+        assert_eq!(
+            rules[0].segments().collect::<Vec<_>>(),
+            vec!["beta-testers"]
+        );
+
+        let mut nested = segment.clone();
+        nested.rules[0].clauses = rules[0].clauses.clone();
+        assert!(matches!(
+            nested.validate(),
+            Err(FeatureFlagError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn test_error_reason() {
+        let err = FeatureFlagError::Validation("bad rule".to_string());
+        assert_eq!(
+            Evaluation::error(&err).reason,
+            Reason::Error {
+                message: err.to_string()
+            }
+        );
+
+        // Internals stay in the logs
+        let err = FeatureFlagError::Storage(rusqlite::Error::InvalidQuery);
+        assert_eq!(
+            Evaluation::error(&err).reason,
+            Reason::Error {
+                message: "Internal server error".to_string()
+            }
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use std::cmp::Ordering;
+
+/// A semantic version such as `2.3.0` or `1.0.0-beta.2`. Missing minor and patch numbers are
+/// read as zero and build metadata is ignored.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Version {
+    numbers: [u64; 3],
+    pre_release: Vec<String>,
+}
+
+impl Version {
+    pub fn parse(input: &str) -> Option<Version> {
+        let input = input.trim();
+        let input = input.split('+').next()?;
+
+        let (release, pre_release) = match input.split_once('-') {
+            Some((release, pre_release)) => (release, Some(pre_release)),
+            None => (input, None),
+        };
+
+        let mut numbers = [0u64; 3];
+        let parts: Vec<&str> = release.split('.').collect();
+        if parts.is_empty() || parts.len() > 3 {
+            return None;
+        }
+        for (number, part) in numbers.iter_mut().zip(parts) {
+            if part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            *number = part.parse().ok()?;
+        }
+
+        let pre_release = match pre_release {
+            Some(pre_release) => {
+                let identifiers: Vec<String> = pre_release.split('.').map(str::to_string).collect();
+                if identifiers.iter().any(|identifier| identifier.is_empty()) {
+                    return None;
+                }
+                identifiers
+            }
+            None => vec![],
+        };
+
+        Some(Version {
+            numbers,
+            pre_release,
+        })
+    }
+}
+
+fn compare_identifiers(left: &str, right: &str) -> Ordering {
+    match (left.parse::<u64>(), right.parse::<u64>()) {
+        (Ok(left), Ok(right)) => left.cmp(&right),
+        // Numeric identifiers sort before alphanumeric ones
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => left.cmp(right),
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.numbers.cmp(&other.numbers).then_with(|| {
+            // A pre-release comes before the release itself
+            match (self.pre_release.is_empty(), other.pre_release.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) => Ordering::Greater,
+                (false, true) => Ordering::Less,
+                (false, false) => self
+                    .pre_release
+                    .iter()
+                    .zip(&other.pre_release)
+                    .map(|(left, right)| compare_identifiers(left, right))
+                    .find(|ordering| *ordering != Ordering::Equal)
+                    .unwrap_or_else(|| self.pre_release.len().cmp(&other.pre_release.len())),
+            }
+        })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(input: &str) -> Version {
+        Version::parse(input).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(version("2.3"), version("2.3.0"));
+        assert_eq!(version("2.3.0+build.5"), version("2.3.0"));
+
+        for invalid in ["", "a.b", "1.2.3.4", "1..2", "1.2.3-", "v1.2"] {
+            assert!(Version::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_ordering() {
+        let ordered = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0",
+            "2.3.0",
+            "2.10.0",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{:?}", pair);
+        }
+    }
+}
@@ -1,2 +1,7 @@
+pub mod config;
 pub mod db;
 pub mod error;
+pub mod eval;
+pub mod rollout;
+pub mod store;
+pub mod value;
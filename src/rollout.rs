@@ -0,0 +1,64 @@
+use sha1::{Digest, Sha1};
+
+/// Largest value of the 60 bits of the hash used for bucketing.
+const BUCKET_SCALE: f64 = 0xFFF_FFFF_FFFF_FFFFu64 as f64;
+
+/// Places a user in a bucket between 0 (inclusive) and 100 (exclusive).
+///
+/// The bucket only depends on its inputs, so a user keeps their bucket across requests and
+/// restarts.
+pub fn bucket(flag_key: &str, user_key: &str, salt: &str) -> f64 {
+    let digest = Sha1::digest(format!("{}.{}.{}", flag_key, salt, user_key).as_bytes());
+
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    let hash = u64::from_be_bytes(prefix) >> 4;
+
+    hash as f64 / BUCKET_SCALE * 100.0
+}
+
+/// Whether the user falls within the first `percentage` percent of buckets.
+pub fn in_rollout(percentage: f64, flag_key: &str, user_key: &str, salt: &str) -> bool {
+    bucket(flag_key, user_key, salt) < percentage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_is_stable() {
+        let first = bucket("new_checkout", "user-1", "");
+
+        assert_eq!(first, bucket("new_checkout", "user-1", ""));
+        assert!((0.0..100.0).contains(&first));
+
+        // Each input moves the user to another bucket
+        assert_ne!(first, bucket("new_checkout", "user-2", ""));
+        assert_ne!(first, bucket("old_checkout", "user-1", ""));
+        assert_ne!(first, bucket("new_checkout", "user-1", "2023"));
+    }
+
+    #[test]
+    fn test_rollout_percentage() {
+        let users: Vec<String> = (0..1000).map(|n| format!("user-{}", n)).collect();
+
+        let count = |percentage: f64| {
+            users
+                .iter()
+                .filter(|user| in_rollout(percentage, "new_checkout", user, ""))
+                .count()
+        };
+
+        assert_eq!(count(0.0), 0);
+        assert_eq!(count(100.0), users.len());
+
+        // Close to 10% of users, and a superset of them at 20%
+        let ten = count(10.0);
+        assert!((70..=130).contains(&ten), "{} users in a 10% rollout", ten);
+        assert!(users
+            .iter()
+            .filter(|user| in_rollout(10.0, "new_checkout", user, ""))
+            .all(|user| in_rollout(20.0, "new_checkout", user, "")));
+    }
+}
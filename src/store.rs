@@ -0,0 +1,616 @@
+//! Storage backends for flags.
+//!
+//! `FlagStore` is what the server and the CLI are written against. `SqliteStore` keeps flags in
+//! SQLite through the `db` module and `MemoryStore` keeps them in memory, mostly for tests.
+//! `FileStore` serves flags read from files without accepting changes.
+//! Servers share a store between tasks through `AsyncFlagStore`, which every `Arc<Mutex<_>>`
+//! around a `FlagStore` implements.
+
+pub mod file;
+pub mod memory;
+pub mod sqlite;
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::db::batch::{Batch, BatchMode, BatchOperation, BatchResult, OperationResult};
+use crate::db::changes::{ChangeCursor, FlagChanges};
+use crate::db::environments::{Environment, NewEnvironment};
+use crate::db::events::FlagEvent;
+use crate::db::projects::{NewProject, Project};
+use crate::db::segments::SegmentUpdate;
+use crate::db::variations::Variation;
+use crate::db::{DeleteOutcome, Flag, FlagFilter, FlagList, FlagPage, FlagUpdate, FlagWithID};
+use crate::error::FeatureFlagError;
+use crate::eval::{Evaluation, EvaluationContext, Rule, Segment};
+use crate::value::FlagValue;
+
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+pub use self::sqlite::SqliteStore;
+
+type Result<T> = std::result::Result<T, FeatureFlagError>;
+
+/// Reads and changes flags and everything attached to them.
+///
+/// Flags are addressed within a project, and a flag id from another project is reported as not
+/// found. Changes to flags are recorded in the audit log under `actor`.
+pub trait FlagStore {
+    /// The flag with its state in `env`.
+    fn get_flag_by_name(&self, project: &str, env: &str, name: &str) -> Result<FlagWithID>;
+    fn get_flag_by_id(&self, project: &str, env: &str, id: u64) -> Result<FlagWithID>;
+    fn get_all_flags(&self, project: &str, env: &str) -> Result<Vec<FlagWithID>>;
+    /// One page of the flags matching `filter`, see `db::listing`.
+    fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> Result<FlagList>;
+    /// Adds a flag that is turned on in `env` and off in every other environment.
+    fn add_flag(&self, project: &str, env: &str, flag: Flag, actor: &str) -> Result<()>;
+    /// Applies every field set on `update` to the flag's state in `env`, or nothing at all.
+    fn update_flag(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        update: FlagUpdate,
+        actor: &str,
+    ) -> Result<()>;
+    /// Removes the flag from every environment. A missing flag is `DeleteOutcome::NotFound`
+    /// rather than an error, a missing project is still `NotFound`.
+    fn delete_flag(&self, project: &str, id: u64, actor: &str) -> Result<DeleteOutcome>;
+    fn delete_flag_by_name(&self, project: &str, name: &str, actor: &str) -> Result<DeleteOutcome>;
+    /// Applies the operations of `batch` in order, in one transaction. An all-or-nothing batch
+    /// keeps none of its changes once an operation fails, see `apply_operations`.
+    fn apply_batch(
+        &self,
+        project: &str,
+        env: &str,
+        batch: Batch,
+        actor: &str,
+    ) -> Result<BatchResult>;
+    /// Audit log of the flag since it was created, oldest first.
+    fn get_flag_history(&self, project: &str, id: u64) -> Result<Vec<FlagEvent>>;
+    /// Audit log of every flag that was ever called `name`, including deleted ones.
+    fn get_flag_history_by_name(&self, project: &str, name: &str) -> Result<Vec<FlagEvent>>;
+    /// Flags created, changed or deleted after `cursor`, with their state in `env`. A cursor the
+    /// store did not hand out gets every flag, see `FlagChanges`.
+    fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> Result<FlagChanges>;
+
+    fn get_variations(&self, project: &str, id: u64) -> Result<Vec<Variation>>;
+    fn add_variation(
+        &self,
+        project: &str,
+        id: u64,
+        variation: Variation,
+        actor: &str,
+    ) -> Result<()>;
+    fn update_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        value: FlagValue,
+        actor: &str,
+    ) -> Result<()>;
+    /// Variations that are still served cannot be removed.
+    fn delete_variation(&self, project: &str, id: u64, name: &str, actor: &str) -> Result<usize>;
+
+    /// Targeting rules of the flag in `env`, in the order they are evaluated.
+    fn get_rules(&self, project: &str, env: &str, id: u64) -> Result<Vec<Rule>>;
+    fn set_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        rules: Vec<Rule>,
+        actor: &str,
+    ) -> Result<()>;
+
+    fn get_segments(&self, project: &str) -> Result<Vec<Segment>>;
+    fn get_segment(&self, project: &str, name: &str) -> Result<Segment>;
+    fn add_segment(&self, project: &str, segment: Segment) -> Result<()>;
+    /// Also changes the flags whose rules refer to the segment, returns their ids.
+    fn update_segment(
+        &self,
+        project: &str,
+        name: &str,
+        update: SegmentUpdate,
+        actor: &str,
+    ) -> Result<Vec<u64>>;
+    /// Segments that rules still refer to cannot be removed.
+    fn delete_segment(&self, project: &str, name: &str) -> Result<usize>;
+
+    fn get_environments(&self) -> Result<Vec<Environment>>;
+    /// Adds an environment holding a copy of every flag's state in another environment.
+    fn add_environment(&self, environment: NewEnvironment) -> Result<()>;
+    fn delete_environment(&self, name: &str) -> Result<usize>;
+
+    fn get_projects(&self) -> Result<Vec<Project>>;
+    fn add_project(&self, project: NewProject) -> Result<()>;
+    /// Only projects without flags can be removed.
+    fn delete_project(&self, name: &str) -> Result<usize>;
+
+    /// Evaluates a flag in `env` for `context`, see `eval::evaluate`.
+    fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> Result<Evaluation>;
+    /// Evaluates every flag of the project, keyed by flag name.
+    fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> Result<BTreeMap<String, Evaluation>>;
+}
+
+/// The async flavour of `FlagStore`, for stores shared between tasks. Every method does the same
+/// as its `FlagStore` namesake.
+pub trait AsyncFlagStore: Clone + Send + Sync + 'static {
+    fn get_flag_by_name(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<FlagWithID>> + Send;
+    fn get_flag_by_id(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+    ) -> impl Future<Output = Result<FlagWithID>> + Send;
+    fn get_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+    ) -> impl Future<Output = Result<Vec<FlagWithID>>> + Send;
+    fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> impl Future<Output = Result<FlagList>> + Send;
+    fn add_flag(
+        &self,
+        project: &str,
+        env: &str,
+        flag: Flag,
+        actor: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn update_flag(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        update: FlagUpdate,
+        actor: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn delete_flag(
+        &self,
+        project: &str,
+        id: u64,
+        actor: &str,
+    ) -> impl Future<Output = Result<DeleteOutcome>> + Send;
+    fn delete_flag_by_name(
+        &self,
+        project: &str,
+        name: &str,
+        actor: &str,
+    ) -> impl Future<Output = Result<DeleteOutcome>> + Send;
+    fn apply_batch(
+        &self,
+        project: &str,
+        env: &str,
+        batch: Batch,
+        actor: &str,
+    ) -> impl Future<Output = Result<BatchResult>> + Send;
+    fn get_flag_history(
+        &self,
+        project: &str,
+        id: u64,
+    ) -> impl Future<Output = Result<Vec<FlagEvent>>> + Send;
+    fn get_flag_history_by_name(
+        &self,
+        project: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<Vec<FlagEvent>>> + Send;
+    fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> impl Future<Output = Result<FlagChanges>> + Send;
+
+    fn get_variations(
+        &self,
+        project: &str,
+        id: u64,
+    ) -> impl Future<Output = Result<Vec<Variation>>> + Send;
+    fn add_variation(
+        &self,
+        project: &str,
+        id: u64,
+        variation: Variation,
+        actor: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn update_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        value: FlagValue,
+        actor: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn delete_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        actor: &str,
+    ) -> impl Future<Output = Result<usize>> + Send;
+
+    fn get_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+    ) -> impl Future<Output = Result<Vec<Rule>>> + Send;
+    fn set_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        rules: Vec<Rule>,
+        actor: &str,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn get_segments(&self, project: &str) -> impl Future<Output = Result<Vec<Segment>>> + Send;
+    fn get_segment(
+        &self,
+        project: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<Segment>> + Send;
+    fn add_segment(
+        &self,
+        project: &str,
+        segment: Segment,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn update_segment(
+        &self,
+        project: &str,
+        name: &str,
+        update: SegmentUpdate,
+        actor: &str,
+    ) -> impl Future<Output = Result<Vec<u64>>> + Send;
+    fn delete_segment(
+        &self,
+        project: &str,
+        name: &str,
+    ) -> impl Future<Output = Result<usize>> + Send;
+
+    fn get_environments(&self) -> impl Future<Output = Result<Vec<Environment>>> + Send;
+    fn add_environment(
+        &self,
+        environment: NewEnvironment,
+    ) -> impl Future<Output = Result<()>> + Send;
+    fn delete_environment(&self, name: &str) -> impl Future<Output = Result<usize>> + Send;
+
+    fn get_projects(&self) -> impl Future<Output = Result<Vec<Project>>> + Send;
+    fn add_project(&self, project: NewProject) -> impl Future<Output = Result<()>> + Send;
+    fn delete_project(&self, name: &str) -> impl Future<Output = Result<usize>> + Send;
+
+    fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> impl Future<Output = Result<Evaluation>> + Send;
+    fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> impl Future<Output = Result<BTreeMap<String, Evaluation>>> + Send;
+}
+
+/// Each call holds the lock for the duration of one `FlagStore` call.
+impl<S: FlagStore + Send + 'static> AsyncFlagStore for Arc<Mutex<S>> {
+    async fn get_flag_by_name(&self, project: &str, env: &str, name: &str) -> Result<FlagWithID> {
+        self.lock().await.get_flag_by_name(project, env, name)
+    }
+
+    async fn get_flag_by_id(&self, project: &str, env: &str, id: u64) -> Result<FlagWithID> {
+        self.lock().await.get_flag_by_id(project, env, id)
+    }
+
+    async fn get_all_flags(&self, project: &str, env: &str) -> Result<Vec<FlagWithID>> {
+        self.lock().await.get_all_flags(project, env)
+    }
+
+    async fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> Result<FlagList> {
+        self.lock().await.list_flags(project, env, filter, page)
+    }
+
+    async fn add_flag(&self, project: &str, env: &str, flag: Flag, actor: &str) -> Result<()> {
+        self.lock().await.add_flag(project, env, flag, actor)
+    }
+
+    async fn update_flag(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        update: FlagUpdate,
+        actor: &str,
+    ) -> Result<()> {
+        self.lock()
+            .await
+            .update_flag(project, env, id, update, actor)
+    }
+
+    async fn delete_flag(&self, project: &str, id: u64, actor: &str) -> Result<DeleteOutcome> {
+        self.lock().await.delete_flag(project, id, actor)
+    }
+
+    async fn delete_flag_by_name(
+        &self,
+        project: &str,
+        name: &str,
+        actor: &str,
+    ) -> Result<DeleteOutcome> {
+        self.lock().await.delete_flag_by_name(project, name, actor)
+    }
+
+    async fn apply_batch(
+        &self,
+        project: &str,
+        env: &str,
+        batch: Batch,
+        actor: &str,
+    ) -> Result<BatchResult> {
+        self.lock().await.apply_batch(project, env, batch, actor)
+    }
+
+    async fn get_flag_history(&self, project: &str, id: u64) -> Result<Vec<FlagEvent>> {
+        self.lock().await.get_flag_history(project, id)
+    }
+
+    async fn get_flag_history_by_name(&self, project: &str, name: &str) -> Result<Vec<FlagEvent>> {
+        self.lock().await.get_flag_history_by_name(project, name)
+    }
+
+    async fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> Result<FlagChanges> {
+        self.lock().await.get_flag_changes(project, env, cursor)
+    }
+
+    async fn get_variations(&self, project: &str, id: u64) -> Result<Vec<Variation>> {
+        self.lock().await.get_variations(project, id)
+    }
+
+    async fn add_variation(
+        &self,
+        project: &str,
+        id: u64,
+        variation: Variation,
+        actor: &str,
+    ) -> Result<()> {
+        self.lock()
+            .await
+            .add_variation(project, id, variation, actor)
+    }
+
+    async fn update_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        value: FlagValue,
+        actor: &str,
+    ) -> Result<()> {
+        self.lock()
+            .await
+            .update_variation(project, id, name, value, actor)
+    }
+
+    async fn delete_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        actor: &str,
+    ) -> Result<usize> {
+        self.lock().await.delete_variation(project, id, name, actor)
+    }
+
+    async fn get_rules(&self, project: &str, env: &str, id: u64) -> Result<Vec<Rule>> {
+        self.lock().await.get_rules(project, env, id)
+    }
+
+    async fn set_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        rules: Vec<Rule>,
+        actor: &str,
+    ) -> Result<()> {
+        self.lock().await.set_rules(project, env, id, rules, actor)
+    }
+
+    async fn get_segments(&self, project: &str) -> Result<Vec<Segment>> {
+        self.lock().await.get_segments(project)
+    }
+
+    async fn get_segment(&self, project: &str, name: &str) -> Result<Segment> {
+        self.lock().await.get_segment(project, name)
+    }
+
+    async fn add_segment(&self, project: &str, segment: Segment) -> Result<()> {
+        self.lock().await.add_segment(project, segment)
+    }
+
+    async fn update_segment(
+        &self,
+        project: &str,
+        name: &str,
+        update: SegmentUpdate,
+        actor: &str,
+    ) -> Result<Vec<u64>> {
+        self.lock()
+            .await
+            .update_segment(project, name, update, actor)
+    }
+
+    async fn delete_segment(&self, project: &str, name: &str) -> Result<usize> {
+        self.lock().await.delete_segment(project, name)
+    }
+
+    async fn get_environments(&self) -> Result<Vec<Environment>> {
+        self.lock().await.get_environments()
+    }
+
+    async fn add_environment(&self, environment: NewEnvironment) -> Result<()> {
+        self.lock().await.add_environment(environment)
+    }
+
+    async fn delete_environment(&self, name: &str) -> Result<usize> {
+        self.lock().await.delete_environment(name)
+    }
+
+    async fn get_projects(&self) -> Result<Vec<Project>> {
+        self.lock().await.get_projects()
+    }
+
+    async fn add_project(&self, project: NewProject) -> Result<()> {
+        self.lock().await.add_project(project)
+    }
+
+    async fn delete_project(&self, name: &str) -> Result<usize> {
+        self.lock().await.delete_project(name)
+    }
+
+    async fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> Result<Evaluation> {
+        self.lock().await.evaluate_flag(project, env, name, context)
+    }
+
+    async fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> Result<BTreeMap<String, Evaluation>> {
+        self.lock().await.evaluate_all_flags(project, env, context)
+    }
+}
+
+/// Applies the operations of `batch` one after the other through `store`, for stores to
+/// implement `FlagStore::apply_batch` with. Once an operation of an all-or-nothing batch fails
+/// the rest are not tried, and the store has to undo the ones before when the result is not
+/// `committed`.
+pub(crate) fn apply_operations<S: FlagStore + ?Sized>(
+    store: &S,
+    project: &str,
+    env: &str,
+    batch: Batch,
+    actor: &str,
+) -> BatchResult {
+    let all_or_nothing = batch.mode == BatchMode::AllOrNothing;
+    let mut failed = false;
+    let mut results = Vec::with_capacity(batch.operations.len());
+
+    for operation in batch.operations {
+        if failed && all_or_nothing {
+            results.push(OperationResult::Aborted);
+            continue;
+        }
+
+        match apply_operation(store, project, env, operation, actor) {
+            Ok(result) => results.push(result),
+            Err(err) => {
+                failed = true;
+                results.push(OperationResult::Failed(err));
+            }
+        }
+    }
+
+    let committed = !(failed && all_or_nothing);
+    if !committed {
+        for result in results.iter_mut() {
+            if !matches!(result, OperationResult::Failed(_)) {
+                *result = OperationResult::Aborted;
+            }
+        }
+    }
+
+    BatchResult { committed, results }
+}
+
+fn apply_operation<S: FlagStore + ?Sized>(
+    store: &S,
+    project: &str,
+    env: &str,
+    operation: BatchOperation,
+    actor: &str,
+) -> Result<OperationResult> {
+    match operation {
+        BatchOperation::Create { flag } => {
+            let name = flag.name.clone();
+            store.add_flag(project, env, flag, actor)?;
+
+            Ok(OperationResult::Created(
+                store.get_flag_by_name(project, env, &name)?,
+            ))
+        }
+        BatchOperation::Update { name, update } => {
+            let id = store.get_flag_by_name(project, env, &name)?.id as u64;
+            store.update_flag(project, env, id, update, actor)?;
+
+            Ok(OperationResult::Updated(
+                store.get_flag_by_id(project, env, id)?,
+            ))
+        }
+        BatchOperation::Delete { name } => {
+            let flag = store.get_flag_by_name(project, env, &name)?;
+            match store.delete_flag(project, flag.id as u64, actor)? {
+                DeleteOutcome::Deleted => Ok(OperationResult::Deleted(flag)),
+                DeleteOutcome::NotFound => {
+                    Err(FeatureFlagError::NotFound(format!("Flag {}", name)))
+                }
+            }
+        }
+    }
+}
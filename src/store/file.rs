@@ -0,0 +1,849 @@
+//! Flags defined in a directory of YAML, JSON or TOML files, so that they can be kept in git and
+//! changed through reviews. The store is read-only.
+//!
+//! Each file holds flags and segments of one project:
+//!
+//! ```yaml
+//! project: payments          # "default" when left out
+//! segments:
+//!   - name: beta-testers
+//!     included: [user-42]
+//! flags:
+//!   - name: checkout
+//!     owner: payments-team   # also `description` and `tags`
+//!     value: control         # served in every environment, turned on unless `enabled: false`
+//!     variations:
+//!       - {name: control, value: control}
+//!       - {name: beta, value: beta}
+//!     rules:
+//!       - clauses: [{op: segment, values: [beta-testers]}]
+//!         variation: beta
+//!     environments:          # overrides per environment
+//!       staging:
+//!         rollout_percentage: 50
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{FlagStore, MemoryStore, Result};
+use crate::db::batch::{Batch, BatchResult};
+use crate::db::changes::{ChangeCursor, FlagChanges};
+use crate::db::environments::{Environment, NewEnvironment, DEFAULT_ENVIRONMENT};
+use crate::db::events::FlagEvent;
+use crate::db::projects::{NewProject, Project, DEFAULT_PROJECT};
+use crate::db::segments::SegmentUpdate;
+use crate::db::variations::Variation;
+use crate::db::{DeleteOutcome, Flag, FlagMetadata, FlagUpdate, FlagWithID};
+use crate::db::{FlagFilter, FlagList, FlagPage};
+use crate::error::FeatureFlagError;
+use crate::eval::{Evaluation, EvaluationContext, Rule, Segment};
+use crate::value::{FlagType, FlagValue};
+
+/// What a flag serves, either in every environment or in one of them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StateDefinition {
+    #[serde(default)]
+    value: Option<FlagValue>,
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    default_variation: Option<String>,
+    #[serde(default)]
+    off_variation: Option<String>,
+    #[serde(default)]
+    rollout_percentage: Option<f64>,
+    #[serde(default)]
+    rules: Option<Vec<Rule>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlagDefinition {
+    name: String,
+    #[serde(rename = "type", default)]
+    flag_type: Option<FlagType>,
+    value: FlagValue,
+    #[serde(default)]
+    enabled: Option<bool>,
+    #[serde(default)]
+    default_variation: Option<String>,
+    #[serde(default)]
+    off_variation: Option<String>,
+    #[serde(default)]
+    rollout_percentage: Option<f64>,
+    #[serde(default)]
+    rules: Vec<Rule>,
+    #[serde(default)]
+    salt: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    variations: Vec<Variation>,
+    /// Keyed by environment name
+    #[serde(default)]
+    environments: BTreeMap<String, StateDefinition>,
+}
+
+impl FlagDefinition {
+    /// Everything the flag serves in `env`, the environment's settings taking precedence.
+    fn update(&self, env: &str) -> (FlagUpdate, Vec<Rule>) {
+        let state = self.environments.get(env);
+        let state = match state {
+            Some(state) => state,
+            None => &StateDefinition::default(),
+        };
+
+        let update = FlagUpdate {
+            value: Some(state.value.clone().unwrap_or_else(|| self.value.clone())),
+            enabled: Some(state.enabled.or(self.enabled).unwrap_or(true)),
+            default_variation: Some(
+                state
+                    .default_variation
+                    .clone()
+                    .or_else(|| self.default_variation.clone()),
+            ),
+            off_variation: Some(
+                state
+                    .off_variation
+                    .clone()
+                    .or_else(|| self.off_variation.clone()),
+            ),
+            rollout_percentage: Some(state.rollout_percentage.or(self.rollout_percentage)),
+            salt: Some(self.salt.clone()),
+            expected_version: None,
+        };
+        let rules = state.rules.clone().unwrap_or_else(|| self.rules.clone());
+
+        (update, rules)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlagFile {
+    #[serde(default)]
+    project: Option<String>,
+    #[serde(default)]
+    flags: Vec<FlagDefinition>,
+    #[serde(default)]
+    segments: Vec<Segment>,
+}
+
+impl FlagFile {
+    fn project(&self) -> &str {
+        self.project.as_deref().unwrap_or(DEFAULT_PROJECT)
+    }
+}
+
+/// Path, modification time and size of every flag file, to tell when the directory changed.
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+fn is_flag_file(path: &Path) -> bool {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    matches!(extension, Some("yaml" | "yml" | "json" | "toml"))
+}
+
+fn fingerprint(dir: &Path) -> Result<Fingerprint> {
+    let mut result = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || !is_flag_file(&path) {
+            continue;
+        }
+
+        let metadata = fs::metadata(&path)?;
+        result.push((path, metadata.modified().ok(), metadata.len()));
+    }
+    result.sort();
+
+    Ok(result)
+}
+
+/// Ties an error to the file it was found in.
+fn in_file(path: &Path, err: FeatureFlagError) -> FeatureFlagError {
+    FeatureFlagError::Validation(format!("{}: {}", path.display(), err))
+}
+
+fn parse(path: &Path) -> Result<FlagFile> {
+    let content = fs::read_to_string(path)?;
+    let extension = path.extension().and_then(|extension| extension.to_str());
+
+    let parsed = match extension {
+        Some("json") => serde_json::from_str(&content).map_err(|err| err.to_string()),
+        Some("toml") => toml::from_str(&content).map_err(|err| err.to_string()),
+        _ => serde_yaml::from_str(&content).map_err(|err| err.to_string()),
+    };
+
+    parsed
+        .map_err(|message| FeatureFlagError::Validation(format!("{}: {}", path.display(), message)))
+}
+
+/// Fills a new store with the flags of every file. Fails on the first invalid definition.
+///
+/// The store takes an epoch after `after`, so that cursors handed out by the store being replaced
+/// get every flag rather than just the ones numbered higher by chance.
+fn load(files: &[(PathBuf, FlagFile)], after: u64) -> Result<MemoryStore> {
+    let store = MemoryStore::new();
+    store.start_epoch_after(after);
+
+    let mut projects = BTreeSet::new();
+    let mut environments = BTreeSet::new();
+    for (_, file) in files.iter() {
+        projects.insert(file.project());
+        for flag in file.flags.iter() {
+            environments.extend(flag.environments.keys().map(String::as_str));
+        }
+    }
+    for name in projects.into_iter().filter(|name| *name != DEFAULT_PROJECT) {
+        let project = NewProject {
+            name: name.to_string(),
+        };
+        store.add_project(project)?;
+    }
+    for name in environments
+        .into_iter()
+        .filter(|name| *name != DEFAULT_ENVIRONMENT)
+    {
+        let environment = NewEnvironment {
+            name: name.to_string(),
+            copy_from: None,
+        };
+        store.add_environment(environment)?;
+    }
+
+    // Rules can refer to segments of any file of the project
+    for (path, file) in files.iter() {
+        for segment in file.segments.iter() {
+            if store.get_segment(file.project(), &segment.name).is_ok() {
+                return Err(in_file(
+                    path,
+                    FeatureFlagError::Validation(format!(
+                        "segment {} is defined more than once",
+                        segment.name
+                    )),
+                ));
+            }
+
+            store
+                .add_segment(file.project(), segment.clone())
+                .map_err(|err| in_file(path, err))?;
+        }
+    }
+
+    let environments = store.get_environments()?;
+    for (path, file) in files.iter() {
+        let project = file.project();
+        let actor = path.display().to_string();
+
+        for definition in file.flags.iter() {
+            let added = add_flag(&store, project, &environments, definition, &actor);
+            added.map_err(|err| in_file(path, err))?;
+        }
+    }
+
+    // The files are versioned, loading them is not a change worth recording
+    store.clear_history();
+
+    Ok(store)
+}
+
+fn add_flag(
+    store: &MemoryStore,
+    project: &str,
+    environments: &[Environment],
+    definition: &FlagDefinition,
+    actor: &str,
+) -> Result<()> {
+    if store
+        .get_flag_by_name(project, DEFAULT_ENVIRONMENT, &definition.name)
+        .is_ok()
+    {
+        return Err(FeatureFlagError::Validation(format!(
+            "flag {} is defined more than once",
+            definition.name
+        )));
+    }
+
+    let flag = Flag {
+        name: definition.name.clone(),
+        flag_type: definition.flag_type,
+        value: definition.value.clone(),
+        metadata: FlagMetadata {
+            description: definition.description.clone(),
+            owner: definition.owner.clone(),
+            tags: definition.tags.clone(),
+        },
+    };
+    store.add_flag(project, DEFAULT_ENVIRONMENT, flag, actor)?;
+    let id = store
+        .get_flag_by_name(project, DEFAULT_ENVIRONMENT, &definition.name)?
+        .id as u64;
+
+    for variation in definition.variations.iter() {
+        store.add_variation(project, id, variation.clone(), actor)?;
+    }
+    for environment in environments.iter() {
+        let (update, rules) = definition.update(&environment.name);
+
+        store.update_flag(project, &environment.name, id, update, actor)?;
+        store.set_rules(project, &environment.name, id, rules, actor)?;
+    }
+
+    Ok(())
+}
+
+/// Everything a flag serves and says about itself in every environment, along with the segments
+/// its rules refer to, leaving out what loading it decides.
+fn definition_of(store: &MemoryStore, project: &str, name: &str) -> Result<Value> {
+    let mut environments = serde_json::Map::new();
+    let mut segments = BTreeMap::new();
+    for environment in store.get_environments()? {
+        let flag = store.get_flag_by_name(project, &environment.name, name)?;
+        let id = flag.id as u64;
+
+        let mut state = serde_json::to_value(flag).unwrap_or_default();
+        if let Value::Object(fields) = &mut state {
+            for field in ["id", "version", "created_at", "updated_at"] {
+                fields.remove(field);
+            }
+        }
+        let rules = store.get_rules(project, &environment.name, id)?;
+        for segment in rules.iter().flat_map(Rule::segments) {
+            if !segments.contains_key(segment) {
+                segments.insert(segment.to_string(), store.get_segment(project, segment)?);
+            }
+        }
+        environments.insert(environment.name, json!({"state": state, "rules": rules}));
+    }
+
+    let id = store
+        .get_flag_by_name(project, DEFAULT_ENVIRONMENT, name)?
+        .id as u64;
+    Ok(json!({
+        "environments": environments,
+        "variations": store.get_variations(project, id)?,
+        "segments": segments,
+    }))
+}
+
+/// Carries the versions of the flags in `previous` over to the same flags, by name, in `store`.
+/// Flags whose definition changed move on to the next version, so that their entity tags change
+/// with them and only with them.
+fn carry_versions(previous: &MemoryStore, store: &MemoryStore) -> Result<()> {
+    for project in store.get_projects()? {
+        let project = project.name;
+
+        for flag in store.get_all_flags(&project, DEFAULT_ENVIRONMENT)? {
+            let before = match previous.get_flag_by_name(&project, DEFAULT_ENVIRONMENT, &flag.name)
+            {
+                Ok(before) => before,
+                Err(_) => continue,
+            };
+
+            let changed = definition_of(previous, &project, &flag.name)?
+                != definition_of(store, &project, &flag.name)?;
+            store.carry_version(&project, flag.id as u64, &before, changed)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_only() -> FeatureFlagError {
+    FeatureFlagError::ReadOnly("flags are defined in files, change the files instead".to_string())
+}
+
+/// Flags read from a directory of files, see the module documentation.
+///
+/// Flag ids follow the order of the files and of the flags in them, so they can change when the
+/// files do. Versions carry over when the files are read again and only go up for flags whose
+/// definition changed. The audit log stays empty, the history of the files is in git.
+pub struct FileStore {
+    dir: PathBuf,
+    fingerprint: Fingerprint,
+    flags: MemoryStore,
+}
+
+impl FileStore {
+    /// Reads every `.yaml`, `.yml`, `.json` and `.toml` file in `dir`, failing with `Validation`
+    /// when any of them is invalid.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileStore> {
+        let dir = dir.as_ref().to_path_buf();
+        let fingerprint = fingerprint(&dir)?;
+        let flags = FileStore::read(&fingerprint, 0)?;
+
+        Ok(FileStore {
+            dir,
+            fingerprint,
+            flags,
+        })
+    }
+
+    fn read(fingerprint: &Fingerprint, after: u64) -> Result<MemoryStore> {
+        let mut files = vec![];
+        for (path, _, _) in fingerprint.iter() {
+            files.push((path.clone(), parse(path)?));
+        }
+
+        load(&files, after)
+    }
+
+    /// Reads the files again when any of them was added, changed or removed, returning whether
+    /// they were. Invalid files are reported and leave the flags that were served before.
+    pub fn reload_if_changed(&mut self) -> Result<bool> {
+        let fingerprint = fingerprint(&self.dir)?;
+        if fingerprint == self.fingerprint {
+            return Ok(false);
+        }
+
+        // Not retried until the files change again
+        let flags = FileStore::read(&fingerprint, self.flags.epoch())
+            .and_then(|flags| carry_versions(&self.flags, &flags).map(|_| flags));
+        self.fingerprint = fingerprint;
+        self.flags = flags?;
+
+        Ok(true)
+    }
+}
+
+impl FlagStore for FileStore {
+    fn get_flag_by_name(&self, project: &str, env: &str, name: &str) -> Result<FlagWithID> {
+        self.flags.get_flag_by_name(project, env, name)
+    }
+
+    fn get_flag_by_id(&self, project: &str, env: &str, id: u64) -> Result<FlagWithID> {
+        self.flags.get_flag_by_id(project, env, id)
+    }
+
+    fn get_all_flags(&self, project: &str, env: &str) -> Result<Vec<FlagWithID>> {
+        self.flags.get_all_flags(project, env)
+    }
+
+    fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> Result<FlagList> {
+        self.flags.list_flags(project, env, filter, page)
+    }
+
+    fn add_flag(&self, _: &str, _: &str, _: Flag, _: &str) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn update_flag(&self, _: &str, _: &str, _: u64, _: FlagUpdate, _: &str) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn delete_flag(&self, _: &str, _: u64, _: &str) -> Result<DeleteOutcome> {
+        Err(read_only())
+    }
+
+    fn delete_flag_by_name(&self, _: &str, _: &str, _: &str) -> Result<DeleteOutcome> {
+        Err(read_only())
+    }
+
+    fn apply_batch(&self, _: &str, _: &str, _: Batch, _: &str) -> Result<BatchResult> {
+        Err(read_only())
+    }
+
+    fn get_flag_history(&self, project: &str, id: u64) -> Result<Vec<FlagEvent>> {
+        self.flags.get_flag_history(project, id)
+    }
+
+    fn get_flag_history_by_name(&self, project: &str, name: &str) -> Result<Vec<FlagEvent>> {
+        self.flags.get_flag_history_by_name(project, name)
+    }
+
+    fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> Result<FlagChanges> {
+        self.flags.get_flag_changes(project, env, cursor)
+    }
+
+    fn get_variations(&self, project: &str, id: u64) -> Result<Vec<Variation>> {
+        self.flags.get_variations(project, id)
+    }
+
+    fn add_variation(&self, _: &str, _: u64, _: Variation, _: &str) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn update_variation(&self, _: &str, _: u64, _: &str, _: FlagValue, _: &str) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn delete_variation(&self, _: &str, _: u64, _: &str, _: &str) -> Result<usize> {
+        Err(read_only())
+    }
+
+    fn get_rules(&self, project: &str, env: &str, id: u64) -> Result<Vec<Rule>> {
+        self.flags.get_rules(project, env, id)
+    }
+
+    fn set_rules(&self, _: &str, _: &str, _: u64, _: Vec<Rule>, _: &str) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn get_segments(&self, project: &str) -> Result<Vec<Segment>> {
+        self.flags.get_segments(project)
+    }
+
+    fn get_segment(&self, project: &str, name: &str) -> Result<Segment> {
+        self.flags.get_segment(project, name)
+    }
+
+    fn add_segment(&self, _: &str, _: Segment) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn update_segment(&self, _: &str, _: &str, _: SegmentUpdate, _: &str) -> Result<Vec<u64>> {
+        Err(read_only())
+    }
+
+    fn delete_segment(&self, _: &str, _: &str) -> Result<usize> {
+        Err(read_only())
+    }
+
+    fn get_environments(&self) -> Result<Vec<Environment>> {
+        self.flags.get_environments()
+    }
+
+    fn add_environment(&self, _: NewEnvironment) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn delete_environment(&self, _: &str) -> Result<usize> {
+        Err(read_only())
+    }
+
+    fn get_projects(&self) -> Result<Vec<Project>> {
+        self.flags.get_projects()
+    }
+
+    fn add_project(&self, _: NewProject) -> Result<()> {
+        Err(read_only())
+    }
+
+    fn delete_project(&self, _: &str) -> Result<usize> {
+        Err(read_only())
+    }
+
+    fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> Result<Evaluation> {
+        self.flags.evaluate_flag(project, env, name, context)
+    }
+
+    fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> Result<BTreeMap<String, Evaluation>> {
+        self.flags.evaluate_all_flags(project, env, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::eval::Reason;
+
+    /// A fresh directory under the system's temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("feature-flags-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    const CHECKOUT_YAML: &str = "
+segments:
+  - name: beta-testers
+    included: [user-42]
+flags:
+  - name: checkout
+    value: control
+    variations:
+      - {name: control, value: control}
+      - {name: beta, value: beta}
+    rules:
+      - clauses: [{op: segment, values: [beta-testers]}]
+        variation: beta
+    environments:
+      staging:
+        enabled: false
+";
+
+    const SEARCH_TOML: &str = r#"
+project = "payments"
+
+[[flags]]
+name = "timeout"
+type = "float"
+value = 30
+"#;
+
+    const LIMITS_JSON: &str = r#"{
+    "project": "payments",
+    "flags": [{"name": "limit", "value": 100, "rollout_percentage": 10}]
+}"#;
+
+    #[test]
+    fn test_reads_every_format() {
+        let dir = temp_dir("formats");
+        fs::write(dir.join("checkout.yaml"), CHECKOUT_YAML).unwrap();
+        fs::write(dir.join("search.toml"), SEARCH_TOML).unwrap();
+        fs::write(dir.join("limits.json"), LIMITS_JSON).unwrap();
+        fs::write(dir.join("README.md"), "not a flag file").unwrap();
+
+        let store = FileStore::open(&dir).unwrap();
+
+        let projects: Vec<_> = store
+            .get_projects()
+            .unwrap()
+            .into_iter()
+            .map(|project| project.name)
+            .collect();
+        assert_eq!(projects, vec![DEFAULT_PROJECT, "payments"]);
+
+        let flags = store
+            .get_all_flags("payments", DEFAULT_ENVIRONMENT)
+            .unwrap();
+        assert_eq!(flags.len(), 2);
+        assert_eq!(flags[0].rollout_percentage, Some(10.0));
+        assert_eq!(flags[1].value, FlagValue::Float(30.0));
+
+        let staging = store
+            .get_flag_by_name(DEFAULT_PROJECT, "staging", "checkout")
+            .unwrap();
+        assert!(!staging.enabled);
+
+        let context = EvaluationContext::new("user-42");
+        let evaluation = store
+            .evaluate_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "checkout", &context)
+            .unwrap();
+        assert_eq!(
+            evaluation.value,
+            Some(FlagValue::String("beta".to_string()))
+        );
+        assert_eq!(evaluation.reason, Reason::RuleMatch { rule_index: 0 });
+
+        assert!(store
+            .get_flag_history_by_name(DEFAULT_PROJECT, "checkout")
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = temp_dir("read-only");
+        fs::write(dir.join("checkout.yaml"), CHECKOUT_YAML).unwrap();
+
+        let store = FileStore::open(&dir).unwrap();
+
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        let result = store.update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "ada");
+        assert!(matches!(result, Err(FeatureFlagError::ReadOnly(_))));
+        let result = store.delete_flag_by_name(DEFAULT_PROJECT, "checkout", "ada");
+        assert!(matches!(result, Err(FeatureFlagError::ReadOnly(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let dir = temp_dir("invalid");
+
+        // Unknown fields are typos rather than something to ignore
+        fs::write(
+            dir.join("checkout.json"),
+            json!({"flags": [{"name": "checkout", "value": true, "enabeld": false}]}).to_string(),
+        )
+        .unwrap();
+        assert!(matches!(
+            FileStore::open(&dir),
+            Err(FeatureFlagError::Validation(_))
+        ));
+
+        // Rules have to serve a variation of the flag
+        fs::write(
+            dir.join("checkout.json"),
+            json!({"flags": [{
+                "name": "checkout",
+                "value": true,
+                "rules": [{"clauses": [{"attribute": "key", "op": "in", "values": ["ada"]}], "variation": "on"}],
+            }]})
+            .to_string(),
+        )
+        .unwrap();
+        let result = FileStore::open(&dir);
+        match result {
+            Err(FeatureFlagError::Validation(message)) => {
+                assert!(message.ends_with("checkout.json: rule serves unknown variation on"))
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        // The same flag in two files
+        fs::write(
+            dir.join("checkout.json"),
+            json!({"flags": [{"name": "checkout", "value": true}]}).to_string(),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("other.yaml"),
+            "flags: [{name: checkout, value: false}]",
+        )
+        .unwrap();
+        assert!(matches!(
+            FileStore::open(&dir),
+            Err(FeatureFlagError::Validation(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = temp_dir("reload");
+        fs::write(
+            dir.join("checkout.yaml"),
+            "flags: [{name: checkout, value: true}]",
+        )
+        .unwrap();
+
+        let mut store = FileStore::open(&dir).unwrap();
+        assert!(!store.reload_if_changed().unwrap());
+        let cursor = store
+            .get_flag_changes(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                ChangeCursor::default(),
+            )
+            .unwrap()
+            .cursor;
+
+        fs::write(
+            dir.join("search.yaml"),
+            "flags: [{name: search, value: false}]",
+        )
+        .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(
+            store
+                .get_all_flags(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT)
+                .unwrap()
+                .len(),
+            2
+        );
+        // Every flag counts as changed for cursors from before
+        let changes = store
+            .get_flag_changes(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, cursor)
+            .unwrap();
+        assert!(changes.full && changes.cursor.epoch > cursor.epoch);
+        assert_eq!(changes.flags.len(), 2);
+
+        // Broken files keep the flags that were served
+        fs::write(dir.join("search.yaml"), "flags: [{name: search}]").unwrap();
+        assert!(store.reload_if_changed().is_err());
+        assert_eq!(
+            store
+                .get_all_flags(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_etags_of_unchanged_flags() {
+        let dir = temp_dir("etags");
+        fs::write(dir.join("checkout.yaml"), CHECKOUT_YAML).unwrap();
+        fs::write(
+            dir.join("search.yaml"),
+            "flags: [{name: search, value: false}]",
+        )
+        .unwrap();
+
+        let mut store = FileStore::open(&dir).unwrap();
+        let etag = |store: &FileStore, name: &str| {
+            store
+                .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, name)
+                .unwrap()
+                .etag()
+        };
+        let checkout = etag(&store, "checkout");
+        let search = etag(&store, "search");
+
+        // Only the value of one variation changes
+        fs::write(
+            dir.join("checkout.yaml"),
+            CHECKOUT_YAML.replace("{name: beta, value: beta}", "{name: beta, value: beta-2}"),
+        )
+        .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_ne!(etag(&store, "checkout"), checkout);
+        assert_eq!(etag(&store, "search"), search);
+
+        let checkout = etag(&store, "checkout");
+        fs::write(
+            dir.join("search.yaml"),
+            "flags: [{name: search, value: false, tags: [beta]}]",
+        )
+        .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(etag(&store, "checkout"), checkout);
+        assert_ne!(etag(&store, "search"), search);
+
+        // The segment the rules of checkout refer to changes who gets beta
+        let search = etag(&store, "search");
+        fs::write(
+            dir.join("checkout.yaml"),
+            CHECKOUT_YAML
+                .replace("{name: beta, value: beta}", "{name: beta, value: beta-2}")
+                .replace("[user-42]", "[user-42, user-7]"),
+        )
+        .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_ne!(etag(&store, "checkout"), checkout);
+        assert_eq!(etag(&store, "search"), search);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
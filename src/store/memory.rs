@@ -0,0 +1,1400 @@
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::Value;
+
+use super::{apply_operations, FlagStore, Result};
+use crate::db::batch::{Batch, BatchResult};
+use crate::db::changes::{ChangeCursor, DeletedFlag, FlagChanges};
+use crate::db::environments::{Environment, NewEnvironment, DEFAULT_ENVIRONMENT};
+use crate::db::events::{rules_value, segment_value, variations_value, FlagAction, FlagEvent};
+use crate::db::projects::{NewProject, Project, DEFAULT_PROJECT};
+use crate::db::segments::SegmentUpdate;
+use crate::db::variations::Variation;
+use crate::db::{self, listing, DeleteOutcome, Flag, FlagMetadata, FlagUpdate, FlagWithID};
+use crate::db::{FlagFilter, FlagList, FlagPage};
+use crate::error::FeatureFlagError;
+use crate::eval::{self, Evaluation, EvaluationContext, Rule, Segment};
+use crate::value::{FlagType, FlagValue};
+
+/// State of a flag in one environment.
+#[derive(Clone)]
+struct FlagState {
+    value: FlagValue,
+    enabled: bool,
+    default_variation: Option<String>,
+    off_variation: Option<String>,
+    rollout_percentage: Option<f64>,
+}
+
+#[derive(Clone)]
+struct StoredFlag {
+    id: u64,
+    project: String,
+    name: String,
+    flag_type: FlagType,
+    salt: String,
+    version: u64,
+    metadata: FlagMetadata,
+    created_at: String,
+    updated_at: String,
+    created_by: String,
+    /// Number of its latest change
+    changed: u64,
+    /// Keyed by environment name
+    states: BTreeMap<String, FlagState>,
+    variations: Vec<Variation>,
+    /// Keyed by environment name
+    rules: BTreeMap<String, Vec<Rule>>,
+}
+
+impl StoredFlag {
+    fn with_id(&self, env: &str) -> Result<FlagWithID> {
+        let state = self
+            .states
+            .get(env)
+            .ok_or_else(|| FeatureFlagError::NotFound(format!("Environment {}", env)))?;
+
+        Ok(FlagWithID {
+            id: self.id as i32,
+            name: self.name.clone(),
+            flag_type: self.flag_type,
+            value: state.value.clone(),
+            enabled: state.enabled,
+            default_variation: state.default_variation.clone(),
+            off_variation: state.off_variation.clone(),
+            rollout_percentage: state.rollout_percentage,
+            salt: self.salt.clone(),
+            version: self.version,
+            metadata: self.metadata.clone(),
+            created_at: self.created_at.clone(),
+            updated_at: self.updated_at.clone(),
+            created_by: self.created_by.clone(),
+        })
+    }
+
+    /// Moves to the next version after change `seq`.
+    fn touch(&mut self, seq: u64) {
+        self.version += 1;
+        self.changed = seq;
+        self.updated_at = now();
+    }
+
+    fn has_variation(&self, name: &str) -> bool {
+        self.variations
+            .iter()
+            .any(|variation| variation.name == name)
+    }
+}
+
+/// A deleted flag, see `FlagStore::get_flag_changes`.
+#[derive(Clone)]
+struct Tombstone {
+    project: String,
+    /// Number of the deletion
+    changed: u64,
+    flag: DeletedFlag,
+}
+
+#[derive(Clone)]
+struct State {
+    projects: Vec<Project>,
+    environments: Vec<Environment>,
+    flags: Vec<StoredFlag>,
+    /// Segments with the name of their project
+    segments: Vec<(String, Segment)>,
+    events: Vec<FlagEvent>,
+    tombstones: Vec<Tombstone>,
+}
+
+/// Flags kept in memory, starting out with the default project and environment.
+///
+/// Behaves like `SqliteStore`, including its errors. Nothing is persisted.
+pub struct MemoryStore {
+    state: RefCell<State>,
+    /// Number of the latest change, kept apart so that flags borrowed from the state can take one
+    latest_change: Cell<u64>,
+    /// Sets the cursors of this store apart from those of others, see `ChangeCursor`
+    epoch: Cell<u64>,
+}
+
+/// Current UTC time in the format SQLite stores timestamps in, e.g. `2023-05-01T12:00:00.000Z`.
+fn now() -> String {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = elapsed.as_secs();
+    let time = seconds % 86_400;
+
+    // Days since the epoch to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+impl State {
+    fn ensure_project(&self, name: &str) -> Result<()> {
+        if self.projects.iter().any(|project| project.name == name) {
+            Ok(())
+        } else {
+            Err(FeatureFlagError::NotFound(format!("Project {}", name)))
+        }
+    }
+
+    fn ensure_environment(&self, name: &str) -> Result<()> {
+        if self.environments.iter().any(|env| env.name == name) {
+            Ok(())
+        } else {
+            Err(FeatureFlagError::NotFound(format!("Environment {}", name)))
+        }
+    }
+
+    fn flag_index(&self, project: &str, id: u64) -> Result<usize> {
+        self.ensure_project(project)?;
+
+        self.flags
+            .iter()
+            .position(|flag| flag.id == id && flag.project == project)
+            .ok_or_else(|| FeatureFlagError::NotFound(format!("Flag {}", id)))
+    }
+
+    fn flag(&self, project: &str, id: u64) -> Result<&StoredFlag> {
+        let index = self.flag_index(project, id)?;
+
+        Ok(&self.flags[index])
+    }
+
+    fn flag_by_name(&self, project: &str, name: &str) -> Result<Option<&StoredFlag>> {
+        self.ensure_project(project)?;
+
+        Ok(self
+            .flags
+            .iter()
+            .find(|flag| flag.project == project && flag.name == name))
+    }
+
+    fn project_segments(&self, project: &str) -> Vec<Segment> {
+        self.segments
+            .iter()
+            .filter(|(owner, _)| owner == project)
+            .map(|(_, segment)| segment.clone())
+            .collect()
+    }
+
+    /// Indexes of the flags of `project` whose rules refer to segment `name`, in any environment.
+    fn segment_users(&self, project: &str, name: &str) -> Vec<usize> {
+        (0..self.flags.len())
+            .filter(|index| {
+                let flag = &self.flags[*index];
+                flag.project == project
+                    && flag
+                        .rules
+                        .values()
+                        .flatten()
+                        .any(|rule| rule.segments().any(|segment| segment == name))
+            })
+            .collect()
+    }
+
+    /// State of a flag in every environment, keyed by environment name.
+    fn snapshot(&self, flag: &StoredFlag) -> Result<Value> {
+        let mut snapshot = serde_json::Map::new();
+        for environment in self.environments.iter() {
+            snapshot.insert(
+                environment.name.clone(),
+                serde_json::to_value(flag.with_id(&environment.name)?).unwrap_or_default(),
+            );
+        }
+
+        Ok(Value::Object(snapshot))
+    }
+
+    fn record(
+        &mut self,
+        flag: &StoredFlag,
+        environment: Option<&str>,
+        actor: &str,
+        action: FlagAction,
+        values: (Option<Value>, Option<Value>),
+    ) {
+        let event = FlagEvent {
+            id: self.events.len() as i64 + 1,
+            flag_id: flag.id,
+            project: flag.project.clone(),
+            flag: flag.name.clone(),
+            environment: environment.map(str::to_string),
+            actor: actor.to_string(),
+            timestamp: now(),
+            action,
+            old_value: values.0,
+            new_value: values.1,
+        };
+        self.events.push(event);
+    }
+
+    /// Records the change of the variations of the flag at `index` from `old`.
+    fn record_variations(&mut self, index: usize, old: Value, actor: &str) {
+        let flag = self.flags[index].clone();
+        let values = (Some(old), Some(variations_value(&flag.variations)));
+        self.record(&flag, None, actor, FlagAction::Updated, values);
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        let state = State {
+            projects: vec![Project {
+                id: 1,
+                name: DEFAULT_PROJECT.to_string(),
+            }],
+            environments: vec![Environment {
+                id: 1,
+                name: DEFAULT_ENVIRONMENT.to_string(),
+            }],
+            flags: vec![],
+            segments: vec![],
+            events: vec![],
+            tombstones: vec![],
+        };
+
+        MemoryStore {
+            state: RefCell::new(state),
+            latest_change: Cell::new(0),
+            epoch: Cell::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_millis() as u64),
+            ),
+        }
+    }
+
+    fn next_change(&self) -> u64 {
+        self.latest_change.set(self.latest_change.get() + 1);
+
+        self.latest_change.get()
+    }
+
+    /// Epoch of the cursors, see `FlagStore::get_flag_changes`.
+    pub(super) fn epoch(&self) -> u64 {
+        self.epoch.get()
+    }
+
+    /// Takes an epoch after `epoch`, the one of the store this one replaces, whose cursors then
+    /// get every flag even when it was made within the same millisecond.
+    pub(super) fn start_epoch_after(&self, epoch: u64) {
+        self.epoch.set(self.epoch.get().max(epoch + 1));
+    }
+
+    /// Gives a flag the version and timestamps of `previous`, the same flag in the store this one
+    /// replaces, moving on to the next version when the flag `changed` since.
+    pub(super) fn carry_version(
+        &self,
+        project: &str,
+        id: u64,
+        previous: &FlagWithID,
+        changed: bool,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+        let flag = &mut state.flags[index];
+
+        flag.version = previous.version;
+        flag.created_at = previous.created_at.clone();
+        match changed {
+            true => flag.version += 1,
+            false => flag.updated_at = previous.updated_at.clone(),
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the audit log, e.g. once a store was filled from elsewhere.
+    pub(super) fn clear_history(&self) {
+        self.state.borrow_mut().events.clear();
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl FlagStore for MemoryStore {
+    fn get_flag_by_name(&self, project: &str, env: &str, name: &str) -> Result<FlagWithID> {
+        let state = self.state.borrow();
+        state.ensure_project(project)?;
+        state.ensure_environment(env)?;
+
+        state
+            .flag_by_name(project, name)?
+            .ok_or_else(|| FeatureFlagError::NotFound(format!("Flag {}", name)))?
+            .with_id(env)
+    }
+
+    fn get_flag_by_id(&self, project: &str, env: &str, id: u64) -> Result<FlagWithID> {
+        let state = self.state.borrow();
+        let flag = state.flag(project, id)?;
+        state.ensure_environment(env)?;
+
+        flag.with_id(env)
+    }
+
+    fn get_all_flags(&self, project: &str, env: &str) -> Result<Vec<FlagWithID>> {
+        let state = self.state.borrow();
+        state.ensure_project(project)?;
+        state.ensure_environment(env)?;
+
+        state
+            .flags
+            .iter()
+            .filter(|flag| flag.project == project)
+            .map(|flag| flag.with_id(env))
+            .collect()
+    }
+
+    fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> Result<FlagList> {
+        listing::page_flags(self.get_all_flags(project, env)?, filter, page)
+    }
+
+    fn add_flag(&self, project: &str, env: &str, flag: Flag, actor: &str) -> Result<()> {
+        let flag_type = flag.resolved_type();
+        let value = flag.value.into_type(flag_type)?;
+
+        let mut state = self.state.borrow_mut();
+        if state.flag_by_name(project, &flag.name)?.is_some() {
+            return Err(FeatureFlagError::AlreadyExists(format!(
+                "Flag {}",
+                flag.name
+            )));
+        }
+        state.ensure_environment(env)?;
+
+        let states = state
+            .environments
+            .iter()
+            .map(|environment| {
+                let flag_state = FlagState {
+                    value: value.clone(),
+                    enabled: environment.name == env,
+                    default_variation: None,
+                    off_variation: None,
+                    rollout_percentage: None,
+                };
+                (environment.name.clone(), flag_state)
+            })
+            .collect();
+        let stored = StoredFlag {
+            id: state.flags.iter().map(|flag| flag.id).max().unwrap_or(0) + 1,
+            project: project.to_string(),
+            name: flag.name,
+            flag_type,
+            salt: String::new(),
+            version: 1,
+            metadata: flag.metadata,
+            created_at: now(),
+            updated_at: now(),
+            created_by: actor.to_string(),
+            changed: self.next_change(),
+            states,
+            variations: vec![],
+            rules: BTreeMap::new(),
+        };
+
+        let snapshot = state.snapshot(&stored)?;
+        state.record(
+            &stored,
+            None,
+            actor,
+            FlagAction::Created,
+            (None, Some(snapshot)),
+        );
+        state.flags.push(stored);
+
+        Ok(())
+    }
+
+    fn update_flag(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        update: FlagUpdate,
+        actor: &str,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+        state.ensure_environment(env)?;
+
+        // Changes are made to a copy so that a failing field leaves the flag as it was
+        let mut flag = state.flags[index].clone();
+        let old = flag.with_id(env)?;
+        db::check_version(&old, update.expected_version)?;
+        if update.is_empty() {
+            return Ok(());
+        }
+        let has_variation = |name: &str| flag.has_variation(name);
+
+        let mut flag_state = flag.states[env].clone();
+        if let Some(value) = update.value {
+            flag_state.value = value.into_type(flag.flag_type)?;
+        }
+        if let Some(enabled) = update.enabled {
+            flag_state.enabled = enabled;
+        }
+        let served = [
+            (&mut flag_state.default_variation, update.default_variation),
+            (&mut flag_state.off_variation, update.off_variation),
+        ];
+        for (current, variation) in served {
+            // Field left out of the update
+            let variation = match variation {
+                Some(variation) => variation,
+                None => continue,
+            };
+
+            if let Some(name) = &variation {
+                if !has_variation(name) {
+                    return Err(FeatureFlagError::Validation(format!(
+                        "flag {} has no variation named {}",
+                        flag.name, name
+                    )));
+                }
+            }
+            *current = variation;
+        }
+        if let Some(percentage) = update.rollout_percentage {
+            if let Some(percentage) = percentage {
+                if !(0.0..=100.0).contains(&percentage) {
+                    return Err(FeatureFlagError::Validation(format!(
+                        "rollout percentage {} is not between 0 and 100",
+                        percentage
+                    )));
+                }
+            }
+            flag_state.rollout_percentage = percentage;
+        }
+
+        flag.states.insert(env.to_string(), flag_state);
+        if let Some(salt) = update.salt {
+            flag.salt = salt;
+        }
+        flag.touch(self.next_change());
+
+        let values = (
+            Some(serde_json::to_value(old).unwrap_or_default()),
+            Some(serde_json::to_value(flag.with_id(env)?).unwrap_or_default()),
+        );
+        state.record(&flag, Some(env), actor, FlagAction::Updated, values);
+        state.flags[index] = flag;
+
+        Ok(())
+    }
+
+    fn delete_flag(&self, project: &str, id: u64, actor: &str) -> Result<DeleteOutcome> {
+        let mut state = self.state.borrow_mut();
+        state.ensure_project(project)?;
+        let index = match state
+            .flags
+            .iter()
+            .position(|flag| flag.id == id && flag.project == project)
+        {
+            Some(index) => index,
+            None => return Ok(DeleteOutcome::NotFound),
+        };
+
+        let flag = state.flags.remove(index);
+        let snapshot = state.snapshot(&flag)?;
+        let tombstone = Tombstone {
+            project: flag.project.clone(),
+            changed: self.next_change(),
+            flag: DeletedFlag {
+                id: flag.id,
+                name: flag.name.clone(),
+            },
+        };
+        state.tombstones.push(tombstone);
+        state.record(
+            &flag,
+            None,
+            actor,
+            FlagAction::Deleted,
+            (Some(snapshot), None),
+        );
+
+        Ok(DeleteOutcome::Deleted)
+    }
+
+    fn delete_flag_by_name(&self, project: &str, name: &str, actor: &str) -> Result<DeleteOutcome> {
+        let id = self
+            .state
+            .borrow()
+            .flag_by_name(project, name)?
+            .map(|flag| flag.id);
+
+        match id {
+            Some(id) => self.delete_flag(project, id, actor),
+            None => Ok(DeleteOutcome::NotFound),
+        }
+    }
+
+    fn apply_batch(
+        &self,
+        project: &str,
+        env: &str,
+        batch: Batch,
+        actor: &str,
+    ) -> Result<BatchResult> {
+        let state = self.state.borrow().clone();
+        let latest_change = self.latest_change.get();
+
+        let result = apply_operations(self, project, env, batch, actor);
+        if !result.committed {
+            *self.state.borrow_mut() = state;
+            self.latest_change.set(latest_change);
+        }
+
+        Ok(result)
+    }
+
+    fn get_flag_history(&self, project: &str, id: u64) -> Result<Vec<FlagEvent>> {
+        let state = self.state.borrow();
+        state.flag(project, id)?;
+
+        // Ids of deleted flags are handed out again
+        let created = state
+            .events
+            .iter()
+            .rposition(|event| event.flag_id == id && event.action == FlagAction::Created)
+            .unwrap_or(0);
+
+        Ok(state.events[created..]
+            .iter()
+            .filter(|event| event.flag_id == id)
+            .cloned()
+            .collect())
+    }
+
+    fn get_flag_history_by_name(&self, project: &str, name: &str) -> Result<Vec<FlagEvent>> {
+        let state = self.state.borrow();
+        state.ensure_project(project)?;
+
+        Ok(state
+            .events
+            .iter()
+            .filter(|event| event.project == project && event.flag == name)
+            .cloned()
+            .collect())
+    }
+
+    fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> Result<FlagChanges> {
+        let state = self.state.borrow();
+        state.ensure_project(project)?;
+        state.ensure_environment(env)?;
+
+        let latest = ChangeCursor {
+            epoch: self.epoch.get(),
+            seq: self.latest_change.get(),
+        };
+        let mut changes = FlagChanges {
+            cursor: latest,
+            full: false,
+            flags: vec![],
+            deleted: vec![],
+        };
+        if cursor == latest {
+            return Ok(changes);
+        }
+        if cursor.epoch != latest.epoch || cursor.seq > latest.seq {
+            changes.full = true;
+            changes.flags = self.get_all_flags(project, env)?;
+            return Ok(changes);
+        }
+
+        let mut flags: Vec<&StoredFlag> = state
+            .flags
+            .iter()
+            .filter(|flag| flag.project == project && flag.changed > cursor.seq)
+            .collect();
+        flags.sort_by_key(|flag| flag.changed);
+        changes.flags = flags
+            .into_iter()
+            .map(|flag| flag.with_id(env))
+            .collect::<Result<_>>()?;
+        changes.deleted = state
+            .tombstones
+            .iter()
+            .filter(|tombstone| tombstone.project == project && tombstone.changed > cursor.seq)
+            .map(|tombstone| tombstone.flag.clone())
+            .collect();
+
+        Ok(changes)
+    }
+
+    fn get_variations(&self, project: &str, id: u64) -> Result<Vec<Variation>> {
+        Ok(self.state.borrow().flag(project, id)?.variations.clone())
+    }
+
+    fn add_variation(
+        &self,
+        project: &str,
+        id: u64,
+        variation: Variation,
+        actor: &str,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+        let flag = &mut state.flags[index];
+
+        if variation.name.trim().is_empty() {
+            return Err(FeatureFlagError::Validation(
+                "variation name must not be empty".to_string(),
+            ));
+        }
+        let value = variation.value.into_type(flag.flag_type)?;
+        if flag.has_variation(&variation.name) {
+            return Err(FeatureFlagError::AlreadyExists(format!(
+                "Variation {}",
+                variation.name
+            )));
+        }
+
+        let old = variations_value(&flag.variations);
+        flag.variations.push(Variation {
+            name: variation.name,
+            value,
+        });
+        flag.touch(self.next_change());
+
+        state.record_variations(index, old, actor);
+
+        Ok(())
+    }
+
+    fn update_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        value: FlagValue,
+        actor: &str,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+        let flag = &mut state.flags[index];
+
+        let value = value.into_type(flag.flag_type)?;
+        let old = variations_value(&flag.variations);
+        let variation = flag
+            .variations
+            .iter_mut()
+            .find(|variation| variation.name == name)
+            .ok_or_else(|| {
+                FeatureFlagError::NotFound(format!("Variation {} of flag {}", name, id))
+            })?;
+        variation.value = value;
+        flag.touch(self.next_change());
+
+        state.record_variations(index, old, actor);
+
+        Ok(())
+    }
+
+    fn delete_variation(&self, project: &str, id: u64, name: &str, actor: &str) -> Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+
+        let flag = &state.flags[index];
+        let serving: Vec<String> = state
+            .environments
+            .iter()
+            .map(|environment| environment.name.clone())
+            .filter(|env| {
+                let served = flag.states.get(env).is_some_and(|flag_state| {
+                    flag_state.default_variation.as_deref() == Some(name)
+                        || flag_state.off_variation.as_deref() == Some(name)
+                });
+                let ruled = flag
+                    .rules
+                    .get(env)
+                    .is_some_and(|rules| rules.iter().any(|rule| rule.variation == name));
+
+                served || ruled
+            })
+            .collect();
+        if !serving.is_empty() {
+            return Err(FeatureFlagError::Conflict(format!(
+                "variation {} is served in {}",
+                name,
+                serving.join(", ")
+            )));
+        }
+
+        let flag = &mut state.flags[index];
+        let old = variations_value(&flag.variations);
+        let before = flag.variations.len();
+        flag.variations.retain(|variation| variation.name != name);
+        let deleted = before - flag.variations.len();
+        if deleted > 0 {
+            flag.touch(self.next_change());
+            state.record_variations(index, old, actor);
+        }
+
+        Ok(deleted)
+    }
+
+    fn get_rules(&self, project: &str, env: &str, id: u64) -> Result<Vec<Rule>> {
+        let state = self.state.borrow();
+        let flag = state.flag(project, id)?;
+        state.ensure_environment(env)?;
+
+        Ok(flag.rules.get(env).cloned().unwrap_or_default())
+    }
+
+    fn set_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        rules: Vec<Rule>,
+        actor: &str,
+    ) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let index = state.flag_index(project, id)?;
+        state.ensure_environment(env)?;
+
+        let flag = &state.flags[index];
+        let segments = state.project_segments(project);
+        for rule in rules.iter() {
+            for clause in rule.clauses.iter() {
+                clause.validate()?;
+            }
+
+            for name in rule.segments() {
+                if !segments.iter().any(|segment| segment.name == name) {
+                    return Err(FeatureFlagError::Validation(format!(
+                        "rule refers to unknown segment {}",
+                        name
+                    )));
+                }
+            }
+
+            if !flag.has_variation(&rule.variation) {
+                return Err(FeatureFlagError::Validation(format!(
+                    "rule serves unknown variation {}",
+                    rule.variation
+                )));
+            }
+        }
+
+        let values = (
+            Some(rules_value(
+                &flag.rules.get(env).cloned().unwrap_or_default(),
+            )),
+            Some(rules_value(&rules)),
+        );
+        state.flags[index].rules.insert(env.to_string(), rules);
+        state.flags[index].touch(self.next_change());
+        let flag = state.flags[index].clone();
+        state.record(&flag, Some(env), actor, FlagAction::Updated, values);
+
+        Ok(())
+    }
+
+    fn get_segments(&self, project: &str) -> Result<Vec<Segment>> {
+        let state = self.state.borrow();
+        state.ensure_project(project)?;
+
+        Ok(state.project_segments(project))
+    }
+
+    fn get_segment(&self, project: &str, name: &str) -> Result<Segment> {
+        self.get_segments(project)?
+            .into_iter()
+            .find(|segment| segment.name == name)
+            .ok_or_else(|| FeatureFlagError::NotFound(format!("Segment {}", name)))
+    }
+
+    fn add_segment(&self, project: &str, segment: Segment) -> Result<()> {
+        db::validate_name("segment", &segment.name)?;
+        segment.validate()?;
+
+        let mut state = self.state.borrow_mut();
+        state.ensure_project(project)?;
+        let exists = state
+            .segments
+            .iter()
+            .any(|(owner, existing)| owner == project && existing.name == segment.name);
+        if exists {
+            return Err(FeatureFlagError::AlreadyExists(format!(
+                "Segment {}",
+                segment.name
+            )));
+        }
+
+        state.segments.push((project.to_string(), segment));
+
+        Ok(())
+    }
+
+    fn update_segment(
+        &self,
+        project: &str,
+        name: &str,
+        update: SegmentUpdate,
+        actor: &str,
+    ) -> Result<Vec<u64>> {
+        let old = self.get_segment(project, name)?;
+        let mut segment = old.clone();
+
+        if let Some(included) = update.included {
+            segment.included = included;
+        }
+        if let Some(excluded) = update.excluded {
+            segment.excluded = excluded;
+        }
+        if let Some(rules) = update.rules {
+            segment.rules = rules;
+        }
+        segment.validate()?;
+
+        let mut state = self.state.borrow_mut();
+        if let Some((_, stored)) = state
+            .segments
+            .iter_mut()
+            .find(|(owner, existing)| owner == project && existing.name == name)
+        {
+            *stored = segment.clone();
+        }
+
+        let users = state.segment_users(project, name);
+        let values = (Some(segment_value(&old)), Some(segment_value(&segment)));
+        for index in users.iter() {
+            state.flags[*index].touch(self.next_change());
+            let flag = state.flags[*index].clone();
+            state.record(&flag, None, actor, FlagAction::Updated, values.clone());
+        }
+
+        Ok(users.iter().map(|index| state.flags[*index].id).collect())
+    }
+
+    fn delete_segment(&self, project: &str, name: &str) -> Result<usize> {
+        let mut state = self.state.borrow_mut();
+        state.ensure_project(project)?;
+
+        let flags: Vec<String> = state
+            .segment_users(project, name)
+            .into_iter()
+            .map(|index| state.flags[index].name.clone())
+            .collect();
+        if !flags.is_empty() {
+            return Err(FeatureFlagError::Conflict(format!(
+                "segment {} is used by {}",
+                name,
+                flags.join(", ")
+            )));
+        }
+
+        let before = state.segments.len();
+        state
+            .segments
+            .retain(|(owner, segment)| !(owner == project && segment.name == name));
+
+        Ok(before - state.segments.len())
+    }
+
+    fn get_environments(&self) -> Result<Vec<Environment>> {
+        Ok(self.state.borrow().environments.clone())
+    }
+
+    fn add_environment(&self, environment: NewEnvironment) -> Result<()> {
+        db::validate_name("environment", &environment.name)?;
+        let source = environment
+            .copy_from
+            .as_deref()
+            .unwrap_or(DEFAULT_ENVIRONMENT);
+
+        let mut state = self.state.borrow_mut();
+        state.ensure_environment(source)?;
+        if state
+            .environments
+            .iter()
+            .any(|existing| existing.name == environment.name)
+        {
+            return Err(FeatureFlagError::AlreadyExists(format!(
+                "Environment {}",
+                environment.name
+            )));
+        }
+
+        let id = state
+            .environments
+            .iter()
+            .map(|env| env.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        state.environments.push(Environment {
+            id,
+            name: environment.name.clone(),
+        });
+        for flag in state.flags.iter_mut() {
+            if let Some(flag_state) = flag.states.get(source).cloned() {
+                flag.states.insert(environment.name.clone(), flag_state);
+            }
+            if let Some(rules) = flag.rules.get(source).cloned() {
+                flag.rules.insert(environment.name.clone(), rules);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_environment(&self, name: &str) -> Result<usize> {
+        if name == DEFAULT_ENVIRONMENT {
+            return Err(FeatureFlagError::Validation(format!(
+                "the {} environment cannot be deleted",
+                DEFAULT_ENVIRONMENT
+            )));
+        }
+
+        let mut state = self.state.borrow_mut();
+        for flag in state.flags.iter_mut() {
+            flag.states.remove(name);
+            flag.rules.remove(name);
+        }
+
+        let before = state.environments.len();
+        state.environments.retain(|env| env.name != name);
+
+        Ok(before - state.environments.len())
+    }
+
+    fn get_projects(&self) -> Result<Vec<Project>> {
+        Ok(self.state.borrow().projects.clone())
+    }
+
+    fn add_project(&self, project: NewProject) -> Result<()> {
+        db::validate_name("project", &project.name)?;
+
+        let mut state = self.state.borrow_mut();
+        if state
+            .projects
+            .iter()
+            .any(|existing| existing.name == project.name)
+        {
+            return Err(FeatureFlagError::AlreadyExists(format!(
+                "Project {}",
+                project.name
+            )));
+        }
+
+        let id = state.projects.iter().map(|p| p.id).max().unwrap_or(0) + 1;
+        state.projects.push(Project {
+            id,
+            name: project.name,
+        });
+
+        Ok(())
+    }
+
+    fn delete_project(&self, name: &str) -> Result<usize> {
+        if name == DEFAULT_PROJECT {
+            return Err(FeatureFlagError::Validation(format!(
+                "the {} project cannot be deleted",
+                DEFAULT_PROJECT
+            )));
+        }
+
+        let mut state = self.state.borrow_mut();
+        let flags = state
+            .flags
+            .iter()
+            .filter(|flag| flag.project == name)
+            .count();
+        if flags > 0 {
+            return Err(FeatureFlagError::Conflict(format!(
+                "project {} still has {} flag(s)",
+                name, flags
+            )));
+        }
+
+        state.segments.retain(|(owner, _)| owner != name);
+        let before = state.projects.len();
+        state.projects.retain(|project| project.name != name);
+
+        Ok(before - state.projects.len())
+    }
+
+    fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> Result<Evaluation> {
+        let flag = self.get_flag_by_name(project, env, name)?;
+        let id = flag.id as u64;
+        let variations = self.get_variations(project, id)?;
+        let rules = self.get_rules(project, env, id)?;
+        let segments = self.get_segments(project)?;
+
+        Ok(eval::evaluate(
+            &flag,
+            &variations,
+            &rules,
+            &segments,
+            context,
+        ))
+    }
+
+    fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> Result<BTreeMap<String, Evaluation>> {
+        let flags = self.get_all_flags(project, env)?;
+        let segments = self.get_segments(project)?;
+
+        let mut result = BTreeMap::new();
+        for flag in flags {
+            let id = flag.id as u64;
+            let loaded = self
+                .get_variations(project, id)
+                .and_then(|variations| Ok((variations, self.get_rules(project, env, id)?)));
+
+            let evaluation = match loaded {
+                Ok((variations, rules)) => {
+                    eval::evaluate(&flag, &variations, &rules, &segments, context)
+                }
+                Err(err) => Evaluation::error(&err),
+            };
+            result.insert(flag.name, evaluation);
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::batch::OperationResult;
+
+    fn add_checkout(store: &MemoryStore) -> Result<()> {
+        let flag = Flag {
+            name: "checkout".to_string(),
+            flag_type: None,
+            value: FlagValue::Bool(true),
+            metadata: FlagMetadata::default(),
+        };
+
+        store.add_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, flag, "ada")
+    }
+
+    #[test]
+    fn test_timestamp_format() {
+        let timestamp = now();
+
+        assert_eq!(timestamp.len(), "2023-05-01T12:00:00.000Z".len());
+        assert!(timestamp.starts_with("20"));
+        assert!(timestamp.ends_with('Z'));
+    }
+
+    #[test]
+    fn test_flags_and_history() {
+        let store = MemoryStore::new();
+
+        add_checkout(&store).unwrap();
+        assert!(add_checkout(&store).is_err());
+
+        let update = FlagUpdate {
+            enabled: Some(false),
+            rollout_percentage: Some(Some(120.0)),
+            ..FlagUpdate::default()
+        };
+        let result = store.update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "bob");
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+
+        // The failed update changed nothing
+        let flag = store
+            .get_flag_by_id(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1)
+            .unwrap();
+        assert!(flag.enabled);
+
+        // Nothing to change, so no version bump and no event
+        store
+            .update_flag(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                1,
+                FlagUpdate::default(),
+                "bob",
+            )
+            .unwrap();
+        let flag = store
+            .get_flag_by_id(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1)
+            .unwrap();
+        assert_eq!(flag.version, 1);
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        store
+            .update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "bob")
+            .unwrap();
+        assert_eq!(
+            DeleteOutcome::Deleted,
+            store
+                .delete_flag_by_name(DEFAULT_PROJECT, "checkout", "carol")
+                .unwrap()
+        );
+
+        let history = store
+            .get_flag_history_by_name(DEFAULT_PROJECT, "checkout")
+            .unwrap();
+        let actions: Vec<_> = history.iter().map(|event| event.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                FlagAction::Created,
+                FlagAction::Updated,
+                FlagAction::Deleted
+            ]
+        );
+        assert_eq!(
+            history[1].new_value.as_ref().unwrap()["enabled"],
+            json!(false)
+        );
+
+        // A new flag reusing the id starts with an empty history
+        add_checkout(&store).unwrap();
+        assert_eq!(store.get_flag_history(DEFAULT_PROJECT, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_history_of_variations_and_rules() {
+        let store = MemoryStore::new();
+
+        add_checkout(&store).unwrap();
+        let variation = Variation {
+            name: "off".to_string(),
+            value: FlagValue::Bool(false),
+        };
+        store
+            .add_variation(DEFAULT_PROJECT, 1, variation, "bob")
+            .unwrap();
+        let rule: Rule = serde_json::from_value(json!({
+            "clauses": [{"attribute": "country", "op": "in", "values": ["fr"]}],
+            "variation": "off"
+        }))
+        .unwrap();
+        store
+            .set_rules(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, vec![rule], "carol")
+            .unwrap();
+
+        let history = store.get_flag_history(DEFAULT_PROJECT, 1).unwrap();
+        let summary: Vec<_> = history
+            .iter()
+            .map(|event| (event.actor.as_str(), event.environment.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("ada", None),
+                ("bob", None),
+                ("carol", Some(DEFAULT_ENVIRONMENT))
+            ]
+        );
+        assert_eq!(history[1].old_value, Some(json!({"variations": []})));
+        assert_eq!(history[2].old_value, Some(json!({"rules": []})));
+    }
+
+    #[test]
+    fn test_environments_copy_flags() {
+        let store = MemoryStore::new();
+
+        add_checkout(&store).unwrap();
+        let environment = NewEnvironment {
+            name: "staging".to_string(),
+            copy_from: None,
+        };
+        store.add_environment(environment).unwrap();
+
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, "staging", "checkout")
+            .unwrap();
+        assert!(flag.enabled);
+
+        let result = store.get_all_flags("payments", DEFAULT_ENVIRONMENT);
+        assert_eq!(
+            format!("{:?}", result),
+            "Err(NotFound(\"Project payments\"))"
+        );
+        let result = store.delete_environment(DEFAULT_ENVIRONMENT);
+        assert!(matches!(result, Err(FeatureFlagError::Validation(_))));
+        assert_eq!(1, store.delete_environment("staging").unwrap());
+    }
+
+    #[test]
+    fn test_flag_changes() {
+        let store = MemoryStore::new();
+        let changes_after = |seq| {
+            let cursor = ChangeCursor {
+                epoch: store.epoch(),
+                seq,
+            };
+            store
+                .get_flag_changes(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, cursor)
+                .unwrap()
+        };
+
+        assert!(changes_after(0).is_empty());
+        add_checkout(&store).unwrap();
+        let changes = store
+            .get_flag_changes(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                ChangeCursor::default(),
+            )
+            .unwrap();
+        assert!(changes.full);
+        assert_eq!((changes.cursor.seq, changes.flags.len()), (1, 1));
+
+        // Nothing to change, so no version bump and no event
+        store
+            .update_flag(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                1,
+                FlagUpdate::default(),
+                "bob",
+            )
+            .unwrap();
+        let flag = store
+            .get_flag_by_id(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1)
+            .unwrap();
+        assert_eq!(flag.version, 1);
+        let update = FlagUpdate {
+            enabled: Some(false),
+            ..FlagUpdate::default()
+        };
+        store
+            .update_flag(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, 1, update, "bob")
+            .unwrap();
+        let changes = changes_after(1);
+        assert!(!changes.full);
+        assert_eq!(changes.cursor.seq, 2);
+        assert!(!changes.flags[0].enabled);
+
+        store.delete_flag(DEFAULT_PROJECT, 1, "carol").unwrap();
+        let changes = changes_after(1);
+        assert!(changes.flags.is_empty());
+        assert_eq!(changes.deleted[0].name, "checkout");
+        assert!(changes_after(3).is_empty());
+        assert!(changes_after(4).full);
+
+        // Cursors of a store this one replaced
+        let cursor = ChangeCursor {
+            epoch: store.epoch(),
+            seq: 3,
+        };
+        let store = MemoryStore::new();
+        store.start_epoch_after(cursor.epoch);
+        let changes = store
+            .get_flag_changes(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, cursor)
+            .unwrap();
+        assert!(changes.full);
+        assert_eq!(changes.cursor.seq, 0);
+        assert!(changes.cursor.epoch > cursor.epoch);
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let store = MemoryStore::new();
+        add_checkout(&store).unwrap();
+        let batch = |mode| {
+            let batch = json!({
+                "mode": mode,
+                "operations": [
+                    {"op": "update", "name": "checkout", "update": {"enabled": false}},
+                    {"op": "delete", "name": "search"},
+                ]
+            });
+            serde_json::from_value::<Batch>(batch).unwrap()
+        };
+
+        let result = store
+            .apply_batch(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                batch("all_or_nothing"),
+                "bob",
+            )
+            .unwrap();
+        assert!(!result.committed);
+        assert!(matches!(
+            result.first_error(),
+            Some(FeatureFlagError::NotFound(_))
+        ));
+        // The update is undone along with its history and change
+        let flag = store
+            .get_flag_by_name(DEFAULT_PROJECT, DEFAULT_ENVIRONMENT, "checkout")
+            .unwrap();
+        assert!(flag.enabled);
+        assert_eq!(flag.version, 1);
+        assert_eq!(store.get_flag_history(DEFAULT_PROJECT, 1).unwrap().len(), 1);
+        assert_eq!(store.latest_change.get(), 1);
+
+        let result = store
+            .apply_batch(
+                DEFAULT_PROJECT,
+                DEFAULT_ENVIRONMENT,
+                batch("best_effort"),
+                "bob",
+            )
+            .unwrap();
+        assert!(result.committed);
+        assert!(matches!(result.results[0], OperationResult::Updated(_)));
+        assert!(matches!(result.results[1], OperationResult::Failed(_)));
+        assert_eq!(store.get_flag_history(DEFAULT_PROJECT, 1).unwrap().len(), 2);
+        assert_eq!(store.latest_change.get(), 2);
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use rusqlite::Connection;
+
+use super::{apply_operations, FlagStore, Result};
+use crate::db::batch::{Batch, BatchResult};
+use crate::db::changes::{self, ChangeCursor, FlagChanges};
+use crate::db::environments::{self, Environment, NewEnvironment};
+use crate::db::events::{self, FlagEvent};
+use crate::db::projects::{self, ensure_flag_in_project, flag_in_project, NewProject, Project};
+use crate::db::segments::{self, SegmentUpdate};
+use crate::db::variations::{self, Variation};
+use crate::db::{self, listing, rules, DeleteOutcome, Flag, FlagUpdate, FlagWithID, Savepoint};
+use crate::db::{FlagFilter, FlagList, FlagPage};
+use crate::eval::{Evaluation, EvaluationContext, Rule, Segment};
+use crate::value::FlagValue;
+
+/// Flags kept in SQLite, see the `db` module. The schema has to be migrated beforehand.
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    pub fn new(conn: Connection) -> SqliteStore {
+        SqliteStore { conn }
+    }
+
+    /// The underlying connection, e.g. for migrations.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+impl FlagStore for SqliteStore {
+    fn get_flag_by_name(&self, project: &str, env: &str, name: &str) -> Result<FlagWithID> {
+        db::get_flag_by_name(&self.conn, project, env, name.to_string())
+    }
+
+    fn get_flag_by_id(&self, project: &str, env: &str, id: u64) -> Result<FlagWithID> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        db::get_flag_by_id(&self.conn, env, id)
+    }
+
+    fn get_all_flags(&self, project: &str, env: &str) -> Result<Vec<FlagWithID>> {
+        db::get_all_flags(&self.conn, project, env)
+    }
+
+    fn list_flags(
+        &self,
+        project: &str,
+        env: &str,
+        filter: &FlagFilter,
+        page: &FlagPage,
+    ) -> Result<FlagList> {
+        listing::list_flags(&self.conn, project, env, filter, page)
+    }
+
+    fn add_flag(&self, project: &str, env: &str, flag: Flag, actor: &str) -> Result<()> {
+        db::insert_flag(&self.conn, project, env, flag, actor).map(|_| ())
+    }
+
+    fn update_flag(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        update: FlagUpdate,
+        actor: &str,
+    ) -> Result<()> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        db::apply_flag_update(&self.conn, env, id, update, actor)
+    }
+
+    fn delete_flag(&self, project: &str, id: u64, actor: &str) -> Result<DeleteOutcome> {
+        if !flag_in_project(&self.conn, project, id)? {
+            return Ok(DeleteOutcome::NotFound);
+        }
+
+        db::delete_flag_by_id(&self.conn, id, actor)
+    }
+
+    fn delete_flag_by_name(&self, project: &str, name: &str, actor: &str) -> Result<DeleteOutcome> {
+        db::delete_flag_by_name(&self.conn, project, name.to_string(), actor)
+    }
+
+    fn apply_batch(
+        &self,
+        project: &str,
+        env: &str,
+        batch: Batch,
+        actor: &str,
+    ) -> Result<BatchResult> {
+        // Every operation is a savepoint of its own inside this one
+        let tx = Savepoint::new(&self.conn)?;
+        let result = apply_operations(self, project, env, batch, actor);
+        if result.committed {
+            tx.commit()?;
+        }
+
+        Ok(result)
+    }
+
+    fn get_flag_history(&self, project: &str, id: u64) -> Result<Vec<FlagEvent>> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        events::get_flag_history(&self.conn, id)
+    }
+
+    fn get_flag_history_by_name(&self, project: &str, name: &str) -> Result<Vec<FlagEvent>> {
+        events::get_flag_history_by_name(&self.conn, project, name)
+    }
+
+    fn get_flag_changes(
+        &self,
+        project: &str,
+        env: &str,
+        cursor: ChangeCursor,
+    ) -> Result<FlagChanges> {
+        changes::get_flag_changes(&self.conn, project, env, cursor)
+    }
+
+    fn get_variations(&self, project: &str, id: u64) -> Result<Vec<Variation>> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        variations::get_variations(&self.conn, id)
+    }
+
+    fn add_variation(
+        &self,
+        project: &str,
+        id: u64,
+        variation: Variation,
+        actor: &str,
+    ) -> Result<()> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        variations::add_variation(&self.conn, id, variation, actor).map(|_| ())
+    }
+
+    fn update_variation(
+        &self,
+        project: &str,
+        id: u64,
+        name: &str,
+        value: FlagValue,
+        actor: &str,
+    ) -> Result<()> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        variations::update_variation(&self.conn, id, name.to_string(), value, actor).map(|_| ())
+    }
+
+    fn delete_variation(&self, project: &str, id: u64, name: &str, actor: &str) -> Result<usize> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        variations::delete_variation(&self.conn, id, name.to_string(), actor)
+    }
+
+    fn get_rules(&self, project: &str, env: &str, id: u64) -> Result<Vec<Rule>> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        rules::get_rules(&self.conn, env, id)
+    }
+
+    fn set_rules(
+        &self,
+        project: &str,
+        env: &str,
+        id: u64,
+        rules: Vec<Rule>,
+        actor: &str,
+    ) -> Result<()> {
+        ensure_flag_in_project(&self.conn, project, id)?;
+
+        rules::set_rules(&self.conn, env, id, rules, actor).map(|_| ())
+    }
+
+    fn get_segments(&self, project: &str) -> Result<Vec<Segment>> {
+        segments::get_segments(&self.conn, project)
+    }
+
+    fn get_segment(&self, project: &str, name: &str) -> Result<Segment> {
+        segments::get_segment(&self.conn, project, name)
+    }
+
+    fn add_segment(&self, project: &str, segment: Segment) -> Result<()> {
+        segments::add_segment(&self.conn, project, segment).map(|_| ())
+    }
+
+    fn update_segment(
+        &self,
+        project: &str,
+        name: &str,
+        update: SegmentUpdate,
+        actor: &str,
+    ) -> Result<Vec<u64>> {
+        segments::update_segment(&self.conn, project, name, update, actor)
+    }
+
+    fn delete_segment(&self, project: &str, name: &str) -> Result<usize> {
+        segments::delete_segment(&self.conn, project, name)
+    }
+
+    fn get_environments(&self) -> Result<Vec<Environment>> {
+        environments::get_environments(&self.conn)
+    }
+
+    fn add_environment(&self, environment: NewEnvironment) -> Result<()> {
+        environments::add_environment(&self.conn, environment).map(|_| ())
+    }
+
+    fn delete_environment(&self, name: &str) -> Result<usize> {
+        environments::delete_environment(&self.conn, name.to_string())
+    }
+
+    fn get_projects(&self) -> Result<Vec<Project>> {
+        projects::get_projects(&self.conn)
+    }
+
+    fn add_project(&self, project: NewProject) -> Result<()> {
+        projects::add_project(&self.conn, project).map(|_| ())
+    }
+
+    fn delete_project(&self, name: &str) -> Result<usize> {
+        projects::delete_project(&self.conn, name.to_string())
+    }
+
+    fn evaluate_flag(
+        &self,
+        project: &str,
+        env: &str,
+        name: &str,
+        context: &EvaluationContext,
+    ) -> Result<Evaluation> {
+        db::evaluate_flag(&self.conn, project, env, name.to_string(), context)
+    }
+
+    fn evaluate_all_flags(
+        &self,
+        project: &str,
+        env: &str,
+        context: &EvaluationContext,
+    ) -> Result<BTreeMap<String, Evaluation>> {
+        db::evaluate_all_flags(&self.conn, project, env, context)
+    }
+}
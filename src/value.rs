@@ -0,0 +1,263 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::FeatureFlagError;
+
+/// The kind of value a flag holds. Stored next to the value so it can be validated on write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagType {
+    Bool,
+    String,
+    Integer,
+    Float,
+    Json,
+}
+
+impl FlagType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FlagType::Bool => "bool",
+            FlagType::String => "string",
+            FlagType::Integer => "integer",
+            FlagType::Float => "float",
+            FlagType::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for FlagType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FlagType {
+    type Err = FeatureFlagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bool" => Ok(FlagType::Bool),
+            "string" => Ok(FlagType::String),
+            "integer" => Ok(FlagType::Integer),
+            "float" => Ok(FlagType::Float),
+            "json" => Ok(FlagType::Json),
+            other => Err(FeatureFlagError::Validation(format!(
+                "unknown flag type '{}'",
+                other
+            ))),
+        }
+    }
+}
+
+impl ToSql for FlagType {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for FlagType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+/// A flag's value. Serialized as the plain JSON value, e.g. `true`, `30`, `"blue"` or `{...}`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FlagValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Json(Value),
+}
+
+impl FlagValue {
+    /// The type a value naturally has when no type was given explicitly.
+    pub fn flag_type(&self) -> FlagType {
+        match self {
+            FlagValue::Bool(_) => FlagType::Bool,
+            FlagValue::Integer(_) => FlagType::Integer,
+            FlagValue::Float(_) => FlagType::Float,
+            FlagValue::String(_) => FlagType::String,
+            FlagValue::Json(_) => FlagType::Json,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            FlagValue::Bool(value) => Value::from(*value),
+            FlagValue::Integer(value) => Value::from(*value),
+            FlagValue::Float(value) => Value::from(*value),
+            FlagValue::String(value) => Value::from(value.as_str()),
+            FlagValue::Json(value) => value.clone(),
+        }
+    }
+
+    /// Checks the value against `flag_type`, widening it where that is lossless
+    /// (integers are accepted for float flags and anything is accepted for json flags).
+    /// `NaN` and infinities are rejected, as JSON cannot hold them.
+    pub fn into_type(self, flag_type: FlagType) -> Result<FlagValue, FeatureFlagError> {
+        if let FlagValue::Float(value) = self {
+            if !value.is_finite() {
+                return Err(FeatureFlagError::Validation(format!(
+                    "{} is not a valid float value",
+                    value
+                )));
+            }
+        }
+
+        match (flag_type, self) {
+            (FlagType::Bool, value @ FlagValue::Bool(_)) => Ok(value),
+            (FlagType::String, value @ FlagValue::String(_)) => Ok(value),
+            (FlagType::Integer, value @ FlagValue::Integer(_)) => Ok(value),
+            (FlagType::Float, value @ FlagValue::Float(_)) => Ok(value),
+            (FlagType::Float, FlagValue::Integer(value)) => Ok(FlagValue::Float(value as f64)),
+            (FlagType::Json, value) => Ok(FlagValue::Json(value.to_json())),
+            (flag_type, value) => Err(FeatureFlagError::Validation(format!(
+                "value {} ({}) does not match flag type {}",
+                value,
+                value.flag_type(),
+                flag_type
+            ))),
+        }
+    }
+
+    /// Builds a value of `flag_type` from its JSON representation, as stored in the db.
+    pub fn from_json(flag_type: FlagType, value: Value) -> Option<FlagValue> {
+        match flag_type {
+            FlagType::Bool => value.as_bool().map(FlagValue::Bool),
+            FlagType::Integer => value.as_i64().map(FlagValue::Integer),
+            FlagType::Float => value.as_f64().map(FlagValue::Float),
+            FlagType::String => value.as_str().map(|s| FlagValue::String(s.to_string())),
+            FlagType::Json => Some(FlagValue::Json(value)),
+        }
+    }
+
+    /// Parses user input, such as a command line argument, as a value of `flag_type`.
+    pub fn parse(flag_type: FlagType, input: &str) -> Result<FlagValue, FeatureFlagError> {
+        let invalid = || {
+            FeatureFlagError::Validation(format!("'{}' is not a valid {} value", input, flag_type))
+        };
+
+        match flag_type {
+            FlagType::Bool => input.parse().map(FlagValue::Bool).map_err(|_| invalid()),
+            FlagType::Integer => input.parse().map(FlagValue::Integer).map_err(|_| invalid()),
+            FlagType::Float => input
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .map(FlagValue::Float)
+                .ok_or_else(invalid),
+            FlagType::String => Ok(FlagValue::String(input.to_string())),
+            FlagType::Json => serde_json::from_str(input)
+                .map(FlagValue::Json)
+                .map_err(|_| invalid()),
+        }
+    }
+}
+
+impl fmt::Display for FlagValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlagValue::Bool(value) => write!(f, "{}", value),
+            FlagValue::Integer(value) => write!(f, "{}", value),
+            FlagValue::Float(value) => write!(f, "{}", value),
+            FlagValue::String(value) => write!(f, "{}", value),
+            FlagValue::Json(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_deserialize_untagged() {
+        let cases = vec![
+            (json!(true), FlagValue::Bool(true)),
+            (json!(30), FlagValue::Integer(30)),
+            (json!(0.5), FlagValue::Float(0.5)),
+            (json!("blue"), FlagValue::String("blue".to_string())),
+            (
+                json!({"theme": "dark"}),
+                FlagValue::Json(json!({"theme": "dark"})),
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let value: FlagValue = serde_json::from_value(input.clone()).unwrap();
+            assert_eq!(value, expected, "Failed input: {:?}", input);
+            assert_eq!(serde_json::to_value(&value).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn test_into_type() {
+        assert_eq!(
+            FlagValue::Integer(2).into_type(FlagType::Float).unwrap(),
+            FlagValue::Float(2.0)
+        );
+        assert_eq!(
+            FlagValue::String("x".to_string())
+                .into_type(FlagType::Json)
+                .unwrap(),
+            FlagValue::Json(json!("x"))
+        );
+        assert_eq!(
+            format!("{:?}", FlagValue::Bool(true).into_type(FlagType::Integer)),
+            "Err(Validation(\"value true (bool) does not match flag type integer\"))"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            FlagValue::parse(FlagType::Bool, "false").unwrap(),
+            FlagValue::Bool(false)
+        );
+        assert_eq!(
+            FlagValue::parse(FlagType::Integer, "30").unwrap(),
+            FlagValue::Integer(30)
+        );
+        assert_eq!(
+            FlagValue::parse(FlagType::Json, "[1, 2]").unwrap(),
+            FlagValue::Json(json!([1, 2]))
+        );
+        assert!(FlagValue::parse(FlagType::Float, "fast").is_err());
+        assert!(FlagValue::parse(FlagType::Float, "NaN").is_err());
+        assert!(FlagValue::parse(FlagType::Float, "inf").is_err());
+        assert!(FlagValue::Float(f64::NAN)
+            .into_type(FlagType::Float)
+            .is_err());
+        assert!(FlagValue::Float(f64::INFINITY)
+            .into_type(FlagType::Json)
+            .is_err());
+    }
+
+    #[test]
+    fn test_flag_type_from_str() {
+        for flag_type in [
+            FlagType::Bool,
+            FlagType::String,
+            FlagType::Integer,
+            FlagType::Float,
+            FlagType::Json,
+        ] {
+            assert_eq!(flag_type.as_str().parse::<FlagType>().unwrap(), flag_type);
+        }
+
+        assert!("number".parse::<FlagType>().is_err());
+    }
+}